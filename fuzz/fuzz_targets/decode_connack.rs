@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mqtr::v5::PacketType;
+
+fuzz_target!(|data: &[u8]| {
+    mqtr::fuzzy::decode(PacketType::ConnAck, data);
+});
@@ -0,0 +1,362 @@
+//! Module implement admission control for incoming connections.
+//!
+//! Connections are admitted by [Listener][crate::Listener] right after accept, and
+//! before any handshake work, by checking:
+//!
+//! * Accept rate, using a token-bucket, per listener.
+//! * Maximum number of active connections in this node, across all listeners.
+//! * Maximum number of active connections per listener.
+//! * IP allow/deny list, in CIDR notation, per listener.
+//! * Maximum number of active connections from the same source IP address, in this
+//!   node, across all listeners.
+//!
+//! For listeners expecting PROXY protocol header, IP based checks are deferred until
+//! the header is read. PROXY header is accepted only from the listener's trusted
+//! proxies, else a client could spoof its source address past the IP based checks.
+
+use std::sync::{atomic::AtomicU32, atomic::Ordering::SeqCst, Arc};
+use std::{collections::BTreeMap, fmt, net, result, str::FromStr, time};
+
+use crate::{Config, ConfigListener, ProxyProtocol, Spinlock};
+use crate::{Error, ErrorKind, Result};
+
+/// Type book-keeps active connections across all listeners in this node.
+pub struct NodeConns {
+    n_conns: AtomicU32,
+    per_ip: Spinlock<BTreeMap<net::IpAddr, u32>>,
+}
+
+impl Default for NodeConns {
+    fn default() -> NodeConns {
+        NodeConns {
+            n_conns: AtomicU32::new(0),
+            per_ip: Spinlock::new(BTreeMap::default()),
+        }
+    }
+}
+
+impl NodeConns {
+    /// Return the number of active connections in this node.
+    pub fn len(&self) -> u32 {
+        self.n_conns.load(SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of active connections from `ip` in this node.
+    pub fn ip_len(&self, ip: &net::IpAddr) -> u32 {
+        self.per_ip.read().get(ip).copied().unwrap_or(0)
+    }
+}
+
+/// Type book-keeps an active connection accepted by the [Listener][crate::Listener].
+///
+/// Travels along with the connection, from handshake to shard and to flusher, and
+/// when dropped the connection is no longer counted against connection limits.
+pub struct ConnGuard {
+    n_conns: Arc<AtomicU32>,
+    node: Arc<NodeConns>,
+    ip: Option<net::IpAddr>,
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.n_conns.fetch_sub(1, SeqCst);
+        self.node.n_conns.fetch_sub(1, SeqCst);
+        if let Some(ip) = self.ip.take() {
+            let mut per_ip = self.node.per_ip.write();
+            match per_ip.get_mut(&ip) {
+                Some(n) if *n > 1 => *n -= 1,
+                Some(_) => {
+                    per_ip.remove(&ip);
+                }
+                None => (),
+            }
+        }
+    }
+}
+
+impl ConnGuard {
+    /// Return the source IP address this connection is counted against, if any.
+    pub fn to_ip(&self) -> Option<net::IpAddr> {
+        self.ip
+    }
+}
+
+/// Reason for rejecting a connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Reject {
+    AcceptRate,
+    MaxConnections,
+    ListenerMaxConnections,
+    Denied,
+    MaxConnectionsPerIp,
+    UntrustedProxy,
+}
+
+/// Type implement admission control for a single listener.
+pub struct Admission {
+    max_connections: Option<u32>,
+    listener_max_connections: Option<u32>,
+    max_connections_per_ip: Option<u32>,
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    trusted_proxies: Vec<Cidr>,
+    bucket: Option<TokenBucket>,
+
+    n_conns: Arc<AtomicU32>,
+    node: Arc<NodeConns>,
+
+    rejects: BTreeMap<Reject, u64>,
+    reported: BTreeMap<Reject, u64>,
+}
+
+impl Admission {
+    /// Create admission control for listener, `config` is the effective configuration
+    /// for the listener, refer [Config::for_listener].
+    pub fn from_config(
+        config: &Config,
+        listener: &ConfigListener,
+        node: Arc<NodeConns>,
+    ) -> Result<Admission> {
+        let allow: Vec<Cidr> =
+            listener.allow.iter().map(|s| s.parse()).collect::<Result<_>>()?;
+        let deny: Vec<Cidr> =
+            listener.deny.iter().map(|s| s.parse()).collect::<Result<_>>()?;
+        let trusted_proxies: Vec<Cidr> =
+            listener.trusted_proxies.iter().map(|s| s.parse()).collect::<Result<_>>()?;
+
+        let bucket = match (listener.accept_rate(), listener.accept_burst()) {
+            (Some(0), _) => err!(InvalidInput, desc: "accept_rate can't be ZERO")?,
+            (Some(rate), Some(burst)) => Some(TokenBucket::new(rate, burst)),
+            (_, _) => None,
+        };
+
+        let val = Admission {
+            max_connections: config.max_connections(),
+            listener_max_connections: listener.max_connections(),
+            max_connections_per_ip: config.max_connections_per_ip(),
+            allow,
+            deny,
+            trusted_proxies,
+            bucket,
+
+            n_conns: Arc::new(AtomicU32::new(0)),
+            node,
+
+            rejects: BTreeMap::default(),
+            reported: BTreeMap::default(),
+        };
+
+        Ok(val)
+    }
+
+    /// Admit a new connection against accept-rate and connection limits. IP based
+    /// checks are done by [Admission::admit_ip].
+    pub fn admit(&mut self) -> result::Result<ConnGuard, Reject> {
+        let now = time::Instant::now();
+        let rate_ok = match &mut self.bucket {
+            Some(bucket) => bucket.take(now),
+            None => true,
+        };
+        let res = match rate_ok {
+            false => Err(Reject::AcceptRate),
+            _ if is_max(self.max_connections, &self.node.n_conns) => {
+                Err(Reject::MaxConnections)
+            }
+            _ if is_max(self.listener_max_connections, &self.n_conns) => {
+                Err(Reject::ListenerMaxConnections)
+            }
+            _ => {
+                self.n_conns.fetch_add(1, SeqCst);
+                self.node.n_conns.fetch_add(1, SeqCst);
+                let node = Arc::clone(&self.node);
+                Ok(ConnGuard { n_conns: Arc::clone(&self.n_conns), node, ip: None })
+            }
+        };
+
+        res.map_err(|reject| self.reject(reject))
+    }
+
+    /// Admit connection, already admitted via [Admission::admit], from source `ip`
+    /// against allow/deny list and per-IP connection limit.
+    pub fn admit_ip(
+        &mut self,
+        guard: &mut ConnGuard,
+        ip: net::IpAddr,
+    ) -> result::Result<(), Reject> {
+        let ip = ip.to_canonical();
+
+        let denied = self.deny.iter().any(|cidr| cidr.contains(&ip));
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|c| c.contains(&ip));
+        if denied || !allowed {
+            return Err(self.reject(Reject::Denied));
+        }
+
+        let mut per_ip = self.node.per_ip.write();
+        let n = per_ip.get(&ip).copied().unwrap_or(0);
+        match self.max_connections_per_ip {
+            Some(max) if n >= max => {
+                std::mem::drop(per_ip);
+                Err(self.reject(Reject::MaxConnectionsPerIp))
+            }
+            _ => {
+                per_ip.insert(ip, n + 1);
+                guard.ip = Some(ip);
+                Ok(())
+            }
+        }
+    }
+
+    /// Return the PROXY protocol to expect from peer `ip`, for a listener configured
+    /// with `proxy_protocol`. PROXY header is accepted only from trusted proxies,
+    /// other peers are direct clients with `optional` and rejected with `required`.
+    pub fn admit_proxy(
+        &mut self,
+        proxy_protocol: ProxyProtocol,
+        ip: net::IpAddr,
+    ) -> result::Result<ProxyProtocol, Reject> {
+        let trusted = self.trusted_proxies.iter().any(|cidr| cidr.contains(&ip));
+        match proxy_protocol {
+            ProxyProtocol::Disabled => Ok(ProxyProtocol::Disabled),
+            proxy_protocol if trusted => Ok(proxy_protocol),
+            ProxyProtocol::Optional => Ok(ProxyProtocol::Disabled),
+            ProxyProtocol::Required => Err(self.reject(Reject::UntrustedProxy)),
+        }
+    }
+
+    /// Return the number of active connections on this listener.
+    pub fn len(&self) -> u32 {
+        self.n_conns.load(SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return rejection counts, since the listener started, if there are new
+    /// rejections since the last call.
+    pub fn to_report(&mut self) -> Option<BTreeMap<Reject, u64>> {
+        match self.rejects == self.reported {
+            true => None,
+            false => {
+                self.reported = self.rejects.clone();
+                Some(self.rejects.clone())
+            }
+        }
+    }
+
+    fn reject(&mut self, reject: Reject) -> Reject {
+        *self.rejects.entry(reject).or_insert(0) += 1;
+        reject
+    }
+}
+
+fn is_max(max: Option<u32>, n_conns: &AtomicU32) -> bool {
+    match max {
+        Some(max) => n_conns.load(SeqCst) >= max,
+        None => false,
+    }
+}
+
+/// Type implement IP address range in CIDR notation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: net::IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: net::IpAddr =
+            err!(InvalidInput, try: addr.trim().parse(), "bad CIDR address {:?}", s)?;
+        let addr = addr.to_canonical();
+        let max_len = match addr {
+            net::IpAddr::V4(_) => 32,
+            net::IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(len) => {
+                err!(InvalidInput, try: len.trim().parse::<u8>(), "bad CIDR {:?}", s)?
+            }
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            err!(InvalidInput, desc: "bad CIDR prefix length {:?}", s)?;
+        }
+
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl Cidr {
+    /// Return whether `ip` falls within this range. IPv4-mapped IPv6 addresses are
+    /// matched as IPv4 addresses.
+    pub fn contains(&self, ip: &net::IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (net::IpAddr::V4(net), net::IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                (u32::from(net) & mask) == (u32::from(ip) & mask)
+            }
+            (net::IpAddr::V6(net), net::IpAddr::V6(ip)) => {
+                let mask =
+                    u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                (u128::from(net) & mask) == (u128::from(ip) & mask)
+            }
+            (_, _) => false,
+        }
+    }
+}
+
+/// Type implement token-bucket rate limiter.
+pub struct TokenBucket {
+    rate: f64,  // tokens per second.
+    burst: f64, // maximum tokens in bucket.
+    tokens: f64,
+    last: time::Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u32, burst: u32) -> TokenBucket {
+        let burst = f64::from(burst.max(1));
+        TokenBucket {
+            rate: f64::from(rate),
+            burst,
+            tokens: burst,
+            last: time::Instant::now(),
+        }
+    }
+
+    /// Take a token from the bucket, return false if bucket is empty.
+    pub fn take(&mut self, now: time::Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        match self.tokens >= 1.0 {
+            true => {
+                self.tokens -= 1.0;
+                true
+            }
+            false => false,
+        }
+    }
+}
+
+#[cfg(test)]
+#[path = "admission_test.rs"]
+mod admission_test;
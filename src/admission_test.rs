@@ -0,0 +1,120 @@
+use super::*;
+
+#[test]
+fn test_cidr() {
+    let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert_eq!(cidr.to_string(), "10.1.0.0/16");
+    assert!(cidr.contains(&"10.1.2.3".parse().unwrap()));
+    assert!(!cidr.contains(&"10.2.2.3".parse().unwrap()));
+    // IPv4-mapped IPv6 address matches as IPv4 address.
+    assert!(cidr.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!cidr.contains(&"2001:db8::1".parse().unwrap()));
+
+    let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+    assert!(cidr.contains(&"2001:db8:1::1".parse().unwrap()));
+    assert!(!cidr.contains(&"2001:db9::1".parse().unwrap()));
+    assert!(!cidr.contains(&"10.1.2.3".parse().unwrap()));
+
+    let cidr: Cidr = "192.168.1.1".parse().unwrap();
+    assert_eq!(cidr.to_string(), "192.168.1.1/32");
+    assert!(cidr.contains(&"192.168.1.1".parse().unwrap()));
+    assert!(!cidr.contains(&"192.168.1.2".parse().unwrap()));
+
+    let cidr: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(cidr.contains(&"1.2.3.4".parse().unwrap()));
+
+    assert!("10.1.0.0/33".parse::<Cidr>().is_err());
+    assert!("::/129".parse::<Cidr>().is_err());
+    assert!("10.1.0/8".parse::<Cidr>().is_err());
+    assert!("10.1.0.0/x".parse::<Cidr>().is_err());
+}
+
+#[test]
+fn test_token_bucket() {
+    let mut bucket = TokenBucket::new(10, 2);
+    let now = bucket.last;
+    assert!(bucket.take(now));
+    assert!(bucket.take(now));
+    assert!(!bucket.take(now));
+
+    let now = now + time::Duration::from_millis(100);
+    assert!(bucket.take(now));
+    assert!(!bucket.take(now));
+
+    // bucket does not fill beyond burst.
+    let now = now + time::Duration::from_secs(10);
+    assert!(bucket.take(now));
+    assert!(bucket.take(now));
+    assert!(!bucket.take(now));
+}
+
+#[test]
+fn test_admission() {
+    let node = Arc::new(NodeConns::default());
+    let config = Config {
+        max_connections: Some(3),
+        max_connections_per_ip: Some(1),
+        ..Config::default()
+    };
+    let listener = ConfigListener {
+        max_connections: Some(2),
+        deny: vec!["10.0.0.0/8".to_string()],
+        ..ConfigListener::default()
+    };
+    let mut adm1 = Admission::from_config(&config, &listener, Arc::clone(&node)).unwrap();
+    let mut adm2 = Admission::from_config(&config, &listener, Arc::clone(&node)).unwrap();
+
+    let ip1: net::IpAddr = "192.168.1.1".parse().unwrap();
+    let ip2: net::IpAddr = "::ffff:192.168.1.2".parse().unwrap();
+
+    let mut g1 = adm1.admit().unwrap();
+    adm1.admit_ip(&mut g1, ip1).unwrap();
+    let mut g2 = adm1.admit().unwrap();
+    assert_eq!(adm1.admit_ip(&mut g2, ip1), Err(Reject::MaxConnectionsPerIp));
+    adm1.admit_ip(&mut g2, ip2).unwrap();
+    assert_eq!(g2.to_ip(), Some("192.168.1.2".parse().unwrap()));
+    assert_eq!(adm1.admit().err(), Some(Reject::ListenerMaxConnections));
+
+    let mut g3 = adm2.admit().unwrap();
+    assert_eq!(adm2.admit_ip(&mut g3, "10.1.1.1".parse().unwrap()), Err(Reject::Denied));
+    assert_eq!(adm2.admit().err(), Some(Reject::MaxConnections));
+    assert_eq!((node.len(), adm1.len(), adm2.len()), (3, 2, 1));
+
+    std::mem::drop(g1);
+    assert_eq!((node.len(), node.ip_len(&ip1)), (2, 0));
+    std::mem::drop(g2);
+    std::mem::drop(g3);
+    assert_eq!((node.len(), adm1.len(), adm2.len()), (0, 0, 0));
+
+    let report = adm1.to_report().unwrap();
+    assert_eq!(report.get(&Reject::MaxConnectionsPerIp), Some(&1));
+    assert_eq!(report.get(&Reject::ListenerMaxConnections), Some(&1));
+    assert_eq!(adm1.to_report(), None);
+
+    let listener = ConfigListener { accept_rate: Some(0), ..ConfigListener::default() };
+    assert!(Admission::from_config(&config, &listener, node).is_err());
+}
+
+#[test]
+fn test_admit_proxy() {
+    use ProxyProtocol::*;
+
+    let node = Arc::new(NodeConns::default());
+    let listener = ConfigListener {
+        trusted_proxies: vec!["10.0.0.0/8".to_string()],
+        ..ConfigListener::default()
+    };
+    let mut adm = Admission::from_config(&Config::default(), &listener, node).unwrap();
+
+    let (proxy, client): (net::IpAddr, net::IpAddr) =
+        ("10.1.1.1".parse().unwrap(), "192.168.1.1".parse().unwrap());
+    assert_eq!(adm.admit_proxy(Required, proxy), Ok(Required));
+    assert_eq!(adm.admit_proxy(Optional, proxy), Ok(Optional));
+    assert_eq!(adm.admit_proxy(Disabled, proxy), Ok(Disabled));
+    // PROXY header from a direct client is never read.
+    assert_eq!(adm.admit_proxy(Optional, client), Ok(Disabled));
+    assert_eq!(adm.admit_proxy(Required, client), Err(Reject::UntrustedProxy));
+
+    let report = adm.to_report().unwrap();
+    assert_eq!(report.get(&Reject::UntrustedProxy), Some(&1));
+}
@@ -0,0 +1,258 @@
+use structopt::StructOpt;
+
+use std::sync::{Arc, Barrier};
+use std::{thread, time};
+
+use mqtr::client::Client;
+use mqtr::v5::{self, RetainForwardRule, SubscribeFilter, SubscriptionOpt};
+use mqtr::{Bytes, ClientID, Result, TopicFilter, TopicName};
+
+use crate::pubsub::{invalid_arg, to_qos, ConnOpt};
+
+/// User property carrying the publish timestamp, in nanoseconds since UNIX_EPOCH.
+const TS_PROP: &str = "mqcl-bench-ts";
+
+#[derive(Clone, StructOpt)]
+pub struct BenchOpt {
+    #[structopt(flatten)]
+    conn: ConnOpt,
+
+    /// Number of publisher connections.
+    #[structopt(long = "publishers", default_value = "1")]
+    publishers: usize,
+
+    /// Number of subscriber connections.
+    #[structopt(long = "subscribers", default_value = "1")]
+    subscribers: usize,
+
+    /// Target publish rate, messages per second across all publishers, ZERO
+    /// publishes as fast as possible.
+    #[structopt(long = "rate", default_value = "1000")]
+    rate: u64,
+
+    /// Duration of the benchmark, in seconds.
+    #[structopt(long = "duration", default_value = "10")]
+    duration: u64,
+
+    #[structopt(long = "payload-size", default_value = "64")]
+    payload_size: usize,
+
+    #[structopt(short = "q", long = "qos", default_value = "0")]
+    qos: u8,
+
+    /// Each publisher publishes round-robin on these many topics, named as
+    /// `<prefix>/<publisher>/<n>`.
+    #[structopt(long = "fan-out", default_value = "1")]
+    fan_out: usize,
+
+    #[structopt(long = "prefix", default_value = "bench")]
+    prefix: String,
+
+    /// Topic filter for subscribers, can be repeated, defaults to `<prefix>/#`.
+    #[structopt(long = "filter")]
+    filters: Vec<String>,
+}
+
+impl BenchOpt {
+    fn to_filters(&self) -> Vec<SubscribeFilter> {
+        let filters = match self.filters.len() {
+            0 => vec![format!("{}/#", self.prefix)],
+            _ => self.filters.clone(),
+        };
+        let qos = to_qos(self.qos);
+        filters
+            .into_iter()
+            .map(|filter| SubscribeFilter {
+                topic_filter: TopicFilter::from(filter),
+                opt: SubscriptionOpt::new(RetainForwardRule::Never, false, false, qos),
+            })
+            .collect()
+    }
+
+    fn to_client(&self, client_id: String) -> Result<Client> {
+        let mut args = self.conn.to_client_args();
+        args.client_id = ClientID(client_id);
+        Client::connect(args)
+    }
+}
+
+#[derive(Default)]
+struct PubStats {
+    n_published: u64,
+}
+
+#[derive(Default)]
+struct SubStats {
+    n_received: u64,
+    // end-to-end latency in micro-seconds.
+    latencies: Vec<u64>,
+}
+
+pub fn handle_bench(opt: &BenchOpt) -> Result<()> {
+    if opt.publishers == 0 || opt.fan_out == 0 {
+        invalid_arg("--publishers and --fan-out must be > 0");
+    }
+    to_qos(opt.qos);
+
+    let duration = time::Duration::from_secs(opt.duration);
+    // all connections are setup before publishing starts.
+    let barrier = Arc::new(Barrier::new(opt.publishers + opt.subscribers + 1));
+
+    let mut subscribers = vec![];
+    for i in 0..opt.subscribers {
+        let (opt, barrier) = (opt.clone(), Arc::clone(&barrier));
+        subscribers.push(thread::spawn(move || subscriber(opt, i, barrier)));
+    }
+    let mut publishers = vec![];
+    for i in 0..opt.publishers {
+        let (opt, barrier) = (opt.clone(), Arc::clone(&barrier));
+        publishers.push(thread::spawn(move || publisher(opt, i, barrier)));
+    }
+
+    barrier.wait();
+    let start = time::Instant::now();
+    println!(
+        "bench publishers:{} subscribers:{} rate:{}/s qos:{} payload:{} for {:?}",
+        opt.publishers, opt.subscribers, opt.rate, opt.qos, opt.payload_size, duration
+    );
+
+    let mut pstats = PubStats::default();
+    for handle in publishers.into_iter() {
+        let stats = handle.join().unwrap()?;
+        pstats.n_published += stats.n_published;
+    }
+    let pub_elapsed = start.elapsed();
+
+    let mut sstats = SubStats::default();
+    for handle in subscribers.into_iter() {
+        let mut stats = handle.join().unwrap()?;
+        sstats.n_received += stats.n_received;
+        sstats.latencies.append(&mut stats.latencies);
+    }
+
+    let secs = pub_elapsed.as_secs_f64();
+    println!(
+        "published {} in {:?}, {:.0} msgs/s",
+        pstats.n_published,
+        pub_elapsed,
+        pstats.n_published as f64 / secs
+    );
+    println!(
+        "received  {} in {:?}, {:.0} msgs/s",
+        sstats.n_received,
+        pub_elapsed,
+        sstats.n_received as f64 / secs
+    );
+
+    let lats = &mut sstats.latencies;
+    if !lats.is_empty() {
+        lats.sort_unstable();
+        let pct = |p: f64| lats[((lats.len() - 1) as f64 * p).round() as usize];
+        println!(
+            "latency(us) min:{} p50:{} p90:{} p99:{} p99.9:{} max:{}",
+            lats[0],
+            pct(0.5),
+            pct(0.9),
+            pct(0.99),
+            pct(0.999),
+            lats[lats.len() - 1]
+        );
+    }
+
+    Ok(())
+}
+
+fn publisher(opt: BenchOpt, id: usize, barrier: Arc<Barrier>) -> Result<PubStats> {
+    let client = opt.to_client(format!("{}-pub-{}", opt.prefix, id));
+    barrier.wait();
+    let mut client = client?;
+
+    let topics: Vec<TopicName> = (0..opt.fan_out)
+        .map(|n| TopicName::from(format!("{}/{}/{}", opt.prefix, id, n)))
+        .collect();
+    let interval = match opt.rate {
+        0 => time::Duration::ZERO,
+        rate => time::Duration::from_secs(opt.publishers as u64) / (rate as u32),
+    };
+    let payload = Bytes::from(vec![0xAB_u8; opt.payload_size]);
+
+    let mut stats = PubStats::default();
+    let start = time::Instant::now();
+    let end = start + time::Duration::from_secs(opt.duration);
+    let mut next_at = start;
+    loop {
+        let now = time::Instant::now();
+        if now >= end {
+            break;
+        } else if now < next_at {
+            // keep the connection alive while pacing.
+            client.recv(Some(next_at - now))?;
+            continue;
+        }
+
+        let props = v5::PublishProperties {
+            user_properties: vec![(TS_PROP.to_string(), to_unix_nanos().to_string())],
+            ..v5::PublishProperties::default()
+        };
+        let publ = v5::Publish {
+            retain: false,
+            qos: to_qos(opt.qos),
+            duplicate: false,
+            topic_name: topics[(stats.n_published as usize) % topics.len()].clone(),
+            packet_id: None,
+            properties: Some(props),
+            payload: Some(payload.clone()),
+        };
+        client.publish(publ)?;
+        stats.n_published += 1;
+        next_at += interval;
+    }
+
+    client.wait_inflight(Some(time::Duration::from_secs(10)))?;
+    client.disconnect(v5::DisconnReasonCode::NormalDisconnect)?;
+
+    Ok(stats)
+}
+
+fn subscriber(opt: BenchOpt, id: usize, barrier: Arc<Barrier>) -> Result<SubStats> {
+    // drain messages that are in-flight after publishers are done.
+    const DRAIN: time::Duration = time::Duration::from_secs(2);
+
+    let client = opt
+        .to_client(format!("{}-sub-{}", opt.prefix, id))
+        .and_then(|mut client| client.subscribe(opt.to_filters(), None).map(|_| client));
+    barrier.wait();
+    let mut client = client?;
+
+    let mut stats = SubStats::default();
+    let end = time::Instant::now() + time::Duration::from_secs(opt.duration) + DRAIN;
+    loop {
+        let now = time::Instant::now();
+        if now >= end {
+            break;
+        }
+        let publ = match client.recv(Some(end - now))? {
+            Some(publ) => publ,
+            None => continue,
+        };
+        stats.n_received += 1;
+
+        let props = publ.properties.as_ref();
+        let ts = props
+            .and_then(|p| p.user_properties.iter().find(|(k, _)| k == TS_PROP))
+            .and_then(|(_, v)| v.parse::<u128>().ok());
+        if let Some(ts) = ts {
+            let lat = to_unix_nanos().saturating_sub(ts) / 1000;
+            stats.latencies.push(lat as u64);
+        }
+    }
+
+    client.disconnect(v5::DisconnReasonCode::NormalDisconnect)?;
+
+    Ok(stats)
+}
+
+fn to_unix_nanos() -> u128 {
+    let now = time::SystemTime::now();
+    now.duration_since(time::UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
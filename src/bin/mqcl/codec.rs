@@ -0,0 +1,352 @@
+use base64::Engine;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use std::io::{self, Read, Write};
+use std::{fs, path, process::exit};
+
+use mqtr::v5::{self, FixedHeader, PacketType, Property, QoS};
+use mqtr::VarU32;
+use mqtr::{Packetize, Result};
+
+use crate::pubsub::{invalid_arg, to_hex};
+
+#[derive(Clone, StructOpt)]
+pub struct DecodeOpt {
+    /// Encoded packets, if not supplied read from --file or stdin.
+    data: Option<String>,
+
+    /// Read encoded packets from file.
+    #[structopt(short = "f", long = "file")]
+    file: Option<path::PathBuf>,
+
+    /// Input encoding, one of hex, base64, bin. Defaults to hex for command line
+    /// argument and bin for file and stdin.
+    #[structopt(long = "input")]
+    input: Option<Encoding>,
+}
+
+#[derive(Clone, StructOpt)]
+pub struct EncodeOpt {
+    /// JSON or TOML file describing the packets, if not supplied read from stdin.
+    file: Option<path::PathBuf>,
+
+    /// Description format, one of json, toml. Defaults to file's extension, else
+    /// json.
+    #[structopt(long = "input")]
+    input: Option<String>,
+
+    /// Output encoding, one of hex, base64, bin.
+    #[structopt(long = "output", default_value = "hex")]
+    output: Encoding,
+
+    /// Skip validating the packets, to craft malformed packets for testing.
+    #[structopt(long = "no-validate")]
+    no_validate: bool,
+}
+
+#[derive(Clone, Copy)]
+pub enum Encoding {
+    Hex,
+    Base64,
+    Bin,
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Encoding, String> {
+        match s {
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            "bin" => Ok(Encoding::Bin),
+            s => Err(format!("invalid encoding {:?}, one of hex, base64, bin", s)),
+        }
+    }
+}
+
+pub fn handle_decode(opt: &DecodeOpt) -> Result<()> {
+    let (data, encoding) = match (&opt.data, &opt.file) {
+        (Some(data), None) => (data.as_bytes().to_vec(), Encoding::Hex),
+        (None, Some(file)) => match fs::read(file) {
+            Ok(data) => (data, Encoding::Bin),
+            Err(err) => invalid_arg(format!("reading {:?}: {}", file, err)),
+        },
+        (None, None) => {
+            let mut data = vec![];
+            if let Err(err) = io::stdin().read_to_end(&mut data) {
+                invalid_arg(format!("reading stdin: {}", err))
+            }
+            (data, Encoding::Bin)
+        }
+        (Some(_), Some(_)) => invalid_arg("supply either data or --file"),
+    };
+    let data = match opt.input.unwrap_or(encoding) {
+        Encoding::Bin => data,
+        Encoding::Hex => from_hex(&String::from_utf8_lossy(&data)),
+        Encoding::Base64 => {
+            let s: String = String::from_utf8_lossy(&data).split_whitespace().collect();
+            match base64::engine::general_purpose::STANDARD.decode(s) {
+                Ok(data) => data,
+                Err(err) => invalid_arg(format!("invalid base64: {}", err)),
+            }
+        }
+    };
+
+    let mut off = 0;
+    while off < data.len() {
+        match decode_packet(&data, off) {
+            Ok(n) => off += n,
+            Err(msg) => {
+                println!("error: {}", msg);
+                exit(1);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Annotate and decode the packet starting at `off`, return the packet size.
+fn decode_packet(data: &[u8], off: usize) -> std::result::Result<usize, String> {
+    let (fh, fh_len) = match FixedHeader::decode(&data[off..]) {
+        Ok(val) => val,
+        Err(err) => Err(format!("fixed-header at byte {}: {}", off, err))?,
+    };
+    let (pkt_type, retain, qos, dup) = fh.unwrap();
+    let size = fh_len + (*fh.remaining_len as usize);
+
+    println!("packet {:?} at [{}..{}], {} bytes", pkt_type, off, off + size, size);
+    println!(
+        "  fixed-header   [{}]  {:#010b}  type:{} dup:{} qos:{} retain:{}",
+        off,
+        fh.byte1,
+        fh.byte1 >> 4,
+        dup,
+        u8::from(qos),
+        retain
+    );
+    println!("  remaining-len  [{}..{}]  {}", off + 1, off + fh_len, *fh.remaining_len);
+    if data.len() < off + size {
+        Err(format!(
+            "packet at byte {} needs {} bytes, only {} available",
+            off,
+            size,
+            data.len() - off
+        ))?;
+    }
+
+    let data = &data[..off + size];
+    let (vh, end) = (off + fh_len, off + size);
+    match pkt_type {
+        PacketType::Connect => {
+            // protocol-name, protocol-version, flags, keep-alive.
+            let name_len = read_u16(data, vh)? as usize;
+            let flags = read_u8(data, vh + 2 + name_len + 1)?;
+            let n = annotate_props(data, vh + 2 + name_len + 4, "properties")?;
+            let n = n + 2 + read_u16(data, n)? as usize; // client-id
+            if (flags & 0b0000_0100) > 0 {
+                annotate_props(data, n, "will-properties")?;
+            }
+        }
+        PacketType::Publish => {
+            let mut n = vh + 2 + read_u16(data, vh)? as usize; // topic-name
+            if qos != QoS::AtMostOnce {
+                n += 2;
+            }
+            let n = annotate_props(data, n, "properties")?;
+            let payload = &data[n.min(end)..end];
+            println!("  payload        [{}..{}]  {} bytes", n, end, payload.len());
+            if !payload.is_empty() {
+                println!("    hex  {}", to_hex(payload));
+                if let Ok(s) = std::str::from_utf8(payload) {
+                    println!("    utf8 {:?}", s);
+                }
+            }
+        }
+        PacketType::PubAck
+        | PacketType::PubRec
+        | PacketType::PubRel
+        | PacketType::PubComp
+            if end > vh + 3 =>
+        {
+            annotate_props(data, vh + 3, "properties")?;
+        }
+        PacketType::ConnAck
+        | PacketType::Subscribe
+        | PacketType::SubAck
+        | PacketType::UnSubscribe
+        | PacketType::UnsubAck => {
+            annotate_props(data, vh + 2, "properties")?;
+        }
+        PacketType::Disconnect | PacketType::Auth if end > vh + 1 => {
+            annotate_props(data, vh + 1, "properties")?;
+        }
+        _ => (),
+    }
+
+    match v5::Packet::decode(&data[off..]) {
+        Ok((pkt, _)) => {
+            // same form as accepted by `mqcl encode`.
+            let tree = match serde_json::to_string_pretty(&pkt) {
+                Ok(tree) => tree,
+                Err(err) => Err(format!("packet at byte {}: {}", off, err))?,
+            };
+            println!("  decoded");
+            for line in tree.lines() {
+                println!("    {}", line);
+            }
+        }
+        Err(err) => Err(format!("packet at byte {}: {}", off, err))?,
+    }
+
+    Ok(size)
+}
+
+// Annotate the property block at `off`, return the offset after the block.
+fn annotate_props(
+    data: &[u8],
+    off: usize,
+    name: &str,
+) -> std::result::Result<usize, String> {
+    let (len, n) = match VarU32::decode(data.get(off..).unwrap_or(&[])) {
+        Ok(val) => val,
+        Err(err) => Err(format!("{} length at byte {}: {}", name, off, err))?,
+    };
+    let (start, end) = (off + n, off + n + (*len as usize));
+    println!("  {:14} [{}..{}]  {} bytes", name, off, end, *len);
+    if end > data.len() {
+        Err(format!(
+            "{} at byte {} overflows the packet by {}",
+            name,
+            off,
+            end - data.len()
+        ))?;
+    }
+
+    let mut n = start;
+    while n < end {
+        match Property::decode(&data[n..end]) {
+            Ok((prop, m)) => {
+                println!(
+                    "    [{}..{}] {:?} {:?}",
+                    n,
+                    n + m,
+                    prop.to_property_type(),
+                    prop
+                );
+                n += m;
+            }
+            Err(err) => {
+                let id = data[n];
+                Err(format!("property (id {:#04x}) at byte {}: {}", id, n, err))?
+            }
+        }
+    }
+
+    Ok(end)
+}
+
+fn read_u8(data: &[u8], off: usize) -> std::result::Result<u8, String> {
+    match data.get(off) {
+        Some(val) => Ok(*val),
+        None => Err(format!("insufficient bytes at byte {}", off)),
+    }
+}
+
+fn read_u16(data: &[u8], off: usize) -> std::result::Result<u16, String> {
+    Ok(u16::from_be_bytes([read_u8(data, off)?, read_u8(data, off + 1)?]))
+}
+
+fn from_hex(s: &str) -> Vec<u8> {
+    // tolerate whitespace, `0x` prefix and `:` separators found in dumps.
+    let s = s.trim().trim_start_matches("0x");
+    let s: Vec<char> = s.chars().filter(|ch| !ch.is_whitespace() && *ch != ':').collect();
+    if s.len() % 2 != 0 {
+        invalid_arg("invalid hex, odd number of digits")
+    }
+
+    let mut data = Vec::with_capacity(s.len() / 2);
+    for (i, pair) in s.chunks(2).enumerate() {
+        let pair: String = pair.iter().collect();
+        match u8::from_str_radix(&pair, 16) {
+            Ok(byte) => data.push(byte),
+            Err(_) => invalid_arg(format!("invalid hex {:?} at byte {}", pair, i)),
+        }
+    }
+    data
+}
+
+pub fn handle_encode(opt: &EncodeOpt) -> Result<()> {
+    let (text, ext) = match &opt.file {
+        Some(file) => match fs::read_to_string(file) {
+            Ok(text) => {
+                let ext = file.extension().map(|e| e.to_string_lossy().to_string());
+                (text, ext)
+            }
+            Err(err) => invalid_arg(format!("reading {:?}: {}", file, err)),
+        },
+        None => {
+            let mut text = String::default();
+            if let Err(err) = io::stdin().read_to_string(&mut text) {
+                invalid_arg(format!("reading stdin: {}", err))
+            }
+            (text, None)
+        }
+    };
+
+    let pkts: Vec<v5::Packet> = match opt.input.clone().or(ext).as_deref() {
+        Some("toml") => match toml::from_str::<Packets>(&text) {
+            Ok(pkts) => pkts.packet,
+            Err(err) => invalid_arg(format!("invalid toml: {}", err)),
+        },
+        Some("json") | None => {
+            let res =
+                serde_json::from_str::<serde_json::Value>(&text).and_then(
+                    |val| match val {
+                        serde_json::Value::Array(_) => serde_json::from_value(val),
+                        val => serde_json::from_value(val).map(|pkt| vec![pkt]),
+                    },
+                );
+            match res {
+                Ok(pkts) => pkts,
+                Err(err) => invalid_arg(format!("invalid json: {}", err)),
+            }
+        }
+        Some(input) => {
+            invalid_arg(format!("invalid input {:?}, one of json, toml", input))
+        }
+    };
+
+    let mut data = vec![];
+    for pkt in pkts.into_iter() {
+        let blob = match opt.no_validate {
+            true => pkt.encode_unchecked()?,
+            false => pkt.encode()?,
+        };
+        data.extend_from_slice(blob.as_ref());
+    }
+
+    let mut stdout = io::stdout().lock();
+    let res = match opt.output {
+        Encoding::Hex => writeln!(stdout, "{}", to_hex(&data)),
+        Encoding::Base64 => {
+            let s = base64::engine::general_purpose::STANDARD.encode(&data);
+            writeln!(stdout, "{}", s)
+        }
+        Encoding::Bin => stdout.write_all(&data),
+    };
+    if let Err(err) = res {
+        invalid_arg(format!("writing to stdout: {}", err))
+    }
+
+    Ok(())
+}
+
+// Packets are described in the same form as printed by `mqcl decode`, refer
+// [v5::serde_util]. TOML description is a list of packets as `packet = [...]`, JSON
+// description is either a single packet or a list of packets.
+#[derive(Deserialize)]
+struct Packets {
+    packet: Vec<v5::Packet>,
+}
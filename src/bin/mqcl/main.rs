@@ -4,6 +4,11 @@ use std::{path, process::exit};
 
 use mqtr::{Config, Error, Result};
 
+mod bench;
+mod codec;
+mod pubsub;
+mod snapshot;
+
 // TODO: Handle CTRL-C to exit the cluster.
 
 #[derive(Clone, StructOpt)]
@@ -23,12 +28,39 @@ pub enum SubCommand {
 
         #[structopt(long = "port")]
         port: Option<u16>,
+
+        /// Uuid of this node, from `nodes` in config file. Defaults to the first node.
+        #[structopt(long = "node")]
+        node: Option<String>,
+
+        /// Load subscriptions and retained messages from snapshot file.
+        #[structopt(long = "import")]
+        import: Option<path::PathBuf>,
+
+        /// Periodically save subscriptions and retained messages to snapshot file.
+        #[structopt(long = "export")]
+        export: Option<path::PathBuf>,
+
+        /// Interval, in seconds, between snapshots saved with --export.
+        #[structopt(long = "export-interval", default_value = "60")]
+        export_interval: u64,
     },
+    /// Publish a message to broker.
+    Pub(pubsub::PubOpt),
+    /// Subscribe to topic-filters and print the received messages.
+    Sub(pubsub::SubOpt),
+    /// Load broker with publishers and subscribers, report throughput and latency.
+    Bench(bench::BenchOpt),
+    /// Decode MQTT packets and print them as annotated tree.
+    Decode(codec::DecodeOpt),
+    /// Encode MQTT packets from JSON or TOML description.
+    Encode(codec::EncodeOpt),
+    /// Inspect a snapshot of subscriptions and retained messages.
+    Snapshot(snapshot::SnapshotOpt),
 }
 
 fn main() {
     setup_logging();
-    println!("{:?}", mqtr::v5::PropertyType::UserProp);
 
     let opts = Opt::from_args();
 
@@ -45,19 +77,73 @@ fn main() {
 
     let res: Result<()> = match &opts.subcmd {
         SubCommand::Start { .. } => handle_start(&opts, config),
+        SubCommand::Pub(opt) => pubsub::handle_pub(opt),
+        SubCommand::Sub(opt) => pubsub::handle_sub(opt),
+        SubCommand::Bench(opt) => bench::handle_bench(opt),
+        SubCommand::Decode(opt) => codec::handle_decode(opt),
+        SubCommand::Encode(opt) => codec::handle_encode(opt),
+        SubCommand::Snapshot(opt) => snapshot::handle_snapshot(opt),
     };
 
-    res.map_err(|err: Error| println!("unexpected error: {}", err)).ok();
+    if let Err(err) = res {
+        let err: Error = err;
+        println!("unexpected error: {}", err);
+        exit(1);
+    }
 }
 
-#[allow(unreachable_code)]
 fn handle_start(opts: &Opt, config: Config) -> Result<()> {
-    use mqtr::Cluster;
+    use mqtr::{Cluster, Node, Snapshot};
+    use std::{sync::mpsc, time};
 
     let config = setup_config(config, opts);
-    let _cluster = Cluster::from_config(config.clone())?.spawn()?;
+    let config_node = match &opts.subcmd {
+        SubCommand::Start { node: Some(uuid), .. } => {
+            match config.nodes.iter().find(|n| n.uuid.as_ref() == Some(uuid)) {
+                Some(config_node) => Some(config_node),
+                None => {
+                    println!("node {} not found in config file", uuid);
+                    exit(1);
+                }
+            }
+        }
+        _ => config.nodes.first(),
+    };
+    let node = match config_node {
+        Some(config_node) => Node::try_from(config_node.clone())?,
+        None => Node::default(),
+    };
+
+    // cluster reports to application via app_tx, block here until cluster exits.
+    let (app_tx, app_rx) = mpsc::sync_channel(1024);
+    let cluster = Cluster::from_config(config.clone())?.spawn(node, app_tx)?;
+
+    let (export, interval) = match &opts.subcmd {
+        SubCommand::Start { import, export, export_interval, .. } => {
+            if let Some(loc) = import {
+                cluster.import_snapshot(Snapshot::load(loc)?)?;
+            }
+            (export.clone(), time::Duration::from_secs(*export_interval))
+        }
+        _ => unreachable!(),
+    };
+
+    let mut last_export = time::Instant::now();
+    loop {
+        match app_rx.recv_timeout(interval) {
+            Ok(msg) => println!("{}", msg),
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if let Some(loc) = export.as_ref().filter(|_| last_export.elapsed() >= interval) {
+            if let Err(err) = cluster.export_snapshot().and_then(|s| s.save(loc)) {
+                println!("export snapshot to {:?}: {}", loc, err);
+            }
+            last_export = time::Instant::now();
+        }
+    }
 
-    loop {}
+    Ok(())
 }
 
 fn setup_config(mut config: Config, opts: &Opt) -> Config {
@@ -68,7 +154,7 @@ fn setup_config(mut config: Config, opts: &Opt) -> Config {
     // c. Toml configuration file.
     // d. System defaults.
     match &opts.subcmd {
-        SubCommand::Start { name, port } => {
+        SubCommand::Start { name, port, .. } => {
             if let Some(name) = name {
                 config.name = name.clone();
             }
@@ -76,6 +162,7 @@ fn setup_config(mut config: Config, opts: &Opt) -> Config {
                 config.port = Some(port.clone())
             }
         }
+        _ => (),
     }
 
     config
@@ -0,0 +1,406 @@
+use structopt::StructOpt;
+
+use std::io::{self, Read, Write};
+use std::{fs, path, process::exit, time};
+
+use mqtr::client::{Client, ClientArgs};
+use mqtr::v5::{self, QoS, RetainForwardRule, SubscribeFilter, SubscriptionOpt};
+use mqtr::{ClientID, Result, TopicFilter, TopicName, VarU32};
+
+/// Options to connect with the broker, common to all client subcommands.
+#[derive(Clone, StructOpt)]
+pub struct ConnOpt {
+    #[structopt(long = "host", default_value = "127.0.0.1")]
+    host: String,
+
+    #[structopt(long = "port", default_value = "1883")]
+    port: u16,
+
+    /// Client identifier, if not supplied broker shall assign one.
+    #[structopt(long = "client-id")]
+    client_id: Option<String>,
+
+    /// Keep-alive interval in seconds.
+    #[structopt(long = "keep-alive", default_value = "60")]
+    keep_alive: u16,
+
+    /// Session expiry interval in seconds, resume session with --no-clean-start.
+    #[structopt(long = "session-expiry")]
+    session_expiry: Option<u32>,
+
+    #[structopt(long = "no-clean-start")]
+    no_clean_start: bool,
+
+    #[structopt(long = "username")]
+    username: Option<String>,
+
+    #[structopt(long = "password")]
+    password: Option<String>,
+}
+
+impl ConnOpt {
+    pub fn to_client_args(&self) -> ClientArgs {
+        use std::net::ToSocketAddrs;
+
+        let addr = format!("{}:{}", self.host, self.port);
+        let address = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(address)) => address,
+            Ok(None) => invalid_arg(format!("no address for {}", addr)),
+            Err(err) => invalid_arg(format!("invalid address {}: {}", addr, err)),
+        };
+
+        let properties = self.session_expiry.map(|secs| v5::ConnectProperties {
+            session_expiry_interval: Some(secs),
+            ..v5::ConnectProperties::default()
+        });
+
+        ClientArgs {
+            address,
+            client_id: ClientID(self.client_id.clone().unwrap_or_default()),
+            clean_start: !self.no_clean_start,
+            keep_alive: self.keep_alive,
+            properties,
+            user_name: self.username.clone(),
+            password: self.password.clone(),
+            ..ClientArgs::default()
+        }
+    }
+}
+
+#[derive(Clone, StructOpt)]
+pub struct PubOpt {
+    #[structopt(flatten)]
+    conn: ConnOpt,
+
+    #[structopt(short = "t", long = "topic")]
+    topic: String,
+
+    #[structopt(short = "q", long = "qos", default_value = "0")]
+    qos: u8,
+
+    #[structopt(short = "r", long = "retain")]
+    retain: bool,
+
+    /// Payload as command line argument.
+    #[structopt(short = "m", long = "message")]
+    message: Option<String>,
+
+    /// Payload from file.
+    #[structopt(short = "f", long = "file")]
+    file: Option<path::PathBuf>,
+
+    /// Payload from stdin.
+    #[structopt(short = "s", long = "stdin")]
+    stdin: bool,
+
+    /// Number of times to publish the message.
+    #[structopt(short = "n", long = "count", default_value = "1")]
+    count: usize,
+
+    /// Interval between publish, in milliseconds.
+    #[structopt(long = "interval", default_value = "0")]
+    interval: u64,
+
+    /// User property as key=value, can be repeated.
+    #[structopt(long = "user-prop")]
+    user_props: Vec<String>,
+
+    #[structopt(long = "content-type")]
+    content_type: Option<String>,
+
+    #[structopt(long = "response-topic")]
+    response_topic: Option<String>,
+
+    #[structopt(long = "correlation-data")]
+    correlation_data: Option<String>,
+
+    /// Message expiry interval in seconds.
+    #[structopt(long = "message-expiry")]
+    message_expiry: Option<u32>,
+
+    /// Mark payload as UTF-8 encoded.
+    #[structopt(long = "utf8")]
+    utf8: bool,
+}
+
+impl PubOpt {
+    fn to_payload(&self) -> Vec<u8> {
+        match (&self.message, &self.file, self.stdin) {
+            (Some(message), None, false) => message.as_bytes().to_vec(),
+            (None, Some(file), false) => match fs::read(file) {
+                Ok(data) => data,
+                Err(err) => invalid_arg(format!("reading {:?}: {}", file, err)),
+            },
+            (None, None, true) => {
+                let mut data = vec![];
+                if let Err(err) = io::stdin().read_to_end(&mut data) {
+                    invalid_arg(format!("reading stdin: {}", err))
+                }
+                data
+            }
+            (_, _, _) => invalid_arg("supply one of --message, --file, --stdin"),
+        }
+    }
+
+    fn to_properties(&self) -> Option<v5::PublishProperties> {
+        let props = v5::PublishProperties {
+            payload_format_indicator: match self.utf8 {
+                true => v5::PayloadFormat::Utf8,
+                false => v5::PayloadFormat::Binary,
+            },
+            message_expiry_interval: self.message_expiry,
+            response_topic: self.response_topic.clone().map(TopicName::from),
+            correlation_data: self.correlation_data.clone().map(|s| s.into_bytes()),
+            content_type: self.content_type.clone(),
+            user_properties: self.user_props.iter().map(|s| to_user_prop(s)).collect(),
+            ..v5::PublishProperties::default()
+        };
+
+        match props == v5::PublishProperties::default() {
+            true => None,
+            false => Some(props),
+        }
+    }
+}
+
+#[derive(Clone, StructOpt)]
+pub struct SubOpt {
+    #[structopt(flatten)]
+    conn: ConnOpt,
+
+    /// Topic filter, can be repeated.
+    #[structopt(short = "t", long = "topic", required = true)]
+    topics: Vec<String>,
+
+    /// Maximum QoS for the subscriptions.
+    #[structopt(short = "q", long = "qos", default_value = "0")]
+    qos: u8,
+
+    #[structopt(long = "no-local")]
+    no_local: bool,
+
+    #[structopt(long = "retain-as-published")]
+    retain_as_published: bool,
+
+    /// 0 - send retained messages on every subscribe, 1 - only on new subscribe,
+    /// 2 - never.
+    #[structopt(long = "retain-handling", default_value = "0")]
+    retain_handling: u8,
+
+    #[structopt(long = "subscription-id")]
+    subscription_id: Option<u32>,
+
+    /// Output format, one of raw, hex, json.
+    #[structopt(long = "format", default_value = "raw")]
+    format: Format,
+
+    /// Exit after receiving these many messages.
+    #[structopt(short = "C", long = "count")]
+    count: Option<usize>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Raw,
+    Hex,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "hex" => Ok(Format::Hex),
+            "json" => Ok(Format::Json),
+            s => Err(format!("invalid format {:?}, one of raw, hex, json", s)),
+        }
+    }
+}
+
+pub fn handle_pub(opt: &PubOpt) -> Result<()> {
+    let publ = v5::Publish {
+        retain: opt.retain,
+        qos: to_qos(opt.qos),
+        duplicate: false,
+        topic_name: TopicName::from(opt.topic.clone()),
+        packet_id: None,
+        properties: opt.to_properties(),
+        payload: Some(opt.to_payload().into()),
+    };
+    let interval = time::Duration::from_millis(opt.interval);
+
+    let mut client = Client::connect(opt.conn.to_client_args())?;
+    for i in 0..opt.count {
+        if i > 0 && !interval.is_zero() {
+            // keep the connection alive while waiting.
+            client.recv(Some(interval))?;
+        }
+        client.publish(publ.clone())?;
+    }
+    client.wait_inflight(None)?;
+    client.disconnect(v5::DisconnReasonCode::NormalDisconnect)
+}
+
+pub fn handle_sub(opt: &SubOpt) -> Result<()> {
+    let rfr = match RetainForwardRule::try_from(opt.retain_handling) {
+        Ok(rfr) => rfr,
+        Err(err) => invalid_arg(format!("invalid --retain-handling: {}", err)),
+    };
+    let qos = to_qos(opt.qos);
+    let filters: Vec<SubscribeFilter> = opt
+        .topics
+        .iter()
+        .map(|topic| SubscribeFilter {
+            topic_filter: TopicFilter::from(topic.clone()),
+            opt: SubscriptionOpt::new(
+                rfr.clone(),
+                opt.retain_as_published,
+                opt.no_local,
+                qos,
+            ),
+        })
+        .collect();
+    let properties = opt.subscription_id.map(|id| v5::SubscribeProperties {
+        subscription_id: Some(VarU32(id)),
+        ..v5::SubscribeProperties::default()
+    });
+
+    let mut client = Client::connect(opt.conn.to_client_args())?;
+    let suback = client.subscribe(filters, properties)?;
+    for (topic, code) in opt.topics.iter().zip(suback.return_codes.iter()) {
+        if (*code as u8) >= 0x80 {
+            eprintln!("subscribe {:?} failed with {:?}", topic, code);
+        }
+    }
+
+    let mut stdout = io::stdout().lock();
+    let mut n = 0;
+    for publ in client.iter() {
+        let publ = publ?;
+        let res = match opt.format {
+            Format::Raw => stdout
+                .write_all(publ.payload.as_deref().unwrap_or(&[]))
+                .and_then(|_| stdout.write_all(b"\n")),
+            Format::Hex => {
+                let payload = publ.payload.as_deref().unwrap_or(&[]);
+                writeln!(stdout, "{}", to_hex(payload))
+            }
+            Format::Json => writeln!(stdout, "{}", to_json(&publ)),
+        };
+        if let Err(err) = res.and_then(|_| stdout.flush()) {
+            eprintln!("writing to stdout: {}", err);
+            break;
+        }
+
+        n += 1;
+        if opt.count.map(|count| n >= count).unwrap_or(false) {
+            break;
+        }
+    }
+
+    client.disconnect(v5::DisconnReasonCode::NormalDisconnect)
+}
+
+pub fn to_qos(qos: u8) -> QoS {
+    match qos {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        qos => invalid_arg(format!("invalid qos {}, one of 0, 1, 2", qos)),
+    }
+}
+
+fn to_user_prop(s: &str) -> (String, String) {
+    match s.split_once('=') {
+        Some((key, value)) => (key.to_string(), value.to_string()),
+        None => invalid_arg(format!("invalid user property {:?}, use key=value", s)),
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Return PUBLISH as a single line of JSON. Payload is rendered as string if it is
+// valid UTF-8, else as hex under `payload_hex`.
+fn to_json(publ: &v5::Publish) -> String {
+    let mut fields = vec![
+        format!("\"topic\":{}", to_json_str(&publ.topic_name)),
+        format!("\"qos\":{}", u8::from(publ.qos)),
+        format!("\"retain\":{}", publ.retain),
+        format!("\"dup\":{}", publ.duplicate),
+    ];
+    if let Some(packet_id) = publ.packet_id {
+        fields.push(format!("\"packet_id\":{}", packet_id));
+    }
+    match std::str::from_utf8(publ.payload.as_deref().unwrap_or(&[])) {
+        Ok(s) => fields.push(format!("\"payload\":{}", to_json_str(s))),
+        Err(_) => {
+            let payload = publ.payload.as_deref().unwrap_or(&[]);
+            fields.push(format!("\"payload_hex\":\"{}\"", to_hex(payload)))
+        }
+    }
+
+    if let Some(props) = &publ.properties {
+        let mut ps = vec![];
+        if let v5::PayloadFormat::Utf8 = props.payload_format_indicator {
+            ps.push("\"payload_format\":\"utf8\"".to_string());
+        }
+        if let Some(val) = props.message_expiry_interval {
+            ps.push(format!("\"message_expiry_interval\":{}", val));
+        }
+        if let Some(val) = props.topic_alias {
+            ps.push(format!("\"topic_alias\":{}", val));
+        }
+        if let Some(val) = &props.response_topic {
+            ps.push(format!("\"response_topic\":{}", to_json_str(val)));
+        }
+        if let Some(val) = &props.correlation_data {
+            ps.push(format!("\"correlation_data\":\"{}\"", to_hex(val)));
+        }
+        if !props.subscribtion_identifier.is_empty() {
+            let ids: Vec<String> =
+                props.subscribtion_identifier.iter().map(|id| id.0.to_string()).collect();
+            ps.push(format!("\"subscription_identifier\":[{}]", ids.join(",")));
+        }
+        if let Some(val) = &props.content_type {
+            ps.push(format!("\"content_type\":{}", to_json_str(val)));
+        }
+        if !props.user_properties.is_empty() {
+            let ups: Vec<String> = props
+                .user_properties
+                .iter()
+                .map(|(k, v)| format!("[{},{}]", to_json_str(k), to_json_str(v)))
+                .collect();
+            ps.push(format!("\"user_properties\":[{}]", ups.join(",")));
+        }
+        fields.push(format!("\"properties\":{{{}}}", ps.join(",")));
+    }
+
+    format!("{{{}}}", fields.join(","))
+}
+
+fn to_json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if (ch as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub fn invalid_arg<S: AsRef<str>>(msg: S) -> ! {
+    eprintln!("{}", msg.as_ref());
+    exit(2)
+}
@@ -0,0 +1,49 @@
+use structopt::StructOpt;
+
+use std::path;
+
+use mqtr::{Result, Snapshot};
+
+use crate::pubsub::to_hex;
+
+#[derive(Clone, StructOpt)]
+pub struct SnapshotOpt {
+    /// Snapshot file, saved by `mqcl start --export`.
+    file: path::PathBuf,
+
+    /// List every subscription and retained message.
+    #[structopt(short = "v", long = "verbose")]
+    verbose: bool,
+}
+
+pub fn handle_snapshot(opt: &SnapshotOpt) -> Result<()> {
+    let snapshot = Snapshot::load(&opt.file)?;
+
+    println!("file          : {:?}", opt.file);
+    println!("version       : {}", Snapshot::VERSION);
+    println!("timestamp     : {}", snapshot.timestamp);
+    println!("subscriptions : {}", snapshot.subscriptions.len());
+    println!("retained      : {}", snapshot.retained.len());
+
+    if opt.verbose {
+        for subscr in snapshot.subscriptions.iter() {
+            println!(
+                "sub {} client:{} qos:{:?} id:{:?}",
+                subscr.topic_filter.as_str(),
+                subscr.client_id.as_str(),
+                subscr.qos,
+                subscr.subscription_id
+            );
+        }
+        for publ in snapshot.retained.iter() {
+            let payload = publ.payload.as_ref().map(|p| p.as_ref()).unwrap_or(&[]);
+            let payload = match std::str::from_utf8(payload) {
+                Ok(s) => s.to_string(),
+                Err(_) => to_hex(payload),
+            };
+            println!("ret {} qos:{:?} {}", publ.topic_name.as_str(), publ.qos, payload);
+        }
+    }
+
+    Ok(())
+}
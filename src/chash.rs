@@ -1,104 +1,114 @@
 use uuid::Uuid;
 
-use std::{fmt, hash, result};
+use std::fmt;
 
-use crate::Hostable;
+use crate::{util, Hostable};
 use crate::{Error, ErrorKind, Result};
 
 // Challenges in having consistent hashing.
+//
+// * With few points on the circle, distribution of keys across nodes is uneven,
+//   hence every node is placed at [ConsistentHash::POINTS] points for each unit of
+//   its weight.
+// * Points must be computed with ZERO knowledge, so that every node in the cluster
+//   computes the same circle for the same set of nodes.
+
+/// Type implement consistent hashing of keys onto weighted nodes.
+///
+/// Every node is placed on a circle of u32 hash values, at points derived from its
+/// uuid. A key is hosted by the node owning the nearest point preceding the key's
+/// hash, hence adding or removing a node only moves the keys that fall next to the
+/// node's points.
+pub struct ConsistentHash {
+    conodes: Vec<(Uuid, u32)>, // (uuid, hash), sorted by hash
+}
 
-pub struct ConsistentHash<H>
-where
-    H: hash::Hasher,
-{
-    conodes: Vec<(Uuid, u32)>, // (uuid, weight)
-    hasher: H,
+impl fmt::Debug for ConsistentHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for n in self.conodes.iter() {
+            writeln!(f, "node-hash-{}: 0x{:08x}", n.0, n.1)?;
+        }
+        Ok(())
+    }
 }
 
 impl ConsistentHash {
-    pub fn from_hasher(hasher: H) -> Result<ConsistentHash> {
-        ConsistentHash { conodes: Vec::default(), hasher }
-    }
+    /// Number of points on the circle, for each unit of node's weight.
+    pub const POINTS: u32 = 64;
 
-    pub fn from_nodes<T: Hostable>(nodes: &[T], hasher: H) -> Result<ConsistentHash> {
+    pub fn from_nodes<T: Hostable>(nodes: &[T]) -> Result<ConsistentHash> {
         match nodes.first() {
-            Some(node) => {
-                let mut ch = Self::from_hasher();
-                let (uuid, weight) = (node.uuid(), node.weight());
-                let conodes = circle_of_hash(uuid, hash(uuid), weight);
-                let mut ch = ConsistentHash { conodes };
-                ch.add_nodes(&nodes[1..])?;
-
+            Some(_) => {
+                let mut ch = ConsistentHash { conodes: Vec::default() };
+                ch.add_nodes(nodes)?;
                 Ok(ch)
             }
             None => err!(InvalidInput, desc: "Atleast one node must be in cluster"),
         }
     }
 
+    /// Add nodes to the circle, nodes already on the circle are re-added with their
+    /// new weight.
     pub fn add_nodes<T: Hostable>(&mut self, nodes: &[T]) -> Result<&mut Self> {
         for node in nodes.iter() {
             let (uuid, weight) = (node.uuid(), node.weight());
+            if weight == 0 {
+                err!(InvalidInput, desc: "node {} with ZERO weight", uuid)?;
+            }
 
-            self.conodes.extend_from_slice(&circle_of_hash(uuid, hash(uuid), weight));
-            self.conodes.sort_by(|a, b| a.1.cmp(&b.1));
+            self.conodes.retain(|(uu, _)| uu != &uuid);
+            self.conodes.extend_from_slice(&circle_of_hash(uuid, weight));
         }
+        self.conodes.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
 
         Ok(self)
     }
 
     pub fn remove_nodes<T: Hostable>(&mut self, nodes: &[T]) -> Result<&mut Self> {
-        for node in nodes.iter() {
-            let uuid = node.uuid();
-            let mut offs: Vec<usize> = self
-                .conodes
-                .iter()
-                .enumerate()
-                .filter(|(_, (uu, _))| &uuid == uu)
-                .map(|(i, _)| i)
-                .collect();
-
-            offs.reverse();
-
-            for i in offs.into_iter() {
-                self.conodes.remove(i);
-            }
+        let uuids: Vec<Uuid> = nodes.iter().map(|n| n.uuid()).collect();
+        if self.conodes.iter().all(|(uu, _)| uuids.contains(uu)) {
+            err!(InvalidInput, desc: "Atleast one node must be in cluster")?;
         }
+        self.conodes.retain(|(uu, _)| !uuids.contains(uu));
 
         Ok(self)
     }
-
-    fn print_nodes(&self) {
-        for n in self.conodes.iter() {
-            println!(f, "node-hash-{}: 0x{:8x}", n.0, n.1)?;
-        }
-        Ok(())
-    }
 }
 
 impl ConsistentHash {
     /// take shard's uuid and return the node's uuid in which the shard is hosted.
     pub fn shard_to_node(&self, uuid: Uuid) -> Uuid {
-        let hash = hash(uuid);
+        self.hash_to_node(hash(uuid))
+    }
+
+    /// take a key's hash and return the node's uuid in which the key is hosted.
+    pub fn hash_to_node(&self, hash: u32) -> Uuid {
         let off = match self.conodes.binary_search_by_key(&hash, |a| a.1) {
-            Ok(0) | Err(0) => self.conodes.len() - 1,
-            Ok(off) | Err(off) => off - 1,
+            Ok(off) => off,
+            Err(0) => self.conodes.len() - 1,
+            Err(off) => off - 1,
         };
         self.conodes[off].0
     }
 }
 
-// return (weight, weighed-hash)
-fn circle_of_hash(uuid: Uuid, hash: u32, weight: u16) -> Vec<(Uuid, u32)> {
-    use rand::{rngs::StdRng, Rng, SeedableRng};
-
-    let mut rng = StdRng::seed_from_u64(((hash as u64) << 32) | ((hash as u64) << 32));
-
-    let mut conodes: Vec<(Uuid, u32)> = (1..weight).map(|_| (uuid, rng.gen())).collect();
-    conodes.insert(0, (uuid, hash));
-    conodes.sort_by_key(|a| a.1);
+// return weight * POINTS number of (uuid, hash), where first point is the uuid's hash.
+// Rest of the points are hashed from (uuid, point-number), with a fixed hash function,
+// so that nodes running different builds compute the same circle.
+fn circle_of_hash(uuid: Uuid, weight: u16) -> Vec<(Uuid, u32)> {
+    let n = (weight as u32) * ConsistentHash::POINTS;
+    let mut conodes: Vec<(Uuid, u32)> =
+        (1..n).map(|i| (uuid, point_hash(uuid, i))).collect();
+    conodes.insert(0, (uuid, hash(uuid)));
     conodes
 }
 
+fn point_hash(uuid: Uuid, point: u32) -> u32 {
+    let mut bytes = uuid.as_bytes().to_vec();
+    bytes.extend_from_slice(&point.to_be_bytes());
+    util::cityhash_u32(&bytes)
+}
+
 fn hash(uuid: Uuid) -> u32 {
     let bytes: &[u8] = uuid.as_ref();
     let mut hash = 0_u32;
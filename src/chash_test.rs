@@ -1,4 +1,6 @@
-use rand::{prelude::random, rngs::StdRng, SeedableRng};
+use rand::{prelude::random, rngs::StdRng, Rng, SeedableRng};
+
+use std::{collections::BTreeMap, path};
 
 use super::*;
 use crate::Shardable;
@@ -13,6 +15,10 @@ impl Hostable for Nd {
     fn weight(&self) -> u16 {
         self.1
     }
+
+    fn path(&self) -> path::PathBuf {
+        "/".into()
+    }
 }
 
 struct Sd(Uuid);
@@ -23,36 +29,61 @@ impl Shardable for Sd {
     }
 }
 
+fn shards_on_node(ch: &ConsistentHash, shards: &[Sd]) -> BTreeMap<Uuid, Vec<Uuid>> {
+    let mut shards_on_node: BTreeMap<Uuid, Vec<Uuid>> = BTreeMap::new();
+    for s in shards.iter() {
+        let node_uuid = ch.shard_to_node(s.uuid());
+        shards_on_node.entry(node_uuid).or_default().push(s.uuid());
+    }
+    shards_on_node
+}
+
 #[test]
 fn test_consistent_hash() {
-    use std::collections::BTreeMap;
-
     let seed: u64 = random();
-    println!("test_client_to_shard_1 seed:{}", seed);
-    let _rng = StdRng::seed_from_u64(seed);
+    println!("test_consistent_hash seed:{}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
 
-    let n_shards = 32;
-    let n_nodes = 2;
-    let weight = 128;
+    let n_shards = 1024;
+    let n_nodes = 4;
 
-    let shards: Vec<Sd> = (0..n_shards).map(|_| Sd(Uuid::new_v4())).collect();
-    let nodes: Vec<Nd> = (0..n_nodes).map(|_| Nd(Uuid::new_v4(), weight)).collect();
+    let shards: Vec<Sd> = (0..n_shards).map(|_| Sd(Uuid::from_u128(rng.gen()))).collect();
+    let nodes: Vec<Nd> =
+        (0..n_nodes).map(|i| Nd(Uuid::from_u128(rng.gen()), 1 + i)).collect();
 
-    let ch = ConsistentHash::from_nodes(&nodes).unwrap();
-    println!("{:?}", ch);
-
-    let mut shards_on_node = BTreeMap::new();
-    for s in shards.iter() {
-        let node_uuid = ch.shard_to_node(s.uuid());
-        match shards_on_node.get_mut(&node_uuid) {
-            Some(value) => *value += 1,
-            None => {
-                shards_on_node.insert(node_uuid, 1);
-            }
-        }
+    let mut ch = ConsistentHash::from_nodes(&nodes).unwrap();
+    let before = shards_on_node(&ch, &shards);
+    for (node, shards) in before.iter() {
+        println!("{} {}", node, shards.len());
     }
+    assert_eq!(before.len(), nodes.len());
+    // heaviest node hosts more shards than the lightest node.
+    assert!(before[&nodes[3].0].len() > before[&nodes[0].0].len());
 
-    for (node, n) in shards_on_node.iter() {
-        println!("{} {}", node, n);
+    // only shards from the removed node are moved.
+    ch.remove_nodes(&nodes[1..2]).unwrap();
+    let after = shards_on_node(&ch, &shards);
+    assert!(!after.contains_key(&nodes[1].0));
+    for (node, shards) in before.iter().filter(|(node, _)| **node != nodes[1].0) {
+        assert!(shards.iter().all(|s| after[node].contains(s)));
     }
+
+    // adding the node back restores the mapping.
+    ch.add_nodes(&nodes[1..2]).unwrap();
+    assert_eq!(shards_on_node(&ch, &shards), before);
+
+    assert!(ConsistentHash::from_nodes::<Nd>(&[]).is_err());
+    assert!(ch.remove_nodes(&nodes).is_err());
+    assert!(ch.add_nodes(&[Nd(Uuid::new_v4(), 0)]).is_err());
+}
+
+#[test]
+fn test_circle_of_hash() {
+    // points are computed with a fixed hash, same across builds and platforms.
+    let uuid = Uuid::from_u128(0x0123456789abcdef0123456789abcdef);
+    let conodes = circle_of_hash(uuid, 2);
+    assert_eq!(conodes.len(), 2 * ConsistentHash::POINTS as usize);
+    assert_eq!(conodes[0], (uuid, hash(uuid)));
+    let points: Vec<u32> = conodes[1..4].iter().map(|(_, h)| *h).collect();
+    assert_eq!(points, [2926261205, 2284045811, 3912425444]);
 }
@@ -0,0 +1,1104 @@
+//! Module implement a synchronous MQTT client, driven by [mio].
+//!
+//! Client is built on the same [v5] codec used by the broker, all packets are
+//! framed using [Packetize], and read/written using the [MQTTRead] and [MQTTWrite]
+//! state machines. A single connection is handled by the calling thread, there are
+//! no background threads. Incoming packets, acknowledgements and keep-alive pings
+//! are processed while the application is blocked in one of the client's methods.
+//!
+//! Optionally, refer [ClientArgs::reconnect], client can reconnect with the broker
+//! when the connection is lost. Reconnect uses jittered exponential backoff and
+//! resumes the session with `clean_start=false`. Unacknowledged QoS-1 and QoS-2
+//! PUBLISH are replayed with DUP flag, and subscriptions are re-done if broker did
+//! not have the session. While offline, PUBLISH is held in a bounded buffer, refer
+//! [Overflow], and flushed after reconnecting.
+
+use log::{debug, error, info, warn};
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::{mem, net, thread, time};
+
+use crate::packet::{MQTTRead, MQTTWrite};
+use crate::v5::{self, PacketType, QoS};
+use crate::{Blob, ClientID, MqttProtocol, PacketID, Packetize, TopicFilter, TopicName};
+use crate::{Config, Error, ErrorKind, ReasonCode, Result};
+
+/// Arguments to connect with MQTT broker, refer [Client::connect].
+#[derive(Clone)]
+pub struct ClientArgs {
+    /// Broker address to connect with.
+    pub address: net::SocketAddr,
+    /// Client identifier, if empty broker shall assign a client identifier.
+    pub client_id: ClientID,
+    /// Start a new session, discarding the existing session if any.
+    pub clean_start: bool,
+    /// Keep-alive interval, in seconds, ZERO disables keep-alive.
+    pub keep_alive: u16,
+    /// CONNECT properties, `max_packet_size` defaults to
+    /// [ClientArgs::max_packet_size].
+    pub properties: Option<v5::ConnectProperties>,
+    /// Will message.
+    pub will: Option<Will>,
+    pub user_name: Option<String>,
+    pub password: Option<String>,
+    /// Maximum size of packets that can be received from broker.
+    pub max_packet_size: u32,
+    /// Time to wait for connection to establish and CONNACK to be received.
+    pub connect_timeout: time::Duration,
+    /// Time to wait for SUBACK and UNSUBACK.
+    pub ack_timeout: time::Duration,
+    /// Reconnect with broker when connection is lost, None disables reconnect.
+    /// Initial connection is not retried, [Client::connect] fails instead.
+    pub reconnect: Option<Reconnect>,
+}
+
+impl Default for ClientArgs {
+    fn default() -> ClientArgs {
+        ClientArgs {
+            address: crate::mqtt_listen_address4(None),
+            client_id: ClientID(String::default()),
+            clean_start: true,
+            keep_alive: Self::DEF_KEEP_ALIVE,
+            properties: None,
+            will: None,
+            user_name: None,
+            password: None,
+            max_packet_size: Config::DEF_MQTT_MAX_PACKET_SIZE,
+            connect_timeout: time::Duration::from_secs(
+                Config::DEF_CONNECT_TIMEOUT as u64,
+            ),
+            ack_timeout: time::Duration::from_secs(Config::DEF_CONNECT_TIMEOUT as u64),
+            reconnect: None,
+        }
+    }
+}
+
+impl ClientArgs {
+    /// Refer to [ClientArgs::keep_alive]
+    pub const DEF_KEEP_ALIVE: u16 = 60; // in seconds.
+
+    /// Return the CONNECT packet for these arguments.
+    pub fn to_connect(&self) -> v5::Connect {
+        use v5::ConnectFlags;
+
+        let mut flags = vec![];
+        if self.clean_start {
+            flags.push(ConnectFlags::CLEAN_START);
+        }
+        if let Some(will) = &self.will {
+            flags.push(ConnectFlags::WILL_FLAG);
+            flags.push(match will.qos {
+                QoS::AtMostOnce => ConnectFlags::WILL_QOS0,
+                QoS::AtLeastOnce => ConnectFlags::WILL_QOS1,
+                QoS::ExactlyOnce => ConnectFlags::WILL_QOS2,
+            });
+            if will.retain {
+                flags.push(ConnectFlags::WILL_RETAIN);
+            }
+        }
+        if self.user_name.is_some() {
+            flags.push(ConnectFlags::USERNAME);
+        }
+        if self.password.is_some() {
+            flags.push(ConnectFlags::PASSWORD);
+        }
+
+        let mut properties = self.properties.clone().unwrap_or_default();
+        if properties.max_packet_size.is_none() {
+            properties.max_packet_size = Some(self.max_packet_size);
+        }
+
+        v5::Connect {
+            protocol_name: "MQTT".to_string(),
+            protocol_version: MqttProtocol::V5,
+            flags: ConnectFlags::new(&flags),
+            keep_alive: self.keep_alive,
+            properties: Some(properties),
+            payload: v5::ConnectPayload {
+                client_id: self.client_id.clone(),
+                will_properties: self.will.as_ref().map(|w| w.properties.clone()),
+                will_topic: self.will.as_ref().map(|w| w.topic.clone()),
+                will_payload: self.will.as_ref().map(|w| w.payload.clone()),
+                user_name: self.user_name.clone(),
+                password: self.password.clone(),
+            },
+        }
+    }
+}
+
+/// Will message, published by the broker when client disconnects abnormally.
+#[derive(Clone)]
+pub struct Will {
+    pub topic: TopicName,
+    pub payload: Vec<u8>,
+    pub qos: QoS,
+    pub retain: bool,
+    pub properties: v5::WillProperties,
+}
+
+/// Reconnect configuration, refer [ClientArgs::reconnect].
+///
+/// Session is resumed only if broker retains it after the connection is lost, use
+/// `session_expiry_interval` in [ClientArgs::properties] for that.
+#[derive(Clone)]
+pub struct Reconnect {
+    /// Backoff before the first reconnect attempt. Backoff doubles after every
+    /// failed attempt.
+    /// * **Default**: [Reconnect::DEF_MIN_BACKOFF]
+    pub min_backoff: time::Duration,
+    /// Backoff does not grow beyond this.
+    /// * **Default**: [Reconnect::DEF_MAX_BACKOFF]
+    pub max_backoff: time::Duration,
+    /// Give up after these many consecutive failed attempts, None retries forever.
+    /// * **Default**: None
+    pub max_retries: Option<u32>,
+    /// Number of PUBLISH held while offline.
+    /// * **Default**: [Reconnect::DEF_BUFFER_SIZE]
+    pub buffer_size: usize,
+    /// What to do with PUBLISH when offline buffer is full.
+    /// * **Default**: [Overflow::DropOldest]
+    pub overflow: Overflow,
+}
+
+impl Default for Reconnect {
+    fn default() -> Reconnect {
+        Reconnect {
+            min_backoff: Self::DEF_MIN_BACKOFF,
+            max_backoff: Self::DEF_MAX_BACKOFF,
+            max_retries: None,
+            buffer_size: Self::DEF_BUFFER_SIZE,
+            overflow: Overflow::DropOldest,
+        }
+    }
+}
+
+impl Reconnect {
+    /// Refer to [Reconnect::min_backoff]
+    pub const DEF_MIN_BACKOFF: time::Duration = time::Duration::from_millis(500);
+    /// Refer to [Reconnect::max_backoff]
+    pub const DEF_MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+    /// Refer to [Reconnect::buffer_size]
+    pub const DEF_BUFFER_SIZE: usize = 1024;
+
+    /// Return the backoff after `retries` consecutive failed attempts. Half of the
+    /// backoff is randomized, so that clients don't reconnect in lock-step.
+    pub fn to_backoff(&self, retries: u32) -> time::Duration {
+        let backoff = match 2_u32.checked_pow(retries) {
+            Some(n) => self.min_backoff.saturating_mul(n),
+            None => self.max_backoff,
+        };
+        let half = backoff.min(self.max_backoff) / 2;
+        half + half.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Policy for PUBLISH when offline buffer is full, refer [Reconnect::buffer_size].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    /// Drop the oldest buffered PUBLISH to make room.
+    DropOldest,
+    /// Drop the PUBLISH being published.
+    DropNewest,
+    /// Fail [Client::publish].
+    Error,
+}
+
+// Outgoing packets waiting for acknowledgement, indexed by packet-id.
+enum Inflight {
+    // QoS-1/QoS-2 PUBLISH sent, waiting for PUBACK/PUBREC.
+    Publish(v5::Publish),
+    // QoS-2 PUBREL sent, waiting for PUBCOMP.
+    PubRel(v5::Publish),
+    // SUBSCRIBE sent, waiting for SUBACK.
+    Subscribe(v5::Subscribe),
+    // SUBSCRIBE re-done after reconnect, waiting for SUBACK.
+    Resubscribe(v5::Subscribe),
+    // UNSUBSCRIBE sent, waiting for UNSUBACK.
+    UnSubscribe(v5::UnSubscribe),
+}
+
+/// Type implement a synchronous MQTT client.
+pub struct Client {
+    pub prefix: String,
+    args: ClientArgs,
+    poll: mio::Poll,
+    conn: mio::net::TcpStream,
+    connack: Option<v5::ConnAck>,
+    // keep-alive, as requested by client or as overridden by server.
+    keep_alive: Option<time::Duration>,
+
+    packetr: MQTTRead,
+    packetw: MQTTWrite,
+    wqueue: VecDeque<Blob>,
+
+    next_packet_id: PacketID,
+    inflight: BTreeMap<PacketID, Inflight>,
+    // QoS-2 PUBLISH received and PUBREC sent, waiting for PUBREL.
+    incoming_qos2: BTreeSet<PacketID>,
+    // SUBACK and UNSUBACK received.
+    acks: BTreeMap<PacketID, v5::Packet>,
+    // PUBLISH received, yet to be consumed by application.
+    messages: VecDeque<v5::Publish>,
+    // successful subscriptions, re-done when session is lost across reconnect.
+    subscriptions: Vec<v5::Subscribe>,
+    // PUBLISH held while offline, or while flushing them after reconnect.
+    offline: VecDeque<v5::Publish>,
+
+    last_tx: time::Instant,
+    // PINGREQ sent, waiting for PINGRESP.
+    ping_at: Option<time::Instant>,
+    // connection lost, next reconnect attempt is due at this instant.
+    reconnect_at: Option<time::Instant>,
+    // number of consecutive failed reconnect attempts.
+    retries: u32,
+    disconnected: bool,
+}
+
+impl Client {
+    const TOKEN_CONN: mio::Token = mio::Token(1);
+
+    /// Connect with broker, block until CONNACK is received or
+    /// [ClientArgs::connect_timeout] is exceeded. Fails if broker rejects the
+    /// connection.
+    pub fn connect(args: ClientArgs) -> Result<Client> {
+        let prefix = format!("client:{}", *args.client_id);
+        let deadline = time::Instant::now() + args.connect_timeout;
+
+        let poll = err!(IOError, try: mio::Poll::new(), "fail creating mio::Poll")?;
+        let conn = open_conn(&prefix, &args, &poll)?;
+
+        let max_packet_size = args.max_packet_size;
+        let mut client = Client {
+            prefix,
+            args,
+            poll,
+            conn,
+            connack: None,
+            keep_alive: None,
+
+            packetr: MQTTRead::new(max_packet_size),
+            // packet size is checked by Client::send, refer broker's max_packet_size.
+            packetw: MQTTWrite::Fin {
+                data: Vec::default(),
+                payloads: Vec::default(),
+                max_size: usize::MAX,
+            },
+            wqueue: VecDeque::default(),
+
+            next_packet_id: 1,
+            inflight: BTreeMap::default(),
+            incoming_qos2: BTreeSet::default(),
+            acks: BTreeMap::default(),
+            messages: VecDeque::default(),
+            subscriptions: Vec::default(),
+            offline: VecDeque::default(),
+
+            last_tx: time::Instant::now(),
+            ping_at: None,
+            reconnect_at: None,
+            retries: 0,
+            disconnected: false,
+        };
+
+        client.wait_connected(deadline)?;
+        client.connack_handshake(deadline)?;
+
+        Ok(client)
+    }
+
+    /// Return the CONNACK received from broker.
+    pub fn as_connack(&self) -> &v5::ConnAck {
+        self.connack.as_ref().unwrap()
+    }
+
+    /// Return whether broker resumed an existing session.
+    pub fn is_session_present(&self) -> bool {
+        self.as_connack().flags.unwrap().unwrap_or(false)
+    }
+
+    /// Return the client identifier, either as supplied in [ClientArgs] or as
+    /// assigned by the broker.
+    pub fn to_client_id(&self) -> ClientID {
+        let props = self.as_connack().properties.as_ref();
+        match props.and_then(|p| p.assigned_client_identifier.as_ref()) {
+            Some(client_id) => ClientID(client_id.clone()),
+            None => self.args.client_id.clone(),
+        }
+    }
+
+    /// Return the number of QoS-1 and QoS-2 PUBLISH waiting for acknowledgement.
+    pub fn inflight_len(&self) -> usize {
+        let iter = self.inflight.values();
+        iter.filter(|i| matches!(i, Inflight::Publish(_) | Inflight::PubRel(_))).count()
+    }
+
+    /// Return the number of PUBLISH held in offline buffer.
+    pub fn offline_len(&self) -> usize {
+        self.offline.len()
+    }
+
+    /// Return whether client is connected with broker. Client is not connected
+    /// while waiting to reconnect.
+    pub fn is_connected(&self) -> bool {
+        !self.disconnected && self.reconnect_at.is_none()
+    }
+
+    /// Return whether connection with broker is closed, either due to an error or
+    /// due to DISCONNECT. When reconnect is enabled, connection is closed only
+    /// after giving up on reconnect.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected
+    }
+
+    /// Publish a message. For QoS-1 and QoS-2, packet-id is assigned by the client
+    /// and returned, if number of unacknowledged publishes has reached broker's
+    /// receive-maximum, block until a slot is available.
+    ///
+    /// Returns after the PUBLISH is queued, use [Client::wait_inflight] to wait for
+    /// acknowledgements. While offline, PUBLISH is held in the offline buffer and
+    /// None is returned, packet-id is assigned when it is flushed.
+    pub fn publish(&mut self, publ: v5::Publish) -> Result<Option<PacketID>> {
+        let props = self.as_connack().properties.clone().unwrap_or_default();
+        if publ.qos > props.maximum_qos() {
+            err!(
+                InvalidInput,
+                desc: "{} qos {:?} > broker's maximum_qos {:?}",
+                self.prefix,
+                publ.qos,
+                props.maximum_qos()
+            )?;
+        }
+        if publ.retain && !props.retain_available.unwrap_or(true) {
+            err!(InvalidInput, desc: "{} broker does not support retain", self.prefix)?;
+        }
+
+        loop {
+            // preserve the publish order, while offline buffer is being flushed.
+            if self.reconnect_at.is_some() || !self.offline.is_empty() {
+                break self.buffer_publish(publ);
+            }
+            if publ.qos == QoS::AtMostOnce || !self.is_inflight_full() {
+                let packet_id = self.send_publish(publ)?;
+                self.drive(Some(time::Instant::now()))?;
+                break Ok(packet_id);
+            }
+            self.drive(None)?;
+        }
+    }
+
+    /// Block until all QoS-1 and QoS-2 PUBLISH are acknowledged by the broker,
+    /// including the PUBLISH held in offline buffer.
+    pub fn wait_inflight(&mut self, timeout: Option<time::Duration>) -> Result<()> {
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        while self.inflight_len() > 0 || !self.offline.is_empty() {
+            match deadline {
+                Some(deadline) if time::Instant::now() >= deadline => err!(
+                    Timeout,
+                    desc: "{} inflight {} after {:?}",
+                    self.prefix,
+                    self.inflight_len(),
+                    timeout
+                )?,
+                _ => self.drive(deadline)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe to one or more topic-filters, block until SUBACK is received or
+    /// [ClientArgs::ack_timeout] is exceeded.
+    pub fn subscribe(
+        &mut self,
+        filters: Vec<v5::SubscribeFilter>,
+        properties: Option<v5::SubscribeProperties>,
+    ) -> Result<v5::SubAck> {
+        let packet_id = self.alloc_packet_id();
+        let sub = v5::Subscribe { packet_id, properties, filters };
+        self.send(&sub)?;
+        self.inflight.insert(packet_id, Inflight::Subscribe(sub));
+
+        match self.wait_ack(packet_id)? {
+            v5::Packet::SubAck(suback) => Ok(suback),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Unsubscribe one or more topic-filters, block until UNSUBACK is received or
+    /// [ClientArgs::ack_timeout] is exceeded.
+    pub fn unsubscribe(
+        &mut self,
+        filters: Vec<TopicFilter>,
+        properties: Option<v5::UnSubscribeProperties>,
+    ) -> Result<v5::UnsubAck> {
+        let packet_id = self.alloc_packet_id();
+        let unsub = v5::UnSubscribe { packet_id, properties, filters };
+        self.send(&unsub)?;
+        self.inflight.insert(packet_id, Inflight::UnSubscribe(unsub));
+
+        match self.wait_ack(packet_id)? {
+            v5::Packet::UnsubAck(unsuback) => Ok(unsuback),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Receive the next message published by broker. Block until a message is
+    /// received or `timeout` is exceeded, in which case return None. If `timeout`
+    /// is None block until a message is received.
+    pub fn recv(
+        &mut self,
+        timeout: Option<time::Duration>,
+    ) -> Result<Option<v5::Publish>> {
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        loop {
+            if let Some(publ) = self.messages.pop_front() {
+                break Ok(Some(publ));
+            }
+            match deadline {
+                Some(deadline) if time::Instant::now() >= deadline => break Ok(None),
+                _ => self.drive(deadline)?,
+            }
+        }
+    }
+
+    /// Return an iterator over messages published by broker, iteration blocks until
+    /// the next message is received and stops after the connection is closed.
+    pub fn iter(&mut self) -> Recv<'_> {
+        Recv { client: self }
+    }
+
+    /// Send DISCONNECT with reason-code and close the connection.
+    pub fn disconnect(mut self, code: v5::DisconnReasonCode) -> Result<()> {
+        let deadline = time::Instant::now() + self.args.ack_timeout;
+
+        if self.reconnect_at.is_some() {
+            let n = self.offline.len();
+            warn!("{} disconnected while offline, dropping {} PUBLISH", self.prefix, n);
+            self.disconnected = true;
+            return Ok(());
+        }
+
+        // only flush the write queue, broker is expected to close the connection
+        // after DISCONNECT, so don't bother reading.
+        self.send(&v5::Disconnect::new(code, None))?;
+        self.write_packets()?;
+        while !self.wqueue.is_empty() || !matches!(&self.packetw, MQTTWrite::Fin { .. }) {
+            let now = time::Instant::now();
+            if now >= deadline {
+                err!(Timeout, desc: "{} disconnect", self.prefix)?;
+            }
+            let mut events = mio::Events::with_capacity(2);
+            let timeout = Some(deadline.saturating_duration_since(now));
+            err!(IOError, try: self.poll.poll(&mut events, timeout))?;
+            self.write_packets()?;
+        }
+
+        info!("{} disconnected with {:?}", self.prefix, code);
+        self.disconnected = true;
+        Ok(())
+    }
+}
+
+/// Iterator over messages published by broker, refer [Client::iter].
+pub struct Recv<'a> {
+    client: &'a mut Client,
+}
+
+impl<'a> Iterator for Recv<'a> {
+    type Item = Result<v5::Publish>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.is_disconnected() {
+            true => None,
+            false => self.client.recv(None).transpose(),
+        }
+    }
+}
+
+impl Client {
+    // Wait for TCP connection to establish.
+    fn wait_connected(&mut self, deadline: time::Instant) -> Result<()> {
+        let mut events = mio::Events::with_capacity(2);
+        loop {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            if timeout.is_zero() {
+                err!(Timeout, desc: "{} connect {}", self.prefix, self.args.address)?;
+            }
+            err!(IOError, try: self.poll.poll(&mut events, Some(timeout)))?;
+
+            if let Some(err) = err!(IOError, try: self.conn.take_error())? {
+                err!(Disconnected, try: Err(err), "{} connect", self.prefix)?;
+            }
+            match self.conn.peer_addr() {
+                Ok(_) => break Ok(()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotConnected => (),
+                Err(err) => err!(Disconnected, try: Err(err), "{} connect", self.prefix)?,
+            }
+        }
+    }
+
+    // Send CONNECT and wait for CONNACK, called for initial connect and reconnect,
+    // so drive the connection directly without Client::drive.
+    fn connack_handshake(&mut self, deadline: time::Instant) -> Result<()> {
+        self.send(&self.args.to_connect())?;
+        while self.connack.is_none() {
+            match time::Instant::now() {
+                now if now < deadline => self.do_drive(Some(deadline))?,
+                _ => err!(Timeout, desc: "{} waiting for CONNACK", self.prefix)?,
+            }
+        }
+
+        let connack = self.as_connack().clone();
+        if connack.code != v5::ConnackReasonCode::Success {
+            let code = ReasonCode::try_from(connack.code as u8)?;
+            err!(ProtocolError, code: UnspecifiedError, "{} CONNACK {:?}", self.prefix, code)?;
+        }
+
+        let props = connack.properties.clone().unwrap_or_default();
+        let keep_alive = props.server_keep_alive.unwrap_or(self.args.keep_alive);
+        self.keep_alive = match keep_alive {
+            0 => None,
+            secs => Some(time::Duration::from_secs(u64::from(secs))),
+        };
+        info!(
+            "{} connected to {} session_present:{}",
+            self.prefix,
+            self.args.address,
+            self.is_session_present()
+        );
+
+        // subsequent connections shall resume this session.
+        self.args.clean_start = false;
+        self.args.client_id = self.to_client_id();
+
+        Ok(())
+    }
+
+    // Reconnect with broker and restore the session.
+    fn reconnect(&mut self) -> Result<()> {
+        let deadline = time::Instant::now() + self.args.connect_timeout;
+
+        err!(IOError, try: self.poll.registry().deregister(&mut self.conn)).ok();
+        self.conn = open_conn(&self.prefix, &self.args, &self.poll)?;
+
+        self.packetr = MQTTRead::new(self.args.max_packet_size);
+        self.packetw = MQTTWrite::Fin {
+            data: Vec::default(),
+            payloads: Vec::default(),
+            max_size: usize::MAX,
+        };
+        self.wqueue.clear();
+        self.last_tx = time::Instant::now();
+        self.ping_at = None;
+
+        // keep the last CONNACK, if reconnect fails.
+        let connack = self.connack.take();
+        match self.do_reconnect(deadline) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.connack = connack;
+                Err(err)
+            }
+        }
+    }
+
+    fn do_reconnect(&mut self, deadline: time::Instant) -> Result<()> {
+        self.wait_connected(deadline)?;
+        self.connack_handshake(deadline)?;
+
+        let session_present = self.is_session_present();
+        if !session_present {
+            // broker shall re-deliver QoS-2 PUBLISH, if at all, with new packet-ids.
+            self.incoming_qos2.clear();
+            for mut sub in self.subscriptions.clone().into_iter() {
+                sub.packet_id = self.alloc_packet_id();
+                self.send(&sub)?;
+                self.inflight.insert(sub.packet_id, Inflight::Resubscribe(sub));
+            }
+        }
+
+        let inflight = mem::take(&mut self.inflight);
+        for (packet_id, inflight) in inflight.into_iter() {
+            match inflight {
+                Inflight::Publish(mut publ) => {
+                    publ.duplicate = true;
+                    self.send(&publ)?;
+                    self.inflight.insert(packet_id, Inflight::Publish(publ));
+                }
+                // broker has already received this message, PUBREL is only
+                // meaningful when broker has the session.
+                Inflight::PubRel(_) if !session_present => (),
+                Inflight::PubRel(publ) => {
+                    let code = ReasonCode::Success;
+                    self.send(&new_pub(PacketType::PubRel, packet_id, code))?;
+                    self.inflight.insert(packet_id, Inflight::PubRel(publ));
+                }
+                Inflight::Subscribe(sub) => {
+                    self.send(&sub)?;
+                    self.inflight.insert(packet_id, Inflight::Subscribe(sub));
+                }
+                Inflight::Resubscribe(sub) => {
+                    self.inflight.insert(packet_id, Inflight::Resubscribe(sub));
+                }
+                Inflight::UnSubscribe(unsub) => {
+                    self.send(&unsub)?;
+                    self.inflight.insert(packet_id, Inflight::UnSubscribe(unsub));
+                }
+            }
+        }
+        info!(
+            "{} replayed {} inflight, {} offline",
+            self.prefix,
+            self.inflight_len(),
+            self.offline.len()
+        );
+
+        self.write_packets()
+    }
+
+    // Connection is lost, schedule a reconnect.
+    fn go_offline(&mut self, err: Error) {
+        let backoff = match &self.args.reconnect {
+            Some(reconnect) => reconnect.to_backoff(self.retries),
+            None => unreachable!(),
+        };
+        warn!("{} connection lost, reconnect after {:?}: {}", self.prefix, backoff, err);
+        self.reconnect_at = Some(time::Instant::now() + backoff);
+    }
+
+    // Wait for the reconnect to be due, at the most until `deadline`, and attempt
+    // to reconnect.
+    fn try_reconnect(&mut self, deadline: Option<time::Instant>) -> Result<()> {
+        let reconnect = self.args.reconnect.clone().unwrap();
+        let reconnect_at = self.reconnect_at.unwrap();
+
+        let now = time::Instant::now();
+        if now < reconnect_at {
+            let till = deadline.map(|d| d.min(reconnect_at)).unwrap_or(reconnect_at);
+            thread::sleep(till.saturating_duration_since(now));
+            if time::Instant::now() < reconnect_at {
+                return Ok(());
+            }
+        }
+
+        match self.reconnect() {
+            Ok(()) => {
+                self.reconnect_at = None;
+                self.retries = 0;
+                Ok(())
+            }
+            Err(err)
+                if reconnect.max_retries.unwrap_or(u32::MAX) <= (self.retries + 1) =>
+            {
+                error!(
+                    "{} giving up reconnect after {} retries",
+                    self.prefix, self.retries
+                );
+                self.reconnect_at = None;
+                self.disconnected = true;
+                Err(err)
+            }
+            Err(err) => {
+                self.retries += 1;
+                self.go_offline(err);
+                Ok(())
+            }
+        }
+    }
+
+    // Wait for SUBACK/UNSUBACK.
+    fn wait_ack(&mut self, packet_id: PacketID) -> Result<v5::Packet> {
+        let deadline = time::Instant::now() + self.args.ack_timeout;
+        loop {
+            if let Some(pkt) = self.acks.remove(&packet_id) {
+                break Ok(pkt);
+            }
+            match time::Instant::now() {
+                now if now < deadline => self.drive(Some(deadline))?,
+                _ => {
+                    self.inflight.remove(&packet_id);
+                    err!(Timeout, desc: "{} ack for packet_id {}", self.prefix, packet_id)?
+                }
+            }
+        }
+    }
+
+    fn is_inflight_full(&self) -> bool {
+        let props = self.as_connack().properties.as_ref();
+        let receive_maximum = props.map(|p| p.receive_maximum()).unwrap_or(u16::MAX);
+        self.inflight_len() >= usize::from(receive_maximum)
+    }
+
+    fn buffer_publish(&mut self, publ: v5::Publish) -> Result<Option<PacketID>> {
+        let reconnect = match &self.args.reconnect {
+            Some(reconnect) => reconnect,
+            None => unreachable!(),
+        };
+
+        if self.offline.len() >= reconnect.buffer_size {
+            match reconnect.overflow {
+                Overflow::DropOldest => {
+                    self.offline.pop_front();
+                }
+                Overflow::DropNewest => {
+                    debug!("{} offline buffer full, dropping PUBLISH", self.prefix);
+                    return Ok(None);
+                }
+                Overflow::Error => err!(
+                    SlowClient,
+                    desc: "{} offline buffer full {}",
+                    self.prefix,
+                    self.offline.len()
+                )?,
+            }
+        }
+        self.offline.push_back(publ);
+
+        Ok(None)
+    }
+
+    fn send_publish(&mut self, mut publ: v5::Publish) -> Result<Option<PacketID>> {
+        let packet_id = match publ.qos {
+            QoS::AtMostOnce => {
+                publ.packet_id = None;
+                None
+            }
+            QoS::AtLeastOnce | QoS::ExactlyOnce => {
+                let packet_id = self.alloc_packet_id();
+                publ.set_packet_id(packet_id);
+                Some(packet_id)
+            }
+        };
+
+        self.send(&publ)?;
+        if let Some(packet_id) = packet_id {
+            self.inflight.insert(packet_id, Inflight::Publish(publ));
+        }
+
+        Ok(packet_id)
+    }
+
+    // Flush PUBLISH held while offline, within the broker's receive-maximum.
+    fn flush_offline(&mut self) -> Result<()> {
+        if self.connack.is_none() {
+            return Ok(()); // still waiting for CONNACK.
+        }
+
+        while let Some(publ) = self.offline.front() {
+            if publ.qos != QoS::AtMostOnce && self.is_inflight_full() {
+                break;
+            }
+            let publ = self.offline.pop_front().unwrap();
+            match self.send_publish(publ) {
+                Ok(_) => (),
+                // failing a message that was accepted by Client::publish is not an
+                // option, log and move on.
+                Err(err) if err.kind() == ErrorKind::InvalidInput => {
+                    error!("{} dropping offline PUBLISH: {}", self.prefix, err)
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn alloc_packet_id(&mut self) -> PacketID {
+        loop {
+            let packet_id = self.next_packet_id;
+            self.next_packet_id = match self.next_packet_id {
+                PacketID::MAX => 1,
+                n => n + 1,
+            };
+            if !self.inflight.contains_key(&packet_id) {
+                break packet_id;
+            }
+        }
+    }
+
+    fn send<P: Packetize>(&mut self, pkt: &P) -> Result<()> {
+        if self.disconnected {
+            err!(Disconnected, desc: "{} connection closed", self.prefix)?;
+        }
+
+        let blob = pkt.encode()?;
+        let props = self.connack.as_ref().and_then(|connack| connack.properties.as_ref());
+        match props.and_then(|props| props.max_packet_size) {
+            Some(max_size) if blob.as_ref().len() > (max_size as usize) => err!(
+                InvalidInput,
+                desc: "{} packet size {} > broker's max_packet_size {}",
+                self.prefix,
+                blob.as_ref().len(),
+                max_size
+            )?,
+            _ => (),
+        }
+        self.wqueue.push_back(blob);
+        Ok(())
+    }
+
+    // Wait for events, at the most until `deadline`, and read/write packets until
+    // the connection would block. Keep-alive and reconnect are handled here.
+    fn drive(&mut self, deadline: Option<time::Instant>) -> Result<()> {
+        if self.disconnected {
+            err!(Disconnected, desc: "{} connection closed", self.prefix)?;
+        }
+        if self.reconnect_at.is_some() {
+            return self.try_reconnect(deadline);
+        }
+
+        match self.do_drive(deadline) {
+            Ok(()) => Ok(()),
+            Err(err) if self.args.reconnect.is_some() => {
+                self.go_offline(err);
+                Ok(())
+            }
+            Err(err) => {
+                self.disconnected = true;
+                Err(err)
+            }
+        }
+    }
+
+    fn do_drive(&mut self, deadline: Option<time::Instant>) -> Result<()> {
+        let mut events = mio::Events::with_capacity(2);
+
+        // try writing first, if connection is writable we don't want to wait.
+        self.flush_offline()?;
+        self.write_packets()?;
+
+        let now = time::Instant::now();
+        let timeout = [deadline, self.keep_alive_deadline()]
+            .into_iter()
+            .flatten()
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now));
+        err!(IOError, try: self.poll.poll(&mut events, timeout))?;
+
+        self.read_packets()?;
+        self.keep_alive()?;
+        self.write_packets()
+    }
+
+    fn read_packets(&mut self) -> Result<()> {
+        loop {
+            match mem::take(&mut self.packetr).read(&self.conn)? {
+                (mut packetr @ MQTTRead::Fin { .. }, _) => {
+                    let pkt = packetr.parse();
+                    self.packetr = packetr.reset();
+                    self.handle_packet(pkt?)?;
+                }
+                (packetr, true) => {
+                    self.packetr = packetr;
+                    break Ok(());
+                }
+                (packetr, false) => self.packetr = packetr,
+            }
+        }
+    }
+
+    fn write_packets(&mut self) -> Result<()> {
+        loop {
+            self.packetw = match mem::take(&mut self.packetw) {
+                packetw @ MQTTWrite::Fin { .. } => match self.wqueue.pop_front() {
+                    Some(blob) => {
+                        self.last_tx = time::Instant::now();
+                        packetw.reset(blob.as_ref())
+                    }
+                    None => {
+                        self.packetw = packetw;
+                        break Ok(());
+                    }
+                },
+                packetw => match packetw.write(&self.conn)? {
+                    (packetw, true) => {
+                        self.packetw = packetw;
+                        break Ok(());
+                    }
+                    (packetw, false) => packetw,
+                },
+            }
+        }
+    }
+
+    fn keep_alive_deadline(&self) -> Option<time::Instant> {
+        match (self.keep_alive, self.ping_at) {
+            (Some(keep_alive), Some(ping_at)) => Some(ping_at + keep_alive),
+            (Some(keep_alive), None) => Some(self.last_tx + keep_alive),
+            (None, _) => None,
+        }
+    }
+
+    fn keep_alive(&mut self) -> Result<()> {
+        let now = time::Instant::now();
+        match (self.keep_alive, self.ping_at) {
+            (Some(keep_alive), Some(ping_at)) if now >= (ping_at + keep_alive) => {
+                err!(Timeout, desc: "{} no PINGRESP after {:?}", self.prefix, keep_alive)
+            }
+            (Some(keep_alive), None) if now >= (self.last_tx + keep_alive) => {
+                debug!("{} sending PINGREQ", self.prefix);
+                self.ping_at = Some(now);
+                self.send(&v5::PingReq)
+            }
+            (_, _) => Ok(()),
+        }
+    }
+
+    fn handle_packet(&mut self, pkt: v5::Packet) -> Result<()> {
+        match pkt {
+            v5::Packet::ConnAck(connack) if self.connack.is_none() => {
+                self.connack = Some(connack);
+            }
+            v5::Packet::Publish(publ) => self.handle_publish(publ)?,
+            v5::Packet::PubAck(puback) => {
+                if puback.code as u8 >= 0x80 {
+                    warn!("{} PUBACK {:?}", self.prefix, puback.code);
+                }
+                self.inflight.remove(&puback.packet_id);
+            }
+            v5::Packet::PubRec(pubrec) => match self.inflight.remove(&pubrec.packet_id) {
+                Some(Inflight::Publish(_)) if pubrec.code as u8 >= 0x80 => {
+                    warn!("{} PUBREC {:?}", self.prefix, pubrec.code);
+                }
+                Some(Inflight::Publish(publ) | Inflight::PubRel(publ)) => {
+                    let packet_id = pubrec.packet_id;
+                    self.inflight.insert(packet_id, Inflight::PubRel(publ));
+                    self.send(&new_pub(
+                        PacketType::PubRel,
+                        packet_id,
+                        ReasonCode::Success,
+                    ))?;
+                }
+                inflight => {
+                    let (packet_id, code) =
+                        (pubrec.packet_id, ReasonCode::PacketIdNotFound);
+                    if let Some(inflight) = inflight {
+                        self.inflight.insert(packet_id, inflight);
+                    }
+                    self.send(&new_pub(PacketType::PubRel, packet_id, code))?;
+                }
+            },
+            v5::Packet::PubRel(pubrel) => {
+                let packet_id = pubrel.packet_id;
+                let code = match self.incoming_qos2.remove(&packet_id) {
+                    true => ReasonCode::Success,
+                    false => ReasonCode::PacketIdNotFound,
+                };
+                self.send(&new_pub(PacketType::PubComp, packet_id, code))?;
+            }
+            v5::Packet::PubComp(pubcomp) => {
+                self.inflight.remove(&pubcomp.packet_id);
+            }
+            v5::Packet::SubAck(suback) => match self.inflight.remove(&suback.packet_id) {
+                Some(Inflight::Subscribe(mut sub)) => {
+                    let codes = suback.return_codes.iter();
+                    let mut iter = codes.map(|code| (*code as u8) < 0x80);
+                    sub.filters.retain(|_| iter.next().unwrap_or(false));
+                    self.add_subscription(sub);
+                    self.acks.insert(suback.packet_id, v5::Packet::SubAck(suback));
+                }
+                Some(Inflight::Resubscribe(sub)) => {
+                    let iter = sub.filters.iter().zip(suback.return_codes.iter());
+                    for (filter, code) in iter.filter(|(_, code)| (**code as u8) >= 0x80)
+                    {
+                        let tf = &filter.topic_filter;
+                        error!("{} resubscribe {:?} failed {:?}", self.prefix, tf, code);
+                    }
+                }
+                _ => debug!("{} SUBACK timed out {}", self.prefix, suback.packet_id),
+            },
+            v5::Packet::UnsubAck(unsuback) => {
+                let packet_id = unsuback.packet_id;
+                if let Some(Inflight::UnSubscribe(unsub)) =
+                    self.inflight.remove(&packet_id)
+                {
+                    self.remove_subscription(&unsub.filters);
+                    self.acks.insert(packet_id, v5::Packet::UnsubAck(unsuback));
+                }
+            }
+            v5::Packet::PingResp => {
+                self.ping_at = None;
+            }
+            v5::Packet::Disconnect(dc) => {
+                error!("{} broker disconnected with {:?}", self.prefix, dc.code);
+                err!(Disconnected, desc: "{} DISCONNECT {:?}", self.prefix, dc.code)?;
+            }
+            pkt => {
+                let pt = pkt.to_packet_type();
+                err!(ProtocolError, code: ProtocolError, "{} unexpected {:?}", self.prefix, pt)?
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_subscription(&mut self, sub: v5::Subscribe) {
+        let filters: Vec<TopicFilter> =
+            sub.filters.iter().map(|f| f.topic_filter.clone()).collect();
+        self.remove_subscription(&filters);
+        if !sub.filters.is_empty() {
+            self.subscriptions.push(sub);
+        }
+    }
+
+    fn remove_subscription(&mut self, filters: &[TopicFilter]) {
+        for sub in self.subscriptions.iter_mut() {
+            sub.filters.retain(|f| !filters.contains(&f.topic_filter));
+        }
+        self.subscriptions.retain(|sub| !sub.filters.is_empty());
+    }
+
+    fn handle_publish(&mut self, publ: v5::Publish) -> Result<()> {
+        match (publ.qos, publ.packet_id) {
+            (QoS::AtMostOnce, _) => self.messages.push_back(publ),
+            (QoS::AtLeastOnce, Some(packet_id)) => {
+                let code = ReasonCode::Success;
+                self.send(&new_pub(PacketType::PubAck, packet_id, code))?;
+                self.messages.push_back(publ);
+            }
+            (QoS::ExactlyOnce, Some(packet_id)) => {
+                // duplicate delivery of QoS-2 PUBLISH, before PUBREL, is ignored.
+                if self.incoming_qos2.insert(packet_id) {
+                    self.messages.push_back(publ);
+                }
+                let code = ReasonCode::Success;
+                self.send(&new_pub(PacketType::PubRec, packet_id, code))?;
+            }
+            (_, None) => unreachable!(), // decoder fails for QoS > 0 without packet_id
+        }
+
+        Ok(())
+    }
+}
+
+fn open_conn(
+    prefix: &str,
+    args: &ClientArgs,
+    poll: &mio::Poll,
+) -> Result<mio::net::TcpStream> {
+    use mio::Interest;
+
+    let mut conn = err!(
+        IOError,
+        try: mio::net::TcpStream::connect(args.address),
+        "{} connect {}",
+        prefix,
+        args.address
+    )?;
+    let interest = Interest::READABLE | Interest::WRITABLE;
+    err!(
+        IOError,
+        try: poll.registry().register(&mut conn, Client::TOKEN_CONN, interest),
+        "{} register",
+        prefix
+    )?;
+
+    Ok(conn)
+}
+
+fn new_pub(packet_type: PacketType, packet_id: PacketID, code: ReasonCode) -> v5::Pub {
+    v5::Pub { packet_type, packet_id, code, properties: None }
+}
+
+#[cfg(test)]
+#[path = "client_test.rs"]
+mod client_test;
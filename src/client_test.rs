@@ -0,0 +1,309 @@
+use std::io::Write;
+use std::{net, thread};
+
+use crate::fixtures::new_publish;
+use crate::v5::{ConnAckProperties, RetainForwardRule, SubscribeFilter, SubscriptionOpt};
+
+use super::*;
+
+// Read a single packet from a blocking stream.
+fn read_packet(conn: &net::TcpStream) -> v5::Packet {
+    let mut packetr = MQTTRead::new(1024);
+    loop {
+        packetr = match packetr.read(conn).unwrap() {
+            (mut packetr @ MQTTRead::Fin { .. }, _) => break packetr.parse().unwrap(),
+            (packetr, _) => packetr,
+        };
+    }
+}
+
+// Same as read_packet, but collect PUBCOMP packet-ids in `comps` and skip them.
+fn read_packet_skip(conn: &net::TcpStream, comps: &mut Vec<PacketID>) -> v5::Packet {
+    loop {
+        match read_packet(conn) {
+            v5::Packet::PubComp(pubcomp) => comps.push(pubcomp.packet_id),
+            pkt => break pkt,
+        }
+    }
+}
+
+fn write_packets(mut conn: &net::TcpStream, pkts: &[v5::Packet]) {
+    // all packets are written back-to-back, to exercise framing on the client side.
+    let mut data = vec![];
+    for pkt in pkts.iter() {
+        data.extend_from_slice(pkt.encode().unwrap().as_ref());
+    }
+    conn.write_all(&data).unwrap();
+}
+
+// Mock broker, script the server side of the session.
+fn broker(server: net::TcpListener) {
+    let (conn, _) = server.accept().unwrap();
+
+    match read_packet(&conn) {
+        v5::Packet::Connect(connect) => {
+            assert_eq!(*connect.payload.client_id, "test-client");
+            assert_eq!(connect.keep_alive, 1);
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    let props = ConnAckProperties { receive_maximum: Some(1), ..Default::default() };
+    let connack = v5::ConnAck::new_success(Some(props));
+    write_packets(&conn, &[v5::Packet::ConnAck(connack)]);
+
+    let packet_id = match read_packet(&conn) {
+        v5::Packet::Subscribe(sub) => sub.packet_id,
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    let suback = v5::SubAck {
+        packet_id,
+        properties: None,
+        return_codes: vec![v5::SubAckReasonCode::QoS2],
+    };
+    // along with SUBACK, deliver a QoS-1 and a QoS-2 message.
+    write_packets(
+        &conn,
+        &[
+            v5::Packet::SubAck(suback),
+            v5::Packet::Publish(new_publish("a/qos1", QoS::AtLeastOnce, Some(10))),
+            v5::Packet::Publish(new_publish("a/qos2", QoS::ExactlyOnce, Some(11))),
+        ],
+    );
+    match read_packet(&conn) {
+        v5::Packet::PubAck(puback) => assert_eq!(puback.packet_id, 10),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    match read_packet(&conn) {
+        v5::Packet::PubRec(pubrec) => assert_eq!(pubrec.packet_id, 11),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    let pubrel = new_pub(PacketType::PubRel, 11, ReasonCode::Success);
+    write_packets(&conn, &[v5::Packet::PubRel(pubrel)]);
+    // client publish QoS-1 and QoS-2, with receive-maximum of 1. Client may start
+    // publishing before it gets the PUBREL, so PUBCOMP can come in any order.
+    let mut comps = vec![];
+    let packet_id = match read_packet_skip(&conn, &mut comps) {
+        v5::Packet::Publish(publ) if publ.qos == QoS::AtLeastOnce => {
+            publ.packet_id.unwrap()
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    let puback = new_pub(PacketType::PubAck, packet_id, ReasonCode::Success);
+    write_packets(&conn, &[v5::Packet::PubAck(puback)]);
+    let packet_id = match read_packet_skip(&conn, &mut comps) {
+        v5::Packet::Publish(publ) if publ.qos == QoS::ExactlyOnce => {
+            publ.packet_id.unwrap()
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    let pubrec = new_pub(PacketType::PubRec, packet_id, ReasonCode::Success);
+    write_packets(&conn, &[v5::Packet::PubRec(pubrec)]);
+    match read_packet_skip(&conn, &mut comps) {
+        v5::Packet::PubRel(pubrel) => assert_eq!(pubrel.packet_id, packet_id),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    let pubcomp = new_pub(PacketType::PubComp, packet_id, ReasonCode::Success);
+    write_packets(&conn, &[v5::Packet::PubComp(pubcomp)]);
+
+    // idle client shall send PINGREQ.
+    match read_packet_skip(&conn, &mut comps) {
+        v5::Packet::PingReq => write_packets(&conn, &[v5::Packet::PingResp]),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+
+    match read_packet(&conn) {
+        v5::Packet::Disconnect(dc) => {
+            assert_eq!(dc.code, v5::DisconnReasonCode::NormalDisconnect)
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    assert_eq!(comps, vec![11]);
+}
+
+#[test]
+fn test_client() {
+    let server = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = thread::spawn(move || broker(server));
+
+    let args = ClientArgs {
+        address,
+        client_id: ClientID("test-client".to_string()),
+        keep_alive: 1,
+        ..ClientArgs::default()
+    };
+    let mut client = Client::connect(args).unwrap();
+    assert!(!client.is_session_present());
+
+    let filter = SubscribeFilter {
+        topic_filter: TopicFilter::from("a/#".to_string()),
+        opt: SubscriptionOpt::new(
+            RetainForwardRule::Never,
+            false,
+            false,
+            QoS::ExactlyOnce,
+        ),
+    };
+    let suback = client.subscribe(vec![filter], None).unwrap();
+    assert_eq!(suback.return_codes, vec![v5::SubAckReasonCode::QoS2]);
+
+    let msgs: Vec<v5::Publish> = client.iter().take(2).map(|m| m.unwrap()).collect();
+    assert_eq!(*msgs[0].topic_name, "a/qos1");
+    assert_eq!(*msgs[1].topic_name, "a/qos2");
+
+    let id1 = client.publish(new_publish("b/qos1", QoS::AtLeastOnce, None)).unwrap();
+    let id2 = client.publish(new_publish("b/qos2", QoS::ExactlyOnce, None)).unwrap();
+    assert!(id1.is_some() && id2.is_some() && id1 != id2);
+    client.wait_inflight(Some(time::Duration::from_secs(5))).unwrap();
+    assert_eq!(client.inflight_len(), 0);
+
+    let timeout = Some(time::Duration::from_millis(1500));
+    assert!(client.recv(timeout).unwrap().is_none());
+    assert!(client.ping_at.is_none());
+
+    client.disconnect(v5::DisconnReasonCode::NormalDisconnect).unwrap();
+    handle.join().unwrap();
+}
+
+// Mock broker, drop the connection with an unacknowledged PUBLISH and expect the
+// session to be restored after reconnect.
+fn broker_reconnect(server: net::TcpListener) {
+    let (conn, _) = server.accept().unwrap();
+    match read_packet(&conn) {
+        v5::Packet::Connect(connect) => assert!(connect.flags.unwrap().0),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    let connack = v5::ConnAck::new_success(None);
+    write_packets(&conn, &[v5::Packet::ConnAck(connack)]);
+    let packet_id = match read_packet(&conn) {
+        v5::Packet::Subscribe(sub) => sub.packet_id,
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    let suback = v5::SubAck {
+        packet_id,
+        properties: None,
+        return_codes: vec![v5::SubAckReasonCode::QoS1],
+    };
+    write_packets(&conn, &[v5::Packet::SubAck(suback)]);
+    let packet_id = match read_packet(&conn) {
+        v5::Packet::Publish(publ) => publ.packet_id.unwrap(),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    std::mem::drop(conn);
+
+    let (conn, _) = server.accept().unwrap();
+    match read_packet(&conn) {
+        v5::Packet::Connect(connect) => {
+            assert!(!connect.flags.unwrap().0);
+            assert_eq!(*connect.payload.client_id, "test-reconnect");
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    // session is not present, client shall resubscribe.
+    let connack = v5::ConnAck::new_success(None);
+    write_packets(&conn, &[v5::Packet::ConnAck(connack)]);
+    let sub_id = match read_packet(&conn) {
+        v5::Packet::Subscribe(sub) => {
+            assert_eq!(*sub.filters[0].topic_filter, "a/#");
+            sub.packet_id
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    match read_packet(&conn) {
+        v5::Packet::Publish(publ) => {
+            assert!(publ.duplicate);
+            assert_eq!(
+                (publ.topic_name.as_str(), publ.packet_id),
+                ("b/1", Some(packet_id))
+            );
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    let suback = v5::SubAck {
+        packet_id: sub_id,
+        properties: None,
+        return_codes: vec![v5::SubAckReasonCode::QoS1],
+    };
+    let puback = new_pub(PacketType::PubAck, packet_id, ReasonCode::Success);
+    write_packets(&conn, &[v5::Packet::SubAck(suback), v5::Packet::PubAck(puback)]);
+
+    // offline buffer, oldest message was dropped.
+    for topic in ["b/3", "b/4"] {
+        match read_packet(&conn) {
+            v5::Packet::Publish(publ) => {
+                assert!(!publ.duplicate);
+                assert_eq!(*publ.topic_name, topic);
+                let packet_id = publ.packet_id.unwrap();
+                let puback = new_pub(PacketType::PubAck, packet_id, ReasonCode::Success);
+                write_packets(&conn, &[v5::Packet::PubAck(puback)]);
+            }
+            pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+        }
+    }
+
+    match read_packet(&conn) {
+        v5::Packet::Disconnect(_) => (),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+}
+
+#[test]
+fn test_client_reconnect() {
+    let server = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = server.local_addr().unwrap();
+    let handle = thread::spawn(move || broker_reconnect(server));
+
+    let reconnect = Reconnect {
+        min_backoff: time::Duration::from_millis(100),
+        buffer_size: 2,
+        ..Reconnect::default()
+    };
+    let args = ClientArgs {
+        address,
+        client_id: ClientID("test-reconnect".to_string()),
+        reconnect: Some(reconnect),
+        ..ClientArgs::default()
+    };
+    let mut client = Client::connect(args).unwrap();
+
+    let filter = SubscribeFilter {
+        topic_filter: TopicFilter::from("a/#".to_string()),
+        opt: SubscriptionOpt::new(
+            RetainForwardRule::Never,
+            false,
+            false,
+            QoS::AtLeastOnce,
+        ),
+    };
+    client.subscribe(vec![filter], None).unwrap();
+
+    let id1 = client.publish(new_publish("b/1", QoS::AtLeastOnce, None)).unwrap();
+    assert!(id1.is_some());
+    // broker drops the connection.
+    let timeout = Some(time::Duration::from_millis(50));
+    while client.is_connected() {
+        assert!(client.recv(timeout).unwrap().is_none());
+    }
+    for topic in ["b/2", "b/3", "b/4"] {
+        let res = client.publish(new_publish(topic, QoS::AtLeastOnce, None));
+        assert_eq!(res.unwrap(), None);
+    }
+    assert_eq!((client.inflight_len(), client.offline_len()), (1, 2));
+
+    client.wait_inflight(Some(time::Duration::from_secs(5))).unwrap();
+    assert!(client.is_connected());
+    assert_eq!((client.inflight_len(), client.offline_len()), (0, 0));
+
+    client.disconnect(v5::DisconnReasonCode::NormalDisconnect).unwrap();
+    handle.join().unwrap();
+
+    let reconnect = Reconnect {
+        min_backoff: time::Duration::from_secs(1),
+        max_backoff: time::Duration::from_secs(4),
+        ..Reconnect::default()
+    };
+    for (retries, max) in [(0, 1000), (1, 2000), (2, 4000), (3, 4000), (40, 4000)] {
+        let backoff = reconnect.to_backoff(retries).as_millis();
+        assert!(backoff >= max / 2 && backoff <= max, "{} {}", retries, backoff);
+    }
+}
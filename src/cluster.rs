@@ -1,14 +1,19 @@
-use log::{debug, info, trace};
+use log::{debug, error, info, trace, warn};
 use mio::event::Events;
 use uuid::Uuid;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::{atomic::AtomicBool, atomic::Ordering::SeqCst, mpsc, Arc};
-use std::{collections::BTreeMap, net, path, time};
+use std::{net, path, thread, time};
 
+use crate::membership::{self, Membership};
+use crate::replica::Replica;
 use crate::thread::{Rx, Thread, Threadable, Tx};
-use crate::{rebalance, ticker, timer, util, v5};
-use crate::{AppTx, Config, ConfigNode, Hostable, RetainedTrie, SubscribedTrie, Timer};
-use crate::{Flusher, Listener, QueueStatus, Shard, Ticker, TopicName};
+use crate::{listener, rebalance, store, ticker, timer, util, v5, wal};
+use crate::{AppTx, Config, ConfigListener, ConfigNode, ConnGuard, Hostable, Timer};
+use crate::{Flusher, Listener, Message, QueueStatus, Shard, Ticker, TopicName};
+use crate::{NodeConns, PeerCred, RetainedTrie, Stream, SubscribedTrie};
+use crate::{RetainRecord, RetainStore, Snapshot};
 
 use crate::{Error, ErrorKind, Result};
 
@@ -51,17 +56,37 @@ struct RunLoop {
     /// Mio pooler for asynchronous handling, aggregate events from consensus port and
     /// waker.
     poll: mio::Poll,
-    /// Listener thread for MQTT connections from remote/local clients.
-    listener: Listener,
+    /// Membership across nodes, None for single-node cluster.
+    membership: Option<Box<Membership>>,
+    /// Listener threads for MQTT connections from remote/local clients, one for
+    /// each configured listener.
+    listeners: Vec<Listener>,
     /// Ticker thread to periodically wake up other threads, defaul is 10ms.
     ticker: Ticker,
     /// Flusher thread for MQTT connections from remote/local clients.
     flusher: Flusher,
     /// Total number of shards within this node.
     shards: BTreeMap<u32, Shard>,
+    /// Tx handle to this cluster, set along with shards, to spawn shards promoted on
+    /// fail-over.
+    cluster: Option<Box<Cluster>>,
+    /// Replicated state of shards mastered by other nodes, refer [crate::replica].
+    replicas: BTreeMap<u32, Replica>,
+    /// Seqno of the next record replicated, for each shard mastered by this node, as
+    /// numbered by the shard.
+    replica_seqnos: BTreeMap<u32, u64>,
+    /// Shards migrated to this node from a live master, waiting for its HANDOVER,
+    /// along with the old master and the deadline to wait until.
+    handovers: BTreeMap<u32, (Uuid, time::Instant)>,
+    /// Messages forwarded by other nodes, to shards mastered by this node, that are
+    /// yet to be sent to the shard.
+    forward_back_log: BTreeMap<u32, Vec<Message>>,
+    /// Subscriptions of shards mastered by other nodes, indexed in `topic_filters`.
+    remote_subscriptions: BTreeMap<u32, Vec<v5::Subscription>>,
+    /// Subscriptions of shards mastered by this node, as last sent to other nodes.
+    exported: BTreeMap<u32, Vec<v5::Subscription>>,
+    exported_at: time::Instant,
 
-    /// Rebalancing algorithm.
-    rebalancer: rebalance::Rebalancer,
     /// Index of subscribed topicfilters across all the sessions, local to this node.
     // TODO: Should we make this part of the ClusterState ?
     topic_filters: SubscribedTrie, // key=TopicFilter, val=(client_id, shard_id)
@@ -76,7 +101,7 @@ struct RunLoop {
 
 pub struct FinState {
     pub state: ClusterState,
-    pub listener: Listener,
+    pub listeners: Vec<Listener>,
     pub ticker: Ticker,
     pub flusher: Flusher,
     pub shards: BTreeMap<u32, Shard>,
@@ -136,6 +161,9 @@ impl Cluster {
                 config.num_shards()
             )?;
         }
+        if config.nodes.len() > 1 {
+            Self::validate_nodes(&config)?;
+        }
 
         let mut val = Cluster {
             name: format!("{}-cluster-init", config.name),
@@ -158,19 +186,40 @@ impl Cluster {
         let poll = err!(IOError, try: mio::Poll::new(), "fail creating mio::Poll")?;
         let waker = Arc::new(Waker::new(poll.registry(), Self::TOKEN_WAKE)?);
 
-        let rebalancer = rebalance::Rebalancer {
-            config: self.config.clone(),
-            algo: rebalance::Algorithm::SingleNode,
-        };
-
-        let state = {
-            let topology = rebalancer.rebalance(&vec![node.clone()], Vec::new());
-            ClusterState::SingleNode {
-                state: SingleNode { config: self.config.clone(), node, topology },
+        let node_uuid = node.uuid;
+        let replicated = Self::is_replicated(&self.config);
+        let (state, membership) = if self.config.nodes.len() > 1 {
+            let is_configured = |n: &ConfigNode| match n.uuid.as_ref() {
+                Some(uuid) => uuid.parse::<Uuid>().ok() == Some(node.uuid),
+                None => false,
+            };
+            if !self.config.nodes.iter().any(is_configured) {
+                err!(InvalidInput, desc: "node {} is not in config.nodes", node.uuid)?;
             }
+            // topology is computed by the membership leader, refer [crate::membership].
+            let state = ClusterState::Elastic {
+                state: MultiNode {
+                    config: self.config.clone(),
+                    epoch: 0,
+                    nodes: vec![node.clone()],
+                    topology: Vec::new(),
+                },
+            };
+            let membership =
+                Membership::new(&self.prefix, &self.config, &node, poll.registry())?;
+            (state, Some(Box::new(membership)))
+        } else {
+            let rebalancer = rebalance::Rebalancer {
+                config: self.config.clone(),
+                algo: rebalance::Algorithm::SingleNode,
+            };
+            let topology = rebalancer.rebalance(&vec![node.clone()], Vec::new())?;
+            let state = ClusterState::SingleNode {
+                state: SingleNode { config: self.config.clone(), node, topology },
+            };
+            (state, None)
         };
 
-        let listener = Listener::default();
         let flusher = Flusher::from_config(self.config.clone())?.spawn(app_tx.clone())?;
         let shards = BTreeMap::default();
 
@@ -185,12 +234,20 @@ impl Cluster {
                 state,
 
                 poll,
-                listener,
+                membership,
+                listeners: Vec::default(),
                 ticker: Ticker::default(),
                 flusher,
                 shards,
+                cluster: None,
+                replicas: BTreeMap::default(),
+                replica_seqnos: BTreeMap::default(),
+                handovers: BTreeMap::default(),
+                forward_back_log: BTreeMap::default(),
+                remote_subscriptions: BTreeMap::default(),
+                exported: BTreeMap::default(),
+                exported_at: time::Instant::now(),
 
-                rebalancer,
                 topic_filters: topic_filters.clone(),
                 retained_messages: retained_messages.clone(),
 
@@ -209,12 +266,27 @@ impl Cluster {
         };
         cluster.prefix = cluster.prefix();
 
+        // retained messages are loaded before shards and listeners are spawned.
+        match &cluster.inner {
+            Inner::Handle(_waker, thrd) => thrd.request(Request::LoadRetained)??,
+            _ => unreachable!(),
+        };
+
+        // for multi-node cluster, this node hosts only its shards.
+        let shard_ids = match cluster.wait_shards_in_node(&node_uuid) {
+            Ok(shard_ids) => shard_ids,
+            Err(err) => {
+                cluster.close_wait();
+                return Err(err);
+            }
+        };
+        info!("{} hosting {} shards", cluster.prefix, shard_ids.len());
+
         {
             let mut ticker_shards = Vec::new();
-            let mut shard_queues = BTreeMap::default();
 
             let mut shards = BTreeMap::default();
-            for shard_id in 0..self.config.num_shards() {
+            for shard_id in shard_ids.into_iter() {
                 let (config, cluster_tx) = (self.config.clone(), cluster.to_tx());
                 let shard = {
                     let args = crate::shard::SpawnArgs {
@@ -222,27 +294,36 @@ impl Cluster {
                         flusher: flusher_tx.to_tx(),
                         topic_filters: topic_filters.clone(),
                         retained_messages: retained_messages.clone(),
+                        replicated,
+                        replay: None,
                     };
                     Shard::from_config(config, shard_id)?.spawn(args, app_tx.clone())?
                 };
 
-                shard_queues.insert(shard.shard_id, shard.to_msg_tx());
                 ticker_shards.push(shard.to_tx());
 
                 shards.insert(shard_id, shard);
             }
 
             for (_shard_id, shard) in shards.iter() {
-                let iter = shard_queues.iter().map(|(id, s)| (*id, s.to_msg_tx()));
-                let shard_queues = BTreeMap::from_iter(iter);
-                shard.set_shard_queues(shard_queues)?;
+                shard.set_shard_queues(to_shard_queues(
+                    &self.config,
+                    &shards,
+                    &cluster,
+                ))?;
             }
 
-            let (config, clust_tx) = (self.config.clone(), cluster.to_tx());
-            let listener = {
-                let listener = Listener::from_config(config)?;
-                listener.spawn(clust_tx, app_tx.clone())?
-            };
+            let mut listeners = Vec::default();
+            let conns = Arc::new(NodeConns::default());
+            for listener_config in self.config.listeners().into_iter() {
+                let config = self.config.clone();
+                let args = listener::SpawnArgs {
+                    cluster: cluster.to_tx(),
+                    conns: Arc::clone(&conns),
+                };
+                let listener = Listener::from_config(config, listener_config)?;
+                listeners.push(listener.spawn(args, app_tx.clone())?);
+            }
 
             let ticker = {
                 let args = ticker::SpawnArgs {
@@ -255,7 +336,12 @@ impl Cluster {
 
             match &cluster.inner {
                 Inner::Handle(_waker, thrd) => {
-                    thrd.request(Request::Set { listener, ticker, shards })??;
+                    thrd.request(Request::Set {
+                        listeners,
+                        ticker: Box::new(ticker),
+                        shards,
+                        cluster: Box::new(cluster.to_tx()),
+                    })??;
                 }
                 _ => unreachable!(),
             }
@@ -264,6 +350,73 @@ impl Cluster {
         Ok(cluster)
     }
 
+    // Shards are replicated in multi-node cluster, refer [crate::replica].
+    fn is_replicated(config: &Config) -> bool {
+        config.nodes.len() > 1 && config.num_replicas() > 0
+    }
+
+    fn validate_nodes(config: &Config) -> Result<()> {
+        if config.nodes.len() > (config.max_nodes() as usize) {
+            err!(
+                InvalidInput,
+                desc: "{} nodes exceed max_nodes {}",
+                config.nodes.len(),
+                config.max_nodes()
+            )?;
+        }
+
+        let mut uuids = Vec::default();
+        for config_node in config.nodes.iter() {
+            let uuid = match config_node.uuid.as_ref() {
+                Some(uuid) => err!(InvalidInput, try: uuid.parse::<Uuid>())?,
+                None => {
+                    err!(InvalidInput, desc: "multi-node cluster, node without uuid")?
+                }
+            };
+            if config_node.weight == Some(0) {
+                err!(InvalidInput, desc: "node {} with ZERO weight", uuid)?;
+            } else if config_node.cluster_address.is_none() {
+                err!(InvalidInput, desc: "node {} without cluster_address", uuid)?;
+            } else if uuids.contains(&uuid) {
+                err!(InvalidInput, desc: "duplicate node {}", uuid)?;
+            }
+            uuids.push(uuid);
+        }
+
+        Ok(())
+    }
+
+    // Wait for the initial topology, refer [crate::membership], and return the
+    // shards hosted by `node`.
+    fn wait_shards_in_node(&self, node: &Uuid) -> Result<Vec<u32>> {
+        let timeout =
+            time::Duration::from_secs(self.config.cluster_join_timeout() as u64);
+        let deadline = time::Instant::now() + timeout;
+        loop {
+            let resp = match &self.inner {
+                Inner::Handle(_waker, thrd) => {
+                    thrd.request(Request::ShardsInNode { node: *node })??
+                }
+                _ => unreachable!(),
+            };
+            match resp {
+                Response::Shards(Some(shard_ids)) => break Ok(shard_ids),
+                Response::Shards(None) if time::Instant::now() < deadline => {
+                    thread::sleep(Membership::HEARTBEAT / 10)
+                }
+                Response::Shards(None) => {
+                    break err!(
+                        Timeout,
+                        desc: "{} nodes did not join after {:?}",
+                        self.prefix,
+                        timeout
+                    )
+                }
+                Response::Ok | Response::Snapshot(_) => unreachable!(),
+            }
+        }
+    }
+
     pub fn to_tx(&self) -> Self {
         info!("{} cloning tx ...", self.prefix);
 
@@ -285,9 +438,10 @@ impl Cluster {
 
 pub enum Request {
     Set {
-        listener: Listener,
-        ticker: Ticker,
+        listeners: Vec<Listener>,
+        ticker: Box<Ticker>,
         shards: BTreeMap<u32, Shard>,
+        cluster: Box<Cluster>,
     },
     SetRetainTopic {
         publish: v5::Publish,
@@ -295,18 +449,42 @@ pub enum Request {
     ResetRetainTopic {
         topic_name: TopicName,
     },
-    AddConnection(AddConnectionArgs),
+    LoadRetained,
+    ShardsInNode {
+        node: Uuid,
+    },
+    ExportSnapshot,
+    ImportSnapshot(Box<Snapshot>),
+    AddConnection(Box<AddConnectionArgs>),
+    Replicate {
+        shard_id: u32,
+        seqno: u64,
+        reset: bool,
+        records: Vec<wal::Record>,
+    },
+    Forward {
+        shard_id: u32,
+        msgs: Vec<Message>,
+    },
+    Handover {
+        shard_id: u32,
+    },
     Close,
 }
 
 pub enum Response {
     Ok,
+    Snapshot(Box<Snapshot>),
+    Shards(Option<Vec<u32>>),
 }
 
 pub struct AddConnectionArgs {
-    pub conn: mio::net::TcpStream,
+    pub conn: Stream,
     pub addr: net::SocketAddr,
+    pub peer_cred: Option<PeerCred>,
     pub pkt: v5::Connect,
+    pub listener: ConfigListener,
+    pub guard: ConnGuard,
 }
 
 // calls to interface with cluster-thread.
@@ -321,8 +499,8 @@ impl Cluster {
     pub fn add_connection(&self, args: AddConnectionArgs) -> Result<()> {
         match &self.inner {
             Inner::Tx(_waker, tx) => {
-                let req = Request::AddConnection(args);
-                tx.request(req)??;
+                let req = Request::AddConnection(Box::new(args));
+                tx.post(req)?;
             }
             _ => unreachable!(),
         };
@@ -354,6 +532,85 @@ impl Cluster {
         Ok(())
     }
 
+    /// Send changes to session state of master shard `shard_id` to its replicas,
+    /// numbered from `seqno`. With `reset`, `records` capture the live state of the
+    /// shard. Refer [crate::replica].
+    pub fn replicate(
+        &self,
+        shard_id: u32,
+        seqno: u64,
+        reset: bool,
+        records: Vec<wal::Record>,
+    ) -> Result<()> {
+        match &self.inner {
+            Inner::Tx(_waker, tx) => {
+                let req = Request::Replicate { shard_id, seqno, reset, records };
+                tx.post(req)?;
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Send `msgs` to shard `shard_id` mastered by another node, refer
+    /// [crate::membership]. Messages in flight are lost if the connection to that
+    /// node fails, hence PUBLISH forwarded to other nodes is delivered at-most-once.
+    pub fn forward(&self, shard_id: u32, msgs: Vec<Message>) -> Result<()> {
+        match &self.inner {
+            Inner::Tx(_waker, tx) => {
+                let req = Request::Forward { shard_id, msgs };
+                tx.post(req)?;
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    // Send HANDOVER of shard `shard_id` to its new master, after the records
+    // replicated by the shard while closing, refer [Cluster::demote_shards].
+    fn handover(&self, shard_id: u32) -> Result<()> {
+        match &self.inner {
+            Inner::Tx(_waker, tx) => {
+                let req = Request::Handover { shard_id };
+                tx.post(req)?;
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Return a snapshot of subscriptions and retained messages, refer
+    /// [crate::snapshot].
+    pub fn export_snapshot(&self) -> Result<Snapshot> {
+        match &self.inner {
+            Inner::Handle(_waker, thrd) => {
+                match thrd.request(Request::ExportSnapshot)?? {
+                    Response::Snapshot(snapshot) => Ok(*snapshot),
+                    Response::Ok | Response::Shards(_) => unreachable!(),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Load subscriptions and retained messages from `snapshot`, typically into a
+    /// freshly spawned cluster. Subscriptions are re-partitioned to this cluster's
+    /// shards, as offline sessions, and retained messages are written to the retain
+    /// store, if configured.
+    pub fn import_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        match &self.inner {
+            Inner::Handle(_waker, thrd) => {
+                thrd.request(Request::ImportSnapshot(Box::new(snapshot)))??;
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(())
+    }
+
     pub fn close_wait(mut self) -> Cluster {
         use std::mem;
 
@@ -368,6 +625,29 @@ impl Cluster {
     }
 }
 
+// Hand over cluster requests to the caller, without spawning the cluster thread.
+#[cfg(any(feature = "fuzzy", test))]
+impl Cluster {
+    /// Create a cluster in tx-state, requests posted to this cluster are queued in
+    /// the returned receiver. Caller shall hold the receiver for the life-time of
+    /// the cluster.
+    pub(crate) fn new_in_memory(config: Config) -> Result<(Cluster, ThreadRx)> {
+        let poll = err!(IOError, try: mio::Poll::new(), "fail creating mio::Poll")?;
+        let waker = Arc::new(mio::Waker::new(poll.registry(), Self::TOKEN_WAKE)?);
+        let (tx, rx) = mpsc::channel();
+
+        let mut val = Cluster {
+            name: format!("{}-cluster-memory", config.name),
+            prefix: String::default(),
+            config,
+            inner: Inner::Tx(waker, Tx::N(tx, None)),
+        };
+        val.prefix = val.prefix();
+
+        Ok((val, rx))
+    }
+}
+
 impl Threadable for Cluster {
     type Req = Request;
     type Resp = Result<Response>;
@@ -383,11 +663,16 @@ impl Threadable for Cluster {
         let mut rt = Rt {
             retain_timer: Timer::default(),
             retain_topics: BTreeMap::default(),
+            retain_store: None,
         };
 
         let mut events = Events::with_capacity(crate::POLL_EVENTS_SIZE);
         loop {
-            let timeout: Option<time::Duration> = None;
+            // membership needs periodic heartbeat, even before ticker is spawned.
+            let timeout: Option<time::Duration> = match self.is_multi_node() {
+                true => Some(Membership::HEARTBEAT / 10),
+                false => None,
+            };
             allow_panic!(&self, self.as_mut_poll().poll(&mut events, timeout));
 
             match self.mio_events(&rx, &events, &mut rt) {
@@ -395,7 +680,10 @@ impl Threadable for Cluster {
                 _exit => (),
             };
 
+            self.update_membership();
+
             self.retain_expires(&mut rt);
+            self.flush_retain_store(&mut rt);
         }
 
         match &self.inner {
@@ -430,8 +718,7 @@ impl Cluster {
                                 (QueueStatus::Disconnected(_), _) => break 'outer true,
                             }
                         },
-                        Self::TOKEN_CONSENSUS => todo!(),
-                        _ => unreachable!(),
+                        _ => self.membership_event(event),
                     }
                 }
                 None => break false,
@@ -467,10 +754,34 @@ impl Cluster {
                 (req @ ResetRetainTopic { .. }, None) => {
                     self.handle_reset_retain_topic(req, rt);
                 }
-                (req @ AddConnection(_), Some(tx)) => {
-                    let resp = self.handle_add_connection(req);
+                (req @ LoadRetained, Some(tx)) => {
+                    let resp = self.handle_load_retained(req, rt);
+                    err!(IPCFail, try: tx.send(resp)).ok();
+                }
+                (req @ ShardsInNode { .. }, Some(tx)) => {
+                    let resp = self.handle_shards_in_node(req);
+                    err!(IPCFail, try: tx.send(Ok(resp))).ok();
+                }
+                (req @ ExportSnapshot, Some(tx)) => {
+                    let resp = self.handle_export_snapshot(req);
                     err!(IPCFail, try: tx.send(Ok(resp))).ok();
                 }
+                (req @ ImportSnapshot(_), Some(tx)) => {
+                    let resp = self.handle_import_snapshot(req, rt);
+                    err!(IPCFail, try: tx.send(resp)).ok();
+                }
+                (req @ AddConnection(_), None) => {
+                    self.handle_add_connection(req);
+                }
+                (req @ Replicate { .. }, None) => {
+                    self.handle_replicate(req);
+                }
+                (req @ Forward { .. }, None) => {
+                    self.handle_forward(req);
+                }
+                (req @ Handover { .. }, None) => {
+                    self.handle_handover(req);
+                }
                 (req @ Close, Some(tx)) => {
                     let resp = self.handle_close(req, rt);
                     err!(IPCFail, try: tx.send(Ok(resp))).ok();
@@ -484,6 +795,536 @@ impl Cluster {
         (status, closed)
     }
 
+    fn membership_event(&mut self, event: &mio::event::Event) {
+        match &mut self.inner {
+            Inner::Main(RunLoop { poll, membership: Some(membership), .. }) => {
+                membership.handle_event(poll.registry(), event)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // Drive membership and move between Elastic and Stable state as nodes leave and
+    // join the cluster. Shards mastered by failed nodes are promoted on their replicas,
+    // and shards migrated by a rebalance are handed over by their old master.
+    fn update_membership(&mut self) {
+        let RunLoop { poll, membership, state, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        let membership = match membership {
+            Some(membership) => membership,
+            None => return,
+        };
+
+        membership.tick(poll.registry());
+        let msgs = membership.take_replicated();
+        let routed = membership.take_routed();
+        let joined = membership.take_joined();
+
+        let MultiNode { config, epoch, topology: old_topology, .. } = match state {
+            ClusterState::Elastic { state } | ClusterState::Stable { state } => state,
+            ClusterState::SingleNode { .. } => unreachable!(),
+        };
+        let (new_epoch, nodes, topology) = membership.to_view();
+        let changed = new_epoch != *epoch;
+        let olds = match changed {
+            true => old_topology.clone(),
+            false => Vec::default(),
+        };
+        if changed {
+            let complete = membership.is_complete();
+            let multi_node = MultiNode {
+                config: config.clone(),
+                epoch: new_epoch,
+                nodes,
+                topology,
+            };
+            info!(
+                "{} membership epoch:{} nodes:{} complete:{}",
+                self.prefix,
+                new_epoch,
+                multi_node.nodes.len(),
+                complete
+            );
+
+            *state = match complete && !multi_node.topology.is_empty() {
+                true => ClusterState::Stable { state: multi_node },
+                false => ClusterState::Elastic { state: multi_node },
+            };
+        }
+
+        if changed {
+            self.demote_shards();
+            self.await_handovers(&olds);
+        }
+        let handed_over = self.apply_replicated(msgs);
+        self.apply_routed(routed);
+        let expired = self.expire_handovers();
+        if changed || !joined.is_empty() || handed_over || expired {
+            self.promote_shards();
+        }
+        if changed || !joined.is_empty() {
+            self.resync_shards();
+        }
+        self.export_subscriptions(!joined.is_empty());
+        self.flush_forwarded();
+    }
+
+    // Apply RESET, REPLICATE and HANDOVER messages, from master shards on other nodes,
+    // and ACK from the replicas of shards mastered by this node. Return whether a
+    // shard was handed over.
+    fn apply_replicated(&mut self, msgs: Vec<membership::Msg>) -> bool {
+        use membership::Msg;
+
+        let RunLoop { membership, state, shards, replicas, handovers, .. } =
+            match &mut self.inner {
+                Inner::Main(run_loop) => run_loop,
+                _ => unreachable!(),
+            };
+
+        let now = store::unix_secs();
+        let mut handed_over = false;
+        let mut applied = BTreeSet::default();
+        for msg in msgs.into_iter() {
+            match msg {
+                Msg::Reset { shard, .. }
+                | Msg::Replicate { shard, .. }
+                | Msg::Handover { shard, .. }
+                    if shards.contains_key(&shard) =>
+                {
+                    warn!("{} shard {} is mastered by this node", self.prefix, shard);
+                }
+                Msg::Reset { shard, seqno, count } => {
+                    debug!(
+                        "{} reset replica shard {} seqno {} count {}",
+                        self.prefix, shard, seqno, count
+                    );
+                    replicas.insert(shard, Replica::new(shard, seqno, count));
+                    applied.insert(shard);
+                }
+                Msg::Replicate { shard, seqno, record } => {
+                    if let Some(replica) = replicas.get_mut(&shard) {
+                        if let Err(err) = replica.apply(seqno, record, now) {
+                            warn!("{} {}", self.prefix, err);
+                        }
+                    }
+                    applied.insert(shard);
+                }
+                Msg::Ack { shard, seqno } => match shards.get(&shard) {
+                    Some(shard) => {
+                        if let Err(err) = shard.replica_ack(seqno) {
+                            error!("{} replica ack: {}", self.prefix, err);
+                        }
+                    }
+                    None => debug!("{} ack for shard {} dropped", self.prefix, shard),
+                },
+                Msg::Handover { shard, seqno } => {
+                    info!("{} shard {} handed over seqno {}", self.prefix, shard, seqno);
+                    handovers.remove(&shard);
+                    handed_over = true;
+                    match replicas.get_mut(&shard) {
+                        Some(replica) => {
+                            if let Err(err) = replica.handover(seqno) {
+                                warn!("{} {}", self.prefix, err);
+                            }
+                        }
+                        // shard is not replicated, its sessions are not carried over.
+                        None if !Self::is_replicated(&self.config) => {
+                            replicas.insert(shard, Replica::new(shard, seqno, 0));
+                        }
+                        None => (),
+                    }
+                }
+                Msg::Hello { .. } | Msg::Auth { .. } => unreachable!(),
+                Msg::View { .. } | Msg::Ping { .. } => unreachable!(),
+                Msg::Forward { .. } | Msg::Subscriptions { .. } => unreachable!(),
+            }
+        }
+
+        // acknowledge the records applied so far, to the master of each shard.
+        if let Some(membership) = membership.as_mut() {
+            for shard in applied.into_iter() {
+                let seqno = match replicas.get(&shard).and_then(|r| r.to_ack()) {
+                    Some(seqno) => seqno,
+                    None => continue,
+                };
+                let res = match state.to_master(shard) {
+                    Some(node) => {
+                        membership.send_to(&node.uuid, &Msg::Ack { shard, seqno })
+                    }
+                    None => err!(Disconnected, desc: "no master for shard {}", shard),
+                };
+                if let Err(err) = res {
+                    debug!("{} ack shard {}: {}", self.prefix, shard, err);
+                }
+            }
+        }
+
+        handed_over
+    }
+
+    // Apply FORWARD and SUBSCRIPTIONS messages, from shards on other nodes.
+    fn apply_routed(&mut self, msgs: Vec<membership::Msg>) {
+        use membership::Msg;
+
+        let RunLoop {
+            membership,
+            state,
+            shards,
+            forward_back_log,
+            remote_subscriptions,
+            topic_filters,
+            ..
+        } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        let local = match membership {
+            Some(membership) => membership.to_local().uuid,
+            None => return,
+        };
+
+        for msg in msgs.into_iter() {
+            match msg {
+                Msg::Forward { shard, msgs }
+                    if state.to_master(shard).map(|n| n.uuid) == Some(local) =>
+                {
+                    forward_back_log.entry(shard).or_default().extend(msgs);
+                }
+                Msg::Forward { shard, msgs } => warn!(
+                    "{} drop {} messages for shard {}, not mastered by this node",
+                    self.prefix,
+                    msgs.len(),
+                    shard
+                ),
+                Msg::Subscriptions { shard, .. } if shards.contains_key(&shard) => {
+                    warn!("{} shard {} is mastered by this node", self.prefix, shard);
+                }
+                Msg::Subscriptions { shard, subscriptions } => {
+                    let olds = remote_subscriptions.remove(&shard).unwrap_or_default();
+                    for old in olds.iter().filter(|s| !is_subscribed(&subscriptions, s)) {
+                        topic_filters.unsubscribe(&old.topic_filter, old);
+                    }
+                    for new in subscriptions.iter().filter(|s| !is_subscribed(&olds, s)) {
+                        topic_filters.subscribe(&new.topic_filter, new.clone());
+                    }
+                    remote_subscriptions.insert(shard, subscriptions);
+                }
+                Msg::Hello { .. } | Msg::Auth { .. } => unreachable!(),
+                Msg::View { .. } | Msg::Ping { .. } => unreachable!(),
+                Msg::Reset { .. } | Msg::Replicate { .. } => unreachable!(),
+                Msg::Handover { .. } | Msg::Ack { .. } => unreachable!(),
+            }
+        }
+    }
+
+    // Send the subscriptions of shards mastered by this node to other nodes, when
+    // changed or when `force`d, say when a node joins. Checked once every heartbeat.
+    fn export_subscriptions(&mut self, force: bool) {
+        use membership::Msg;
+
+        let RunLoop {
+            membership,
+            shards,
+            topic_filters,
+            exported,
+            exported_at,
+            ..
+        } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        let membership = match membership {
+            Some(membership) => membership,
+            None => return,
+        };
+        if !force && exported_at.elapsed() < Membership::HEARTBEAT {
+            return;
+        }
+        *exported_at = time::Instant::now();
+
+        let mut subscrs: BTreeMap<u32, Vec<v5::Subscription>> =
+            shards.keys().map(|shard_id| (*shard_id, Vec::default())).collect();
+        for subscr in topic_filters.to_values().into_iter() {
+            if let Some(subscrs) = subscrs.get_mut(&subscr.shard_id) {
+                subscrs.push(subscr)
+            }
+        }
+
+        for (shard, mut subscriptions) in subscrs.into_iter() {
+            subscriptions.sort();
+            if !force && exported.get(&shard) == Some(&subscriptions) {
+                continue;
+            }
+            let msg = Msg::Subscriptions { shard, subscriptions: subscriptions.clone() };
+            match membership.broadcast(&msg) {
+                Ok(()) => {
+                    exported.insert(shard, subscriptions);
+                }
+                Err(err) => error!("{} export shard {}: {}", self.prefix, shard, err),
+            }
+        }
+    }
+
+    // Send messages forwarded by other nodes to the shards mastered by this node.
+    fn flush_forwarded(&mut self) {
+        let RunLoop { shards, forward_back_log, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let back_log = std::mem::take(forward_back_log);
+        for (shard_id, msgs) in back_log.into_iter() {
+            // shard is not yet spawned, refer Cluster::spawn and promote_shards.
+            let mut status = match shards.get(&shard_id) {
+                Some(shard) => shard.to_msg_tx().send_messages(msgs),
+                None => QueueStatus::Block(msgs),
+            };
+            // re-index the remaining messages, may be the shard is busy.
+            let msgs = status.take_values();
+            match status {
+                QueueStatus::Ok(_) | QueueStatus::Block(_) if msgs.is_empty() => (),
+                QueueStatus::Ok(_) | QueueStatus::Block(_) => {
+                    forward_back_log.insert(shard_id, msgs);
+                }
+                QueueStatus::Disconnected(_) => {
+                    warn!("{} shard-msg-rx {} has closed", self.prefix, shard_id);
+                }
+            }
+        }
+    }
+
+    // Send the live state of shards mastered by this node to their replicas.
+    fn resync_shards(&mut self) {
+        let RunLoop { shards, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        for shard in shards.values() {
+            if let Err(err) = shard.resync() {
+                error!("{} resync shard {}: {}", self.prefix, shard.shard_id, err);
+            }
+        }
+    }
+
+    // Spawn shards that are mastered by this node as per the topology, but not yet
+    // hosted, that is, shards promoted on fail-over, or handed over by their old
+    // master after a rebalance.
+    fn promote_shards(&mut self) {
+        use crate::shard::SpawnArgs;
+
+        let RunLoop {
+            state,
+            membership,
+            ticker,
+            flusher,
+            shards,
+            cluster,
+            replicas,
+            handovers,
+            remote_subscriptions,
+            topic_filters,
+            retained_messages,
+            app_tx,
+            ..
+        } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        // shards are not yet spawned, refer Cluster::spawn.
+        let (membership, cluster) = match (membership, cluster) {
+            (Some(membership), Some(cluster)) => (membership, cluster),
+            (_, _) => return,
+        };
+
+        let shard_ids: Vec<u32> = state
+            .shards_in_node(&membership.to_local().uuid)
+            .into_iter()
+            .filter(|shard_id| !shards.contains_key(shard_id))
+            .filter(|shard_id| !handovers.contains_key(shard_id))
+            .collect();
+        if shard_ids.is_empty() {
+            return;
+        }
+
+        let now = store::unix_secs();
+        for shard_id in shard_ids.into_iter() {
+            // promoted shard shall index its own subscriptions, from the replica.
+            for subscr in remote_subscriptions.remove(&shard_id).unwrap_or_default() {
+                topic_filters.unsubscribe(&subscr.topic_filter, &subscr);
+            }
+            // sessions are lost if promoted from a stale replica, or without one,
+            // wait for the next RESET, refer [crate::replica].
+            let replay = match replicas.remove(&shard_id) {
+                Some(replica) if replica.is_stale() => {
+                    error!("{} shard {} replica is stale", self.prefix, shard_id);
+                    replicas.insert(shard_id, replica);
+                    continue;
+                }
+                Some(replica) => Some(replica.into_replay(now)),
+                None => {
+                    error!("{} shard {} has no replica", self.prefix, shard_id);
+                    continue;
+                }
+            };
+            let args = SpawnArgs {
+                cluster: cluster.to_tx(),
+                flusher: flusher.to_tx(),
+                topic_filters: topic_filters.clone(),
+                retained_messages: retained_messages.clone(),
+                replicated: Self::is_replicated(&self.config),
+                replay,
+            };
+            let res = Shard::from_config(self.config.clone(), shard_id)
+                .and_then(|shard| shard.spawn(args, app_tx.clone()));
+            match res {
+                Ok(shard) => {
+                    info!("{} promoted shard {}", self.prefix, shard_id);
+                    if let Err(err) = ticker.add_shard(shard.to_tx()) {
+                        error!("{} ticker add shard {}: {}", self.prefix, shard_id, err);
+                    }
+                    shards.insert(shard_id, shard);
+                }
+                Err(err) => error!("{} promote shard {}: {}", self.prefix, shard_id, err),
+            }
+        }
+
+        // wire up all the shards with the promoted shards.
+        for shard in shards.values() {
+            let shard_queues = to_shard_queues(&self.config, shards, cluster);
+            if let Err(err) = shard.set_shard_queues(shard_queues) {
+                error!("{} shard queues {}: {}", self.prefix, shard.shard_id, err);
+            }
+        }
+    }
+
+    // Close shards hosted by this node, but mastered by another node as per the
+    // topology, that is, shards migrated by a rebalance. Shard replicates its live
+    // state and its final records, to the new master as well, followed by HANDOVER.
+    fn demote_shards(&mut self) {
+        let RunLoop {
+            state,
+            membership,
+            ticker,
+            shards,
+            cluster,
+            forward_back_log,
+            exported,
+            topic_filters,
+            ..
+        } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        // shards are not yet spawned, refer Cluster::spawn.
+        let (membership, cluster) = match (membership, cluster) {
+            (Some(membership), Some(cluster)) => (membership, cluster),
+            (_, _) => return,
+        };
+
+        let local = membership.to_local().uuid;
+        let shard_ids: Vec<u32> = shards
+            .keys()
+            .filter(|shard_id| {
+                matches!(state.to_master(**shard_id), Some(node) if node.uuid != local)
+            })
+            .copied()
+            .collect();
+        if shard_ids.is_empty() {
+            return;
+        }
+
+        for shard_id in shard_ids.into_iter() {
+            info!("{} demote shard {}", self.prefix, shard_id);
+            let shard = shards.remove(&shard_id).unwrap();
+            if let Err(err) = ticker.remove_shard(shard_id) {
+                error!("{} ticker remove shard {}: {}", self.prefix, shard_id, err);
+            }
+            if let Err(err) = shard.resync() {
+                error!("{} resync shard {}: {}", self.prefix, shard_id, err);
+            }
+            shard.close_wait();
+            if let Err(err) = cluster.handover(shard_id) {
+                error!("{} handover shard {}: {}", self.prefix, shard_id, err);
+            }
+
+            // new master shall index and export its own subscriptions.
+            let subscrs = topic_filters.to_values().into_iter();
+            for subscr in subscrs.filter(|s| s.shard_id == shard_id) {
+                topic_filters.unsubscribe(&subscr.topic_filter, &subscr);
+            }
+            exported.remove(&shard_id);
+            if let Some(msgs) = forward_back_log.remove(&shard_id) {
+                if let Err(err) = cluster.forward(shard_id, msgs) {
+                    error!("{} forward shard {}: {}", self.prefix, shard_id, err);
+                }
+            }
+        }
+
+        // wire up the remaining shards with the demoted shards' new master.
+        for shard in shards.values() {
+            let shard_queues = to_shard_queues(&self.config, shards, cluster);
+            if let Err(err) = shard.set_shard_queues(shard_queues) {
+                error!("{} shard queues {}: {}", self.prefix, shard.shard_id, err);
+            }
+        }
+    }
+
+    // Shards migrated to this node from a live master are spawned only after its
+    // HANDOVER, refer demote_shards. Shards whose old master has since failed are
+    // promoted on fail-over, like any other shard.
+    fn await_handovers(&mut self, olds: &[rebalance::Topology]) {
+        let RunLoop { state, membership, shards, handovers, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        let membership = match membership {
+            Some(membership) => membership,
+            None => return,
+        };
+
+        let local = membership.to_local().uuid;
+        let (_, nodes, _) = membership.to_view();
+        let is_live = |uuid: &Uuid| nodes.iter().any(|n| &n.uuid == uuid);
+        let is_local = |shard: u32| state.to_master(shard).map(|n| n.uuid) == Some(local);
+
+        handovers.retain(|shard, (uuid, _)| is_local(*shard) && is_live(uuid));
+
+        let timeout =
+            time::Duration::from_secs(self.config.cluster_join_timeout() as u64);
+        let deadline = time::Instant::now() + timeout;
+        for old in olds.iter() {
+            let uuid = old.master.uuid;
+            let hosted = shards.contains_key(&old.shard);
+            if uuid != local && is_live(&uuid) && is_local(old.shard) && !hosted {
+                debug!("{} shard {} wait for handover", self.prefix, old.shard);
+                handovers.entry(old.shard).or_insert((uuid, deadline));
+            }
+        }
+    }
+
+    // Give up waiting for HANDOVER after cluster_join_timeout, shards are promoted
+    // from whatever is replicated. Return whether any shard was given up.
+    fn expire_handovers(&mut self) -> bool {
+        let RunLoop { handovers, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let now = time::Instant::now();
+        let n = handovers.len();
+        handovers.retain(|shard, (uuid, deadline)| match *deadline > now {
+            true => true,
+            false => {
+                error!("{} shard {} no handover from {}", self.prefix, shard, uuid);
+                false
+            }
+        });
+        handovers.len() != n
+    }
+
     fn retain_expires(&mut self, rt: &mut Rt) {
         use crate::timer::TimeoutValue;
 
@@ -500,6 +1341,11 @@ impl Cluster {
             assert!(item.is_deleted() == false);
 
             retained_messages.remove(&item.topic_name);
+            if let Some(store) = rt.retain_store.as_mut() {
+                if let Err(err) = store.reset(&item.topic_name) {
+                    error!("{} retain store reset: {}", self.prefix, err);
+                }
+            }
 
             match rt.retain_topics.remove(&item.topic_name) {
                 Some(_) => (),
@@ -521,18 +1367,68 @@ impl Cluster {
         };
 
         match req {
-            Request::Set { listener, ticker, shards } => {
-                run_loop.ticker = ticker;
-                run_loop.listener = listener;
+            Request::Set { listeners, ticker, shards, cluster } => {
+                run_loop.ticker = *ticker;
+                run_loop.listeners = listeners;
                 run_loop.shards = shards;
+                run_loop.cluster = Some(cluster);
             }
             _ => unreachable!(),
         }
+        // shards promoted, or handed over, while this node was spawning.
+        self.promote_shards();
+        self.resync_shards();
 
         Response::Ok
     }
 
     fn handle_set_retain_topic(&mut self, req: Request, rt: &mut Rt) {
+        let publish = match req {
+            Request::SetRetainTopic { publish } => publish,
+            _ => unreachable!(),
+        };
+
+        let record = RetainRecord::new(publish);
+        if let Some(store) = rt.retain_store.as_mut() {
+            if let Err(err) = store.set(&record) {
+                error!("{} retain store set: {}", self.prefix, err);
+            }
+        }
+
+        self.book_retain(record.publish, rt);
+    }
+
+    fn handle_load_retained(&mut self, _req: Request, rt: &mut Rt) -> Result<Response> {
+        rt.retain_store = crate::store::from_config(&self.config)?;
+        let records = match rt.retain_store.as_mut() {
+            Some(store) => store.load()?,
+            None => return Ok(Response::Ok),
+        };
+
+        let (n, mut expired) = (records.len(), Vec::new());
+        for record in records.into_iter() {
+            match record.to_publish() {
+                Some(publish) => self.book_retain(publish, rt),
+                None => expired.push(record.publish.topic_name),
+            }
+        }
+        if let Some(store) = rt.retain_store.as_mut() {
+            for topic_name in expired.iter() {
+                store.reset(topic_name)?;
+            }
+        }
+
+        info!(
+            "{} loaded {} retained messages, {} expired",
+            self.prefix,
+            n,
+            expired.len()
+        );
+
+        Ok(Response::Ok)
+    }
+
+    fn book_retain(&mut self, publish: v5::Publish, rt: &mut Rt) {
         use crate::timer::TimeoutValue;
 
         let RunLoop { retained_messages, .. } = match &mut self.inner {
@@ -540,11 +1436,6 @@ impl Cluster {
             _ => unreachable!(),
         };
 
-        let publish = match req {
-            Request::SetRetainTopic { publish } => publish,
-            _ => unreachable!(),
-        };
-
         let retain = Arc::new(Retain {
             topic_name: publish.topic_name.clone(),
             deleted: AtomicBool::new(false),
@@ -588,18 +1479,260 @@ impl Cluster {
         };
 
         retained_messages.remove(&topic_name);
+
+        if let Some(store) = rt.retain_store.as_mut() {
+            if let Err(err) = store.reset(&topic_name) {
+                error!("{} retain store reset: {}", self.prefix, err);
+            }
+        }
+    }
+
+    fn handle_shards_in_node(&mut self, req: Request) -> Response {
+        let node = match req {
+            Request::ShardsInNode { node } => node,
+            _ => unreachable!(),
+        };
+
+        let RunLoop { state, handovers, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        match state {
+            // waiting for nodes to join.
+            ClusterState::Elastic { state } if state.topology.is_empty() => {
+                Response::Shards(None)
+            }
+            // shards yet to be handed over are spawned later, refer promote_shards.
+            state => {
+                let mut shard_ids = state.shards_in_node(&node);
+                shard_ids.retain(|shard_id| !handovers.contains_key(shard_id));
+                Response::Shards(Some(shard_ids))
+            }
+        }
+    }
+
+    fn handle_export_snapshot(&mut self, _req: Request) -> Response {
+        let RunLoop { topic_filters, retained_messages, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let snapshot = Snapshot::new(topic_filters, retained_messages);
+        info!(
+            "{} export snapshot subscriptions:{} retained:{}",
+            self.prefix,
+            snapshot.subscriptions.len(),
+            snapshot.retained.len()
+        );
+
+        Response::Snapshot(Box::new(snapshot))
+    }
+
+    fn handle_import_snapshot(&mut self, req: Request, rt: &mut Rt) -> Result<Response> {
+        let snapshot = match req {
+            Request::ImportSnapshot(snapshot) => *snapshot,
+            _ => unreachable!(),
+        };
+        let Snapshot { subscriptions, retained, .. } = snapshot;
+        info!(
+            "{} import snapshot subscriptions:{} retained:{}",
+            self.prefix,
+            subscriptions.len(),
+            retained.len()
+        );
+
+        let num_shards = self.config.num_shards();
+        let RunLoop { shards, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let mut shard_subscrs: BTreeMap<u32, Vec<v5::Subscription>> = BTreeMap::default();
+        for mut subscr in subscriptions.into_iter() {
+            // number of shards can differ from the exporting cluster.
+            subscr.shard_id =
+                rebalance::Rebalancer::session_partition(&*subscr.client_id, num_shards);
+            shard_subscrs.entry(subscr.shard_id).or_default().push(subscr);
+        }
+        // sessions are created on the shard that owns them, shards mastered by other
+        // nodes import them on their own node.
+        for (shard_id, subscrs) in shard_subscrs.into_iter() {
+            match shards.get(&shard_id) {
+                Some(shard) => shard.import_sessions(subscrs)?,
+                None => warn!(
+                    "{} import, skip {} subscriptions for remote shard:{}",
+                    self.prefix,
+                    subscrs.len(),
+                    shard_id
+                ),
+            }
+        }
+
+        for publish in retained.into_iter() {
+            self.handle_set_retain_topic(Request::SetRetainTopic { publish }, rt);
+        }
+
+        Ok(Response::Ok)
+    }
+
+    // Send records from master shard to its replica nodes, refer [crate::replica].
+    fn handle_replicate(&mut self, req: Request) -> Response {
+        use membership::Msg;
+
+        let (shard, mut seqno, reset, records) = match req {
+            Request::Replicate { shard_id, seqno, reset, records } => {
+                (shard_id, seqno, reset, records)
+            }
+            _ => unreachable!(),
+        };
+
+        // shards flush their last records while the cluster is closing.
+        let RunLoop { membership, state, shards, replica_seqnos, .. } =
+            match &mut self.inner {
+                Inner::Main(run_loop) => run_loop,
+                Inner::Close(_) => return Response::Ok,
+                _ => unreachable!(),
+            };
+        let membership = match membership {
+            Some(membership) => membership,
+            None => return Response::Ok,
+        };
+
+        let mut msgs = Vec::with_capacity(records.len() + 1);
+        if reset {
+            let count = u64::try_from(records.len()).unwrap();
+            msgs.push(Msg::Reset { shard, seqno, count });
+        }
+        for record in records.into_iter() {
+            msgs.push(Msg::Replicate { shard, seqno, record });
+            seqno += 1;
+        }
+        replica_seqnos.insert(shard, seqno);
+
+        // records sent to a disconnected node are lost, replica shall be reset when
+        // the node re-joins. New master of a demoted shard receives them as well,
+        // refer Cluster::demote_shards.
+        let local = membership.to_local().uuid;
+        let master = state.to_master(shard).filter(|node| node.uuid != local);
+        for node in master.into_iter().chain(state.to_replicas(shard).iter()) {
+            for msg in msgs.iter() {
+                if let Err(err) = membership.send_to(&node.uuid, msg) {
+                    debug!("{} replicate shard {}: {}", self.prefix, shard, err);
+                    break;
+                }
+            }
+        }
+
+        // without replicas, say the only live node, there is no one to wait for.
+        let replicated = state.to_replicas(shard).iter().any(|node| node.uuid != local);
+        if let Some(master) = shards.get(&shard).filter(|_| !replicated) {
+            if let Err(err) = master.replica_ack(seqno - 1) {
+                error!("{} replica ack shard {}: {}", self.prefix, shard, err);
+            }
+        }
+
+        Response::Ok
+    }
+
+    // Send messages from local shard to shard `shard_id` mastered by another node.
+    fn handle_forward(&mut self, req: Request) -> Response {
+        use membership::Msg;
+
+        let (shard, msgs) = match req {
+            Request::Forward { shard_id, msgs } => (shard_id, msgs),
+            _ => unreachable!(),
+        };
+
+        // shards flush their last messages while the cluster is closing.
+        let RunLoop { membership, state, forward_back_log, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            Inner::Close(_) => return Response::Ok,
+            _ => unreachable!(),
+        };
+        let membership = match membership {
+            Some(membership) => membership,
+            None => return Response::Ok,
+        };
+
+        let n = msgs.len();
+        let res = match state.to_master(shard) {
+            // shard is promoted on this node after the sender was wired up.
+            Some(node) if node.uuid == membership.to_local().uuid => {
+                forward_back_log.entry(shard).or_default().extend(msgs);
+                Ok(())
+            }
+            Some(node) => membership.send_to(&node.uuid, &Msg::Forward { shard, msgs }),
+            None => err!(Disconnected, desc: "no master for shard {}", shard),
+        };
+        if let Err(err) = res {
+            warn!("{} drop {} messages to shard {}: {}", self.prefix, n, shard, err);
+        }
+
+        Response::Ok
+    }
+
+    // Send HANDOVER to the new master of demoted shard, refer demote_shards.
+    fn handle_handover(&mut self, req: Request) -> Response {
+        use membership::Msg;
+
+        let shard = match req {
+            Request::Handover { shard_id } => shard_id,
+            _ => unreachable!(),
+        };
+
+        let RunLoop { membership, state, replica_seqnos, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            Inner::Close(_) => return Response::Ok,
+            _ => unreachable!(),
+        };
+        let membership = match membership {
+            Some(membership) => membership,
+            None => return Response::Ok,
+        };
+
+        let seqno = replica_seqnos.remove(&shard).unwrap_or(1);
+        let res = match state.to_master(shard) {
+            Some(node) if node.uuid == membership.to_local().uuid => {
+                err!(InvalidInput, desc: "shard {} is mastered by this node", shard)
+            }
+            Some(node) => membership.send_to(&node.uuid, &Msg::Handover { shard, seqno }),
+            None => err!(Disconnected, desc: "no master for shard {}", shard),
+        };
+        match res {
+            Ok(()) => info!("{} handover shard {} seqno {}", self.prefix, shard, seqno),
+            Err(err) => error!("{} handover shard {}: {}", self.prefix, shard, err),
+        }
+
+        Response::Ok
+    }
+
+    fn flush_retain_store(&mut self, rt: &mut Rt) {
+        if let Some(store) = rt.retain_store.as_mut() {
+            if let Err(err) = store.flush() {
+                error!("{} retain store flush: {}", self.prefix, err);
+            }
+        }
     }
 
     // Errors - IPCFail,
     fn handle_add_connection(&mut self, req: Request) -> Response {
         use crate::shard::AddSessionArgs;
 
-        let AddConnectionArgs { conn, addr, pkt: connect } = match req {
-            Request::AddConnection(args) => args,
+        let args = match req {
+            Request::AddConnection(args) => *args,
             _ => unreachable!(),
         };
-
-        let RunLoop { shards, .. } = match &mut self.inner {
+        let AddConnectionArgs {
+            conn,
+            addr,
+            peer_cred,
+            pkt: connect,
+            listener,
+            guard,
+        } = args;
+
+        let RunLoop { shards, state, membership, .. } = match &mut self.inner {
             Inner::Main(run_loop) => run_loop,
             _ => unreachable!(),
         };
@@ -614,17 +1747,34 @@ impl Cluster {
             Some(shard) => shard,
             None => {
                 // multi-node cluster, look at the topology and redirect client using
-                // connack::server_reference, and close the connection.
-                todo!()
+                // connack::server_reference, and close the connection. Shard mastered
+                // by this node, but not promoted, is unavailable.
+                let local = membership.as_ref().map(|m| m.to_local().uuid);
+                let server = state
+                    .to_master(shard_id)
+                    .filter(|node| Some(node.uuid) != local)
+                    .map(|node| node.mqtt_address);
+                info!(
+                    "{}, new connection {:?} for shard {} redirect to {:?}",
+                    self.prefix, addr, shard_id, server
+                );
+                redirect_connection(&self.prefix, &self.config, conn, server);
+                std::mem::drop(guard);
+                return Response::Ok;
             }
         };
         info!("{}, new connection {:?} mapped to shard {}", self.prefix, addr, shard_id);
 
         // Add session to the shard.
-        allow_panic!(
-            &self,
-            shard.add_session(AddSessionArgs { conn, addr, pkt: connect })
-        );
+        let args = AddSessionArgs {
+            conn,
+            addr,
+            peer_cred,
+            pkt: connect,
+            listener,
+            guard,
+        };
+        allow_panic!(&self, shard.add_session(args));
 
         Response::Ok
     }
@@ -636,6 +1786,7 @@ impl Cluster {
             Inner::Main(mut run_loop) => {
                 info!("{}, closing shards:{}", self.prefix, run_loop.shards.len());
 
+                mem::drop(run_loop.membership);
                 mem::drop(run_loop.poll);
 
                 let mut shards = BTreeMap::default();
@@ -643,8 +1794,10 @@ impl Cluster {
                     shards.insert(shard_id, shard.close_wait());
                 }
 
-                let listener = mem::replace(&mut run_loop.listener, Listener::default())
-                    .close_wait();
+                let listeners = mem::replace(&mut run_loop.listeners, Vec::default())
+                    .into_iter()
+                    .map(|listener| listener.close_wait())
+                    .collect();
 
                 let ticker =
                     mem::replace(&mut run_loop.ticker, Ticker::default()).close_wait();
@@ -652,11 +1805,15 @@ impl Cluster {
                 let flusher =
                     mem::replace(&mut run_loop.flusher, Flusher::default()).close_wait();
 
-                mem::drop(run_loop.rebalancer);
+                if let Some(mut store) = rt.retain_store.take() {
+                    if let Err(err) = store.sync() {
+                        error!("{}, retain store sync: {}", self.prefix, err);
+                    }
+                }
 
                 let fin_state = FinState {
                     state: run_loop.state,
-                    listener,
+                    listeners,
                     ticker,
                     flusher,
                     shards,
@@ -690,6 +1847,13 @@ impl Cluster {
         }
     }
 
+    fn is_multi_node(&self) -> bool {
+        match &self.inner {
+            Inner::Main(RunLoop { membership, .. }) => membership.is_some(),
+            _ => unreachable!(),
+        }
+    }
+
     fn as_app_tx(&self) -> &mpsc::SyncSender<String> {
         match &self.inner {
             Inner::Main(RunLoop { app_tx, .. }) => app_tx,
@@ -713,6 +1877,8 @@ pub struct Node {
     pub weight: u16,
     /// Refer to [ConfigNode::mqtt_address].
     pub mqtt_address: net::SocketAddr, // listen address
+    /// Refer to [ConfigNode::cluster_address].
+    pub cluster_address: Option<net::SocketAddr>,
 }
 
 impl PartialEq for Node {
@@ -728,6 +1894,7 @@ impl Default for Node {
         let config = ConfigNode::default();
         Node {
             mqtt_address: config.mqtt_address.clone(),
+            cluster_address: config.cluster_address,
             path: config.path.clone(),
             weight: config.weight.unwrap(),
             uuid: config.uuid.unwrap().parse().unwrap(),
@@ -747,6 +1914,7 @@ impl TryFrom<ConfigNode> for Node {
 
         let val = Node {
             mqtt_address: c.mqtt_address,
+            cluster_address: c.cluster_address,
             path: c.path,
             weight: c.weight.unwrap_or(node.weight),
             uuid,
@@ -774,10 +1942,8 @@ pub enum ClusterState {
     /// Cluster is single-node.
     SingleNode { state: SingleNode },
     /// Cluster is in the process of updating its gods&nodes, and working out rebalance.
-    #[allow(dead_code)]
     Elastic { state: MultiNode },
-    /// Cluster is stable.
-    #[allow(dead_code)]
+    /// Cluster is stable, all the configured nodes are live.
     Stable { state: MultiNode },
 }
 
@@ -792,25 +1958,96 @@ pub struct SingleNode {
 #[allow(dead_code)]
 pub struct MultiNode {
     config: Config,
+    /// Epoch of the membership view, refer [crate::membership].
+    epoch: u64,
     nodes: Vec<Node>, // TODO: should we split this into gods and nodes.
     topology: Vec<rebalance::Topology>, // list of shards mapped to node.
 }
 
 impl ClusterState {
     /// Return the list of shard-numbers that are hosted in this node.
-    #[allow(dead_code)]
     fn shards_in_node(&self, node: &Uuid) -> Vec<u32> {
+        let topology = self.to_topology();
+        topology.iter().filter(|t| node == &t.master.uuid).map(|t| t.shard).collect()
+    }
+
+    /// Return the node hosting the master for `shard`.
+    fn to_master(&self, shard: u32) -> Option<&Node> {
+        let topology = self.to_topology();
+        topology.iter().find(|t| t.shard == shard).map(|t| &t.master)
+    }
+
+    /// Return the nodes hosting the replicas for `shard`.
+    fn to_replicas(&self, shard: u32) -> &[Node] {
+        let topology = self.to_topology();
+        match topology.iter().find(|t| t.shard == shard) {
+            Some(t) => &t.replicas,
+            None => &[],
+        }
+    }
+
+    fn to_topology(&self) -> &[rebalance::Topology] {
         use ClusterState::*;
 
-        let topology = match self {
-            SingleNode { state } if node == &state.node.uuid => &state.topology,
-            Stable { state } => &state.topology,
-            _ => unreachable!(), // TODO: meaningful return.
+        match self {
+            SingleNode { state } => &state.topology,
+            Elastic { state } | Stable { state } => &state.topology,
+        }
+    }
+}
+
+// Return the message queues for all the shards in the cluster, shards mastered by
+// other nodes are reached via `cluster`, refer [Cluster::forward].
+fn to_shard_queues(
+    config: &Config,
+    shards: &BTreeMap<u32, Shard>,
+    cluster: &Cluster,
+) -> BTreeMap<u32, Shard> {
+    let iter = (0..config.num_shards()).map(|shard_id| {
+        let shard = match shards.get(&shard_id) {
+            Some(shard) => shard.to_msg_tx(),
+            None => Shard::new_remote(config.clone(), shard_id, cluster.to_tx()),
         };
-        topology.iter().filter(|t| node == &t.master.uuid).map(|t| t.shard).collect()
+        (shard_id, shard)
+    });
+    BTreeMap::from_iter(iter)
+}
+
+// Return whether `subscr` is in `subscrs` sorted by client_id and topic_filter, with
+// the same options.
+fn is_subscribed(subscrs: &[v5::Subscription], subscr: &v5::Subscription) -> bool {
+    match subscrs.binary_search(subscr) {
+        Ok(off) => &subscrs[off] == subscr,
+        Err(_) => false,
     }
 }
 
+// Reply with CONNACK, UseAnotherServer and server_reference set to `server`, or
+// ServerUnavailable if `server` is not known. Connection is closed when dropped.
+fn redirect_connection(
+    prefix: &str,
+    config: &Config,
+    conn: Stream,
+    server: Option<net::SocketAddr>,
+) {
+    use crate::packet::send_connack_with;
+    use v5::ConnackReasonCode::{ServerUnavailable, UseAnotherServer};
+
+    let mut cack = match server {
+        Some(_) => v5::ConnAck::from_reason_code(UseAnotherServer),
+        None => v5::ConnAck::from_reason_code(ServerUnavailable),
+    };
+    cack.properties = server.map(|server| v5::ConnAckProperties {
+        server_reference: Some(server.to_string()),
+        ..v5::ConnAckProperties::default()
+    });
+
+    let timeout = time::Instant::now()
+        + time::Duration::from_secs(config.mqtt_write_timeout() as u64);
+    let max_size = config.mqtt_max_packet_size();
+    send_connack_with(prefix, cack, &conn, timeout, max_size).ok();
+}
+
 pub struct Retain {
     topic_name: TopicName,
     deleted: AtomicBool,
@@ -829,4 +2066,14 @@ impl timer::TimeoutValue for Arc<Retain> {
 struct Rt {
     retain_timer: Timer<Arc<Retain>>,
     retain_topics: BTreeMap<TopicName, Arc<Retain>>,
+    // persistent store for retained messages, None if not configured.
+    retain_store: Option<Box<dyn RetainStore>>,
 }
+
+#[cfg(test)]
+#[path = "conformance_test.rs"]
+mod conformance_test;
+
+#[cfg(test)]
+#[path = "cluster_test.rs"]
+mod cluster_test;
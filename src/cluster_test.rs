@@ -0,0 +1,379 @@
+use super::*;
+
+use std::io::Write;
+
+use crate::fixtures::{free_port, new_connect};
+use crate::packet::MQTTRead;
+use crate::{Packetize, TopicFilter};
+
+fn connect(port: u16, client_id: &str) -> v5::ConnAck {
+    connect_with(port, new_connect(client_id, true)).1
+}
+
+fn connect_with(port: u16, connect: v5::Connect) -> (net::TcpStream, v5::ConnAck) {
+    let mut conn = net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    conn.set_read_timeout(Some(crate::SLEEP_10MS)).unwrap();
+
+    let data = v5::Packet::Connect(connect).encode().unwrap();
+    conn.write_all(data.as_ref()).unwrap();
+
+    match recv(&conn) {
+        v5::Packet::ConnAck(connack) => (conn, connack),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+}
+
+fn recv(conn: &net::TcpStream) -> v5::Packet {
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    let mut packetr = MQTTRead::new(Config::DEF_MQTT_MAX_PACKET_SIZE);
+    loop {
+        packetr = match packetr.read(conn).unwrap() {
+            (mut packetr @ MQTTRead::Fin { .. }, _) => break packetr.parse().unwrap(),
+            (_, true) if time::Instant::now() > deadline => panic!("no packet"),
+            (packetr, _) => packetr,
+        }
+    }
+}
+
+fn spawn_nodes(config: &Config, config_nodes: &[ConfigNode]) -> Vec<(Node, Cluster)> {
+    let handles: Vec<thread::JoinHandle<(Node, Cluster)>> = config_nodes
+        .iter()
+        .map(|config_node| {
+            let port = config_node.mqtt_address.port();
+            let config = Config { port: Some(port), ..config.clone() };
+            let node = Node::try_from(config_node.clone()).unwrap();
+            thread::spawn(move || {
+                let (app_tx, app_rx) = mpsc::sync_channel(1024);
+                thread::spawn(move || for _msg in app_rx {});
+                let cluster =
+                    Cluster::from_config(config).unwrap().spawn(node.clone(), app_tx);
+                (node, cluster.unwrap())
+            })
+        })
+        .collect();
+    handles.into_iter().map(|h| h.join().unwrap()).collect()
+}
+
+fn new_config(name: &str, num_nodes: usize) -> (Config, Vec<ConfigNode>) {
+    let config_nodes: Vec<ConfigNode> = (0..num_nodes)
+        .map(|_| ConfigNode {
+            uuid: Some(Uuid::new_v4().to_string()),
+            path: "/".into(),
+            weight: Some(1),
+            mqtt_address: format!("127.0.0.1:{}", free_port()).parse().unwrap(),
+            cluster_address: Some(format!("127.0.0.1:{}", free_port()).parse().unwrap()),
+        })
+        .collect();
+    let config = Config {
+        name: name.to_string(),
+        max_nodes: Some(num_nodes as u32),
+        num_shards: Some(8),
+        bind_address: Some("127.0.0.1".parse().unwrap()),
+        nodes: config_nodes.clone(),
+        cluster_join_timeout: Some(10),
+        cluster_peer_timeout: Some(2),
+        cluster_secret: Some("secret".to_string()),
+        ..Config::default()
+    };
+    (config, config_nodes)
+}
+
+#[test]
+fn test_multi_node() {
+    let (config, config_nodes) = new_config("multi-node", 3);
+
+    // a node alone can't make up the cluster.
+    {
+        let config = Config { cluster_join_timeout: Some(1), ..config.clone() };
+        let node = Node::try_from(config_nodes[0].clone()).unwrap();
+        let (app_tx, _app_rx) = mpsc::sync_channel(1024);
+        let res = Cluster::from_config(config).unwrap().spawn(node, app_tx);
+        assert_eq!(res.err().unwrap().kind(), ErrorKind::Timeout);
+    }
+
+    let mut clusters = spawn_nodes(&config, &config_nodes);
+
+    // every shard is hosted by exactly one node.
+    let mut hosted: Vec<(u32, Node)> = vec![];
+    for (node, cluster) in clusters.iter() {
+        for shard_id in cluster.wait_shards_in_node(&node.uuid).unwrap() {
+            hosted.push((shard_id, node.clone()));
+        }
+    }
+    hosted.sort_by_key(|(shard_id, _)| *shard_id);
+    let shard_ids: Vec<u32> = hosted.iter().map(|(shard_id, _)| *shard_id).collect();
+    assert_eq!(shard_ids, (0..8).collect::<Vec<u32>>());
+
+    // client connecting to a node not hosting its shard is redirected.
+    for client_id in ["client-1", "client-2", "client-3", "client-4"].iter() {
+        let shard_id = rebalance::Rebalancer::session_partition(client_id, 8);
+        let master = &hosted[shard_id as usize].1;
+        for (node, _) in clusters.iter() {
+            let connack = connect(node.mqtt_address.port(), client_id);
+            if node == master {
+                assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+            } else {
+                assert_eq!(connack.code, v5::ConnackReasonCode::UseAnotherServer);
+                let server = connack.properties.unwrap().server_reference.unwrap();
+                assert_eq!(server, master.mqtt_address.to_string());
+            }
+        }
+    }
+
+    // remaining nodes detect the failed node.
+    let (failed, cluster) = clusters.pop().unwrap();
+    cluster.close_wait();
+    thread::sleep(time::Duration::from_secs(3));
+    for (node, cluster) in clusters.into_iter() {
+        let cluster = cluster.close_wait();
+        match &cluster.inner {
+            Inner::Close(FinState { state: ClusterState::Elastic { state }, .. }) => {
+                assert!(state.nodes.contains(&node));
+                assert!(!state.nodes.contains(&failed));
+                assert_eq!(state.topology.len(), 8);
+            }
+            _ => panic!("expected elastic cluster"),
+        }
+    }
+}
+
+#[test]
+fn test_fail_over() {
+    let (config, config_nodes) = new_config("fail-over", 3);
+    let mut clusters = spawn_nodes(&config, &config_nodes);
+
+    let master_of = |clusters: &[(Node, Cluster)], client_id: &str| -> usize {
+        let shard_id = rebalance::Rebalancer::session_partition(&client_id, 8);
+        let mut iter = clusters.iter().enumerate();
+        iter.find(|(_, (node, cluster))| {
+            cluster.wait_shards_in_node(&node.uuid).unwrap().contains(&shard_id)
+        })
+        .unwrap()
+        .0
+    };
+
+    // persistent session, with a subscription, on its master.
+    let off = master_of(&clusters, "durable");
+    let port = clusters[off].0.mqtt_address.port();
+    let (mut conn, connack) = connect_with(port, new_connect("durable", false));
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    let filters = vec![v5::SubscribeFilter {
+        topic_filter: TopicFilter::from("fail/+".to_string()),
+        opt: v5::SubscriptionOpt::new(
+            v5::RetainForwardRule::OnEverySubscribe,
+            false,
+            false,
+            v5::QoS::AtLeastOnce,
+        ),
+    }];
+    let sub = v5::Subscribe { packet_id: 1, properties: None, filters };
+    let data = v5::Packet::Subscribe(sub).encode().unwrap();
+    conn.write_all(data.as_ref()).unwrap();
+    assert!(matches!(recv(&conn), v5::Packet::SubAck(_)));
+    thread::sleep(time::Duration::from_millis(500));
+
+    // master fails, while the client is connected.
+    let (failed, cluster) = clusters.remove(off);
+    cluster.close_wait();
+    std::mem::drop(conn);
+    thread::sleep(time::Duration::from_secs(3));
+
+    // replica is promoted, and the session is resumed with its subscription.
+    let off = master_of(&clusters, "durable");
+    let promoted = clusters[off].0.clone();
+    assert!(promoted != failed);
+    for (node, _) in clusters.iter().filter(|(node, _)| node != &promoted) {
+        let connack = connect(node.mqtt_address.port(), "durable");
+        assert_eq!(connack.code, v5::ConnackReasonCode::UseAnotherServer);
+        let server = connack.properties.unwrap().server_reference.unwrap();
+        assert_eq!(server, promoted.mqtt_address.to_string());
+    }
+    let port = promoted.mqtt_address.port();
+    let (conn, connack) = connect_with(port, new_connect("durable", false));
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    assert!(connack.flags.unwrap().unwrap());
+
+    let client_id = (0..)
+        .map(|i| format!("publisher-{}", i))
+        .find(|client_id| master_of(&clusters, client_id) == off)
+        .unwrap();
+    let (mut publ, _) = connect_with(port, new_connect(&client_id, true));
+    let publish = v5::Publish {
+        retain: false,
+        qos: v5::QoS::AtLeastOnce,
+        duplicate: false,
+        topic_name: TopicName::from("fail/a".to_string()),
+        packet_id: Some(1),
+        properties: None,
+        payload: Some(b"after fail-over".to_vec().into()),
+    };
+    let data = v5::Packet::Publish(publish).encode().unwrap();
+    publ.write_all(data.as_ref()).unwrap();
+    match recv(&conn) {
+        v5::Packet::Publish(publish) => assert_eq!(publish.topic_name.as_str(), "fail/a"),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+
+    for (_, cluster) in clusters.into_iter() {
+        cluster.close_wait();
+    }
+}
+
+#[test]
+fn test_forward_publish() {
+    let (config, config_nodes) = new_config("forward-publish", 2);
+    let clusters = spawn_nodes(&config, &config_nodes);
+
+    let master_of = |client_id: &str| -> usize {
+        let shard_id = rebalance::Rebalancer::session_partition(&client_id, 8);
+        let mut iter = clusters.iter().enumerate();
+        iter.find(|(_, (node, cluster))| {
+            cluster.wait_shards_in_node(&node.uuid).unwrap().contains(&shard_id)
+        })
+        .unwrap()
+        .0
+    };
+
+    // subscriber and publisher are hosted by different nodes.
+    let sub_off = master_of("subscriber");
+    let client_id = (0..)
+        .map(|i| format!("publisher-{}", i))
+        .find(|client_id| master_of(client_id) != sub_off)
+        .unwrap();
+    let pub_off = master_of(&client_id);
+
+    let port = clusters[sub_off].0.mqtt_address.port();
+    let (mut conn, connack) = connect_with(port, new_connect("subscriber", true));
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    let filters = vec![v5::SubscribeFilter {
+        topic_filter: TopicFilter::from("forward/+".to_string()),
+        opt: v5::SubscriptionOpt::new(
+            v5::RetainForwardRule::OnEverySubscribe,
+            false,
+            false,
+            v5::QoS::AtLeastOnce,
+        ),
+    }];
+    let sub = v5::Subscribe { packet_id: 1, properties: None, filters };
+    let data = v5::Packet::Subscribe(sub).encode().unwrap();
+    conn.write_all(data.as_ref()).unwrap();
+    assert!(matches!(recv(&conn), v5::Packet::SubAck(_)));
+    // subscriptions are exported to other nodes once every heartbeat.
+    thread::sleep(2 * Membership::HEARTBEAT);
+
+    let port = clusters[pub_off].0.mqtt_address.port();
+    let (mut publ, connack) = connect_with(port, new_connect(&client_id, true));
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    let publish = v5::Publish {
+        retain: false,
+        qos: v5::QoS::AtLeastOnce,
+        duplicate: false,
+        topic_name: TopicName::from("forward/a".to_string()),
+        packet_id: Some(1),
+        properties: None,
+        payload: Some(b"across nodes".to_vec().into()),
+    };
+    let data = v5::Packet::Publish(publish).encode().unwrap();
+    publ.write_all(data.as_ref()).unwrap();
+
+    match recv(&conn) {
+        v5::Packet::Publish(publish) => {
+            assert_eq!(publish.topic_name.as_str(), "forward/a");
+            assert_eq!(publish.payload.unwrap().as_ref(), b"across nodes");
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+    // PUBACK once the subscribing shard, on the other node, has acknowledged.
+    match recv(&publ) {
+        v5::Packet::PubAck(puback) => assert_eq!(puback.packet_id, 1),
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+
+    for (_, cluster) in clusters.into_iter() {
+        cluster.close_wait();
+    }
+}
+
+#[test]
+fn test_restart_node() {
+    let (config, config_nodes) = new_config("restart-node", 3);
+    let mut clusters = spawn_nodes(&config, &config_nodes);
+
+    // shard -> master, as seen by each node.
+    let masters = |clusters: &[(Node, Cluster)]| -> Vec<Vec<Uuid>> {
+        let mut views = vec![];
+        for (_, cluster) in clusters.iter() {
+            let mut masters = vec![Uuid::nil(); 8];
+            for (node, _) in clusters.iter() {
+                for shard_id in cluster.wait_shards_in_node(&node.uuid).unwrap() {
+                    assert!(masters[shard_id as usize].is_nil());
+                    masters[shard_id as usize] = node.uuid;
+                }
+            }
+            views.push(masters);
+        }
+        views
+    };
+
+    let original = masters(&clusters).remove(0);
+    // replicas are reset once the master shards are spawned.
+    thread::sleep(Membership::HEARTBEAT);
+
+    // a node, hosting shards, fails and its shards are promoted on replicas.
+    let off = clusters
+        .iter()
+        .position(|(node, cluster)| {
+            !cluster.wait_shards_in_node(&node.uuid).unwrap().is_empty()
+        })
+        .unwrap();
+    let (failed, cluster) = clusters.remove(off);
+    cluster.close_wait();
+    thread::sleep(time::Duration::from_secs(3));
+    let promoted = masters(&clusters);
+    assert!(promoted[0].iter().all(|uuid| !uuid.is_nil() && uuid != &failed.uuid));
+
+    // persistent session on a promoted shard, that shall migrate back.
+    let client_id = (0..)
+        .map(|i| format!("durable-{}", i))
+        .find(|id| {
+            let shard_id = rebalance::Rebalancer::session_partition(id, 8);
+            original[shard_id as usize] == failed.uuid
+        })
+        .unwrap();
+    let shard_id = rebalance::Rebalancer::session_partition(&client_id, 8);
+    let node = clusters.iter().find(|(n, _)| n.uuid == promoted[0][shard_id as usize]);
+    let port = node.unwrap().0.mqtt_address.port();
+    let (conn, connack) = connect_with(port, new_connect(&client_id, false));
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    std::mem::drop(conn);
+    thread::sleep(time::Duration::from_millis(500));
+
+    // restarted node joins with the promoted topology, and shards are migrated back
+    // to it once all the nodes have adopted that view.
+    let config_node = config_nodes.iter().find(|c| {
+        c.uuid.as_ref().map(|uuid| uuid.parse::<Uuid>().unwrap()) == Some(failed.uuid)
+    });
+    clusters.extend(spawn_nodes(&config, &[config_node.unwrap().clone()]));
+    let deadline = time::Instant::now() + time::Duration::from_secs(10);
+    let views = loop {
+        let views = masters(&clusters);
+        match views.iter().all(|view| view == &original) {
+            true => break views,
+            false if time::Instant::now() < deadline => {
+                thread::sleep(Membership::HEARTBEAT)
+            }
+            false => panic!("shards not migrated back"),
+        }
+    };
+    assert!(views[0].contains(&failed.uuid));
+
+    // session is handed over to the restarted node.
+    let (_conn, connack) =
+        connect_with(failed.mqtt_address.port(), new_connect(&client_id, false));
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    assert!(connack.flags.unwrap().unwrap());
+
+    for (_, cluster) in clusters.into_iter() {
+        cluster.close_wait();
+    }
+}
@@ -25,18 +25,65 @@ pub struct Config {
     /// * **Mutable**: No
     pub num_shards: Option<u32>,
 
+    /// Number of replicas for each shard, in multi-node cluster. Replicas are placed
+    /// on nodes away from the master, refer to [ConfigNode::path]. Capped by the
+    /// number of nodes in the cluster.
+    /// * **Default**: [Config::DEF_NUM_REPLICAS]
+    /// * **Mutable**: No
+    pub num_replicas: Option<u32>,
+
     /// Network listening port for each node in this cluster. Once the cluster is
     /// spawned it will listen on all the available interfaces using this port.
+    /// Ignored if one or more `[[listener]]` tables are configured.
     /// * **Default**: "0.0.0.0:1883", Refer to [Config::DEF_MQTT_PORT]
     /// * **Mutable**: No
     pub port: Option<u16>,
 
+    /// Network address to bind, along with [Config::port], for each node in this
+    /// cluster. Use "::" to listen on all the available interfaces for both IPv6 and
+    /// IPv4 clients, that is dual-stack. Ignored if one or more `[[listener]]` tables
+    /// are configured.
+    /// * **Default**: "0.0.0.0", Refer to [Config::DEF_BIND_ADDRESS]
+    /// * **Mutable**: No
+    pub bind_address: Option<net::IpAddr>,
+
+    /// List of listeners, each configured as a `[[listener]]` table. All listeners
+    /// feed connections to the same cluster. If not provided, a single TCP listener
+    /// is started on [Config::port].
+    /// * **Default**: [],
+    /// * **Mutable**: No
+    #[serde(rename = "listener", default)]
+    pub listeners: Vec<ConfigListener>,
+
     /// Initial set of nodes that are going be part of this. If not provided, will start
     /// a single node cluster.
     /// * **Default**: [],
     /// * **Mutable**: No
     pub nodes: Vec<ConfigNode>,
 
+    /// Time, in seconds, a node shall wait while spawning, for the initial topology.
+    /// Topology is computed once all the configured [Config::nodes] have joined the
+    /// cluster, or a majority of them have joined and [Config::cluster_peer_timeout]
+    /// has elapsed, refer [crate::membership]. Applicable only for multi-node cluster.
+    /// Same is the time a node shall wait for the old master to hand over a shard
+    /// migrated to this node.
+    /// * **Default**: [Config::DEF_CLUSTER_JOIN_TIMEOUT]
+    /// * **Mutable**: No
+    pub cluster_join_timeout: Option<u32>,
+
+    /// Time, in seconds, after which a silent peer node is considered failed and
+    /// removed from the cluster membership. Applicable only for multi-node cluster.
+    /// * **Default**: [Config::DEF_CLUSTER_PEER_TIMEOUT]
+    /// * **Mutable**: No
+    pub cluster_peer_timeout: Option<u32>,
+
+    /// Secret shared by all the [Config::nodes]. Nodes prove the knowledge of this
+    /// secret to each other, before they are admitted on the cluster port, refer
+    /// [crate::membership]. Required for multi-node cluster.
+    /// * **Default**: None
+    /// * **Mutable**: No
+    pub cluster_secret: Option<String>,
+
     /// Connect handshake timeout on MQTT socket, in seconds. For every new connection,
     /// this timer will kick in, and within the timeout period if connect/connack
     /// handshake is not complete, connection will be closed.
@@ -44,6 +91,18 @@ pub struct Config {
     /// * **Mutable**: No
     pub connect_timeout: Option<u32>,
 
+    /// Maximum number of connections that can be active on this node, across all
+    /// listeners. New connections beyond this limit are closed right after accept.
+    /// * **Default**: None, no limit.
+    /// * **Mutable**: No
+    pub max_connections: Option<u32>,
+
+    /// Maximum number of connections that can be active on this node from the same
+    /// source IP address, across all listeners.
+    /// * **Default**: None, no limit.
+    /// * **Mutable**: No
+    pub max_connections_per_ip: Option<u32>,
+
     /// Read timeout on MQTT socket, in seconds. For every new packet this timeout
     /// will kick in, and within the timeout period if a new packet is not completely
     /// read, connection will be closed.
@@ -133,6 +192,70 @@ pub struct Config {
     /// * **Default**: [Config::DEF_MQTT_IGNORE_DUPLICATE]
     /// * **Mutable**: No
     pub mqtt_ignore_duplicate: Option<bool>,
+
+    /// Directory to persist retained messages. If configured, retained messages are
+    /// written to an append-only log under this directory, and loaded back when the
+    /// cluster is spawned.
+    /// * **Default**: None, retained messages are held only in memory.
+    /// * **Mutable**: No
+    pub retain_store_dir: Option<path::PathBuf>,
+
+    /// Fsync policy for the retained message store, one of `always`, `interval`,
+    /// `never`.
+    /// * **Default**: [Config::DEF_RETAIN_STORE_FSYNC]
+    /// * **Mutable**: No
+    pub retain_store_fsync: Option<FsyncPolicy>,
+
+    /// Interval, in seconds, between fsync calls, applicable only when
+    /// `retain_store_fsync` is `interval`.
+    /// * **Default**: [Config::DEF_RETAIN_STORE_FSYNC_INTERVAL]
+    /// * **Mutable**: No
+    pub retain_store_fsync_interval: Option<u32>,
+
+    /// Directory to persist session state. If configured, every shard writes its
+    /// persistent sessions, their subscriptions and un-acknowledged QoS-1 messages
+    /// to a write-ahead log under this directory, and rebuilds them when the shard
+    /// is spawned.
+    /// * **Default**: None, session state is held only in memory.
+    /// * **Mutable**: No
+    pub session_store_dir: Option<path::PathBuf>,
+
+    /// Fsync policy for the session store, one of `always`, `interval`, `never`.
+    /// * **Default**: [Config::DEF_SESSION_STORE_FSYNC]
+    /// * **Mutable**: No
+    pub session_store_fsync: Option<FsyncPolicy>,
+
+    /// Interval, in seconds, between fsync calls, applicable only when
+    /// `session_store_fsync` is `interval`.
+    /// * **Default**: [Config::DEF_SESSION_STORE_FSYNC_INTERVAL]
+    /// * **Mutable**: No
+    pub session_store_fsync_interval: Option<u32>,
+
+    /// Maximum number of messages queued for a persistent session, while its client
+    /// is offline. Only QoS-1 and QoS-2 messages are queued.
+    /// * **Default**: [Config::DEF_OFFLINE_QUEUE_MAX_MESSAGES]
+    /// * **Mutable**: No
+    pub offline_queue_max_messages: Option<u32>,
+
+    /// Maximum size, in bytes, of messages queued for a persistent session, while its
+    /// client is offline. Size of a message is its topic-name and payload.
+    /// * **Default**: [Config::DEF_OFFLINE_QUEUE_MAX_BYTES]
+    /// * **Mutable**: No
+    pub offline_queue_max_bytes: Option<u32>,
+
+    /// What to do when a session's offline queue is full, one of `drop_oldest`,
+    /// `drop_newest`, `disconnect`.
+    /// * **Default**: [Config::DEF_OFFLINE_QUEUE_OVERFLOW]
+    /// * **Mutable**: No
+    pub offline_queue_overflow: Option<OverflowPolicy>,
+
+    /// Directory to spill offline queues. If configured, queued messages are written
+    /// to files under this directory, and only their book-keeping is held in memory.
+    /// Spill files are not reused after a restart, queues are rebuilt from the
+    /// session log, refer [Config::session_store_dir].
+    /// * **Default**: None, queued messages are held in memory.
+    /// * **Mutable**: No
+    pub offline_queue_spill_dir: Option<path::PathBuf>,
 }
 
 impl Default for Config {
@@ -147,9 +270,17 @@ impl Default for Config {
             name: "poc".to_string(),
             max_nodes: Some(Self::DEF_MAX_NODES),
             num_shards: Some(num_cores),
+            num_replicas: Some(Self::DEF_NUM_REPLICAS),
             port: Some(Self::DEF_MQTT_PORT),
+            bind_address: Some(Self::DEF_BIND_ADDRESS),
+            listeners: Vec::default(),
             nodes: Vec::default(),
+            cluster_join_timeout: Some(Self::DEF_CLUSTER_JOIN_TIMEOUT),
+            cluster_peer_timeout: Some(Self::DEF_CLUSTER_PEER_TIMEOUT),
+            cluster_secret: None,
             connect_timeout: Some(Self::DEF_CONNECT_TIMEOUT),
+            max_connections: None,
+            max_connections_per_ip: None,
             mqtt_read_timeout: Some(Self::DEF_MQTT_READ_TIMEOUT),
             mqtt_write_timeout: Some(Self::DEF_MQTT_WRITE_TIMEOUT),
             mqtt_flush_timeout: Some(Self::DEF_MQTT_FLUSH_TIMEOUT),
@@ -163,6 +294,16 @@ impl Default for Config {
             mqtt_retain_available: Some(Self::DEF_MQTT_RETAIN_AVAILABLE),
             mqtt_topic_alias_max: Some(Self::DEF_MQTT_TOPIC_ALIAS_MAX),
             mqtt_ignore_duplicate: Some(Self::DEF_MQTT_IGNORE_DUPLICATE),
+            retain_store_dir: None,
+            retain_store_fsync: Some(Self::DEF_RETAIN_STORE_FSYNC),
+            retain_store_fsync_interval: Some(Self::DEF_RETAIN_STORE_FSYNC_INTERVAL),
+            session_store_dir: None,
+            session_store_fsync: Some(Self::DEF_SESSION_STORE_FSYNC),
+            session_store_fsync_interval: Some(Self::DEF_SESSION_STORE_FSYNC_INTERVAL),
+            offline_queue_max_messages: Some(Self::DEF_OFFLINE_QUEUE_MAX_MESSAGES),
+            offline_queue_max_bytes: Some(Self::DEF_OFFLINE_QUEUE_MAX_BYTES),
+            offline_queue_overflow: Some(Self::DEF_OFFLINE_QUEUE_OVERFLOW),
+            offline_queue_spill_dir: None,
         }
     }
 }
@@ -170,8 +311,20 @@ impl Default for Config {
 impl Config {
     /// Refer to [Config::port]
     pub const DEF_MQTT_PORT: u16 = 1883;
+    /// Default port for `tls` listeners, refer [ConfigListener::address]
+    pub const DEF_MQTT_TLS_PORT: u16 = 8883;
+    /// Default port for `ws` listeners, refer [ConfigListener::address]
+    pub const DEF_MQTT_WS_PORT: u16 = 8083;
+    /// Refer to [Config::bind_address]
+    pub const DEF_BIND_ADDRESS: net::IpAddr = net::IpAddr::V4(net::Ipv4Addr::UNSPECIFIED);
     /// Refer to [Config::max_nodes]
     pub const DEF_MAX_NODES: u32 = 1;
+    /// Refer to [Config::num_replicas]
+    pub const DEF_NUM_REPLICAS: u32 = 1;
+    /// Refer to [Config::cluster_join_timeout]
+    pub const DEF_CLUSTER_JOIN_TIMEOUT: u32 = 30; // in seconds.
+    /// Refer to [Config::cluster_peer_timeout]
+    pub const DEF_CLUSTER_PEER_TIMEOUT: u32 = 5; // in seconds.
     /// Refer to [Config::connect_timeout]
     pub const DEF_CONNECT_TIMEOUT: u32 = 5; // in seconds.
     /// Refer to [Config::mqtt_read_timeout]
@@ -196,6 +349,20 @@ impl Config {
     pub const DEF_MQTT_TOPIC_ALIAS_MAX: u16 = 65535;
     /// Refer to [Config::mqtt_ignore_duplicate]
     pub const DEF_MQTT_IGNORE_DUPLICATE: bool = true;
+    /// Refer to [Config::retain_store_fsync]
+    pub const DEF_RETAIN_STORE_FSYNC: FsyncPolicy = FsyncPolicy::Interval;
+    /// Refer to [Config::retain_store_fsync_interval]
+    pub const DEF_RETAIN_STORE_FSYNC_INTERVAL: u32 = 1; // in seconds.
+    /// Refer to [Config::session_store_fsync]
+    pub const DEF_SESSION_STORE_FSYNC: FsyncPolicy = FsyncPolicy::Interval;
+    /// Refer to [Config::session_store_fsync_interval]
+    pub const DEF_SESSION_STORE_FSYNC_INTERVAL: u32 = 1; // in seconds.
+    /// Refer to [Config::offline_queue_max_messages]
+    pub const DEF_OFFLINE_QUEUE_MAX_MESSAGES: u32 = 1000;
+    /// Refer to [Config::offline_queue_max_bytes]
+    pub const DEF_OFFLINE_QUEUE_MAX_BYTES: u32 = 16 * 1024 * 1024; // default is 16MB.
+    /// Refer to [Config::offline_queue_overflow]
+    pub const DEF_OFFLINE_QUEUE_OVERFLOW: OverflowPolicy = OverflowPolicy::DropOldest;
 
     /// Construct a new configuration from a file located by `loc`.
     pub fn from_file<P>(loc: P) -> Result<Config>
@@ -205,10 +372,54 @@ impl Config {
         load_toml(loc)
     }
 
+    /// Return the list of listeners to start. If no listener is configured, return
+    /// a single TCP listener bound to [Config::bind_address] and [Config::port].
+    pub fn listeners(&self) -> Vec<ConfigListener> {
+        match self.listeners.len() {
+            0 => {
+                let port = self.port.unwrap_or(Self::DEF_MQTT_PORT);
+                let ip = self.bind_address.unwrap_or(Self::DEF_BIND_ADDRESS);
+                let address = net::SocketAddr::new(ip, port);
+                vec![ConfigListener {
+                    address: Some(address),
+                    ..ConfigListener::default()
+                }]
+            }
+            _ => self.listeners.clone(),
+        }
+    }
+
+    /// Return a copy of this configuration, with per-listener settings applied.
+    pub fn for_listener(&self, listener: &ConfigListener) -> Config {
+        let mut config = self.clone();
+        if let Some(val) = listener.connect_timeout {
+            config.connect_timeout = Some(val);
+        }
+        if let Some(val) = listener.mqtt_maximum_qos {
+            config.mqtt_maximum_qos = Some(val);
+        }
+        if let Some(val) = listener.max_connections_per_ip {
+            config.max_connections_per_ip = Some(val);
+        }
+        config
+    }
+
     pub fn max_nodes(&self) -> u32 {
         self.max_nodes.unwrap_or(Self::DEF_MAX_NODES)
     }
 
+    pub fn cluster_join_timeout(&self) -> u32 {
+        self.cluster_join_timeout.unwrap_or(Self::DEF_CLUSTER_JOIN_TIMEOUT)
+    }
+
+    pub fn cluster_peer_timeout(&self) -> u32 {
+        self.cluster_peer_timeout.unwrap_or(Self::DEF_CLUSTER_PEER_TIMEOUT)
+    }
+
+    pub fn cluster_secret(&self) -> Option<&str> {
+        self.cluster_secret.as_deref()
+    }
+
     pub fn num_shards(&self) -> u32 {
         use crate::util::ceil_power_of_2;
 
@@ -219,10 +430,22 @@ impl Config {
         self.num_shards.unwrap_or(num_cores)
     }
 
+    pub fn num_replicas(&self) -> u32 {
+        self.num_replicas.unwrap_or(Self::DEF_NUM_REPLICAS)
+    }
+
     pub fn connect_timeout(&self) -> u32 {
         self.connect_timeout.unwrap_or(Self::DEF_CONNECT_TIMEOUT)
     }
 
+    pub fn max_connections(&self) -> Option<u32> {
+        self.max_connections
+    }
+
+    pub fn max_connections_per_ip(&self) -> Option<u32> {
+        self.max_connections_per_ip
+    }
+
     pub fn mqtt_read_timeout(&self) -> u32 {
         self.mqtt_read_timeout.unwrap_or(Self::DEF_MQTT_READ_TIMEOUT)
     }
@@ -292,6 +515,290 @@ impl Config {
     pub fn mqtt_ignore_duplicate(&self) -> bool {
         self.mqtt_ignore_duplicate.unwrap_or(Self::DEF_MQTT_IGNORE_DUPLICATE)
     }
+
+    pub fn retain_store_dir(&self) -> Option<&path::Path> {
+        self.retain_store_dir.as_deref()
+    }
+
+    pub fn retain_store_fsync(&self) -> FsyncPolicy {
+        self.retain_store_fsync.unwrap_or(Self::DEF_RETAIN_STORE_FSYNC)
+    }
+
+    pub fn retain_store_fsync_interval(&self) -> u32 {
+        self.retain_store_fsync_interval
+            .unwrap_or(Self::DEF_RETAIN_STORE_FSYNC_INTERVAL)
+    }
+
+    pub fn session_store_dir(&self) -> Option<&path::Path> {
+        self.session_store_dir.as_deref()
+    }
+
+    pub fn session_store_fsync(&self) -> FsyncPolicy {
+        self.session_store_fsync.unwrap_or(Self::DEF_SESSION_STORE_FSYNC)
+    }
+
+    pub fn session_store_fsync_interval(&self) -> u32 {
+        self.session_store_fsync_interval
+            .unwrap_or(Self::DEF_SESSION_STORE_FSYNC_INTERVAL)
+    }
+
+    pub fn offline_queue_max_messages(&self) -> u32 {
+        self.offline_queue_max_messages.unwrap_or(Self::DEF_OFFLINE_QUEUE_MAX_MESSAGES)
+    }
+
+    pub fn offline_queue_max_bytes(&self) -> u32 {
+        self.offline_queue_max_bytes.unwrap_or(Self::DEF_OFFLINE_QUEUE_MAX_BYTES)
+    }
+
+    pub fn offline_queue_overflow(&self) -> OverflowPolicy {
+        self.offline_queue_overflow.unwrap_or(Self::DEF_OFFLINE_QUEUE_OVERFLOW)
+    }
+
+    pub fn offline_queue_spill_dir(&self) -> Option<&path::Path> {
+        self.offline_queue_spill_dir.as_deref()
+    }
+}
+
+/// Listener configuration, one for each `[[listener]]` table.
+///
+/// Parameters that are not specified for a listener fallback on the corresponding
+/// cluster wide parameter in [Config].
+#[derive(Clone, Default, Deserialize)]
+pub struct ConfigListener {
+    /// Address to bind for incoming connections, IPv4 like "0.0.0.0:1883" or IPv6
+    /// like "[::]:1883".
+    /// * **Default**: "0.0.0.0:1883", "0.0.0.0:8883" for `tls` and "0.0.0.0:8083"
+    ///   for `ws` transport, Refer to [Config::DEF_MQTT_PORT],
+    ///   [Config::DEF_MQTT_TLS_PORT] and [Config::DEF_MQTT_WS_PORT]
+    /// * **Mutable**: No
+    pub address: Option<net::SocketAddr>,
+
+    /// Applicable only for IPv6 `address`. If true, listener accepts only IPv6
+    /// clients, by setting IPV6_V6ONLY on the socket. Otherwise, binding "[::]"
+    /// accepts both IPv6 and IPv4 clients, that is dual-stack, and IPv4 clients are
+    /// identified by their IPv4 address.
+    /// * **Default**: false
+    /// * **Mutable**: No
+    pub ipv6_only: Option<bool>,
+
+    /// Transport protocol for this listener, one of `tcp`, `tls`, `ws`, `unix`.
+    /// * **Default**: [Transport::Tcp]
+    /// * **Mutable**: No
+    pub transport: Option<Transport>,
+
+    /// PEM file with the server certificate, followed by its chain, for `tls`
+    /// transport.
+    /// * **Default**: None, must be specified for `tls` transport.
+    /// * **Mutable**: No
+    pub tls_cert: Option<path::PathBuf>,
+
+    /// PEM file with the private key for [ConfigListener::tls_cert], in PKCS#8, PKCS#1
+    /// or SEC1 format.
+    /// * **Default**: None, must be specified for `tls` transport.
+    /// * **Mutable**: No
+    pub tls_key: Option<path::PathBuf>,
+
+    /// File system path to bind for `unix` transport. Stale socket file, if any, is
+    /// removed before binding, any other file at the path fails the bind.
+    /// * **Default**: None, must be specified for `unix` transport.
+    /// * **Mutable**: No
+    pub path: Option<path::PathBuf>,
+
+    /// File permissions for the socket file created by `unix` transport, like
+    /// `0o660`.
+    /// * **Default**: None, as created by the process umask.
+    /// * **Mutable**: No
+    pub permissions: Option<u32>,
+
+    /// Expect PROXY protocol header, version 1 or version 2, from load balancers
+    /// before the MQTT CONNECT packet, one of `disabled`, `optional`, `required`.
+    /// Not applicable for `tls` and `ws` transports.
+    /// When available, client address from the header is used for this connection.
+    /// Header is accepted only from [ConfigListener::trusted_proxies].
+    /// * **Default**: [ProxyProtocol::Disabled]
+    /// * **Mutable**: No
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    /// List of IP addresses in CIDR notation, of load balancers allowed to send the
+    /// PROXY header. Connections from other addresses are treated as direct clients
+    /// with `optional` proxy_protocol, and are rejected with `required`. Must be
+    /// specified for a `tcp` listener with proxy_protocol enabled, peers of a `unix`
+    /// listener are trusted.
+    /// * **Default**: []
+    /// * **Mutable**: No
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+
+    /// Maximum number of connections that can be active on this listener. New
+    /// connections beyond this limit are closed right after accept.
+    /// * **Default**: None, no limit.
+    /// * **Mutable**: No
+    pub max_connections: Option<u32>,
+
+    /// Maximum number of connections that can be active on this node from the same
+    /// source IP address, applied to connections accepted on this listener.
+    /// * **Default**: [Config::max_connections_per_ip]
+    /// * **Mutable**: No
+    pub max_connections_per_ip: Option<u32>,
+
+    /// Rate limit on accepting new connections, in connections per second. Connections
+    /// beyond the rate are closed right after accept.
+    /// * **Default**: None, no limit.
+    /// * **Mutable**: No
+    pub accept_rate: Option<u32>,
+
+    /// Burst size for `accept_rate`, number of connections that can be accepted
+    /// back-to-back before rate-limiting kicks in.
+    /// * **Default**: same as `accept_rate`.
+    /// * **Mutable**: No
+    pub accept_burst: Option<u32>,
+
+    /// List of IP addresses in CIDR notation, like "10.0.0.0/8" or "fd00::/8", allowed
+    /// to connect via this listener. If empty, all addresses are allowed unless
+    /// denied.
+    /// * **Default**: []
+    /// * **Mutable**: No
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    /// List of IP addresses in CIDR notation, denied from connecting via this
+    /// listener. Takes precedence over `allow`.
+    /// * **Default**: []
+    /// * **Mutable**: No
+    #[serde(default)]
+    pub deny: Vec<String>,
+
+    /// Connect handshake timeout, in seconds, for connections on this listener.
+    /// * **Default**: [Config::connect_timeout]
+    /// * **Mutable**: No
+    pub connect_timeout: Option<u32>,
+
+    /// Maximum supported QoS level advertised to clients on this listener.
+    /// * **Default**: [Config::mqtt_maximum_qos]
+    /// * **Mutable**: No
+    pub mqtt_maximum_qos: Option<u8>,
+
+    /// Mount-point prefix. Topic-names and topic-filters from clients connected via
+    /// this listener are prefixed with this string, and the prefix is stripped from
+    /// PUBLISH packets sent to them.
+    /// * **Default**: None
+    /// * **Mutable**: No
+    pub mount_point: Option<String>,
+}
+
+impl ConfigListener {
+    pub fn address(&self) -> net::SocketAddr {
+        match (self.address, self.transport()) {
+            (Some(address), _) => address,
+            (None, Transport::Tls) => {
+                crate::mqtt_listen_address4(Some(Config::DEF_MQTT_TLS_PORT))
+            }
+            (None, Transport::Ws) => {
+                crate::mqtt_listen_address4(Some(Config::DEF_MQTT_WS_PORT))
+            }
+            (None, _) => crate::mqtt_listen_address4(None),
+        }
+    }
+
+    pub fn ipv6_only(&self) -> bool {
+        self.ipv6_only.unwrap_or(false)
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport.unwrap_or(Transport::Tcp)
+    }
+
+    pub fn tls_cert(&self) -> Option<&path::Path> {
+        self.tls_cert.as_deref()
+    }
+
+    pub fn tls_key(&self) -> Option<&path::Path> {
+        self.tls_key.as_deref()
+    }
+
+    pub fn path(&self) -> Option<&path::Path> {
+        self.path.as_deref()
+    }
+
+    pub fn permissions(&self) -> Option<u32> {
+        self.permissions
+    }
+
+    pub fn proxy_protocol(&self) -> ProxyProtocol {
+        self.proxy_protocol.unwrap_or(ProxyProtocol::Disabled)
+    }
+
+    pub fn max_connections(&self) -> Option<u32> {
+        self.max_connections
+    }
+
+    pub fn accept_rate(&self) -> Option<u32> {
+        self.accept_rate
+    }
+
+    pub fn accept_burst(&self) -> Option<u32> {
+        self.accept_burst.or(self.accept_rate)
+    }
+
+    pub fn mount_point(&self) -> Option<&str> {
+        match &self.mount_point {
+            Some(val) if val.is_empty() => None,
+            Some(val) => Some(val.as_str()),
+            None => None,
+        }
+    }
+}
+
+/// Transport protocol for [ConfigListener].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// Plain TCP, over IPv4 or IPv6.
+    Tcp,
+    /// TLS over TCP, refer [ConfigListener::tls_cert] and [ConfigListener::tls_key].
+    Tls,
+    /// WebSocket over TCP, MQTT packets are carried in binary frames.
+    Ws,
+    /// Unix domain socket, bound to [ConfigListener::path].
+    Unix,
+}
+
+/// PROXY protocol handling for [ConfigListener].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// PROXY header is not expected.
+    Disabled,
+    /// Connection may or may not start with a PROXY header.
+    Optional,
+    /// Connections without a PROXY header are rejected.
+    Required,
+}
+
+/// Fsync policy for [Config::retain_store_fsync] and [Config::session_store_fsync].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsyncPolicy {
+    /// Fsync after every write.
+    Always,
+    /// Fsync periodically, refer to [Config::retain_store_fsync_interval] and
+    /// [Config::session_store_fsync_interval].
+    Interval,
+    /// Never fsync, leave it to the operating system.
+    Never,
+}
+
+/// Overflow policy for offline queues, refer [Config::offline_queue_overflow].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued messages to make room for the new message.
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Drop the new message, and disconnect the client with `QuotaExceeded` when it
+    /// reconnects. Queued messages are delivered on its next connection.
+    Disconnect,
 }
 
 /// Node configuration
@@ -303,7 +810,9 @@ pub struct ConfigNode {
     /// * **Default**: <Shall be generated by the cluster>
     /// * **Mutable**: No
     pub uuid: Option<String>,
-    /// Hierarchical path to nodes. Will be useful in selecting replica, and selecting
+    /// Hierarchical path to nodes, like `/<zone>/<rack>`. Replicas for a shard are
+    /// placed on nodes whose path diverge from the master at the highest level, so
+    /// that shards survive the failure of a zone. Will be useful in selecting
     /// `bridge-nodes` across the cluster.
     pub path: path::PathBuf,
     /// Weight to be given for each nodes, typically based on the number of cores,
@@ -313,6 +822,11 @@ pub struct ConfigNode {
     pub weight: Option<u16>,
     /// MQTT address on which nodes listen, and clients can connect.
     pub mqtt_address: net::SocketAddr,
+    /// Inter-node address on which nodes listen, and other nodes in the cluster
+    /// connect. Must be supplied for multi-node cluster.
+    /// * **Default**: None
+    /// * **Mutable**: No
+    pub cluster_address: Option<net::SocketAddr>,
 }
 
 impl Default for ConfigNode {
@@ -321,6 +835,7 @@ impl Default for ConfigNode {
 
         ConfigNode {
             mqtt_address: "0.0.0.0:1883".parse().unwrap(),
+            cluster_address: None,
             path: "/".into(), // TODO: a meaningful path.
             weight: Some(u16::try_from(num_cpus::get()).unwrap()),
             uuid: Some(Uuid::new_v4().to_string()),
@@ -0,0 +1,1169 @@
+//! MQTT v5 conformance suite, drive an in-process [Cluster] with scripted raw packet
+//! exchanges.
+//!
+//! Every case is tagged with the normative statement, `MQTT-x.x.x-y`, it checks.
+//! All cases are run against the same broker and a conformance report is printed
+//! at the end, set `MQTR_CONFORMANCE_REPORT` to a file path to save the report.
+
+use std::io::Write;
+use std::{env, fmt, fs, mem, net, sync::mpsc, thread, time};
+
+use crate::fixtures::{new_connect, new_publish, new_retained};
+use crate::packet::MQTTRead;
+use crate::v5::{self, QoS, RetainForwardRule, SubscribeFilter, SubscriptionOpt};
+use crate::{Cluster, Config, ConfigNode, Node, Packetize};
+use crate::{PacketID, TopicFilter, TopicName};
+
+const RECV_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+// Time taken by the cluster to commit retained messages and by sessions to notice
+// a closed connection.
+const SETTLE: time::Duration = time::Duration::from_millis(200);
+
+type Verdict<T = ()> = std::result::Result<T, String>;
+
+macro_rules! ensure {
+    ($cond:expr, $($arg:expr),+) => {
+        if !$cond {
+            return Err(format!($($arg),+));
+        }
+    };
+}
+
+struct Case {
+    id: &'static str,
+    desc: &'static str,
+    run: fn(&Broker) -> Verdict,
+}
+
+const CASES: &[Case] = &[
+    // CONNECT / CONNACK
+    Case {
+        id: "MQTT-3.1.0-1",
+        desc: "first packet from client must be CONNECT",
+        run: first_packet_connect,
+    },
+    Case {
+        id: "MQTT-3.1.0-2",
+        desc: "second CONNECT is a protocol error",
+        run: second_connect,
+    },
+    Case {
+        id: "MQTT-3.1.2-1",
+        desc: "protocol name must be MQTT",
+        run: protocol_name,
+    },
+    Case {
+        id: "MQTT-3.1.2-3",
+        desc: "reserved flag in CONNECT must be zero",
+        run: connect_reserved_flag,
+    },
+    Case {
+        id: "MQTT-3.1.2-12",
+        desc: "will QoS 3 is a malformed packet",
+        run: connect_will_qos3,
+    },
+    Case {
+        id: "MQTT-3.1.3-7",
+        desc: "zero length ClientID gets an assigned client identifier",
+        run: assigned_client_id,
+    },
+    Case {
+        id: "MQTT-3.1.4-3",
+        desc: "existing connection for ClientID is taken over",
+        run: session_taken_over,
+    },
+    Case {
+        id: "MQTT-3.2.0-1",
+        desc: "CONNACK is the first packet sent by server",
+        run: connack_first,
+    },
+    Case {
+        id: "MQTT-3.2.2-2",
+        desc: "session present is 0 for clean start",
+        run: connack_clean_start,
+    },
+    Case {
+        id: "MQTT-3.2.2-9",
+        desc: "server advertises its maximum QoS",
+        run: connack_maximum_qos,
+    },
+    Case {
+        id: "MQTT-3.12.4-1",
+        desc: "PINGREQ is answered with PINGRESP",
+        run: ping,
+    },
+    Case {
+        id: "MQTT-3.1.2-22",
+        desc: "connection is closed after 1.5 times keep alive",
+        run: keep_alive_timeout,
+    },
+    // QoS flows
+    Case {
+        id: "MQTT-3.8.4-2",
+        desc: "SUBACK carries the SUBSCRIBE packet identifier",
+        run: suback_packet_id,
+    },
+    Case {
+        id: "MQTT-3.2.2-10",
+        desc: "SUBSCRIBE with QoS beyond maximum QoS is granted maximum QoS",
+        run: subscribe_qos2,
+    },
+    Case {
+        id: "MQTT-3.3.4-1",
+        desc: "QoS 1 PUBLISH is acknowledged with PUBACK",
+        run: publish_qos1_puback,
+    },
+    Case {
+        id: "MQTT-3.8.4-7",
+        desc: "delivery QoS is the minimum of publish and granted QoS",
+        run: delivery_qos,
+    },
+    Case {
+        id: "MQTT-2.2.1-4",
+        desc: "server assigns non-zero, unused packet identifiers",
+        run: delivery_packet_ids,
+    },
+    Case {
+        id: "MQTT-3.3.1-3",
+        desc: "DUP flag is not propagated to subscribers",
+        run: dup_not_propagated,
+    },
+    Case {
+        id: "MQTT-3.10.4-4",
+        desc: "UNSUBSCRIBE is answered with UNSUBACK",
+        run: unsubscribe,
+    },
+    Case {
+        id: "MQTT-3.10.4-2",
+        desc: "no delivery after UNSUBSCRIBE",
+        run: unsubscribe_stops_delivery,
+    },
+    Case {
+        id: "MQTT-4.7.2-1",
+        desc: "wildcard filters do not match topics starting with $",
+        run: dollar_topics,
+    },
+    // retained messages
+    Case {
+        id: "MQTT-3.3.1-9",
+        desc: "retained message is sent on subscribe, with RETAIN set",
+        run: retained_on_subscribe,
+    },
+    Case {
+        id: "MQTT-3.3.1-11",
+        desc: "retain handling 2 does not send retained messages",
+        run: retained_handling_never,
+    },
+    Case {
+        id: "MQTT-3.3.1-6",
+        desc: "zero length retained payload removes retained message",
+        run: retained_remove,
+    },
+    Case {
+        id: "MQTT-3.3.1-12",
+        desc: "RETAIN is cleared on forward unless retain-as-published",
+        run: retain_as_published,
+    },
+    // wills
+    Case {
+        id: "MQTT-3.1.2-8",
+        desc: "will is published when connection closes abnormally",
+        run: will_on_abnormal_close,
+    },
+    Case {
+        id: "MQTT-3.14.4-3",
+        desc: "will is discarded on normal DISCONNECT",
+        run: will_discarded_on_disconnect,
+    },
+    Case {
+        id: "MQTT-3.1.2-15",
+        desc: "will with will-retain is published as retained message",
+        run: will_retained,
+    },
+    // topic aliases
+    Case {
+        id: "MQTT-3.3.2-12",
+        desc: "server accepts topic aliases up to its maximum",
+        run: topic_alias,
+    },
+    Case {
+        id: "MQTT-3.3.2-9",
+        desc: "topic alias beyond maximum is a protocol error",
+        run: topic_alias_exceeds_max,
+    },
+    Case {
+        id: "MQTT-3.3.2-7",
+        desc: "topic aliases are not carried across connections",
+        run: topic_alias_per_connection,
+    },
+    // error disconnects
+    Case {
+        id: "MQTT-3.2.2-11",
+        desc: "PUBLISH beyond maximum QoS is disconnected with 0x9B",
+        run: publish_qos_not_supported,
+    },
+    Case {
+        id: "MQTT-3.3.2-2",
+        desc: "wildcard in topic name is a malformed packet",
+        run: publish_wildcard_topic,
+    },
+    Case {
+        id: "MQTT-3.8.3-4",
+        desc: "no-local on shared subscription is a protocol error",
+        run: subscribe_shared_no_local,
+    },
+    Case {
+        id: "MQTT-4.13.1-1",
+        desc: "malformed packet closes the connection",
+        run: malformed_packet,
+    },
+];
+
+#[test]
+fn test_conformance() {
+    let broker = Broker::start();
+
+    let mut report = Report::default();
+    for case in CASES.iter() {
+        let verdict = (case.run)(&broker);
+        report.results.push((case, verdict));
+    }
+
+    broker.stop();
+
+    println!("{}", report);
+    if let Ok(loc) = env::var("MQTR_CONFORMANCE_REPORT") {
+        fs::write(&loc, report.to_string()).unwrap();
+    }
+    assert_eq!(report.failed(), 0, "{}", report);
+}
+
+#[test]
+fn test_retain_store_restart() {
+    let dir = env::temp_dir().join(format!("mqtr-retain-{}", uuid::Uuid::new_v4()));
+    let config = Config {
+        retain_store_dir: Some(dir.clone()),
+        ..Config::default()
+    };
+
+    let broker = Broker::start_with(config.clone());
+    let (mut conn, _) = broker.connect("restart-pub").unwrap();
+    conn.publish(new_retained("restart/a", "kept", None)).unwrap();
+    conn.publish(new_retained("restart/b", "removed", None)).unwrap();
+    conn.publish(new_retained("restart/b", "", None)).unwrap();
+    conn.disconnect().unwrap();
+    thread::sleep(SETTLE);
+    broker.stop();
+
+    let broker = Broker::start_with(config);
+    let mut sub = broker.subscriber("restart-sub", "restart/+", QoS::AtMostOnce).unwrap();
+    let publ = sub.recv_publish().unwrap();
+    assert_eq!(publ.topic_name.as_str(), "restart/a");
+    assert_eq!(publ.payload.as_ref().map(|p| p.as_ref()), Some(b"kept".as_ref()));
+    sub.recv_none().unwrap();
+    broker.stop();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_session_store_restart() {
+    let dir = env::temp_dir().join(format!("mqtr-session-{}", uuid::Uuid::new_v4()));
+    let config = Config {
+        session_store_dir: Some(dir.clone()),
+        ..Config::default()
+    };
+
+    let broker = Broker::start_with(config.clone());
+    let (mut sub, connack) = broker.connect_with(new_connect("durable", false)).unwrap();
+    assert_eq!(connack.flags.unwrap().unwrap(), false);
+    sub.subscribe(1, new_filter("durable/+", QoS::AtLeastOnce)).unwrap();
+
+    let (mut publ, _) = broker.connect("durable-pub").unwrap();
+    publ.publish(new_publish("durable/a", QoS::AtLeastOnce, Some(1))).unwrap();
+    // leave the message un-acknowledged and drop the connection.
+    let packet_id = sub.recv_publish().unwrap().packet_id;
+    mem::drop(sub);
+    publ.disconnect().unwrap();
+    thread::sleep(SETTLE);
+    broker.stop();
+
+    let broker = Broker::start_with(config);
+    let (mut sub, connack) = broker.connect_with(new_connect("durable", false)).unwrap();
+    assert_eq!(connack.flags.unwrap().unwrap(), true);
+    let resent = sub.recv_publish().unwrap();
+    assert_eq!(resent.topic_name.as_str(), "durable/a");
+    assert_eq!((resent.duplicate, resent.packet_id), (true, packet_id));
+    sub.puback(packet_id.unwrap()).unwrap();
+
+    // subscription survived the restart.
+    let (mut publ, _) = broker.connect("durable-pub").unwrap();
+    publ.publish(new_publish("durable/b", QoS::AtLeastOnce, Some(2))).unwrap();
+    let publish = sub.recv_publish().unwrap();
+    assert_eq!(publish.topic_name.as_str(), "durable/b");
+    sub.puback(publish.packet_id.unwrap()).unwrap();
+    mem::drop(sub);
+    thread::sleep(SETTLE);
+
+    // clean-start discards the session.
+    let mut connect = new_connect("durable", false);
+    connect.flags = v5::ConnectFlags::new(&[v5::ConnectFlags::CLEAN_START]);
+    let (sub, connack) = broker.connect_with(connect).unwrap();
+    assert_eq!(connack.flags.unwrap().unwrap(), false);
+    sub.disconnect().unwrap();
+    broker.stop();
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_offline_queue() {
+    let config = Config {
+        offline_queue_max_messages: Some(3),
+        ..Config::default()
+    };
+    let broker = Broker::start_with(config);
+    let (mut sub, _) = broker.connect_with(new_connect("offline", false)).unwrap();
+    sub.subscribe(1, new_filter("offline/+", QoS::AtLeastOnce)).unwrap();
+    sub.disconnect().unwrap();
+    thread::sleep(SETTLE);
+
+    // QoS-0 is not queued, and the oldest message is dropped on overflow.
+    let (mut publ, _) = broker.connect("offline-pub").unwrap();
+    publ.publish(new_publish("offline/0", QoS::AtMostOnce, None)).unwrap();
+    for (i, topic) in
+        ["offline/1", "offline/2", "offline/3", "offline/4"].iter().enumerate()
+    {
+        let packet_id = Some(i as PacketID + 1);
+        publ.publish(new_publish(topic, QoS::AtLeastOnce, packet_id)).unwrap();
+    }
+    thread::sleep(SETTLE);
+
+    // queued messages are delivered in order, within client's receive-maximum.
+    let mut connect = new_connect("offline", false);
+    connect.properties.as_mut().unwrap().receive_maximum = Some(2);
+    let (mut sub, connack) = broker.connect_with(connect).unwrap();
+    assert_eq!(connack.flags.unwrap().unwrap(), true);
+    let first = sub.recv_publish().unwrap();
+    assert_eq!(first.topic_name.as_str(), "offline/2");
+    assert_eq!(sub.recv_publish().unwrap().topic_name.as_str(), "offline/3");
+    sub.recv_none().unwrap();
+    sub.puback(first.packet_id.unwrap()).unwrap();
+    assert_eq!(sub.recv_publish().unwrap().topic_name.as_str(), "offline/4");
+    sub.disconnect().unwrap();
+    publ.disconnect().unwrap();
+    broker.stop();
+
+    // overflow disconnects the client on reconnect, and the queue is delivered on
+    // the next reconnect.
+    let config = Config {
+        offline_queue_max_messages: Some(1),
+        offline_queue_overflow: Some(crate::OverflowPolicy::Disconnect),
+        ..Config::default()
+    };
+    let broker = Broker::start_with(config);
+    let (mut sub, _) = broker.connect_with(new_connect("offline", false)).unwrap();
+    sub.subscribe(1, new_filter("offline/+", QoS::AtLeastOnce)).unwrap();
+    sub.disconnect().unwrap();
+    thread::sleep(SETTLE);
+
+    let (mut publ, _) = broker.connect("offline-pub").unwrap();
+    publ.publish(new_publish("offline/1", QoS::AtLeastOnce, Some(1))).unwrap();
+    publ.publish(new_publish("offline/2", QoS::AtLeastOnce, Some(2))).unwrap();
+    thread::sleep(SETTLE);
+
+    let (mut sub, _) = broker.connect_with(new_connect("offline", false)).unwrap();
+    sub.recv_disconnect(v5::DisconnReasonCode::QuotaExceeded).unwrap();
+    thread::sleep(SETTLE);
+    let (mut sub, connack) = broker.connect_with(new_connect("offline", false)).unwrap();
+    assert_eq!(connack.flags.unwrap().unwrap(), true);
+    assert_eq!(sub.recv_publish().unwrap().topic_name.as_str(), "offline/1");
+    sub.recv_none().unwrap();
+    sub.disconnect().unwrap();
+    publ.disconnect().unwrap();
+    broker.stop();
+}
+
+#[test]
+fn test_snapshot_migrate() {
+    let broker = Broker::start();
+    let (mut sub, _) = broker.connect_with(new_connect("snap-sub", false)).unwrap();
+    sub.subscribe(1, new_filter("snap/+", QoS::AtLeastOnce)).unwrap();
+    let (mut publ, _) = broker.connect("snap-pub").unwrap();
+    publ.publish(new_retained("snap/a", "kept", None)).unwrap();
+    thread::sleep(SETTLE);
+
+    let snapshot = broker.cluster.export_snapshot().unwrap();
+    sub.disconnect().unwrap();
+    publ.disconnect().unwrap();
+    broker.stop();
+    assert_eq!(snapshot.subscriptions.len(), 1);
+    assert_eq!(snapshot.retained.len(), 1);
+
+    let broker = Broker::start();
+    broker.cluster.import_snapshot(snapshot.clone()).unwrap();
+    let imported = broker.cluster.export_snapshot().unwrap();
+    assert_eq!(imported.subscriptions, snapshot.subscriptions);
+    assert_eq!(imported.retained, snapshot.retained);
+
+    let mut sub = broker.subscriber("snap-new", "snap/#", QoS::AtMostOnce).unwrap();
+    let publish = sub.recv_publish().unwrap();
+    assert_eq!(publish.topic_name.as_str(), "snap/a");
+    sub.disconnect().unwrap();
+
+    // imported subscriptions are held by offline sessions, until their client
+    // reconnects.
+    let (mut publ, _) = broker.connect("snap-pub").unwrap();
+    publ.publish(new_publish("snap/b", QoS::AtLeastOnce, Some(1))).unwrap();
+    thread::sleep(SETTLE);
+    let (mut sub, connack) = broker.connect_with(new_connect("snap-sub", false)).unwrap();
+    assert!(connack.flags.unwrap().unwrap());
+    let publish = sub.recv_publish().unwrap();
+    assert_eq!(publish.topic_name.as_str(), "snap/b");
+    sub.disconnect().unwrap();
+    publ.disconnect().unwrap();
+    broker.stop();
+}
+
+#[derive(Default)]
+struct Report {
+    results: Vec<(&'static Case, Verdict)>,
+}
+
+impl Report {
+    fn failed(&self) -> usize {
+        self.results.iter().filter(|(_, verdict)| verdict.is_err()).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "MQTT v5 conformance report")?;
+        for (case, verdict) in self.results.iter() {
+            match verdict {
+                Ok(()) => writeln!(f, "  PASS {:<14} {}", case.id, case.desc)?,
+                Err(err) => writeln!(f, "  FAIL {:<14} {}: {}", case.id, case.desc, err)?,
+            }
+        }
+        let (n, failed) = (self.results.len(), self.failed());
+        write!(f, "passed {}/{}, failed {}", n - failed, n, failed)
+    }
+}
+
+struct Broker {
+    port: u16,
+    cluster: Cluster,
+}
+
+impl Broker {
+    fn start() -> Broker {
+        Broker::start_with(Config::default())
+    }
+
+    // start broker with `config`, name, shards and listening port are overridden.
+    fn start_with(config: Config) -> Broker {
+        // pick a free port, there is a small window for another process to grab it.
+        let port = {
+            let lis = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            lis.local_addr().unwrap().port()
+        };
+
+        let config = Config {
+            name: "conformance".to_string(),
+            num_shards: Some(2),
+            port: Some(port),
+            bind_address: Some("127.0.0.1".parse().unwrap()),
+            ..config
+        };
+        let node = Node::try_from(ConfigNode {
+            mqtt_address: format!("127.0.0.1:{}", port).parse().unwrap(),
+            ..ConfigNode::default()
+        })
+        .unwrap();
+
+        let (app_tx, app_rx) = mpsc::sync_channel(1024);
+        thread::spawn(move || for _msg in app_rx {});
+        let cluster = Cluster::from_config(config).unwrap().spawn(node, app_tx).unwrap();
+
+        let broker = Broker { port, cluster };
+        // wait for the listener.
+        for _ in 0..100 {
+            if net::TcpStream::connect(("127.0.0.1", port)).is_ok() {
+                break;
+            }
+            thread::sleep(crate::SLEEP_10MS);
+        }
+        broker
+    }
+
+    fn stop(self) {
+        self.cluster.close_wait();
+    }
+
+    fn open(&self) -> Verdict<Conn> {
+        let conn = net::TcpStream::connect(("127.0.0.1", self.port))
+            .map_err(|err| format!("connect: {}", err))?;
+        conn.set_read_timeout(Some(crate::SLEEP_10MS)).unwrap();
+        conn.set_nodelay(true).unwrap();
+        Ok(Conn { conn })
+    }
+
+    fn connect(&self, client_id: &str) -> Verdict<(Conn, v5::ConnAck)> {
+        self.connect_with(new_connect(client_id, true))
+    }
+
+    fn connect_with(&self, connect: v5::Connect) -> Verdict<(Conn, v5::ConnAck)> {
+        let mut conn = self.open()?;
+        conn.send(v5::Packet::Connect(connect))?;
+        match conn.recv()? {
+            v5::Packet::ConnAck(connack)
+                if connack.code == v5::ConnackReasonCode::Success =>
+            {
+                Ok((conn, connack))
+            }
+            v5::Packet::ConnAck(connack) => Err(format!("connack {:?}", connack.code)),
+            pkt => Err(format!("expected CONNACK got {:?}", pkt.to_packet_type())),
+        }
+    }
+
+    // connect and subscribe to `filter`, returns after SUBACK.
+    fn subscriber(&self, client_id: &str, filter: &str, qos: QoS) -> Verdict<Conn> {
+        let (mut conn, _) = self.connect(client_id)?;
+        conn.subscribe(1, new_filter(filter, qos))?;
+        Ok(conn)
+    }
+}
+
+struct Conn {
+    conn: net::TcpStream,
+}
+
+impl Conn {
+    fn send(&mut self, pkt: v5::Packet) -> Verdict {
+        let data = pkt.encode().map_err(|err| format!("encode: {}", err))?;
+        self.send_raw(data.as_ref())
+    }
+
+    fn send_raw(&mut self, data: &[u8]) -> Verdict {
+        self.conn.write_all(data).map_err(|err| format!("write: {}", err))
+    }
+
+    // receive the next packet, fail if connection is closed or on timeout.
+    fn recv(&mut self) -> Verdict<v5::Packet> {
+        match self.recv_timeout(RECV_TIMEOUT)? {
+            Some(pkt) => Ok(pkt),
+            None => Err(format!("no packet after {:?}", RECV_TIMEOUT)),
+        }
+    }
+
+    // receive the next packet, return None on timeout.
+    fn recv_timeout(&mut self, timeout: time::Duration) -> Verdict<Option<v5::Packet>> {
+        let deadline = time::Instant::now() + timeout;
+        let mut packetr = MQTTRead::new(Config::DEF_MQTT_MAX_PACKET_SIZE);
+        loop {
+            packetr = match packetr.read(&self.conn) {
+                Ok((mut packetr @ MQTTRead::Fin { .. }, _)) => {
+                    break match packetr.parse() {
+                        Ok(pkt) => Ok(Some(pkt)),
+                        Err(err) => Err(format!("parse: {}", err)),
+                    };
+                }
+                Ok((MQTTRead::Init { .. }, true)) if time::Instant::now() > deadline => {
+                    break Ok(None);
+                }
+                Ok((packetr, _)) => packetr,
+                Err(_) => break Err("connection closed".to_string()),
+            };
+        }
+    }
+
+    // expect a PUBLISH packet.
+    fn recv_publish(&mut self) -> Verdict<v5::Publish> {
+        match self.recv()? {
+            v5::Packet::Publish(publ) => Ok(publ),
+            pkt => Err(format!("expected PUBLISH got {:?}", pkt.to_packet_type())),
+        }
+    }
+
+    // expect no packet for a while.
+    fn recv_none(&mut self) -> Verdict {
+        match self.recv_timeout(SETTLE * 2)? {
+            Some(pkt) => Err(format!("unexpected {:?}", pkt.to_packet_type())),
+            None => Ok(()),
+        }
+    }
+
+    // read packets until the server closes the connection, return the last packet.
+    fn recv_closed(&mut self) -> Verdict<Option<v5::Packet>> {
+        let mut last = None;
+        loop {
+            match self.recv_timeout(RECV_TIMEOUT) {
+                Ok(Some(pkt)) => last = Some(pkt),
+                Ok(None) => break Err(format!("not closed after {:?}", RECV_TIMEOUT)),
+                Err(_) => break Ok(last),
+            }
+        }
+    }
+
+    // expect the server to close the connection with DISCONNECT carrying `code`.
+    fn recv_disconnect(&mut self, code: v5::DisconnReasonCode) -> Verdict {
+        match self.recv_closed()? {
+            Some(v5::Packet::Disconnect(dc)) if dc.code == code => Ok(()),
+            Some(v5::Packet::Disconnect(dc)) => Err(format!("disconnect {:?}", dc.code)),
+            Some(pkt) => Err(format!("closed after {:?}", pkt.to_packet_type())),
+            None => Err(format!("closed without DISCONNECT {:?}", code)),
+        }
+    }
+
+    fn subscribe(&mut self, packet_id: PacketID, filter: SubscribeFilter) -> Verdict {
+        let sub = v5::Subscribe { packet_id, properties: None, filters: vec![filter] };
+        self.send(v5::Packet::Subscribe(sub))?;
+        match self.recv()? {
+            v5::Packet::SubAck(suback) if suback.packet_id == packet_id => Ok(()),
+            v5::Packet::SubAck(suback) => Err(format!("suback {}", suback.packet_id)),
+            pkt => Err(format!("expected SUBACK got {:?}", pkt.to_packet_type())),
+        }
+    }
+
+    fn publish(&mut self, publ: v5::Publish) -> Verdict {
+        let (qos, packet_id) = (publ.qos, publ.packet_id);
+        self.send(v5::Packet::Publish(publ))?;
+        match qos {
+            QoS::AtMostOnce => Ok(()),
+            _ => match self.recv()? {
+                v5::Packet::PubAck(puback) if Some(puback.packet_id) == packet_id => {
+                    Ok(())
+                }
+                v5::Packet::PubAck(puback) => Err(format!("puback {}", puback.packet_id)),
+                pkt => Err(format!("expected PUBACK got {:?}", pkt.to_packet_type())),
+            },
+        }
+    }
+
+    fn puback(&mut self, packet_id: PacketID) -> Verdict {
+        let puback = v5::Pub {
+            packet_type: v5::PacketType::PubAck,
+            packet_id,
+            code: crate::ReasonCode::Success,
+            properties: None,
+        };
+        self.send(v5::Packet::PubAck(puback))
+    }
+
+    fn disconnect(mut self) -> Verdict {
+        let dc = v5::Disconnect::new(v5::DisconnReasonCode::NormalDisconnect, None);
+        self.send(v5::Packet::Disconnect(dc))
+    }
+}
+
+fn new_will_connect(client_id: &str, topic: &str, retain: bool) -> v5::Connect {
+    use v5::ConnectFlags as F;
+
+    let mut connect = new_connect(client_id, true);
+    connect.flags = match retain {
+        true => F::new(&[F::CLEAN_START, F::WILL_FLAG, F::WILL_RETAIN]),
+        false => F::new(&[F::CLEAN_START, F::WILL_FLAG]),
+    };
+    connect.payload.will_properties = Some(v5::WillProperties::default());
+    connect.payload.will_topic = Some(TopicName::from(topic.to_string()));
+    connect.payload.will_payload = Some(b"gone".to_vec());
+    connect
+}
+
+fn new_filter(filter: &str, qos: QoS) -> SubscribeFilter {
+    SubscribeFilter {
+        topic_filter: TopicFilter::from(filter.to_string()),
+        opt: SubscriptionOpt::new(RetainForwardRule::OnEverySubscribe, false, false, qos),
+    }
+}
+
+fn first_packet_connect(broker: &Broker) -> Verdict {
+    let mut conn = broker.open()?;
+    conn.send(v5::Packet::PingReq)?;
+    match conn.recv_closed()? {
+        None => Ok(()),
+        Some(v5::Packet::ConnAck(connack)) if connack.code as u8 >= 0x80 => Ok(()),
+        Some(pkt) => Err(format!("closed after {:?}", pkt.to_packet_type())),
+    }
+}
+
+fn second_connect(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("second-connect")?;
+    conn.send(v5::Packet::Connect(new_connect("second-connect", true)))?;
+    conn.recv_disconnect(v5::DisconnReasonCode::ProtocolError)
+}
+
+fn protocol_name(broker: &Broker) -> Verdict {
+    let mut connect =
+        new_connect("protocol-name", true).encode().unwrap().as_ref().to_vec();
+    // fixed-header(2) + name-length(2) + "MQTT".
+    connect[4..8].copy_from_slice(b"MQTX");
+
+    let mut conn = broker.open()?;
+    conn.send_raw(&connect)?;
+    match conn.recv_closed()? {
+        None => Ok(()),
+        Some(v5::Packet::ConnAck(connack)) if connack.code as u8 >= 0x80 => Ok(()),
+        Some(pkt) => Err(format!("closed after {:?}", pkt.to_packet_type())),
+    }
+}
+
+fn connect_reserved_flag(broker: &Broker) -> Verdict {
+    let mut connect =
+        new_connect("reserved-flag", true).encode().unwrap().as_ref().to_vec();
+    // fixed-header(2) + "MQTT"(6) + version(1)
+    connect[9] |= 0x01;
+
+    let mut conn = broker.open()?;
+    conn.send_raw(&connect)?;
+    match conn.recv_closed()? {
+        None => Ok(()),
+        Some(v5::Packet::ConnAck(connack)) if connack.code as u8 >= 0x80 => Ok(()),
+        Some(pkt) => Err(format!("closed after {:?}", pkt.to_packet_type())),
+    }
+}
+
+fn connect_will_qos3(broker: &Broker) -> Verdict {
+    let connect = new_will_connect("will-qos3", "will/qos3", false);
+    let mut connect = connect.encode().unwrap().as_ref().to_vec();
+    connect[9] |= 0b_0001_1000;
+
+    let mut conn = broker.open()?;
+    conn.send_raw(&connect)?;
+    match conn.recv_closed()? {
+        None => Ok(()),
+        Some(v5::Packet::ConnAck(connack)) if connack.code as u8 >= 0x80 => Ok(()),
+        Some(pkt) => Err(format!("closed after {:?}", pkt.to_packet_type())),
+    }
+}
+
+fn assigned_client_id(broker: &Broker) -> Verdict {
+    let (conn, connack) = broker.connect("")?;
+    let props = connack.properties.unwrap_or_default();
+    match props.assigned_client_identifier {
+        Some(client_id) if !client_id.is_empty() => conn.disconnect(),
+        _ => Err("missing assigned client identifier".to_string()),
+    }
+}
+
+fn session_taken_over(broker: &Broker) -> Verdict {
+    let (mut old, _) = broker.connect("taken-over")?;
+    let (new, _) = broker.connect("taken-over")?;
+    old.recv_disconnect(v5::DisconnReasonCode::SessionTakenOver)?;
+    new.disconnect()
+}
+
+fn connack_first(broker: &Broker) -> Verdict {
+    let (conn, _) = broker.connect("connack-first")?;
+    conn.disconnect()
+}
+
+fn connack_clean_start(broker: &Broker) -> Verdict {
+    let (conn, connack) = broker.connect("clean-start")?;
+    ensure!(
+        connack.flags.unwrap().ok() == Some(false),
+        "session present with clean start"
+    );
+    conn.disconnect()
+}
+
+fn connack_maximum_qos(broker: &Broker) -> Verdict {
+    let (conn, connack) = broker.connect("maximum-qos")?;
+    let props = connack.properties.unwrap_or_default();
+    ensure!(
+        props.maximum_qos == Some(QoS::AtLeastOnce),
+        "maximum_qos {:?}",
+        props.maximum_qos
+    );
+    conn.disconnect()
+}
+
+fn ping(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("ping")?;
+    conn.send(v5::Packet::PingReq)?;
+    match conn.recv()? {
+        v5::Packet::PingResp => conn.disconnect(),
+        pkt => Err(format!("expected PINGRESP got {:?}", pkt.to_packet_type())),
+    }
+}
+
+fn keep_alive_timeout(broker: &Broker) -> Verdict {
+    let mut connect = new_connect("keep-alive", true);
+    connect.keep_alive = 1;
+    let (mut conn, _) = broker.connect_with(connect)?;
+
+    // connection must be alive within the keep-alive period.
+    thread::sleep(time::Duration::from_millis(500));
+    conn.send(v5::Packet::PingReq)?;
+    match conn.recv()? {
+        v5::Packet::PingResp => (),
+        pkt => Err(format!("expected PINGRESP got {:?}", pkt.to_packet_type()))?,
+    }
+    conn.recv_disconnect(v5::DisconnReasonCode::KeepAliveTimeout)
+}
+
+fn suback_packet_id(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("suback")?;
+    conn.subscribe(0x1234, new_filter("suback/+", QoS::AtLeastOnce))?;
+    conn.disconnect()
+}
+
+fn subscribe_qos2(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("subscribe-qos2")?;
+    let filters = vec![
+        new_filter("subscribe-qos2/0", QoS::AtMostOnce),
+        new_filter("subscribe-qos2/2", QoS::ExactlyOnce),
+    ];
+    let sub = v5::Subscribe { packet_id: 1, properties: None, filters };
+    conn.send(v5::Packet::Subscribe(sub))?;
+    match conn.recv()? {
+        v5::Packet::SubAck(suback) => {
+            use v5::SubAckReasonCode::{QoS0, QoS1};
+
+            let codes = suback.return_codes;
+            ensure!(codes == vec![QoS0, QoS1], "suback return codes {:?}", codes);
+        }
+        pkt => Err(format!("expected SUBACK got {:?}", pkt.to_packet_type()))?,
+    }
+    conn.disconnect()
+}
+
+fn publish_qos1_puback(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("puback-sub", "puback/#", QoS::AtLeastOnce)?;
+    let (mut conn, _) = broker.connect("puback-pub")?;
+
+    // with and without subscribers.
+    conn.publish(new_publish("puback/a", QoS::AtLeastOnce, Some(10)))?;
+    conn.publish(new_publish("nosubscribers/a", QoS::AtLeastOnce, Some(11)))?;
+
+    let publ = sub.recv_publish()?;
+    sub.puback(publ.packet_id.unwrap_or(0))?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn delivery_qos(broker: &Broker) -> Verdict {
+    let mut sub0 = broker.subscriber("qos-sub0", "qos/#", QoS::AtMostOnce)?;
+    let mut sub1 = broker.subscriber("qos-sub1", "qos/#", QoS::AtLeastOnce)?;
+    let (mut conn, _) = broker.connect("qos-pub")?;
+
+    conn.publish(new_publish("qos/1", QoS::AtLeastOnce, Some(1)))?;
+    conn.publish(new_publish("qos/0", QoS::AtMostOnce, None))?;
+
+    let publ = sub0.recv_publish()?;
+    ensure!(publ.qos == QoS::AtMostOnce, "sub0 got {:?}", publ.qos);
+    ensure!(publ.packet_id.is_none(), "sub0 got packet_id for QoS0");
+    let publ = sub0.recv_publish()?;
+    ensure!(publ.qos == QoS::AtMostOnce, "sub0 got {:?}", publ.qos);
+
+    let publ = sub1.recv_publish()?;
+    ensure!(publ.qos == QoS::AtLeastOnce, "sub1 got {:?}", publ.qos);
+    sub1.puback(publ.packet_id.unwrap_or(0))?;
+    let publ = sub1.recv_publish()?;
+    ensure!(publ.qos == QoS::AtMostOnce, "sub1 got {:?}", publ.qos);
+
+    conn.disconnect()?;
+    sub0.disconnect()?;
+    sub1.disconnect()
+}
+
+fn delivery_packet_ids(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("pktid-sub", "pktid/#", QoS::AtLeastOnce)?;
+    let (mut conn, _) = broker.connect("pktid-pub")?;
+
+    for i in 1..=5 {
+        conn.publish(new_publish("pktid/a", QoS::AtLeastOnce, Some(i)))?;
+    }
+    let mut packet_ids = vec![];
+    for _ in 1..=5 {
+        let publ = sub.recv_publish()?;
+        match publ.packet_id {
+            Some(0) | None => Err("packet_id missing or ZERO".to_string())?,
+            Some(id) if packet_ids.contains(&id) => Err(format!("reused {}", id))?,
+            Some(id) => packet_ids.push(id),
+        }
+    }
+    for id in packet_ids.into_iter() {
+        sub.puback(id)?;
+    }
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn dup_not_propagated(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("dup-sub", "dup/#", QoS::AtLeastOnce)?;
+    let (mut conn, _) = broker.connect("dup-pub")?;
+
+    let mut publ = new_publish("dup/a", QoS::AtLeastOnce, Some(1));
+    publ.duplicate = true;
+    conn.publish(publ)?;
+
+    let publ = sub.recv_publish()?;
+    ensure!(!publ.duplicate, "DUP flag propagated");
+    sub.puback(publ.packet_id.unwrap_or(0))?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn unsubscribe(broker: &Broker) -> Verdict {
+    let mut conn = broker.subscriber("unsub", "unsub/a", QoS::AtMostOnce)?;
+    let filters = vec![
+        TopicFilter::from("unsub/a".to_string()),
+        TopicFilter::from("unsub/b".to_string()),
+    ];
+    let unsub = v5::UnSubscribe { packet_id: 7, properties: None, filters };
+    conn.send(v5::Packet::UnSubscribe(unsub))?;
+    match conn.recv()? {
+        v5::Packet::UnsubAck(unsuback) => {
+            use v5::UnsubAckReasonCode::{NoSubscriptionExisted, QoS0};
+
+            ensure!(unsuback.packet_id == 7, "unsuback {}", unsuback.packet_id);
+            let codes = unsuback.return_codes;
+            ensure!(
+                codes == vec![QoS0, NoSubscriptionExisted],
+                "unsuback return codes {:?}",
+                codes
+            );
+        }
+        pkt => Err(format!("expected UNSUBACK got {:?}", pkt.to_packet_type()))?,
+    }
+    conn.disconnect()
+}
+
+fn unsubscribe_stops_delivery(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("unsub-sub", "unsub2/#", QoS::AtMostOnce)?;
+    let (mut conn, _) = broker.connect("unsub-pub")?;
+
+    let filters = vec![TopicFilter::from("unsub2/#".to_string())];
+    let unsub = v5::UnSubscribe { packet_id: 2, properties: None, filters };
+    sub.send(v5::Packet::UnSubscribe(unsub))?;
+    match sub.recv()? {
+        v5::Packet::UnsubAck(_) => (),
+        pkt => Err(format!("expected UNSUBACK got {:?}", pkt.to_packet_type()))?,
+    }
+
+    conn.publish(new_publish("unsub2/a", QoS::AtMostOnce, None))?;
+    sub.recv_none()?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn dollar_topics(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("dollar-sub", "#", QoS::AtMostOnce)?;
+    let (mut conn, _) = broker.connect("dollar-pub")?;
+
+    conn.publish(new_publish("$SYS/dollar", QoS::AtMostOnce, None))?;
+    sub.recv_none()?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn retained_on_subscribe(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("retain-pub")?;
+    conn.publish(new_retained("retain/a/b", "retained", None))?;
+    thread::sleep(SETTLE);
+
+    let mut sub = broker.subscriber("retain-sub", "retain/+/b", QoS::AtMostOnce)?;
+    let publ = sub.recv_publish()?;
+    ensure!(publ.retain, "RETAIN flag not set");
+    ensure!(publ.topic_name.as_str() == "retain/a/b", "topic {:?}", publ.topic_name);
+    ensure!(
+        publ.payload.as_ref().map(|p| p.as_ref()) == Some(b"retained".as_ref()),
+        "payload mismatch"
+    );
+
+    conn.publish(new_retained("retain/a/b", "", None))?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn retained_handling_never(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("retain-never-pub")?;
+    conn.publish(new_retained("retain-never/a", "retained", None))?;
+    thread::sleep(SETTLE);
+
+    let (mut sub, _) = broker.connect("retain-never-sub")?;
+    let mut filter = new_filter("retain-never/#", QoS::AtMostOnce);
+    filter.opt =
+        SubscriptionOpt::new(RetainForwardRule::Never, false, false, QoS::AtMostOnce);
+    sub.subscribe(1, filter)?;
+    sub.recv_none()?;
+
+    conn.publish(new_retained("retain-never/a", "", None))?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn retained_remove(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("retain-rm-pub")?;
+    conn.publish(new_retained("retain-rm/a", "retained", None))?;
+    conn.publish(new_retained("retain-rm/a", "", None))?;
+    thread::sleep(SETTLE);
+
+    let mut sub = broker.subscriber("retain-rm-sub", "retain-rm/#", QoS::AtMostOnce)?;
+    sub.recv_none()?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn retain_as_published(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("rap-sub", "rap/#", QoS::AtMostOnce)?;
+    let mut filter = new_filter("rap/#", QoS::AtMostOnce);
+    filter.opt =
+        SubscriptionOpt::new(RetainForwardRule::Never, true, false, QoS::AtMostOnce);
+    let (mut rap, _) = broker.connect("rap-sub-rap")?;
+    rap.subscribe(1, filter)?;
+
+    let (mut conn, _) = broker.connect("rap-pub")?;
+    conn.publish(new_retained("rap/a", "retained", None))?;
+
+    let publ = sub.recv_publish()?;
+    ensure!(!publ.retain, "RETAIN set without retain-as-published");
+    let publ = rap.recv_publish()?;
+    ensure!(publ.retain, "RETAIN cleared with retain-as-published");
+
+    conn.publish(new_retained("rap/a", "", None))?;
+    conn.disconnect()?;
+    rap.disconnect()?;
+    sub.disconnect()
+}
+
+fn will_on_abnormal_close(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("will-sub", "will/abnormal", QoS::AtMostOnce)?;
+    let (conn, _) =
+        broker.connect_with(new_will_connect("will-pub", "will/abnormal", false))?;
+    conn.conn.shutdown(net::Shutdown::Both).ok();
+
+    let publ = sub.recv_publish()?;
+    ensure!(
+        publ.payload.as_ref().map(|p| p.as_ref()) == Some(b"gone".as_ref()),
+        "will payload mismatch"
+    );
+    sub.disconnect()
+}
+
+fn will_discarded_on_disconnect(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("will-dc-sub", "will/normal", QoS::AtMostOnce)?;
+    let (conn, _) =
+        broker.connect_with(new_will_connect("will-dc-pub", "will/normal", false))?;
+    conn.disconnect()?;
+
+    sub.recv_none()?;
+    sub.disconnect()
+}
+
+fn will_retained(broker: &Broker) -> Verdict {
+    let (conn, _) =
+        broker.connect_with(new_will_connect("will-rt-pub", "will/retained", true))?;
+    conn.conn.shutdown(net::Shutdown::Both).ok();
+    thread::sleep(SETTLE);
+
+    let mut sub = broker.subscriber("will-rt-sub", "will/retained", QoS::AtMostOnce)?;
+    let publ = sub.recv_publish()?;
+    ensure!(publ.retain, "will not retained");
+
+    let (mut conn, _) = broker.connect("will-rt-clean")?;
+    conn.publish(new_retained("will/retained", "", None))?;
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn topic_alias(broker: &Broker) -> Verdict {
+    let mut sub = broker.subscriber("alias-sub", "alias/#", QoS::AtMostOnce)?;
+    let (mut conn, connack) = broker.connect("alias-pub")?;
+    let alias_max = connack.properties.unwrap_or_default().topic_alias_max;
+    let alias_max = match alias_max {
+        Some(alias_max) => alias_max,
+        None => Err("server does not support topic alias".to_string())?,
+    };
+
+    // set the alias, and then publish with only the alias.
+    for (topic, alias) in
+        [("alias/a", 1), ("", 1), ("alias/b", alias_max), ("", alias_max)]
+    {
+        let mut publ = new_publish(topic, QoS::AtMostOnce, None);
+        publ.properties = Some(v5::PublishProperties {
+            topic_alias: Some(alias),
+            ..Default::default()
+        });
+        conn.publish(publ)?;
+    }
+
+    for want in ["alias/a", "alias/a", "alias/b", "alias/b"] {
+        let publ = sub.recv_publish()?;
+        ensure!(publ.topic_name.as_str() == want, "topic {:?}", publ.topic_name);
+    }
+    conn.disconnect()?;
+    sub.disconnect()
+}
+
+fn topic_alias_exceeds_max(broker: &Broker) -> Verdict {
+    let (mut conn, connack) = broker.connect("alias-max")?;
+    let alias_max = connack.properties.unwrap_or_default().topic_alias_max;
+    let alias = match alias_max {
+        Some(u16::MAX) => return Ok(()), // cannot exceed
+        Some(alias_max) => alias_max + 1,
+        None => 1,
+    };
+
+    let mut publ = new_publish("alias-max/a", QoS::AtMostOnce, None);
+    publ.properties =
+        Some(v5::PublishProperties { topic_alias: Some(alias), ..Default::default() });
+    conn.publish(publ)?;
+    conn.recv_disconnect(v5::DisconnReasonCode::TopicAliasInvalid)
+}
+
+fn topic_alias_per_connection(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("alias-conn")?;
+    let mut publ = new_publish("alias-conn/a", QoS::AtMostOnce, None);
+    publ.properties =
+        Some(v5::PublishProperties { topic_alias: Some(1), ..Default::default() });
+    conn.publish(publ)?;
+    conn.disconnect()?;
+    thread::sleep(SETTLE);
+
+    let (mut conn, _) = broker.connect("alias-conn")?;
+    let mut publ = new_publish("", QoS::AtMostOnce, None);
+    publ.properties =
+        Some(v5::PublishProperties { topic_alias: Some(1), ..Default::default() });
+    conn.publish(publ)?;
+    conn.recv_disconnect(v5::DisconnReasonCode::TopicAliasInvalid)
+}
+
+fn publish_qos_not_supported(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("qos2-pub")?;
+    conn.send(v5::Packet::Publish(new_publish("qos2/a", QoS::ExactlyOnce, Some(1))))?;
+    conn.recv_disconnect(v5::DisconnReasonCode::QoSNotSupported)
+}
+
+fn publish_wildcard_topic(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("wildcard-pub")?;
+    // encoder refuses wildcard topic names, patch the bytes instead.
+    let publ = new_publish("wildcard/x", QoS::AtMostOnce, None);
+    let mut data = publ.encode().unwrap().as_ref().to_vec();
+    // fixed-header(2) + topic-length(2) + "wildcard/"
+    data[13] = b'#';
+    conn.send_raw(&data)?;
+    conn.recv_disconnect(v5::DisconnReasonCode::MalformedPacket)
+}
+
+fn subscribe_shared_no_local(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("shared-no-local")?;
+    let sub = v5::Subscribe {
+        packet_id: 1,
+        properties: None,
+        filters: vec![new_filter("$share/g/shared/a", QoS::AtMostOnce)],
+    };
+    let mut data = sub.encode().unwrap().as_ref().to_vec();
+    // no-local is bit-2 of the trailing subscription options.
+    *data.last_mut().unwrap() |= 0b_0000_0100;
+    conn.send_raw(&data)?;
+    conn.recv_disconnect(v5::DisconnReasonCode::ProtocolError)
+}
+
+fn malformed_packet(broker: &Broker) -> Verdict {
+    let (mut conn, _) = broker.connect("malformed")?;
+    // PINGREQ with reserved flag bits set.
+    conn.send_raw(&[0xC1, 0x00])?;
+    conn.recv_disconnect(v5::DisconnReasonCode::MalformedPacket)
+}
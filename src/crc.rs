@@ -46,19 +46,20 @@ const CRC32TAB: [u32; 256] = [
     0xb40bbe37, 0xc30c8ea1, 0x5a05df1b, 0x2d02ef8d,
 ];
 
+#[allow(dead_code)]
 pub fn client_to_shard<K: AsRef<[u8]>>(key: &K, nshards: usize) -> Result<u32> {
     use crate::util;
 
+    let nshards: u32 = nshards.try_into()?;
     if util::is_power_of_2(nshards) {
         let key: &[u8] = key.as_ref();
         let mut crc = u32::MAX;
 
-        for k in key.into_iter() {
+        for k in key.iter() {
             let off = ((crc as u64) ^ (*k as u64)) & 0xff;
             crc = (crc >> 8) ^ CRC32TAB[off as usize];
         }
 
-        let nshards: u32 = nshards.try_into()?;
         let hash = ((!crc) >> 16) & (nshards - 1);
         Ok(hash)
     } else {
@@ -70,7 +71,7 @@ pub fn crc32<K: AsRef<[u8]>>(key: K) -> u32 {
     let key: &[u8] = key.as_ref();
     let mut crc = u32::MAX;
 
-    for k in key.into_iter() {
+    for k in key.iter() {
         let off = ((crc as u64) ^ (*k as u64)) & 0xff;
         crc = (crc >> 8) ^ CRC32TAB[off as usize];
     }
@@ -1,3 +1,6 @@
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
+
 use std::{self, fmt, result};
 
 use crate::Result;
@@ -296,6 +299,7 @@ pub enum ErrorKind {
     // network error
     Disconnected,
     SlowClient,
+    Timeout,
     // thread / ipc error
     IPCFail,
     RxClosed,
@@ -322,6 +326,7 @@ impl fmt::Display for ErrorKind {
             // network error
             Disconnected => write!(f, "Disconnected"),
             SlowClient => write!(f, "SlowClient"),
+            Timeout => write!(f, "Timeout"),
             // thread / ipc error
             IPCFail => write!(f, "IPCFail"),
             RxClosed => write!(f, "RxClosed"),
@@ -335,6 +340,7 @@ impl fmt::Display for ErrorKind {
 
 /// ReasonCode defined by `MQTT-spec`, each variant defines error value.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum ReasonCode {
     Success = 0x00, // NormalDisconnect, QoS0
@@ -0,0 +1,104 @@
+//! Module implement fixtures shared by unit tests across modules.
+
+use std::{fs, net, path};
+
+use crate::{v5, ClientID, MqttProtocol, PacketID, TopicFilter, TopicName};
+
+/// Create a new, uniquely named, directory under the system's temporary directory.
+pub fn temp_dir(name: &str) -> path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Return client-id for `id`.
+pub fn client_id(id: &str) -> ClientID {
+    ClientID(id.to_string())
+}
+
+/// Return a PUBLISH packet for `topic`, with topic as the payload.
+pub fn new_publish(
+    topic: &str,
+    qos: v5::QoS,
+    packet_id: Option<PacketID>,
+) -> v5::Publish {
+    v5::Publish {
+        retain: false,
+        qos,
+        duplicate: false,
+        topic_name: TopicName::from(topic.to_string()),
+        packet_id,
+        properties: None,
+        payload: Some(topic.as_bytes().to_vec().into()),
+    }
+}
+
+/// Return a retained PUBLISH packet for `topic`, with optional message-expiry.
+pub fn new_retained(topic: &str, payload: &str, expiry: Option<u32>) -> v5::Publish {
+    let properties = expiry.map(|secs| v5::PublishProperties {
+        message_expiry_interval: Some(secs),
+        ..v5::PublishProperties::default()
+    });
+    v5::Publish {
+        retain: true,
+        qos: v5::QoS::AtMostOnce,
+        duplicate: false,
+        topic_name: TopicName::from(topic.to_string()),
+        packet_id: None,
+        properties,
+        payload: Some(payload.as_bytes().to_vec().into()),
+    }
+}
+
+/// Return a subscription on `filter` for client `id`, tests can override the
+/// subscription options using struct-update syntax.
+pub fn new_subscription(id: &str, filter: &str) -> v5::Subscription {
+    v5::Subscription {
+        topic_filter: TopicFilter::from(filter.to_string()),
+        client_id: client_id(id),
+        shard_id: 0,
+        subscription_id: Some(10),
+        qos: v5::QoS::AtLeastOnce,
+        no_local: true,
+        retain_as_published: false,
+        retain_forward_rule: v5::RetainForwardRule::OnNewSubscribe,
+    }
+}
+
+/// Return a free TCP port on localhost.
+pub fn free_port() -> u16 {
+    // there is a small window for another process to grab the port.
+    let lis = net::TcpListener::bind("127.0.0.1:0").unwrap();
+    lis.local_addr().unwrap().port()
+}
+
+/// Return a CONNECT packet for `client_id`, with clean-start, or with a persistent
+/// session when `clean_start` is false.
+pub fn new_connect(client_id: &str, clean_start: bool) -> v5::Connect {
+    let (flags, properties) = match clean_start {
+        true => (v5::ConnectFlags::new(&[v5::ConnectFlags::CLEAN_START]), None),
+        // persistent session.
+        false => {
+            let props = v5::ConnectProperties {
+                session_expiry_interval: Some(3600),
+                ..v5::ConnectProperties::default()
+            };
+            (v5::ConnectFlags::new(&[]), Some(props))
+        }
+    };
+    v5::Connect {
+        protocol_name: "MQTT".to_string(),
+        protocol_version: MqttProtocol::V5,
+        flags,
+        keep_alive: 0,
+        properties,
+        payload: v5::ConnectPayload {
+            client_id: ClientID(client_id.to_string()),
+            will_properties: None,
+            will_topic: None,
+            will_payload: None,
+            user_name: None,
+            password: None,
+        },
+    }
+}
@@ -150,6 +150,10 @@ impl Flusher {
                 socket: args.socket,
                 err: args.err,
             })??,
+            Inner::Tx(tx) => tx.request(Request::FlushConnection {
+                socket: args.socket,
+                err: args.err,
+            })??,
             _ => unreachable!(),
         };
 
@@ -0,0 +1,320 @@
+//! Module implement fuzzing entry points for this package.
+//!
+//! Entry points are plain functions taking the fuzzer's input as byte-slice, they
+//! can be called from coverage-guided fuzzers like `cargo-fuzz`, refer to `fuzz/`
+//! directory, or from unit-tests with random input. A panic within an entry point
+//! is a bug.
+//!
+//! * [decode], decode bytes as packet of given type and check that the decoded
+//!   packet round-trips via encode/decode.
+//! * [decode_packet], same as [decode], but packet type is detected from the fixed
+//!   header.
+//! * [session], feed a sequence of arbitrary packets, or raw bytes, into a
+//!   [crate::Session] hosted by an in-memory [crate::Shard].
+//!
+//! Additionally [Fuzzy] trait can be implemented by packets and fields to generate
+//! valid and invalid values.
+
+use arbitrary::{Arbitrary, Unstructured};
+use rand::rngs::StdRng;
+
+use std::{collections::VecDeque, fmt, io::Write, net, sync::mpsc, sync::Arc};
+
+use crate::packet::{MQTTRead, MQTTWrite};
+use crate::v5::{self, PacketType};
+use crate::{
+    Admission, ClientID, Cluster, Config, ConfigListener, Error, NodeConns, Packetize,
+};
+use crate::{PktRx, PktTx, QueueStatus, Result, Shard, Socket, Stream};
+
+/// Maximum number of packets fed into a session, per input.
+pub const MAX_SESSION_PACKETS: usize = 64;
+
+/// Context shared by [Fuzzy] implementations, while generating values.
+#[derive(Clone, Debug)]
+pub struct Context {
+    /// Maximum QoS supported by the server.
+    pub maximum_qos: v5::QoS,
+}
+
+impl Default for Context {
+    fn default() -> Context {
+        Context {
+            maximum_qos: v5::QoS::try_from(Config::DEF_MQTT_MAX_QOS).unwrap(),
+        }
+    }
+}
+
+/// Trait implemented by packets and fields, to generate values for fuzzing.
+pub trait Fuzzy: Sized {
+    /// Name of the type, for logging purpose.
+    fn type_name() -> &'static str;
+
+    /// Return a valid value.
+    fn valid_value(rng: &mut StdRng, ctx: &mut Context) -> Self;
+
+    /// Return an invalid value, along with the error expected while validating it.
+    fn invalid_value(rng: &mut StdRng, ctx: &mut Context) -> Option<(Self, Error)>;
+
+    /// Return a valid value in serialized form.
+    fn valid_binary(rng: &mut StdRng, ctx: &mut Context) -> Vec<u8>;
+
+    /// Return an invalid value in serialized form, along with the error expected
+    /// while decoding it.
+    fn invalid_binary(rng: &mut StdRng, ctx: &mut Context) -> Option<(Vec<u8>, Error)>;
+
+    /// Validate value within the `ctx`.
+    fn validate(&self, ctx: &mut Context) -> Result<()>;
+}
+
+/// Decode `data` as packet of type `pkt_type`, if successful, check that the packet
+/// encodes and decodes back to the same value.
+pub fn decode(pkt_type: PacketType, data: &[u8]) {
+    match pkt_type {
+        PacketType::Connect => round_trip::<v5::Connect>(data),
+        PacketType::ConnAck => round_trip::<v5::ConnAck>(data),
+        PacketType::Publish => round_trip::<v5::Publish>(data),
+        PacketType::PubAck => round_trip::<v5::Pub>(data),
+        PacketType::PubRec => round_trip::<v5::Pub>(data),
+        PacketType::PubRel => round_trip::<v5::Pub>(data),
+        PacketType::PubComp => round_trip::<v5::Pub>(data),
+        PacketType::Subscribe => round_trip::<v5::Subscribe>(data),
+        PacketType::SubAck => round_trip::<v5::SubAck>(data),
+        PacketType::UnSubscribe => round_trip::<v5::UnSubscribe>(data),
+        PacketType::UnsubAck => round_trip::<v5::UnsubAck>(data),
+        PacketType::PingReq => round_trip::<v5::PingReq>(data),
+        PacketType::PingResp => round_trip::<v5::PingResp>(data),
+        PacketType::Disconnect => round_trip::<v5::Disconnect>(data),
+        PacketType::Auth => round_trip::<v5::Auth>(data),
+    }
+}
+
+/// Decode `data` as [v5::Packet], if successful, check that the packet encodes and
+/// decodes back to the same value.
+pub fn decode_packet(data: &[u8]) {
+    round_trip::<v5::Packet>(data)
+}
+
+/// Feed `data` to a session hosted by in-memory shard. If the first byte is odd,
+/// rest of `data` is treated as raw bytes, refer [session_raw], otherwise as
+/// arbitrary packets, refer [session_packets].
+pub fn session(data: &[u8]) {
+    match data.split_first() {
+        Some((byte, data)) if byte & 1 == 1 => session_raw(data),
+        Some((_, data)) => session_packets(data),
+        None => (),
+    }
+}
+
+/// Interpret `data` as a CONNECT packet followed by a sequence of packets, upto
+/// [MAX_SESSION_PACKETS], and feed them to a session hosted by in-memory shard.
+/// Packets are serialized and de-serialized before feeding them to the session,
+/// like it happens with the socket.
+pub fn session_packets(data: &[u8]) {
+    let mut uns = Unstructured::new(data);
+
+    let connect = match v5::Connect::arbitrary(&mut uns).map(v5::Packet::Connect) {
+        Ok(pkt) => match wire(pkt) {
+            Some(v5::Packet::Connect(connect)) => connect,
+            _ => return,
+        },
+        Err(_) => return,
+    };
+    let mut pkts = vec![];
+    while pkts.len() < MAX_SESSION_PACKETS {
+        match arbitrary_packet(&mut uns) {
+            Ok(pkt) => pkts.extend(wire(pkt)),
+            Err(_) => break,
+        }
+    }
+
+    let config = Config::default();
+    let (app_tx, _app_rx) = mpsc::sync_channel(1024);
+    // retained messages are queued with the cluster, but never committed.
+    let (cluster, _cluster_rx) = Cluster::new_in_memory(config.clone()).unwrap();
+    let (mut shard, msg_rx) =
+        Shard::new_in_memory(config.clone(), cluster, app_tx).unwrap();
+    let (mut upstream, downstream) = shard.add_session_in_memory(addr(), &connect);
+
+    for pkt in pkts.into_iter() {
+        let mut status = upstream.try_sends("fuzzy", vec![pkt]);
+        status.take_values();
+        if !shard.run_in_memory(&msg_rx).is_empty() {
+            break;
+        }
+        // client shall drain the packets sent by the session.
+        downstream.try_recvs("fuzzy");
+        if let QueueStatus::Disconnected(_) = status {
+            break;
+        }
+    }
+    shard.run_in_memory(&msg_rx);
+}
+
+/// Interpret `data` as raw bytes sent by a client. CONNECT packet is read like the
+/// handshake does, and the bytes that follow are read by a [Socket], like it happens
+/// with a connected client, into the session hosted by in-memory shard.
+pub fn session_raw(data: &[u8]) {
+    let config = Config::default();
+
+    let (connect, data) = match read_packet(data, config.mqtt_max_packet_size()) {
+        Some((v5::Packet::Connect(connect), data)) => (connect, data),
+        _ => return,
+    };
+
+    let (app_tx, _app_rx) = mpsc::sync_channel(1024);
+    // retained messages are queued with the cluster, but never committed.
+    let (cluster, _cluster_rx) = Cluster::new_in_memory(config.clone()).unwrap();
+    let (mut shard, msg_rx) =
+        Shard::new_in_memory(config.clone(), cluster, app_tx).unwrap();
+    let (upstream, downstream) = shard.add_session_in_memory(addr(), &connect);
+
+    let client_id = ClientID::from_connect(&connect.payload.client_id);
+    let (mut socket, mut client) = socket_in_memory(&config, client_id, upstream);
+    // write as much as the socket buffer can hold, the rest is discarded.
+    client.write_all(data).ok();
+
+    for _ in 0..MAX_SESSION_PACKETS {
+        // MalformedPacket and ProtocolError, shall close the socket.
+        let status = match socket.read_packets("fuzzy", &config) {
+            Ok(status) => status,
+            Err(_) => break,
+        };
+        if !shard.run_in_memory(&msg_rx).is_empty() {
+            break;
+        }
+        // client shall drain the packets sent by the session.
+        downstream.try_recvs("fuzzy");
+        match status {
+            QueueStatus::Ok(_) => (),
+            QueueStatus::Block(_) if socket.rd.packets.is_empty() => break,
+            QueueStatus::Block(_) => (),
+            QueueStatus::Disconnected(_) => break,
+        }
+    }
+    shard.run_in_memory(&msg_rx);
+}
+
+fn round_trip<T>(data: &[u8])
+where
+    T: Packetize + PartialEq + fmt::Debug,
+{
+    let type_name = std::any::type_name::<T>();
+
+    let (val, n) = match T::decode(data) {
+        Ok(res) => res,
+        Err(_) => return,
+    };
+    assert!(n <= data.len(), "{} decoded {} bytes from {}", type_name, n, data.len());
+
+    let out = match val.encode() {
+        Ok(out) => out,
+        Err(err) => panic!("{} decoded but fail to encode {:?}: {}", type_name, val, err),
+    };
+    let (res, m) = match T::decode(out.as_ref()) {
+        Ok(res) => res,
+        Err(err) => panic!("{} encoded but fail to decode {:?}: {}", type_name, val, err),
+    };
+    assert_eq!(val, res, "{} round trip", type_name);
+    assert_eq!(m, out.as_ref().len(), "{} round trip length", type_name);
+}
+
+// Serialize and de-serialize `pkt`, return None if either of them fail.
+fn wire(pkt: v5::Packet) -> Option<v5::Packet> {
+    let out = pkt.encode().ok()?;
+    let (pkt, _) = v5::Packet::decode(out.as_ref()).ok()?;
+    Some(pkt)
+}
+
+fn addr() -> net::SocketAddr {
+    "127.0.0.1:1883".parse().unwrap()
+}
+
+// Read the first packet from `data`, return the packet and the bytes that follow.
+fn read_packet(mut data: &[u8], max_size: u32) -> Option<(v5::Packet, &[u8])> {
+    let mut packetr = MQTTRead::new(max_size);
+    loop {
+        packetr = match packetr.read(&mut data).ok()? {
+            (mut packetr @ MQTTRead::Fin { .. }, _) => {
+                break Some((packetr.parse().ok()?, data));
+            }
+            (packetr, _) => packetr,
+        }
+    }
+}
+
+// Return a socket, over one end of unix stream-pair, sending packets to `session_tx`,
+// along with the other end of the pair for client.
+fn socket_in_memory(
+    config: &Config,
+    client_id: ClientID,
+    session_tx: PktTx,
+) -> (Socket, mio::net::UnixStream) {
+    let (conn, client) = mio::net::UnixStream::pair().unwrap();
+    let node = Arc::new(NodeConns::default());
+    let guard = Admission::from_config(config, &ConfigListener::default(), node)
+        .unwrap()
+        .admit()
+        .unwrap();
+    let (_miot_tx, miot_rx): (PktTx, PktRx) = {
+        let size = config.mqtt_pkt_batch_size() as usize;
+        let poll = mio::Poll::new().unwrap();
+        let waker = Arc::new(mio::Waker::new(poll.registry(), mio::Token(0)).unwrap());
+        crate::socket::pkt_channel(0, size, waker)
+    };
+
+    let rd = crate::socket::Source {
+        pr: MQTTRead::new(config.mqtt_max_packet_size()),
+        timeout: None,
+        session_tx,
+        packets: VecDeque::default(),
+    };
+    let wt = crate::socket::Sink {
+        pw: MQTTWrite::new(&[], config.mqtt_max_packet_size()),
+        timeout: None,
+        miot_rx,
+        packets: VecDeque::default(),
+    };
+    let socket = Socket {
+        client_id,
+        conn: Stream::from(conn),
+        addr: addr(),
+        token: mio::Token(0),
+        rd,
+        wt,
+        guard,
+    };
+
+    (socket, client)
+}
+
+fn arbitrary_packet(uns: &mut Unstructured) -> arbitrary::Result<v5::Packet> {
+    let pkt = match uns.arbitrary::<u8>()? % 12 {
+        0 => v5::Packet::Connect(uns.arbitrary()?),
+        1 | 2 => v5::Packet::Publish(uns.arbitrary()?),
+        3 => {
+            let pkt: v5::Pub = uns.arbitrary()?;
+            match pkt.packet_type {
+                PacketType::PubAck => v5::Packet::PubAck(pkt),
+                PacketType::PubRec => v5::Packet::PubRec(pkt),
+                PacketType::PubRel => v5::Packet::PubRel(pkt),
+                PacketType::PubComp => v5::Packet::PubComp(pkt),
+                _ => unreachable!(),
+            }
+        }
+        4 | 5 => v5::Packet::Subscribe(uns.arbitrary()?),
+        6 => v5::Packet::SubAck(uns.arbitrary()?),
+        7 => v5::Packet::UnSubscribe(uns.arbitrary()?),
+        8 => v5::Packet::UnsubAck(uns.arbitrary()?),
+        9 => v5::Packet::PingReq,
+        10 => v5::Packet::Disconnect(uns.arbitrary()?),
+        11 => v5::Packet::Auth(uns.arbitrary()?),
+        _ => unreachable!(),
+    };
+
+    Ok(pkt)
+}
+
+#[cfg(test)]
+#[path = "fuzzy_test.rs"]
+mod fuzzy_test;
@@ -0,0 +1,98 @@
+use rand::{prelude::random, rngs::StdRng, Rng, SeedableRng};
+
+use super::*;
+
+#[test]
+fn test_fuzzy_decode() {
+    let seed: u64 = random();
+    println!("test_fuzzy_decode seed:{}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    for _ in 0..10_000 {
+        let bytes: Vec<u8> = (0..1024).map(|_| rng.gen()).collect();
+        let mut uns = Unstructured::new(&bytes);
+        let mut data = match arbitrary_packet(&mut uns).map(|pkt| pkt.encode()) {
+            Ok(Ok(out)) => out.as_ref().to_vec(),
+            _ => continue,
+        };
+        // mutate few bytes, keeping the fixed header intact most of the time.
+        for _ in 0..(rng.gen::<usize>() % 3) {
+            let off = rng.gen::<usize>() % data.len();
+            data[off] = rng.gen();
+        }
+
+        decode_packet(&data);
+        if let Ok((fh, _)) = v5::FixedHeader::decode(&data) {
+            decode(fh.unwrap().0, &data)
+        }
+    }
+}
+
+#[test]
+fn test_encoded_len() {
+    let seed: u64 = random();
+    println!("test_encoded_len seed:{}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    for _ in 0..10_000 {
+        let bytes: Vec<u8> = (0..1024).map(|_| rng.gen()).collect();
+        let mut uns = Unstructured::new(&bytes);
+        let pkt = match arbitrary_packet(&mut uns) {
+            Ok(pkt) => pkt,
+            _ => continue,
+        };
+        if let Ok(out) = pkt.encode() {
+            assert_eq!(pkt.encoded_len().unwrap(), out.as_ref().len(), "{:?}", pkt);
+            let (val, _) = v5::Packet::decode(out.as_ref()).unwrap();
+            assert_eq!(val.encode().unwrap().as_ref(), out.as_ref());
+        }
+    }
+}
+
+#[test]
+fn test_fuzzy_session() {
+    let seed: u64 = random();
+    println!("test_fuzzy_session seed:{}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    for _ in 0..200 {
+        let bytes: Vec<u8> =
+            (0..(rng.gen::<usize>() % 8192)).map(|_| rng.gen()).collect();
+        session(&bytes);
+    }
+}
+
+#[test]
+fn test_fuzzy_session_raw() {
+    let seed: u64 = random();
+    println!("test_fuzzy_session_raw seed:{}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut n = 0;
+    while n < 200 {
+        let bytes: Vec<u8> = (0..1024).map(|_| rng.gen()).collect();
+        let mut uns = Unstructured::new(&bytes);
+        let mut data = match v5::Connect::arbitrary(&mut uns).map(|pkt| pkt.encode()) {
+            Ok(Ok(out)) => out.as_ref().to_vec(),
+            _ => continue,
+        };
+        let start = data.len();
+        for _ in 0..(rng.gen::<usize>() % 16) {
+            let bytes: Vec<u8> = (0..1024).map(|_| rng.gen()).collect();
+            let mut uns = Unstructured::new(&bytes);
+            if let Ok(Ok(out)) = arbitrary_packet(&mut uns).map(|pkt| pkt.encode()) {
+                data.extend_from_slice(out.as_ref());
+            }
+        }
+        // mutate few bytes, following the CONNECT packet.
+        for _ in 0..(rng.gen::<usize>() % 3) {
+            if data.len() > start {
+                let off = start + (rng.gen::<usize>() % (data.len() - start));
+                data[off] = rng.gen();
+            }
+        }
+
+        session_raw(&data);
+        n += 1;
+    }
+}
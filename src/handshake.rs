@@ -1,108 +1,309 @@
 use log::{error, info};
 
-use std::{net, thread, time};
+use std::{mem, net, time};
 
-use crate::packet::{send_connack, MQTTRead};
-use crate::thread::{Rx, Threadable};
-use crate::{v5, Cluster, Config, SLEEP_10MS};
+use crate::packet::{MQTTRead, MQTTWrite};
+use crate::{util, v5, Cluster, Config, ConfigListener, ConnGuard, Packetize};
 use crate::{Error, ErrorKind, ReasonCode};
+use crate::{PeerCred, Stream};
+use crate::{ProxyProtocol, ProxyRead};
 
 /// Type handles incoming connection.
 ///
-/// Complete the handshake by sending the appropriate CONNACK packet. This type is
-/// threadable and spawned for every incoming connection, once the handshake is
-/// completed, connection is handed over to the [Cluster].
+/// Complete the handshake by sending the appropriate CONNACK packet. Handshakes are
+/// multiplexed on the [crate::Listener]'s poll loop, the listener calls
+/// [Handshake::read] and [Handshake::write] every time the connection is ready, and
+/// [Handshake::expire] once the deadline is exceeded. When the handshake is completed
+/// it calls [Handshake::finish] to hand over the connection to the [Cluster]. None of
+/// these calls block the poll loop.
 pub struct Handshake {
     pub prefix: String,
-    pub conn: Option<mio::net::TcpStream>,
+    pub conn: Stream,
     pub addr: net::SocketAddr,
+    pub peer_cred: Option<PeerCred>,
     pub config: Config,
-    pub cluster: Cluster,
+    pub listener: ConfigListener,
+    pub guard: ConnGuard,
+    /// Source IP is yet to be admitted, after reading the PROXY header.
+    pub ip_pending: bool,
+    /// Handshake should complete before this deadline, computed from
+    /// [Config::connect_timeout]. While sending a failure CONNACK, it is pushed by
+    /// [Handshake::CONNACK_TIMEOUT].
+    pub deadline: time::Instant,
+    proxied: bool,
+    state: State,
 }
 
-impl Threadable for Handshake {
-    type Req = ();
-    type Resp = ();
+pub struct HandshakeArgs {
+    pub prefix: String,
+    pub conn: Stream,
+    pub addr: net::SocketAddr,
+    pub peer_cred: Option<PeerCred>,
+    pub config: Config,
+    pub listener: ConfigListener,
+    pub guard: ConnGuard,
+    /// PROXY protocol expected from this peer, refer [crate::Admission::admit_proxy].
+    pub proxy_protocol: ProxyProtocol,
+    pub ip_pending: bool,
+}
 
-    fn main_loop(mut self, _rx: Rx<(), ()>) -> Self {
-        use crate::cluster::AddConnectionArgs;
+enum State {
+    Proxy(ProxyRead),
+    Connect(MQTTRead),
+    // Handshake succeeded, with CONNECT packet.
+    Done(Box<v5::Connect>),
+    // Handshake failed, CONNACK shall be sent with the reason-code, if `connack` is
+    // true, and connection is closed.
+    Fail { code: ReasonCode, connack: bool },
+    // Handshake failed, writing the CONNACK with failure reason-code.
+    Ack(MQTTWrite),
+}
 
+impl Handshake {
+    /// Time allowed to write a failure CONNACK, before dropping the connection.
+    pub const CONNACK_TIMEOUT: time::Duration = time::Duration::from_secs(1);
+
+    pub fn new(args: HandshakeArgs) -> Handshake {
         let now = time::Instant::now();
-        info!("{} new connection {:?} at {:?}", self.prefix, self.addr, now);
+        info!("{} new connection {:?} at {:?}", args.prefix, args.addr, now);
 
+        let connect_timeout = args.config.connect_timeout();
+        let deadline = now + time::Duration::from_secs(connect_timeout as u64);
+        let state = match args.proxy_protocol {
+            ProxyProtocol::Disabled => {
+                State::Connect(MQTTRead::new(args.config.mqtt_max_packet_size()))
+            }
+            ProxyProtocol::Optional => State::Proxy(ProxyRead::new(false)),
+            ProxyProtocol::Required => State::Proxy(ProxyRead::new(true)),
+        };
+
+        Handshake {
+            prefix: args.prefix,
+            conn: args.conn,
+            addr: args.addr,
+            peer_cred: args.peer_cred,
+            config: args.config,
+            listener: args.listener,
+            guard: args.guard,
+            ip_pending: args.ip_pending,
+            deadline,
+            proxied: false,
+            state,
+        }
+    }
+
+    /// Read from connection until it would block. Return true if reading is complete,
+    /// successfully or otherwise, and [Handshake::write] is due.
+    pub fn read(&mut self) -> bool {
         let max_size = self.config.mqtt_max_packet_size();
-        let connect_timeout = self.config.connect_timeout();
-
-        let mut packetr = MQTTRead::new(max_size);
-        let (conn, addr) = (self.conn.take().unwrap(), self.addr);
-        let timeout = now + time::Duration::from_secs(connect_timeout as u64);
-        let prefix = self.prefix.clone();
-
-        let (code, connack, pkt_connect) = loop {
-            packetr = match packetr.read(&conn) {
-                Ok((val, _would_block)) => val,
-                Err(err) if err.kind() == ErrorKind::MalformedPacket => {
-                    error!("{}, fail read, error {}", prefix, err);
-                    break (err.code(), true, None);
-                }
-                Err(err) if err.kind() == ErrorKind::ProtocolError => {
-                    error!("{}, fail read, error {}", prefix, err);
-                    break (err.code(), true, None);
-                }
-                Err(err) => unreachable!("unexpected error {}", err),
-            };
-            match &packetr {
-                MQTTRead::Init { .. } if time::Instant::now() < timeout => {
-                    thread::sleep(SLEEP_10MS);
-                }
-                MQTTRead::Header { .. } if time::Instant::now() < timeout => {
-                    thread::sleep(SLEEP_10MS);
-                }
-                MQTTRead::Remain { .. } if time::Instant::now() < timeout => {
-                    thread::sleep(SLEEP_10MS);
-                }
-                MQTTRead::Fin { .. } => match packetr.parse() {
-                    Ok(v5::Packet::Connect(val)) => {
-                        break (ReasonCode::Success, false, Some(val))
+
+        loop {
+            self.state = match mem::replace(&mut self.state, State::fail()) {
+                State::Proxy(proxyr) => match proxyr.read(&self.conn) {
+                    Ok((ProxyRead::Fin { header }, _)) => {
+                        if let Some(source) = header.source {
+                            self.set_source_addr(source);
+                        }
+                        State::Connect(MQTTRead::new(max_size))
+                    }
+                    Ok((ProxyRead::Skip { byte1 }, _)) => {
+                        State::Connect(MQTTRead::from_byte1(max_size, byte1))
                     }
-                    Ok(pkt) => {
-                        let pt = pkt.to_packet_type();
-                        error!("{}, unexpect {:?} on new connection", prefix, pt);
-                        break (ReasonCode::ProtocolError, true, None);
+                    Ok((proxyr, true)) => {
+                        self.state = State::Proxy(proxyr);
+                        break false;
                     }
+                    Ok((proxyr, false)) => State::Proxy(proxyr),
+                    Err(err) => {
+                        // drop the connection without CONNACK.
+                        error!(
+                            "{}, fail reading PROXY header, error {}",
+                            self.prefix, err
+                        );
+                        State::fail()
+                    }
+                },
+                State::Connect(packetr) => match packetr.read(&self.conn) {
+                    Ok((packetr @ MQTTRead::Fin { .. }, _)) => self.parse(packetr),
+                    Ok((packetr, true)) => {
+                        self.state = State::Connect(packetr);
+                        break false;
+                    }
+                    Ok((packetr, false)) => State::Connect(packetr),
                     Err(err) if err.kind() == ErrorKind::MalformedPacket => {
-                        error!("{}, fail parse, error {}", prefix, err);
-                        break (err.code(), true, None);
+                        error!("{}, fail read, error {}", self.prefix, err);
+                        State::Fail { code: err.code(), connack: true }
                     }
                     Err(err) if err.kind() == ErrorKind::ProtocolError => {
-                        error!("{}, fail parse, error {}", prefix, err);
-                        break (err.code(), true, None);
+                        error!("{}, fail read, error {}", self.prefix, err);
+                        State::Fail { code: err.code(), connack: true }
+                    }
+                    Err(err) => {
+                        error!("{}, fail read, error {}", self.prefix, err);
+                        State::fail()
                     }
-                    Err(err) => unreachable!("unexpected error {}", err),
                 },
-                _ => {
-                    error!("{}, fail after {:?}", prefix, time::Instant::now());
-                    break (ReasonCode::UnspecifiedError, true, None);
+                state @ (State::Done(_) | State::Fail { .. } | State::Ack(_)) => {
+                    self.state = state;
+                    break true;
                 }
-            };
-        };
+            }
+        }
+    }
+
+    /// Write failure CONNACK, if any, until it would block. Return true if handshake
+    /// is complete and [Handshake::finish] is due. Shall be called after
+    /// [Handshake::read] returns true.
+    pub fn write(&mut self) -> bool {
+        let max_size = self.config.mqtt_max_packet_size();
+
+        loop {
+            self.state = match mem::replace(&mut self.state, State::fail()) {
+                State::Fail { code, connack: true } => {
+                    let code = v5::ConnackReasonCode::try_from(code as u8).unwrap();
+                    let cack = v5::ConnAck::from_reason_code(code);
+                    let packetw =
+                        MQTTWrite::new(cack.encode().unwrap().as_ref(), max_size);
+                    self.deadline = time::Instant::now() + Self::CONNACK_TIMEOUT;
+                    State::Ack(packetw)
+                }
+                State::Ack(packetw) => match packetw.write(&self.conn) {
+                    Ok((MQTTWrite::Fin { .. }, _)) => State::fail(),
+                    Ok((packetw, true)) => {
+                        self.state = State::Ack(packetw);
+                        break false;
+                    }
+                    Ok((packetw, false)) => State::Ack(packetw),
+                    Err(err) => {
+                        error!("{} problem writing connack packet {}", self.prefix, err);
+                        State::fail()
+                    }
+                },
+                state @ (State::Proxy(_) | State::Connect(_)) => {
+                    self.state = state;
+                    break false;
+                }
+                state @ (State::Done(_) | State::Fail { connack: false, .. }) => {
+                    self.state = state;
+                    break true;
+                }
+            }
+        }
+    }
+
+    /// Fail the handshake after its deadline. While reading CONNECT, a CONNACK is due
+    /// and [Handshake::write] shall be called, otherwise the connection is dropped.
+    pub fn expire(&mut self) {
+        self.state = match mem::replace(&mut self.state, State::fail()) {
+            State::Proxy(_) => {
+                error!("{}, PROXY header timeout {:?}", self.prefix, self.deadline);
+                State::fail()
+            }
+            State::Connect(_) => {
+                error!("{}, fail after {:?}", self.prefix, self.deadline);
+                State::Fail { code: ReasonCode::UnspecifiedError, connack: true }
+            }
+            State::Ack(_) => {
+                error!("{}, fail writing connack after {:?}", self.prefix, self.deadline);
+                State::fail()
+            }
+            state @ (State::Done(_) | State::Fail { .. }) => state,
+        }
+    }
+
+    /// Return true if handshake is still reading the PROXY header.
+    pub fn is_proxy_pending(&self) -> bool {
+        matches!(self.state, State::Proxy(_))
+    }
+
+    /// Return true if [Handshake::addr] is the client address carried in PROXY header.
+    pub fn is_proxied(&self) -> bool {
+        self.proxied
+    }
+
+    /// Return true if handshake is waiting to write the failure CONNACK.
+    pub fn is_write_pending(&self) -> bool {
+        matches!(self.state, State::Ack(_))
+    }
+
+    /// Return true if handshake did not complete before its deadline.
+    pub fn is_expired(&self, now: time::Instant) -> bool {
+        match &self.state {
+            State::Proxy(_) | State::Connect(_) | State::Ack(_) => self.deadline < now,
+            State::Done(_) | State::Fail { .. } => false,
+        }
+    }
 
-        if connack {
-            // if error, connect-ack shall be sent right here and ignored.
-            let code = v5::ConnackReasonCode::try_from(code as u8).unwrap();
-            send_connack(&prefix, code, &conn, timeout, max_size).ok();
-        } else if let Some(pkt_connect) = pkt_connect {
-            let args = AddConnectionArgs { conn, addr, pkt: pkt_connect };
-            err!(
-                IPCFail,
-                try: self.cluster.add_connection(args),
-                "cluster.add_connection"
-            )
-            .ok();
-        } else {
-            unreachable!()
+    /// Finish the handshake, either hand over the connection to `cluster` or close
+    /// the connection. Connection is posted to the cluster thread, without waiting.
+    pub fn finish(self, cluster: &Cluster) {
+        use crate::cluster::AddConnectionArgs;
+
+        match self.state {
+            State::Done(pkt_connect) => {
+                let args = AddConnectionArgs {
+                    conn: self.conn,
+                    addr: self.addr,
+                    peer_cred: self.peer_cred,
+                    pkt: *pkt_connect,
+                    listener: self.listener,
+                    guard: self.guard,
+                };
+                err!(
+                    IPCFail,
+                    try: cluster.add_connection(args),
+                    "cluster.add_connection"
+                )
+                .ok();
+            }
+            State::Fail { .. } | State::Ack(_) => (),
+            State::Proxy(_) | State::Connect(_) => {
+                error!("{}, fail after {:?}", self.prefix, self.deadline);
+            }
         }
+    }
+}
+
+impl Handshake {
+    fn parse(&self, mut packetr: MQTTRead) -> State {
+        match packetr.parse() {
+            Ok(v5::Packet::Connect(val)) => State::Done(Box::new(val)),
+            Ok(pkt) => {
+                let pt = pkt.to_packet_type();
+                error!("{}, unexpect {:?} on new connection", self.prefix, pt);
+                State::Fail { code: ReasonCode::ProtocolError, connack: true }
+            }
+            Err(err) if err.kind() == ErrorKind::MalformedPacket => {
+                error!("{}, fail parse, error {}", self.prefix, err);
+                State::Fail { code: err.code(), connack: true }
+            }
+            Err(err) if err.kind() == ErrorKind::ProtocolError => {
+                error!("{}, fail parse, error {}", self.prefix, err);
+                State::Fail { code: err.code(), connack: true }
+            }
+            Err(err) => {
+                // handshake runs on the listener thread, never panic on bad input.
+                error!("{}, fail parse, error {}", self.prefix, err);
+                State::Fail { code: ReasonCode::MalformedPacket, connack: true }
+            }
+        }
+    }
+
+    // Switch to the client address carried in PROXY header.
+    fn set_source_addr(&mut self, source: net::SocketAddr) {
+        let source = util::canonical_addr(source);
+        info!("{} proxied connection from {}", self.prefix, source);
+        let addr = self.addr.to_string();
+        if let Some(prefix) = self.prefix.strip_suffix(&addr) {
+            self.prefix = format!("{}{}", prefix, source);
+        }
+        self.addr = source;
+        self.proxied = true;
+    }
+}
 
-        self
+impl State {
+    fn fail() -> State {
+        State::Fail { code: ReasonCode::UnspecifiedError, connack: false }
     }
 }
@@ -31,7 +31,7 @@ impl KeepAlive {
             Some(interval) => {
                 let now = time::Duration::from_secs(interval as u64);
                 let inst = self.alive_at + now;
-                if inst < time::Instant::now() {
+                if time::Instant::now() < inst {
                     Ok(())
                 } else {
                     err!(
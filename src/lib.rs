@@ -1,8 +1,6 @@
 //! Package implement MQTT protocol as multi-threaded library.
 
 // TODO: review all err!() calls and tally them with MQTT spec.
-// TODO: validate()? calls must be wired into all Packetize::{encode, decode}
-//       implementation
 
 #![feature(backtrace)]
 #![feature(error_iter)]
@@ -11,50 +9,80 @@
 mod error;
 #[macro_use]
 pub mod v5;
+pub mod client;
+#[cfg(any(feature = "fuzzy", test))]
+pub mod fuzzy;
 pub mod util;
 
-// mod chash; TODO
+mod admission;
+mod chash;
 mod cluster;
 mod config;
+mod crc;
 mod flush;
 mod handshake;
 mod keep_alive;
 mod listener;
+mod membership;
 mod message;
 mod miot;
+mod offline;
 mod packet;
+mod proxy;
 mod rebalance;
+mod replica;
 mod rr;
 mod session;
 mod shard;
+mod snapshot;
 mod socket;
 mod spinlock;
+mod store;
+mod stream;
 mod thread;
 mod ticker;
 mod timer;
+mod tls;
 mod ttrie;
 mod types;
+mod wal;
+mod ws;
 
-// pub use chash::ConsistentHash; TODO
+#[cfg(test)]
+mod fixtures;
+
+pub use admission::{Admission, Cidr, ConnGuard, NodeConns, Reject, TokenBucket};
+pub use chash::ConsistentHash;
 pub use cluster::{Cluster, Node};
-pub use config::{Config, ConfigNode};
+pub use config::Transport;
+pub use config::{
+    Config, ConfigListener, ConfigNode, FsyncPolicy, OverflowPolicy, ProxyProtocol,
+};
 pub use error::{Error, ErrorKind, ReasonCode};
 pub use flush::Flusher;
-pub use handshake::Handshake;
+pub use handshake::{Handshake, HandshakeArgs};
 pub use keep_alive::KeepAlive;
 pub use listener::Listener;
 pub use message::{Message, MsgRx, MsgTx};
 pub use miot::Miot;
+pub use offline::OfflineQueue;
+pub use proxy::{ProxyHeader, ProxyRead};
 pub use session::Session;
 pub use shard::Shard;
+pub use snapshot::Snapshot;
 pub use socket::{PktRx, PktTx, Socket};
 pub use spinlock::Spinlock;
+pub use store::{RetainLog, RetainRecord, RetainStore};
+pub use stream::{PeerCred, Stream};
 pub use thread::{Rx, Thread, Threadable, Tx};
 pub use ticker::Ticker;
 pub use timer::{TimeoutValue, Timer};
+pub use tls::TlsStream;
 pub use ttrie::{RetainedTrie, SubscribedTrie};
-pub use types::{Blob, MqttProtocol, UserProperty, VarU32};
+pub use types::{Blob, Bytes, MqttProtocol, UserProperty, VarU32};
 pub use types::{ClientID, TopicFilter, TopicName};
+pub use wal::SessionLog;
+pub use ws::WsStream;
 
 use std::{net, path, sync::mpsc, time};
 
@@ -88,6 +116,21 @@ pub trait Packetize: Sized {
 
     /// Serialize value into bytes.
     fn encode(&self) -> Result<Blob>;
+
+    /// Serialize value and append the bytes to `data`, return the number of bytes
+    /// appended. Unlike [Packetize::encode] this allows many packets to be serialized
+    /// into the same buffer, v5 packets override this to encode in-place.
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let blob = self.encode()?;
+        data.extend_from_slice(blob.as_ref());
+        Ok(blob.as_ref().len())
+    }
+
+    /// Return the number of bytes [Packetize::encode_into] shall append to `data`. v5
+    /// packets use this to encode their fixed-header before the rest of the packet.
+    fn encoded_len(&self) -> Result<usize> {
+        Ok(self.encode()?.as_ref().len())
+    }
 }
 
 /// Trait to be implemented by nodes that can host [Cluster] and one or more [Shard].
@@ -150,3 +193,12 @@ pub fn mqtt_listen_address4(port: Option<u16>) -> net::SocketAddr {
     let port = port.unwrap_or(Config::DEF_MQTT_PORT);
     net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
 }
+
+/// Default IPv6 listen address for MQTT packets: `[::]:1883`, dual-stack unless
+/// [ConfigListener::ipv6_only] is set.
+pub fn mqtt_listen_address6(port: Option<u16>) -> net::SocketAddr {
+    use std::net::{IpAddr, Ipv6Addr};
+
+    let port = port.unwrap_or(Config::DEF_MQTT_PORT);
+    net::SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port)
+}
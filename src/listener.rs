@@ -1,25 +1,30 @@
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use mio::event::Events;
 
-use std::{net, sync::Arc, time};
+use std::{collections::BTreeMap, fs, io, net, sync::Arc, time};
 
 use crate::thread::{Rx, Thread, Threadable};
-use crate::{AppTx, Cluster, Config, QueueStatus};
+use crate::util;
+use crate::{Admission, Handshake, HandshakeArgs, NodeConns};
+use crate::{AppTx, Cluster, Config, ConfigListener, ProxyProtocol, QueueStatus};
 use crate::{Error, ErrorKind, Result};
+use crate::{Stream, TlsStream, Transport, WsStream};
 
 type ThreadRx = Rx<Request, Result<Response>>;
 type QueueReq = crate::thread::QueueReq<Request, Result<Response>>;
 
 /// Type binds to MQTT port and listens for incoming connection.
 ///
-/// This type is threadable and singleton.
+/// This type is threadable, and there shall be one instance for every
+/// [ConfigListener] in [Config::listeners].
 pub struct Listener {
     /// Human readable name for this mio thread.
     pub name: String,
-    /// Port to listen to.
-    pub port: u16,
+    /// Address to listen to.
+    pub address: net::SocketAddr,
     prefix: String,
     config: Config,
+    listener_config: ConfigListener,
     inner: Inner,
 }
 
@@ -37,10 +42,22 @@ struct RunLoop {
     /// Mio poller for asynchronous handling, aggregate events from server and
     /// thread-waker.
     poll: mio::Poll,
-    /// MQTT server listening on `port`.
-    server: mio::net::TcpListener,
+    /// MQTT server listening on `address`, or on `path` for unix transport. TLS and
+    /// WebSocket connections are wrapped as they are accepted.
+    server: Server,
     /// Tx-handle to send messages to cluster.
     cluster: Box<Cluster>,
+    /// Admission control for incoming connections.
+    admission: Box<Admission>,
+    /// Rejected connections are reported to application at this time.
+    report_at: time::Instant,
+    /// Accept is paused till this time, after an accept error, like running out of
+    /// file descriptors.
+    accept_at: Option<time::Instant>,
+    /// Connections waiting for CONNECT, indexed by their poll register token.
+    handshakes: BTreeMap<mio::Token, Handshake>,
+    /// Next poll register token for incoming connection.
+    next_token: mio::Token,
 
     /// Back channel communicate with application.
     app_tx: AppTx,
@@ -48,14 +65,64 @@ struct RunLoop {
 
 pub struct FinState;
 
+pub struct SpawnArgs {
+    /// Tx-handle to send messages to cluster.
+    pub cluster: Cluster,
+    /// Active connections across all listeners in this node.
+    pub conns: Arc<NodeConns>,
+}
+
+enum Server {
+    Tcp(mio::net::TcpListener),
+    Tls(mio::net::TcpListener, Arc<rustls::ServerConfig>),
+    Ws(mio::net::TcpListener),
+    Unix(mio::net::UnixListener),
+}
+
+impl Server {
+    // `seqno` identifies unix peers, refer [Stream::unix_peer_addr].
+    fn accept(&self, seqno: u16) -> io::Result<(Stream, net::SocketAddr)> {
+        match self {
+            Server::Tcp(server) => {
+                let (conn, addr) = server.accept()?;
+                Ok((Stream::from(conn), util::canonical_addr(addr)))
+            }
+            Server::Tls(server, tls_config) => {
+                let (conn, addr) = server.accept()?;
+                let conn = TlsStream::new(conn, Arc::clone(tls_config))?;
+                Ok((Stream::from(conn), util::canonical_addr(addr)))
+            }
+            Server::Ws(server) => {
+                let (conn, addr) = server.accept()?;
+                Ok((Stream::from(WsStream::new(conn)), util::canonical_addr(addr)))
+            }
+            Server::Unix(server) => {
+                let (conn, _addr) = server.accept()?;
+                Ok((Stream::from(conn), Stream::unix_peer_addr(seqno)))
+            }
+        }
+    }
+
+    fn as_mut_source(&mut self) -> &mut dyn mio::event::Source {
+        match self {
+            Server::Tcp(server) => server,
+            Server::Tls(server, _) => server,
+            Server::Ws(server) => server,
+            Server::Unix(server) => server,
+        }
+    }
+}
+
 impl Default for Listener {
     fn default() -> Listener {
         let config = Config::default();
+        let listener_config = config.listeners().remove(0);
         let mut def = Listener {
             name: format!("{}-listener-init", config.name),
-            port: config.port.unwrap(),
+            address: listener_config.address(),
             prefix: String::default(),
             config,
+            listener_config,
             inner: Inner::Init,
         };
         def.prefix = def.prefix();
@@ -83,16 +150,60 @@ impl Listener {
     pub const TOKEN_WAKE: mio::Token = mio::Token(1);
     /// Poll register for server TcpStream.
     pub const TOKEN_SERVER: mio::Token = mio::Token(2);
+    /// Poll register token for the first incoming connection, subsequent connections
+    /// shall use monotonically increasing token values.
+    pub const FIRST_TOKEN: mio::Token = mio::Token(3);
+    /// Maximum length of the queue of pending connections, for TCP listener.
+    pub const BACKLOG: i32 = 1024;
+    /// Rejected connections, if any, are reported to application at this interval.
+    pub const REPORT_INTERVAL: time::Duration = time::Duration::from_secs(1);
+    /// Pause accepting connections for this long, after an accept error.
+    pub const ACCEPT_BACKOFF: time::Duration = time::Duration::from_millis(100);
 
     /// Create a listener from configuration. Listener shall be in `Init` state. To start
     /// this listener thread call [Listener::spawn].
-    pub fn from_config(config: Config) -> Result<Listener> {
-        let def = Listener::default();
+    ///
+    /// `listener_config` is one of the listeners from [Config::listeners].
+    pub fn from_config(
+        config: Config,
+        listener_config: ConfigListener,
+    ) -> Result<Listener> {
+        let transport = listener_config.transport();
+        let ipv6_only = transport != Transport::Unix && listener_config.ipv6_only();
+        if ipv6_only && listener_config.address().is_ipv4() {
+            err!(InvalidInput, desc: "listener ipv6_only set for IPv4 address")?
+        }
+        let proxied = listener_config.proxy_protocol() != ProxyProtocol::Disabled;
+        match transport {
+            Transport::Tcp if listener_config.trusted_proxies.is_empty() && proxied => {
+                err!(InvalidInput, desc: "listener trusted_proxies missing for proxy")?
+            }
+            Transport::Tcp => (),
+            // PROXY header, preceding the TLS and WebSocket handshakes, is not handled.
+            Transport::Tls | Transport::Ws if proxied => err!(
+                InvalidInput,
+                desc: "listener proxy_protocol not supported for {:?} transport",
+                transport
+            )?,
+            Transport::Tls if listener_config.tls_cert().is_none() => {
+                err!(InvalidInput, desc: "listener tls_cert missing for tls transport")?
+            }
+            Transport::Tls if listener_config.tls_key().is_none() => {
+                err!(InvalidInput, desc: "listener tls_key missing for tls transport")?
+            }
+            Transport::Tls | Transport::Ws => (),
+            Transport::Unix if listener_config.path().is_none() => {
+                err!(InvalidInput, desc: "listener path missing for unix transport")?
+            }
+            Transport::Unix => (),
+        }
+
         let mut val = Listener {
             name: format!("{}-listener-init", config.name),
-            port: config.port.unwrap_or(def.port),
+            address: listener_config.address(),
             prefix: String::default(),
-            config,
+            config: config.for_listener(&listener_config),
+            listener_config,
             inner: Inner::Init,
         };
         val.prefix = val.prefix();
@@ -100,31 +211,40 @@ impl Listener {
         Ok(val)
     }
 
-    pub fn spawn(self, cluster: Cluster, app_tx: AppTx) -> Result<Listener> {
+    pub fn spawn(self, args: SpawnArgs, app_tx: AppTx) -> Result<Listener> {
         use mio::{Interest, Waker};
 
         if matches!(&self.inner, Inner::Handle(_, _) | Inner::Main(_)) {
             err!(InvalidInput, desc: "listener can be spawned only in init-state ")?;
         }
 
-        let mut server = {
-            let sock_addr: net::SocketAddr = self.server_address().parse().unwrap();
-            mio::net::TcpListener::bind(sock_addr)?
-        };
+        let admission =
+            Admission::from_config(&self.config, &self.listener_config, args.conns)?;
+        let mut server = self.bind()?;
 
         let poll = err!(IOError, try: mio::Poll::new(), "fail creating mio::Poll")?;
-        poll.registry().register(&mut server, Self::TOKEN_SERVER, Interest::READABLE)?;
+        poll.registry().register(
+            server.as_mut_source(),
+            Self::TOKEN_SERVER,
+            Interest::READABLE,
+        )?;
         let waker = Arc::new(Waker::new(poll.registry(), Self::TOKEN_WAKE)?);
 
         let mut listener = Listener {
             name: format!("{}-listener-main", self.config.name),
-            port: self.port,
+            address: self.address,
             prefix: String::default(),
             config: self.config.clone(),
+            listener_config: self.listener_config.clone(),
             inner: Inner::Main(RunLoop {
                 poll,
                 server: server,
-                cluster: Box::new(cluster),
+                cluster: Box::new(args.cluster),
+                admission: Box::new(admission),
+                report_at: time::Instant::now() + Self::REPORT_INTERVAL,
+                accept_at: None,
+                handshakes: BTreeMap::default(),
+                next_token: Self::FIRST_TOKEN,
 
                 app_tx,
             }),
@@ -135,9 +255,10 @@ impl Listener {
 
         let mut listener = Listener {
             name: format!("{}-listener-handle", self.config.name),
-            port: self.port,
+            address: self.address,
             prefix: String::default(),
             config: self.config.clone(),
+            listener_config: self.listener_config.clone(),
             inner: Inner::Handle(waker, thrd),
         };
         listener.prefix = listener.prefix();
@@ -175,17 +296,21 @@ impl Threadable for Listener {
     type Resp = Result<Response>;
 
     fn main_loop(mut self, rx: ThreadRx) -> Self {
-        info!("{}, spawn thread port:{} ...", self.prefix, self.port);
+        info!("{}, spawn thread address:{} ...", self.prefix, self.address);
 
         let mut events = Events::with_capacity(crate::POLL_EVENTS_SIZE);
         loop {
-            let timeout: Option<time::Duration> = None;
+            let timeout = self.poll_timeout();
             allow_panic!(&self, self.as_mut_poll().poll(&mut events, timeout));
 
             match self.mio_events(&rx, &events) {
                 true => break,
                 _exit => (),
             };
+
+            self.resume_accept();
+            self.expire_handshakes();
+            self.report_rejects();
         }
 
         match &self.inner {
@@ -220,14 +345,8 @@ impl Listener {
                                 (QueueStatus::Disconnected(_), _) => break 'outer true,
                             }
                         },
-                        Self::TOKEN_SERVER => loop {
-                            match self.accept_conn() {
-                                QueueStatus::Ok(_) => (),
-                                QueueStatus::Block(_) => break,
-                                QueueStatus::Disconnected(_) => break 'outer true,
-                            };
-                        },
-                        _ => unreachable!(),
+                        Self::TOKEN_SERVER => self.accept_conns(),
+                        token => self.do_handshake(token),
                     }
                 }
                 None => break false,
@@ -262,35 +381,363 @@ impl Listener {
         (status, closed)
     }
 
-    fn accept_conn(&mut self) -> QueueStatus<()> {
-        use crate::Handshake;
-        use std::io;
+    fn bind(&self) -> Result<Server> {
+        let server = match self.listener_config.transport() {
+            Transport::Tcp => Server::Tcp(self.bind_tcp()?),
+            Transport::Tls => {
+                let tls_config = crate::tls::server_config(
+                    self.listener_config.tls_cert().unwrap(),
+                    self.listener_config.tls_key().unwrap(),
+                )?;
+                Server::Tls(self.bind_tcp()?, tls_config)
+            }
+            Transport::Ws => Server::Ws(self.bind_tcp()?),
+            Transport::Unix => Server::Unix(self.bind_unix()?),
+        };
 
-        let (server, cluster) = match &self.inner {
-            Inner::Main(RunLoop { server, cluster, .. }) => (server, cluster),
+        Ok(server)
+    }
+
+    // Bind unix listener. Socket is bound inside a private, 0700, directory and its
+    // permissions are set before it is renamed to the configured path, so that the
+    // socket is never reachable with the default permissions. Only a stale socket
+    // is removed from the configured path, any other file fails the bind.
+    fn bind_unix(&self) -> Result<mio::net::UnixListener> {
+        use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+
+        let path = self.listener_config.path().unwrap();
+        match fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => {
+                err!(IOError, try: fs::remove_file(path), "stale socket {:?}", path)?;
+            }
+            Ok(_) => err!(InvalidInput, desc: "{:?} exists and is not a socket", path)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => err!(IOError, cause: err, "stat {:?}", path)?,
+        }
+
+        let dir = match path.file_name() {
+            Some(name) => {
+                let mut name = name.to_os_string();
+                name.push(format!(".{}.tmp", std::process::id()));
+                path.with_file_name(name)
+            }
+            None => err!(InvalidInput, desc: "invalid socket path {:?}", path)?,
+        };
+        let mut builder = fs::DirBuilder::new();
+        builder.mode(0o700);
+        err!(IOError, try: builder.create(&dir), "creating {:?}", dir)?;
+
+        let tmp_path = dir.join("sock");
+        let res = || -> Result<mio::net::UnixListener> {
+            let server = err!(
+                IOError,
+                try: mio::net::UnixListener::bind(&tmp_path),
+                "binding listener {:?}",
+                path
+            )?;
+            if let Some(mode) = self.listener_config.permissions() {
+                let perms = fs::Permissions::from_mode(mode);
+                err!(
+                    IOError,
+                    try: fs::set_permissions(&tmp_path, perms),
+                    "setting permissions {:o} on {:?}",
+                    mode,
+                    path
+                )?;
+            }
+            err!(IOError, try: fs::rename(&tmp_path, path), "rename to {:?}", path)?;
+            Ok(server)
+        }();
+
+        fs::remove_file(&tmp_path).ok();
+        err!(IOError, try: fs::remove_dir(&dir), "removing {:?}", dir)?;
+
+        res
+    }
+
+    // Bind TCP listener, for IPv6 address IPV6_V6ONLY is set explicitly, instead of
+    // falling back on the system default, so that "[::]" is dual-stack.
+    fn bind_tcp(&self) -> Result<mio::net::TcpListener> {
+        use socket2::{Domain, Socket, Type};
+
+        let address = self.address;
+        let socket = err!(
+            IOError,
+            try: Socket::new(Domain::for_address(address), Type::STREAM, None),
+            "socket for listener {}",
+            address
+        )?;
+        if address.is_ipv6() {
+            let only_v6 = self.listener_config.ipv6_only();
+            err!(IOError, try: socket.set_only_v6(only_v6), "IPV6_V6ONLY {}", address)?;
+        }
+        err!(IOError, try: socket.set_reuse_address(true), "SO_REUSEADDR {}", address)?;
+        err!(IOError, try: socket.set_nonblocking(true), "O_NONBLOCK {}", address)?;
+        err!(
+            IOError,
+            try: socket.bind(&address.into()),
+            "binding listener {}",
+            address
+        )?;
+        err!(IOError, try: socket.listen(Self::BACKLOG), "listen {}", address)?;
+
+        Ok(mio::net::TcpListener::from_std(socket.into()))
+    }
+
+    // Accept connections until the backlog is drained, or until accept fails.
+    fn accept_conns(&mut self) {
+        let paused = match &self.inner {
+            Inner::Main(RunLoop { accept_at, .. }) => accept_at.is_some(),
             _ => unreachable!(),
         };
+        if !paused {
+            while let QueueStatus::Ok(_) = self.accept_conn() {}
+        }
+    }
+
+    // Resume accepting connections, if paused and back-off has elapsed. Server is
+    // polled edge-triggered, hence connections pending in the backlog must be
+    // accepted without waiting for the next event.
+    fn resume_accept(&mut self) {
+        let now = time::Instant::now();
+        match &mut self.inner {
+            Inner::Main(RunLoop { accept_at, .. }) => match accept_at {
+                Some(at) if *at <= now => *accept_at = None,
+                _ => return,
+            },
+            _ => unreachable!(),
+        }
+        self.accept_conns()
+    }
+
+    fn accept_conn(&mut self) -> QueueStatus<()> {
+        use mio::Interest;
+
+        let prefix = self.prefix.clone();
+        let (config, listener_config) = (&self.config, &self.listener_config);
+        let is_unix = matches!(listener_config.transport(), Transport::Unix);
+        let RunLoop {
+            poll,
+            server,
+            admission,
+            handshakes,
+            next_token,
+            accept_at,
+            ..
+        } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        // port 0 is not a valid peer port.
+        let seqno = (next_token.0 % (u16::MAX as usize)) as u16 + 1;
+        match server.accept(seqno) {
+            Ok((mut conn, addr)) => {
+                // drop the connection right away, on rejection.
+                let mut guard = match admission.admit() {
+                    Ok(guard) => guard,
+                    Err(reject) => {
+                        warn!("{}, {:?} rejected {}", prefix, reject, addr);
+                        return QueueStatus::Ok(Vec::new());
+                    }
+                };
+                let proxy_protocol = match listener_config.proxy_protocol() {
+                    proxy_protocol if is_unix => proxy_protocol,
+                    proxy_protocol => {
+                        match admission.admit_proxy(proxy_protocol, addr.ip()) {
+                            Ok(proxy_protocol) => proxy_protocol,
+                            Err(reject) => {
+                                warn!("{}, {:?} rejected {}", prefix, reject, addr);
+                                return QueueStatus::Ok(Vec::new());
+                            }
+                        }
+                    }
+                };
+                // source IP, for unix and proxied connections, is known only after
+                // the PROXY header is read.
+                let ip_pending = proxy_protocol != ProxyProtocol::Disabled;
+                if !ip_pending && !is_unix {
+                    if let Err(reject) = admission.admit_ip(&mut guard, addr.ip()) {
+                        warn!("{}, {:?} rejected {}", prefix, reject, addr);
+                        return QueueStatus::Ok(Vec::new());
+                    }
+                }
+
+                let peer_cred = match conn.peer_cred() {
+                    Ok(peer_cred) => peer_cred,
+                    Err(err) => {
+                        error!("{}, fail reading peer credentials {}", prefix, err);
+                        return QueueStatus::Ok(Vec::new());
+                    }
+                };
 
-        match server.accept() {
-            Ok((conn, addr)) => {
-                // for every successful accept launch a handshake thread.
-                let hs = Handshake {
-                    prefix: format!("{}:handshake:{}", self.prefix, addr),
-                    conn: Some(conn),
+                let token = *next_token;
+                *next_token = mio::Token(next_token.0 + 1);
+                let res = poll.registry().register(&mut conn, token, Interest::READABLE);
+                if let Err(err) = res {
+                    error!("{}, fail register {} error {}", prefix, addr, err);
+                    return QueueStatus::Ok(Vec::new());
+                }
+
+                // for every successful accept start a handshake, that shall complete
+                // as and when connection becomes readable.
+                let prefix = match &peer_cred {
+                    Some(peer_cred) => {
+                        format!("{}:{}:handshake:{}", prefix, peer_cred, addr)
+                    }
+                    None => format!("{}:handshake:{}", prefix, addr),
+                };
+                let args = HandshakeArgs {
+                    prefix,
+                    conn,
                     addr,
-                    config: self.config.clone(),
-                    cluster: cluster.to_tx(),
+                    peer_cred,
+                    config: config.clone(),
+                    listener: listener_config.clone(),
+                    guard,
+                    proxy_protocol,
+                    ip_pending,
                 };
-                let _thrd = Thread::spawn_sync("handshake", 1, hs);
+                handshakes.insert(token, Handshake::new(args));
                 QueueStatus::Ok(Vec::new())
             }
             Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
                 QueueStatus::Block(Vec::new())
             }
             Err(err) => {
-                error!("{}, connection accept error, {}", self.prefix, err);
-                QueueStatus::Disconnected(Vec::new())
+                // say EMFILE, back-off and keep the listener running, connections
+                // shall wait in the backlog.
+                error!("{}, connection accept error, {}", prefix, err);
+                *accept_at = Some(time::Instant::now() + Self::ACCEPT_BACKOFF);
+                QueueStatus::Block(Vec::new())
+            }
+        }
+    }
+
+    fn do_handshake(&mut self, token: mio::Token) {
+        let is_unix = matches!(self.listener_config.transport(), Transport::Unix);
+        let RunLoop { poll, cluster, admission, handshakes, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let (done, rejected) = match handshakes.get_mut(&token) {
+            Some(hs) => {
+                let done = hs.read() && hs.write();
+                let rejected = match hs.ip_pending && !hs.is_proxy_pending() {
+                    true => {
+                        hs.ip_pending = false;
+                        match is_unix && !hs.is_proxied() {
+                            true => false,
+                            false => {
+                                let ip = hs.addr.ip();
+                                match admission.admit_ip(&mut hs.guard, ip) {
+                                    Ok(()) => false,
+                                    Err(reject) => {
+                                        warn!("{}, {:?} rejected", hs.prefix, reject);
+                                        true
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    false => false,
+                };
+                (done, rejected)
             }
+            None => (false, false), // spurious event after handshake is finished.
+        };
+        if rejected {
+            // drop the connection right away.
+            let mut hs = handshakes.remove(&token).unwrap();
+            poll.registry().deregister(&mut hs.conn).ok();
+        } else if done {
+            let mut hs = handshakes.remove(&token).unwrap();
+            if let Err(err) = poll.registry().deregister(&mut hs.conn) {
+                error!("{}, fail deregister error {}", hs.prefix, err);
+            }
+            hs.finish(cluster);
+        } else if let Some(hs) = handshakes.get_mut(&token) {
+            Self::write_interest(poll, token, hs);
+        }
+    }
+
+    fn expire_handshakes(&mut self) {
+        let RunLoop { poll, cluster, handshakes, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let now = time::Instant::now();
+        let tokens: Vec<mio::Token> = handshakes
+            .iter()
+            .filter_map(
+                |(token, hs)| if hs.is_expired(now) { Some(*token) } else { None },
+            )
+            .collect();
+
+        for token in tokens.into_iter() {
+            let hs = handshakes.get_mut(&token).unwrap();
+            hs.expire();
+            match hs.write() {
+                true => {
+                    let mut hs = handshakes.remove(&token).unwrap();
+                    poll.registry().deregister(&mut hs.conn).ok();
+                    hs.finish(cluster);
+                }
+                false => Self::write_interest(poll, token, hs),
+            }
+        }
+    }
+
+    // Failure CONNACK could not be written in full, wait for the connection to be
+    // writable.
+    fn write_interest(poll: &mio::Poll, token: mio::Token, hs: &mut Handshake) {
+        use mio::Interest;
+
+        if hs.is_write_pending() {
+            let res = poll.registry().reregister(&mut hs.conn, token, Interest::WRITABLE);
+            if let Err(err) = res {
+                error!("{}, fail reregister error {}", hs.prefix, err);
+            }
+        }
+    }
+
+    // Report rejected connections, if any, to application.
+    fn report_rejects(&mut self) {
+        let now = time::Instant::now();
+        let prefix = self.prefix.clone();
+        let RunLoop { admission, report_at, app_tx, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        if now < *report_at {
+            return;
+        }
+        *report_at = now + Self::REPORT_INTERVAL;
+
+        if let Some(rejects) = admission.to_report() {
+            warn!("{}, rejected connections {:?}", prefix, rejects);
+            app_tx
+                .try_send(format!("{}, rejected connections {:?}", prefix, rejects))
+                .ok();
+        }
+    }
+
+    // Poll timeout, so that the main loop wakes up for the earliest handshake deadline,
+    // for the next rejection report, or to resume accepting connections.
+    fn poll_timeout(&self) -> Option<time::Duration> {
+        let now = time::Instant::now();
+        match &self.inner {
+            Inner::Main(RunLoop { handshakes, report_at, accept_at, .. }) => handshakes
+                .values()
+                .map(|hs| hs.deadline)
+                .chain(Some(*report_at))
+                .chain(*accept_at)
+                .map(|deadline| deadline.saturating_duration_since(now))
+                .min(),
+            _ => unreachable!(),
         }
     }
 }
@@ -303,6 +750,12 @@ impl Listener {
             Inner::Main(_run_loop) => {
                 info!("{} closing ...", self.prefix);
 
+                if let Some(path) = self.listener_config.path() {
+                    if let Transport::Unix = self.listener_config.transport() {
+                        fs::remove_file(path).ok();
+                    }
+                }
+
                 // Drop, poll, server, cluster and app_tx.
                 let _init = mem::replace(&mut self.inner, Inner::Close(FinState));
                 Response::Ok
@@ -314,12 +767,13 @@ impl Listener {
 }
 
 impl Listener {
-    fn server_address(&self) -> String {
-        format!("0.0.0.0:{}", self.port)
-    }
-
     fn prefix(&self) -> String {
-        format!("{}:listener:{}", self.name, self.server_address())
+        match (self.listener_config.transport(), self.listener_config.path()) {
+            (Transport::Unix, Some(path)) => {
+                format!("{}:listener:{}", self.name, path.display())
+            }
+            (_, _) => format!("{}:listener:{}", self.name, self.address),
+        }
     }
 
     fn as_mut_poll(&mut self) -> &mut mio::Poll {
@@ -336,3 +790,7 @@ impl Listener {
         }
     }
 }
+
+#[cfg(test)]
+#[path = "listener_test.rs"]
+mod listener_test;
@@ -0,0 +1,225 @@
+use super::*;
+
+use std::io::{Read, Write};
+use std::os::unix::{fs::FileTypeExt, fs::PermissionsExt, net::UnixStream};
+use std::{net::TcpStream, path, sync::mpsc, thread};
+
+use crate::fixtures::{free_port, new_connect};
+use crate::packet::MQTTRead;
+use crate::{v5, ConfigNode, Node, Packetize};
+
+fn connect(path: &path::Path, client_id: &str) -> (UnixStream, v5::ConnAck) {
+    let mut conn = UnixStream::connect(path).unwrap();
+    conn.set_read_timeout(Some(crate::SLEEP_10MS)).unwrap();
+
+    conn.write_all(new_connect(client_id, true).encode().unwrap().as_ref()).unwrap();
+
+    let connack = read_connack(&conn);
+    (conn, connack)
+}
+
+fn read_connack<R: io::Read>(mut conn: R) -> v5::ConnAck {
+    let deadline = time::Instant::now() + time::Duration::from_secs(5);
+    let mut packetr = MQTTRead::new(Config::DEF_MQTT_MAX_PACKET_SIZE);
+    let pkt = loop {
+        packetr = match packetr.read(&mut conn).unwrap() {
+            (mut packetr @ MQTTRead::Fin { .. }, _) => break packetr.parse().unwrap(),
+            (_, true) if time::Instant::now() > deadline => panic!("no packet"),
+            (packetr, _) => packetr,
+        }
+    };
+    match pkt {
+        v5::Packet::ConnAck(connack) => connack,
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+}
+
+#[test]
+fn test_unix_listener() {
+    let path = std::env::temp_dir().join(format!("mqtr-{}.sock", std::process::id()));
+    let config = Config {
+        name: "unix-listener".to_string(),
+        num_shards: Some(2),
+        connect_timeout: Some(1),
+        listeners: vec![ConfigListener {
+            transport: Some(Transport::Unix),
+            path: Some(path.clone()),
+            permissions: Some(0o600),
+            ..ConfigListener::default()
+        }],
+        ..Config::default()
+    };
+
+    // file, that is not a socket, is not removed.
+    fs::write(&path, b"data").unwrap();
+    let listener =
+        Listener::from_config(config.clone(), config.listeners[0].clone()).unwrap();
+    assert!(listener.bind_unix().is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"data");
+    fs::remove_file(&path).unwrap();
+
+    // stale socket file from a previous run.
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    assert!(fs::symlink_metadata(&path).unwrap().file_type().is_socket());
+    let node = Node::try_from(ConfigNode {
+        mqtt_address: "127.0.0.1:1883".parse().unwrap(),
+        ..ConfigNode::default()
+    })
+    .unwrap();
+    let (app_tx, app_rx) = mpsc::sync_channel(1024);
+    thread::spawn(move || for _msg in app_rx {});
+    let cluster = Cluster::from_config(config).unwrap().spawn(node, app_tx).unwrap();
+
+    let meta = fs::metadata(&path).unwrap();
+    assert!(meta.file_type().is_socket());
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+
+    // concurrent unix peers are admitted, each with its own identity.
+    let (_conn1, connack) = connect(&path, "unix-1");
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    let (_conn2, connack) = connect(&path, "unix-2");
+    assert_eq!(connack.code, v5::ConnackReasonCode::Success);
+    assert_ne!(Stream::unix_peer_addr(1), Stream::unix_peer_addr(2));
+
+    // silent peer gets CONNACK after connect-timeout.
+    let conn = UnixStream::connect(&path).unwrap();
+    conn.set_read_timeout(Some(crate::SLEEP_10MS)).unwrap();
+    let connack = read_connack(&conn);
+    assert_eq!(connack.code, v5::ConnackReasonCode::UnspecifiedError);
+
+    // credentials of the peer process.
+    let (conn, _) = mio::net::UnixStream::pair().unwrap();
+    let peer_cred = Stream::from(conn).peer_cred().unwrap().unwrap();
+    assert_eq!(peer_cred.pid as u32, std::process::id());
+    assert_eq!(peer_cred.uid, unsafe { libc::getuid() });
+
+    cluster.close_wait();
+    assert!(!path.exists());
+}
+
+// Masked client frame.
+fn ws_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![if fin { 0x80 | opcode } else { opcode }];
+    frame.push(0x80 | u8::try_from(payload.len()).unwrap());
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    frame
+}
+
+// Unmasked server frame, return (opcode, payload).
+fn read_ws_frame(conn: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut header = [0; 2];
+    conn.read_exact(&mut header).unwrap();
+    assert_eq!(header[1] & 0x80, 0, "server frame shall not be masked");
+    let mut payload = vec![0; usize::from(header[1] & 0x7F)];
+    conn.read_exact(&mut payload).unwrap();
+    (header[0] & 0x0F, payload)
+}
+
+#[test]
+fn test_tls_ws_listener() {
+    use rustls::pki_types::{pem::PemObject, CertificateDer, ServerName};
+
+    let testdata = path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+    let (tls_port, ws_port) = (free_port(), free_port());
+    let tls_listener = ConfigListener {
+        address: Some(([127, 0, 0, 1], tls_port).into()),
+        transport: Some(Transport::Tls),
+        tls_cert: Some(testdata.join("tls-cert.pem")),
+        tls_key: Some(testdata.join("tls-key.pem")),
+        ..ConfigListener::default()
+    };
+    let ws_listener = ConfigListener {
+        address: Some(([127, 0, 0, 1], ws_port).into()),
+        transport: Some(Transport::Ws),
+        ..ConfigListener::default()
+    };
+    let config = Config {
+        name: "tls-ws-listener".to_string(),
+        num_shards: Some(2),
+        listeners: vec![tls_listener.clone(), ws_listener.clone()],
+        ..Config::default()
+    };
+
+    // default ports, certificate must be supplied, PROXY header is not handled.
+    let listener = ConfigListener { address: None, ..tls_listener.clone() };
+    assert_eq!(listener.address().port(), 8883);
+    let listener = ConfigListener { address: None, ..ws_listener.clone() };
+    assert_eq!(listener.address().port(), 8083);
+    let listener = ConfigListener { tls_key: None, ..tls_listener.clone() };
+    assert!(Listener::from_config(config.clone(), listener).is_err());
+    let listener = ConfigListener {
+        proxy_protocol: Some(ProxyProtocol::Optional),
+        trusted_proxies: vec!["127.0.0.1/32".to_string()],
+        ..ws_listener.clone()
+    };
+    assert!(Listener::from_config(config.clone(), listener).is_err());
+
+    let node = Node::try_from(ConfigNode {
+        mqtt_address: "127.0.0.1:1883".parse().unwrap(),
+        ..ConfigNode::default()
+    })
+    .unwrap();
+    let (app_tx, app_rx) = mpsc::sync_channel(1024);
+    thread::spawn(move || for _msg in app_rx {});
+    let cluster = Cluster::from_config(config).unwrap().spawn(node, app_tx).unwrap();
+
+    // TLS client trusting the self-signed server certificate.
+    let mut roots = rustls::RootCertStore::empty();
+    let cert = CertificateDer::from_pem_file(testdata.join("tls-cert.pem")).unwrap();
+    roots.add(cert).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls_config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let name = ServerName::try_from("localhost").unwrap();
+    let tls = rustls::ClientConnection::new(Arc::new(tls_config), name).unwrap();
+    let conn = TcpStream::connect(("127.0.0.1", tls_port)).unwrap();
+    conn.set_read_timeout(Some(crate::SLEEP_10MS)).unwrap();
+    let mut conn = rustls::StreamOwned::new(tls, conn);
+    conn.write_all(new_connect("tls-1", true).encode().unwrap().as_ref()).unwrap();
+    assert_eq!(read_connack(&mut conn).code, v5::ConnackReasonCode::Success);
+
+    // WebSocket client, with the sample key from RFC 6455.
+    let mut conn = TcpStream::connect(("127.0.0.1", ws_port)).unwrap();
+    conn.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+    let request = "GET /mqtt HTTP/1.1\r\n\
+        Host: localhost\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+        Sec-WebSocket-Protocol: mqtt\r\n\
+        Sec-WebSocket-Version: 13\r\n\r\n";
+    conn.write_all(request.as_bytes()).unwrap();
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut byte = [0];
+        conn.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+    }
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 101 "), "{}", response);
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert!(response.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+    // PING is answered, CONNECT split across a binary and a continuation frame.
+    let data = new_connect("ws-1", true).encode().unwrap().as_ref().to_vec();
+    let mut frames = ws_frame(true, 0x9, b"ping");
+    frames.extend(ws_frame(false, 0x2, &data[..5]));
+    frames.extend(ws_frame(true, 0x0, &data[5..]));
+    conn.write_all(&frames).unwrap();
+    assert_eq!(read_ws_frame(&mut conn), (0xA, b"ping".to_vec()));
+    let (opcode, payload) = read_ws_frame(&mut conn);
+    assert_eq!(opcode, 0x2);
+    match v5::Packet::decode(&payload).unwrap().0 {
+        v5::Packet::ConnAck(connack) => {
+            assert_eq!(connack.code, v5::ConnackReasonCode::Success)
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    }
+
+    cluster.close_wait();
+}
@@ -0,0 +1,1164 @@
+//! Module implement membership for multi-node cluster.
+//!
+//! Nodes listed in [Config::nodes][crate::Config::nodes] talk to each other on their
+//! [ConfigNode::cluster_address], a dedicated inter-node TCP port. For every pair of
+//! nodes, the node with the lower uuid dials the other, and re-dials every
+//! [Membership::HEARTBEAT] until the connection is established. Once connected, both
+//! ends send HELLO, carrying their [Node] description and a random nonce, and answer
+//! the other end's HELLO with AUTH, an HMAC-SHA256 of the other end's nonce and their
+//! own uuid, keyed by [Config::cluster_secret][crate::Config::cluster_secret]. Peer
+//! is admitted only after its AUTH is verified, and there after both ends send PING
+//! every [Membership::HEARTBEAT]. Any other message before AUTH, or a failed AUTH,
+//! closes the connection. A peer that is not heard from within
+//! [Config::cluster_peer_timeout][crate::Config::cluster_peer_timeout] is considered
+//! failed and its connection is closed. So is a peer that can't keep up with the
+//! messages sent to it, once they exceed a fixed multiple of the maximum message
+//! size.
+//!
+//! Among the live nodes, the node with the lowest uuid is the leader. Leader
+//! computes the membership view, the list of live nodes sorted by uuid, along with
+//! the shard topology, and broadcasts them with a new epoch whenever they change.
+//! Other nodes adopt any view with a higher epoch, they never compute the topology
+//! on their own.
+//!
+//! * Initial topology is computed using the [Rebalancer][crate::rebalance::Rebalancer]
+//!   once all the configured nodes have joined, or once a majority of them have
+//!   joined and [Config::cluster_peer_timeout][crate::Config::cluster_peer_timeout]
+//!   has elapsed since start.
+//! * There after, as nodes fail, replicas are promoted in the topology, refer
+//!   [promote_replicas][crate::rebalance::promote_replicas], and replicas lost along
+//!   with the failed nodes are placed on other nodes, refer
+//!   [top_up_replicas][crate::rebalance::top_up_replicas].
+//! * Nodes that join later, or re-join after a failure, host no shards in the view
+//!   they join in. Once every node has adopted that view, as told by their PING, and
+//!   a heartbeat has elapsed, leader rebalances the topology with the
+//!   [Rebalancer][crate::rebalance::Rebalancer]. Old master of a migrating shard
+//!   closes it, replicates its final state to the new master and sends HANDOVER. New
+//!   master spawns the shard only after HANDOVER, or after the old master fails, or
+//!   [Config::cluster_join_timeout][crate::Config::cluster_join_timeout] elapses.
+//! * Leader changes the view, and the topology, only while a majority of the
+//!   configured nodes are live. In a network partition, only the majority side, if
+//!   any, promotes replicas.
+//! * When a peer says HELLO with an older epoch, its view is sent to the peer. A
+//!   leader waits for the view from peers that have a newer epoch, before computing
+//!   its own. Having adopted it, say after a restart, leader waits until the nodes in
+//!   that view connect, or cluster_peer_timeout has elapsed since start, before
+//!   dropping them from the view.
+//!
+//! Leader sends its view to a peer whose PING carries an older epoch.
+//!
+//! Same connections carry the replication stream from master shards to their
+//! replicas and their acknowledgements, refer [crate::replica], PUBLISH and its acknowledgement between shards
+//! on different nodes, refer [crate::message::Message], and the subscriptions of
+//! shards mastered by each node.
+//!
+//! **Wire format**
+//!
+//! Messages use the same framing as [RetainLog][crate::RetainLog].
+//!
+//! * HELLO, body is the sender's epoch as u64, followed by the sender's node and a
+//!   nonce of 16 bytes.
+//! * AUTH, body is the 32 byte HMAC-SHA256 of `mqtr-cluster`, the receiver's nonce
+//!   and the sender's uuid.
+//! * VIEW, body is the epoch as u64, number of nodes as u32, followed by the nodes,
+//!   number of shards in topology as u32, followed by the topology of each shard.
+//! * PING, body is the sender's epoch as u64.
+//! * RESET, body is the shard as u32, seqno as u64, followed by the number of records
+//!   capturing the live state as u64.
+//! * REPLICATE, body is the shard as u32, seqno as u64, followed by the session log
+//!   record, refer [Record][crate::wal::Record], as op in one byte and its body.
+//! * FORWARD, body is the target shard as u32, number of messages as u32, followed
+//!   by the messages.
+//! * SUBSCRIPTIONS, body is the shard as u32, number of subscriptions as u32,
+//!   followed by every subscription of that shard.
+//! * HANDOVER, body is the shard as u32, followed by the seqno of the next record as
+//!   u64.
+//! * ACK, body is the shard as u32, followed by the seqno of the last record applied
+//!   by the replica as u64.
+//!
+//! Node is encoded as its uuid in 16 bytes, weight as u16, followed by path,
+//! mqtt_address and cluster_address as MQTT strings, where cluster_address is an
+//! empty string if missing. Topology of a shard is encoded as the shard as u32, its
+//! master node, number of replicas as u32 followed by the replica nodes. Forwarded
+//! message is encoded as its kind in one byte, LocalAck as shard_id as u32 and
+//! last_received_ack as u64, Packet as shard_id as u32, seqno as u64, packet_id as
+//! u16, client_id, number of subscriptions as u32, the subscriptions, and the
+//! PUBLISH packet. All integers are in big-endian.
+
+use log::{debug, error, info, warn};
+use mio::net::{TcpListener, TcpStream};
+use uuid::Uuid;
+
+use std::io::{self, Read, Write};
+use std::{collections::BTreeMap, net, time};
+
+use crate::rebalance::{self, Rebalancer, Topology};
+use crate::store::{decode_record, encode_record};
+use crate::wal::{self, decode_field, decode_u64};
+use crate::wal::{decode_subscription, encode_subscription};
+use crate::{v5, Cluster, Config, ConfigNode, Message, Node, Packetize};
+use crate::{Error, ErrorKind, Result};
+
+/// Messages exchanged between nodes, refer to module documentation.
+#[derive(Clone, PartialEq)]
+pub enum Msg {
+    Hello {
+        epoch: u64,
+        node: Node,
+        nonce: [u8; 16],
+    },
+    Auth {
+        mac: [u8; 32],
+    },
+    View {
+        epoch: u64,
+        nodes: Vec<Node>,
+        topology: Vec<Topology>,
+    },
+    Ping {
+        epoch: u64,
+    },
+    Reset {
+        shard: u32,
+        seqno: u64,
+        count: u64,
+    },
+    Replicate {
+        shard: u32,
+        seqno: u64,
+        record: wal::Record,
+    },
+    Forward {
+        shard: u32,
+        msgs: Vec<Message>,
+    },
+    Subscriptions {
+        shard: u32,
+        subscriptions: Vec<v5::Subscription>,
+    },
+    Handover {
+        shard: u32,
+        seqno: u64,
+    },
+    Ack {
+        shard: u32,
+        seqno: u64,
+    },
+}
+
+impl Msg {
+    const OP_HELLO: u8 = 1;
+    const OP_VIEW: u8 = 2;
+    const OP_PING: u8 = 3;
+    const OP_RESET: u8 = 4;
+    const OP_REPLICATE: u8 = 5;
+    const OP_FORWARD: u8 = 6;
+    const OP_SUBSCRIPTIONS: u8 = 7;
+    const OP_AUTH: u8 = 8;
+    const OP_HANDOVER: u8 = 9;
+    const OP_ACK: u8 = 10;
+
+    const MSG_LOCAL_ACK: u8 = 1;
+    const MSG_PACKET: u8 = 2;
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        let op = match self {
+            Msg::Hello { epoch, node, nonce } => {
+                body.extend_from_slice(&epoch.to_be_bytes());
+                encode_node(node, &mut body)?;
+                body.extend_from_slice(nonce);
+                Self::OP_HELLO
+            }
+            Msg::Auth { mac } => {
+                body.extend_from_slice(mac);
+                Self::OP_AUTH
+            }
+            Msg::View { epoch, nodes, topology } => {
+                body.extend_from_slice(&epoch.to_be_bytes());
+                body.extend_from_slice(&u32::try_from(nodes.len())?.to_be_bytes());
+                for node in nodes.iter() {
+                    encode_node(node, &mut body)?;
+                }
+                body.extend_from_slice(&u32::try_from(topology.len())?.to_be_bytes());
+                for t in topology.iter() {
+                    encode_topology(t, &mut body)?;
+                }
+                Self::OP_VIEW
+            }
+            Msg::Ping { epoch } => {
+                body.extend_from_slice(&epoch.to_be_bytes());
+                Self::OP_PING
+            }
+            Msg::Reset { shard, seqno, count } => {
+                body.extend_from_slice(&shard.to_be_bytes());
+                body.extend_from_slice(&seqno.to_be_bytes());
+                body.extend_from_slice(&count.to_be_bytes());
+                Self::OP_RESET
+            }
+            Msg::Replicate { shard, seqno, record } => {
+                let (op, data) = record.encode()?;
+                body.extend_from_slice(&shard.to_be_bytes());
+                body.extend_from_slice(&seqno.to_be_bytes());
+                body.push(op);
+                body.extend_from_slice(&data);
+                Self::OP_REPLICATE
+            }
+            Msg::Forward { shard, msgs } => {
+                body.extend_from_slice(&shard.to_be_bytes());
+                body.extend_from_slice(&u32::try_from(msgs.len())?.to_be_bytes());
+                for msg in msgs.iter() {
+                    encode_message(msg, &mut body)?;
+                }
+                Self::OP_FORWARD
+            }
+            Msg::Subscriptions { shard, subscriptions } => {
+                body.extend_from_slice(&shard.to_be_bytes());
+                body.extend_from_slice(
+                    &u32::try_from(subscriptions.len())?.to_be_bytes(),
+                );
+                for subscription in subscriptions.iter() {
+                    encode_subscription(subscription, &mut body)?;
+                }
+                Self::OP_SUBSCRIPTIONS
+            }
+            Msg::Handover { shard, seqno } => {
+                body.extend_from_slice(&shard.to_be_bytes());
+                body.extend_from_slice(&seqno.to_be_bytes());
+                Self::OP_HANDOVER
+            }
+            Msg::Ack { shard, seqno } => {
+                body.extend_from_slice(&shard.to_be_bytes());
+                body.extend_from_slice(&seqno.to_be_bytes());
+                Self::OP_ACK
+            }
+        };
+        encode_record(op, &body)
+    }
+
+    pub fn decode(op: u8, body: &[u8]) -> Result<Msg> {
+        let mut n = 0;
+        let msg = match op {
+            Self::OP_HELLO => {
+                let epoch = decode_u64(body, &mut n)?;
+                let node = decode_node(body, &mut n)?;
+                let nonce = match body.get(n..n + 16) {
+                    Some(nonce) => nonce.try_into().unwrap(),
+                    None => err!(InsufficientBytes, desc: "hello nonce")?,
+                };
+                Msg::Hello { epoch, node, nonce }
+            }
+            Self::OP_AUTH => match body.try_into() {
+                Ok(mac) => Msg::Auth { mac },
+                Err(_) => err!(InvalidInput, desc: "auth mac {} bytes", body.len())?,
+            },
+            Self::OP_VIEW => {
+                let epoch = decode_u64(body, &mut n)?;
+                let count: u32 = decode_field(body, &mut n)?;
+                let mut nodes = vec![];
+                for _ in 0..count {
+                    nodes.push(decode_node(body, &mut n)?);
+                }
+                let count: u32 = decode_field(body, &mut n)?;
+                let mut topology = vec![];
+                for _ in 0..count {
+                    topology.push(decode_topology(body, &mut n)?);
+                }
+                Msg::View { epoch, nodes, topology }
+            }
+            Self::OP_PING => Msg::Ping { epoch: decode_u64(body, &mut n)? },
+            Self::OP_RESET => Msg::Reset {
+                shard: decode_field(body, &mut n)?,
+                seqno: decode_u64(body, &mut n)?,
+                count: decode_u64(body, &mut n)?,
+            },
+            Self::OP_REPLICATE => {
+                let shard = decode_field(body, &mut n)?;
+                let seqno = decode_u64(body, &mut n)?;
+                let record = match body.get(n) {
+                    Some(op) => wal::Record::decode(*op, &body[n + 1..])?,
+                    None => err!(InsufficientBytes, desc: "replicate record")?,
+                };
+                Msg::Replicate { shard, seqno, record }
+            }
+            Self::OP_FORWARD => {
+                let shard = decode_field(body, &mut n)?;
+                let count: u32 = decode_field(body, &mut n)?;
+                let mut msgs = vec![];
+                for _ in 0..count {
+                    msgs.push(decode_message(body, &mut n)?);
+                }
+                Msg::Forward { shard, msgs }
+            }
+            Self::OP_SUBSCRIPTIONS => {
+                let shard = decode_field(body, &mut n)?;
+                let count: u32 = decode_field(body, &mut n)?;
+                let mut subscriptions = vec![];
+                for _ in 0..count {
+                    subscriptions.push(decode_subscription(body, &mut n)?);
+                }
+                Msg::Subscriptions { shard, subscriptions }
+            }
+            Self::OP_HANDOVER => Msg::Handover {
+                shard: decode_field(body, &mut n)?,
+                seqno: decode_u64(body, &mut n)?,
+            },
+            Self::OP_ACK => Msg::Ack {
+                shard: decode_field(body, &mut n)?,
+                seqno: decode_u64(body, &mut n)?,
+            },
+            op => err!(InvalidInput, desc: "invalid membership op {}", op)?,
+        };
+
+        Ok(msg)
+    }
+}
+
+/// Type implement node membership, refer to module documentation.
+pub struct Membership {
+    prefix: String,
+    local: Node,
+    /// Configured nodes other than the local node, indexed by uuid.
+    nodes: BTreeMap<Uuid, ConfigNode>,
+    /// Refer [Config::cluster_secret].
+    secret: String,
+    listener: TcpListener,
+    peers: BTreeMap<mio::Token, Peer>,
+    next_token: usize,
+    peer_timeout: time::Duration,
+    max_msg_size: usize,
+    last_heartbeat: time::Instant,
+
+    rebalancer: Rebalancer,
+    started: time::Instant,
+
+    epoch: u64,
+    /// Time when the epoch last changed.
+    viewed_at: time::Instant,
+    /// Epoch for which the leader has last considered a rebalance.
+    rebalanced: u64,
+    view: Vec<Node>,
+    /// Topology agreed along with the view, empty until computed by the leader.
+    topology: Vec<Topology>,
+
+    /// Nodes that have said HELLO, since the last call to take_joined.
+    joined: Vec<Uuid>,
+    /// RESET, REPLICATE and HANDOVER messages, since the last call to
+    /// take_replicated.
+    replicated: Vec<Msg>,
+    /// FORWARD and SUBSCRIPTIONS messages, since the last call to take_routed.
+    routed: Vec<Msg>,
+}
+
+struct Peer {
+    conn: TcpStream,
+    /// Node description from HELLO, None until authenticated.
+    node: Option<Node>,
+    /// Epoch and node description from HELLO, until authenticated by AUTH.
+    hello: Option<(u64, Node)>,
+    /// Nonce sent in HELLO, peer shall answer it in AUTH.
+    nonce: [u8; 16],
+    /// Uuid of the dialed node, None for accepted connections.
+    dialed: Option<Uuid>,
+    /// Epoch of the view seen by the node, as of its HELLO or last PING.
+    epoch: u64,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    /// Limit on `wbuf`, peer that can't keep up is closed once exceeded.
+    max_wbuf_size: usize,
+    /// Peer failed while sending, it is closed on the next tick.
+    failed: bool,
+    last_seen: time::Instant,
+}
+
+impl Membership {
+    /// Interval between heartbeats and re-dials.
+    pub const HEARTBEAT: time::Duration = time::Duration::from_secs(1);
+
+    // Peer tokens are allocated after the tokens reserved by cluster.
+    const PEER_TOKEN: usize = 16;
+    // Limit on the size of a single message, over and above the MQTT packet size,
+    // for REPLICATE messages carrying PUBLISH.
+    const MAX_MSG_SIZE: usize = 1024 * 1024;
+    // Limit on the messages buffered for a peer, as multiples of max_msg_size.
+    const MAX_WBUF_MSGS: usize = 64;
+
+    /// Bind the `local` node's cluster_address and register with `registry` for
+    /// [Cluster::TOKEN_CONSENSUS].
+    pub fn new(
+        prefix: &str,
+        config: &Config,
+        local: &Node,
+        registry: &mio::Registry,
+    ) -> Result<Membership> {
+        let addr = match local.cluster_address {
+            Some(addr) => addr,
+            None => err!(InvalidInput, desc: "{} node has no cluster_address", prefix)?,
+        };
+        let secret = match config.cluster_secret() {
+            Some(secret) if !secret.is_empty() => secret.to_string(),
+            _ => err!(InvalidInput, desc: "{} cluster_secret is not configured", prefix)?,
+        };
+
+        let mut nodes = BTreeMap::default();
+        for config_node in config.nodes.iter() {
+            let node = Node::try_from(config_node.clone())?;
+            if node.uuid != local.uuid {
+                nodes.insert(node.uuid, config_node.clone());
+            }
+        }
+
+        let mut listener =
+            err!(IOError, try: TcpListener::bind(addr), "bind cluster_address {}", addr)?;
+        let interests = mio::Interest::READABLE;
+        err!(
+            IOError,
+            try: registry.register(&mut listener, Cluster::TOKEN_CONSENSUS, interests),
+            "register cluster_address {}",
+            addr
+        )?;
+        info!("{} membership listening on {}", prefix, addr);
+
+        let val = Membership {
+            prefix: prefix.to_string(),
+            local: local.clone(),
+            nodes,
+            secret,
+            listener,
+            peers: BTreeMap::default(),
+            next_token: Self::PEER_TOKEN,
+            peer_timeout: time::Duration::from_secs(config.cluster_peer_timeout() as u64),
+            max_msg_size: Self::MAX_MSG_SIZE + (config.mqtt_max_packet_size() as usize),
+            last_heartbeat: time::Instant::now() - Self::HEARTBEAT,
+
+            rebalancer: Rebalancer {
+                config: config.clone(),
+                algo: rebalance::Algorithm::Replicated,
+            },
+            started: time::Instant::now(),
+
+            epoch: 0,
+            viewed_at: time::Instant::now(),
+            rebalanced: 0,
+            view: vec![local.clone()],
+            topology: Vec::default(),
+
+            joined: Vec::default(),
+            replicated: Vec::default(),
+            routed: Vec::default(),
+        };
+
+        Ok(val)
+    }
+
+    /// Return the current view as (epoch, nodes, topology). Topology is empty until
+    /// computed by the leader.
+    pub fn to_view(&self) -> (u64, Vec<Node>, Vec<Topology>) {
+        (self.epoch, self.view.clone(), self.topology.clone())
+    }
+
+    /// Return the local node.
+    pub fn to_local(&self) -> &Node {
+        &self.local
+    }
+
+    /// Return the nodes that have joined, or re-joined, since the last call.
+    pub fn take_joined(&mut self) -> Vec<Uuid> {
+        std::mem::take(&mut self.joined)
+    }
+
+    /// Return the RESET, REPLICATE and HANDOVER messages received since the last
+    /// call, in the order they were received.
+    pub fn take_replicated(&mut self) -> Vec<Msg> {
+        std::mem::take(&mut self.replicated)
+    }
+
+    /// Return the FORWARD and SUBSCRIPTIONS messages received since the last call, in
+    /// the order they were received.
+    pub fn take_routed(&mut self) -> Vec<Msg> {
+        std::mem::take(&mut self.routed)
+    }
+
+    /// Send `msg` to node `uuid`. Message is dropped if the node is not connected.
+    pub fn send_to(&mut self, uuid: &Uuid, msg: &Msg) -> Result<()> {
+        let data = self.encode(msg)?;
+        let peer = self
+            .peers
+            .values_mut()
+            .find(|p| p.node.as_ref().map(|n| &n.uuid) == Some(uuid));
+        match peer {
+            Some(peer) => peer.send(&data),
+            None => {
+                err!(Disconnected, desc: "{} peer {} not connected", self.prefix, uuid)
+            }
+        }
+    }
+
+    /// Send `msg` to all the joined nodes. Message is dropped for nodes whose
+    /// connection has failed, they are detected on the next [Membership::tick].
+    pub fn broadcast(&mut self, msg: &Msg) -> Result<()> {
+        let data = self.encode(msg)?;
+        for peer in self.peers.values_mut().filter(|p| p.node.is_some()) {
+            peer.send(&data).ok();
+        }
+        Ok(())
+    }
+
+    /// Return whether all the configured nodes are in the current view.
+    pub fn is_complete(&self) -> bool {
+        let mut uuids = self.nodes.keys().chain(std::iter::once(&self.local.uuid));
+        uuids.all(|uuid| self.view.iter().any(|n| &n.uuid == uuid))
+    }
+
+    /// Handle poll event for [Cluster::TOKEN_CONSENSUS] and peer connections.
+    pub fn handle_event(&mut self, registry: &mio::Registry, event: &mio::event::Event) {
+        match event.token() {
+            Cluster::TOKEN_CONSENSUS => self.accept(registry),
+            token => {
+                let ok = match self.peers.get_mut(&token) {
+                    Some(peer) if event.is_error() => {
+                        debug!("{} peer {:?} error", self.prefix, peer.dialed);
+                        false
+                    }
+                    Some(peer) if peer.failed => false,
+                    Some(peer) => peer.flush().is_ok() && self.read(token),
+                    None => true,
+                };
+                if !ok {
+                    self.remove_peer(registry, token);
+                }
+            }
+        }
+        self.update_view();
+    }
+
+    /// Dial missing nodes, send heartbeats and detect failed peers. Shall be
+    /// called periodically.
+    pub fn tick(&mut self, registry: &mio::Registry) {
+        if self.last_heartbeat.elapsed() < Self::HEARTBEAT {
+            return;
+        }
+        self.last_heartbeat = time::Instant::now();
+
+        let ping = match self.encode(&Msg::Ping { epoch: self.epoch }) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("{} encode ping: {}", self.prefix, err);
+                return;
+            }
+        };
+        let failed: Vec<mio::Token> = self
+            .peers
+            .iter_mut()
+            .filter_map(|(token, peer)| {
+                if peer.failed {
+                    warn!("{} peer {:?} failed", self.prefix, peer.to_uuid());
+                    Some(*token)
+                } else if peer.last_seen.elapsed() > self.peer_timeout {
+                    warn!("{} peer {:?} timeout", self.prefix, peer.to_uuid());
+                    Some(*token)
+                } else if peer.send(&ping).is_err() {
+                    Some(*token)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for token in failed.into_iter() {
+            self.remove_peer(registry, token);
+        }
+
+        // lower uuid dials the higher uuid.
+        let dials: Vec<ConfigNode> = self
+            .nodes
+            .iter()
+            .filter(|(uuid, _)| **uuid > self.local.uuid)
+            .filter(|(uuid, _)| !self.peers.values().any(|p| p.to_uuid() == Some(**uuid)))
+            .map(|(_, config_node)| config_node.clone())
+            .collect();
+        for config_node in dials.into_iter() {
+            self.dial(registry, config_node);
+        }
+
+        self.update_view();
+    }
+
+    fn accept(&mut self, registry: &mio::Registry) {
+        loop {
+            match self.listener.accept() {
+                Ok((conn, addr)) => {
+                    debug!("{} accepted peer connection from {}", self.prefix, addr);
+                    self.add_peer(registry, conn, None);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    error!("{} accept peer connection: {}", self.prefix, err);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn dial(&mut self, registry: &mio::Registry, config_node: ConfigNode) {
+        let addr = match config_node.cluster_address {
+            Some(addr) => addr,
+            None => return,
+        };
+        let uuid = Node::try_from(config_node).ok().map(|n| n.uuid);
+        match TcpStream::connect(addr) {
+            Ok(conn) => self.add_peer(registry, conn, uuid),
+            Err(err) => debug!("{} dial {}: {}", self.prefix, addr, err),
+        }
+    }
+
+    fn add_peer(
+        &mut self,
+        registry: &mio::Registry,
+        conn: TcpStream,
+        dialed: Option<Uuid>,
+    ) {
+        let token = mio::Token(self.next_token);
+        self.next_token += 1;
+
+        let mut peer = Peer {
+            conn,
+            node: None,
+            hello: None,
+            nonce: rand::random(),
+            dialed,
+            epoch: 0,
+            rbuf: Vec::default(),
+            wbuf: Vec::default(),
+            max_wbuf_size: self.max_msg_size * Self::MAX_WBUF_MSGS,
+            failed: false,
+            last_seen: time::Instant::now(),
+        };
+
+        let interests = mio::Interest::READABLE | mio::Interest::WRITABLE;
+        if let Err(err) = registry.register(&mut peer.conn, token, interests) {
+            error!("{} register peer connection: {}", self.prefix, err);
+            return;
+        }
+        // for dialed connections this is buffered until the connection is established.
+        let node = self.local.clone();
+        let hello = Msg::Hello { epoch: self.epoch, node, nonce: peer.nonce };
+        match self.encode(&hello) {
+            Ok(data) => peer.send(&data).ok(),
+            Err(err) => {
+                error!("{} encode hello: {}", self.prefix, err);
+                return;
+            }
+        };
+
+        self.peers.insert(token, peer);
+    }
+
+    fn remove_peer(&mut self, registry: &mio::Registry, token: mio::Token) {
+        if let Some(mut peer) = self.peers.remove(&token) {
+            info!("{} peer {:?} disconnected", self.prefix, peer.to_uuid());
+            registry.deregister(&mut peer.conn).ok();
+        }
+    }
+
+    // return false if peer connection is to be closed.
+    fn read(&mut self, token: mio::Token) -> bool {
+        let msgs = match self.peers.get_mut(&token) {
+            Some(peer) => match peer.recv(self.max_msg_size) {
+                Ok(msgs) => msgs,
+                Err(err) => {
+                    debug!("{} peer {:?}: {}", self.prefix, peer.to_uuid(), err);
+                    return false;
+                }
+            },
+            None => return true,
+        };
+
+        for msg in msgs.into_iter() {
+            let peer = self.peers.get_mut(&token).unwrap();
+            match &msg {
+                Msg::Hello { .. } if peer.hello.is_some() || peer.node.is_some() => {
+                    warn!("{} peer {:?} repeats hello", self.prefix, peer.to_uuid());
+                    return false;
+                }
+                Msg::Hello { .. } | Msg::Auth { .. } | Msg::Ping { .. } => (),
+                _ if peer.node.is_none() => {
+                    warn!("{} peer {:?} not authenticated", self.prefix, peer.to_uuid());
+                    return false;
+                }
+                _ => (),
+            }
+
+            match msg {
+                Msg::Hello { node, .. } if !self.nodes.contains_key(&node.uuid) => {
+                    warn!("{} peer {} not in config.nodes", self.prefix, node.uuid);
+                    return false;
+                }
+                Msg::Hello { epoch, node, nonce } => {
+                    let mac = auth_mac(&self.secret, &nonce, &self.local.uuid);
+                    let data = match self.encode(&Msg::Auth { mac }) {
+                        Ok(data) => data,
+                        Err(err) => {
+                            error!("{} encode auth: {}", self.prefix, err);
+                            return false;
+                        }
+                    };
+                    let peer = self.peers.get_mut(&token).unwrap();
+                    peer.hello = Some((epoch, node));
+                    if peer.send(&data).is_err() {
+                        return false;
+                    }
+                }
+                Msg::Auth { mac } => {
+                    let peer = self.peers.get_mut(&token).unwrap();
+                    let (epoch, node) = match peer.hello.take() {
+                        Some((epoch, node)) if peer.node.is_none() => (epoch, node),
+                        Some(_) | None => {
+                            warn!(
+                                "{} peer {:?} unexpected auth",
+                                self.prefix, peer.dialed
+                            );
+                            return false;
+                        }
+                    };
+                    let input = auth_input(&peer.nonce, &node.uuid);
+                    if !hmac_sha256::HMAC::verify(input, &self.secret, &mac) {
+                        warn!("{} peer {} failed auth", self.prefix, node.uuid);
+                        return false;
+                    }
+
+                    // an older connection to the same node is stale, say after a
+                    // restart.
+                    self.peers.retain(|t, p| {
+                        *t == token || p.node.as_ref().map(|n| n.uuid) != Some(node.uuid)
+                    });
+                    info!("{} peer {} joined epoch:{}", self.prefix, node.uuid, epoch);
+                    self.joined.push(node.uuid);
+                    let peer = self.peers.get_mut(&token).unwrap();
+                    peer.node = Some(node);
+                    peer.epoch = epoch;
+
+                    // peer that has missed views, say after a restart, shall adopt
+                    // the latest view and topology instead of computing its own.
+                    let old_epoch = self.epoch;
+                    self.update_view();
+                    if self.epoch == old_epoch && self.epoch > epoch {
+                        let data = match self.encode(&self.to_view_msg()) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                error!("{} encode view: {}", self.prefix, err);
+                                return false;
+                            }
+                        };
+                        if self.peers.get_mut(&token).unwrap().send(&data).is_err() {
+                            return false;
+                        }
+                    }
+                }
+                Msg::View { epoch, nodes, topology } if epoch > self.epoch => {
+                    info!(
+                        "{} adopt view epoch:{} nodes:{} shards:{}",
+                        self.prefix,
+                        epoch,
+                        nodes.len(),
+                        topology.len()
+                    );
+                    self.epoch = epoch;
+                    self.viewed_at = time::Instant::now();
+                    self.view = nodes;
+                    self.topology = topology;
+                }
+                Msg::View { .. } => (),
+                Msg::Ping { epoch } if peer.node.is_some() => {
+                    peer.epoch = epoch;
+                    // peer that has missed a view, say while its connection with
+                    // the leader was down, shall catch up.
+                    if epoch < self.epoch && self.is_leader() {
+                        let data = match self.encode(&self.to_view_msg()) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                error!("{} encode view: {}", self.prefix, err);
+                                return false;
+                            }
+                        };
+                        if self.peers.get_mut(&token).unwrap().send(&data).is_err() {
+                            return false;
+                        }
+                    }
+                }
+                Msg::Ping { .. } => (),
+                msg @ (Msg::Reset { .. }
+                | Msg::Replicate { .. }
+                | Msg::Handover { .. }
+                | Msg::Ack { .. }) => self.replicated.push(msg),
+                msg @ (Msg::Forward { .. } | Msg::Subscriptions { .. }) => {
+                    self.routed.push(msg)
+                }
+            }
+        }
+
+        true
+    }
+
+    fn is_leader(&self) -> bool {
+        let uuid = self.local.uuid;
+        self.peers.values().filter_map(|p| p.node.as_ref()).all(|n| uuid < n.uuid)
+    }
+
+    // Leader shall recompute the view and topology, and broadcast them if changed.
+    fn update_view(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+        // wait for the newer view from peer, refer Msg::Hello.
+        if self.peers.values().any(|p| p.node.is_some() && p.epoch > self.epoch) {
+            return;
+        }
+
+        let mut nodes: Vec<Node> =
+            self.peers.values().filter_map(|p| p.node.clone()).collect();
+        nodes.push(self.local.clone());
+        nodes.sort_by_key(|n| n.uuid);
+        nodes.dedup_by_key(|n| n.uuid);
+
+        // leader of a minority, say in a network partition, shall not change the view
+        // nor promote replicas.
+        if !self.is_quorum(&nodes) {
+            return;
+        }
+        // leader that has just started, say after a restart, shall wait for the nodes
+        // in the adopted view to connect, before considering them failed.
+        let grace = self.started.elapsed() < self.peer_timeout;
+        if grace && self.view.iter().any(|n| !nodes.contains(n)) {
+            return;
+        }
+
+        let topology = match self.topology.is_empty() {
+            true if self.is_initial(&nodes) => {
+                match self.rebalancer.rebalance(&nodes, vec![]) {
+                    Ok(topology) => topology,
+                    Err(err) => {
+                        error!("{} rebalance: {}", self.prefix, err);
+                        return;
+                    }
+                }
+            }
+            true => Vec::default(),
+            false if self.is_settled(&nodes) && self.is_idle(&nodes) => {
+                self.rebalanced = self.epoch;
+                match self.rebalancer.rebalance(&nodes, self.topology.clone()) {
+                    Ok(topology) => {
+                        let diffs = rebalance::diff_topology(&self.topology, &topology);
+                        info!(
+                            "{} rebalance migrates {} shards",
+                            self.prefix,
+                            diffs.len()
+                        );
+                        topology
+                    }
+                    Err(err) => {
+                        error!("{} rebalance: {}", self.prefix, err);
+                        return;
+                    }
+                }
+            }
+            false => {
+                let topology = rebalance::promote_replicas(&self.topology, &nodes);
+                let config = &self.rebalancer.config;
+                rebalance::top_up_replicas(config, &topology, &nodes)
+            }
+        };
+
+        let uuids = |nodes: &[Node]| nodes.iter().map(|n| n.uuid).collect::<Vec<Uuid>>();
+        if uuids(&nodes) == uuids(&self.view) && topology == self.topology {
+            return;
+        }
+
+        self.epoch += 1;
+        self.viewed_at = time::Instant::now();
+        self.view = nodes;
+        self.topology = topology;
+        info!(
+            "{} new view epoch:{} nodes:{} shards:{}",
+            self.prefix,
+            self.epoch,
+            self.view.len(),
+            self.topology.len()
+        );
+
+        if let Err(err) = self.broadcast(&self.to_view_msg()) {
+            error!("{} broadcast view: {}", self.prefix, err);
+        }
+    }
+
+    // Return whether `nodes` are a majority of the configured nodes.
+    fn is_quorum(&self, nodes: &[Node]) -> bool {
+        (nodes.len() * 2) > (self.nodes.len() + 1)
+    }
+
+    // Return whether `nodes` are the current view, and all of them have adopted it
+    // at least a heartbeat ago, refer Msg::Ping. Rebalance shall wait until then, so
+    // that nodes see the view they joined in before the one that migrates shards.
+    fn is_settled(&self, nodes: &[Node]) -> bool {
+        let uuids = |nodes: &[Node]| nodes.iter().map(|n| n.uuid).collect::<Vec<Uuid>>();
+        let mut peers = self.peers.values().filter(|p| p.node.is_some());
+        uuids(nodes) == uuids(&self.view)
+            && self.viewed_at.elapsed() >= Self::HEARTBEAT
+            && peers.all(|p| p.epoch == self.epoch)
+    }
+
+    // Return whether any of the `nodes` host no shard in the current topology, say a
+    // node that has joined or re-joined, and a rebalance is yet to be considered for
+    // this epoch.
+    fn is_idle(&self, nodes: &[Node]) -> bool {
+        let hosts = |node: &Node| {
+            let mut iter = self.topology.iter();
+            iter.any(|t| &t.master == node || t.replicas.contains(node))
+        };
+        self.rebalanced < self.epoch && nodes.iter().any(|n| !hosts(n))
+    }
+
+    // Initial topology is computed once all the configured nodes have joined, or
+    // peer_timeout has elapsed since start.
+    fn is_initial(&self, nodes: &[Node]) -> bool {
+        nodes.len() == (self.nodes.len() + 1)
+            || self.started.elapsed() >= self.peer_timeout
+    }
+
+    fn to_view_msg(&self) -> Msg {
+        Msg::View {
+            epoch: self.epoch,
+            nodes: self.view.clone(),
+            topology: self.topology.clone(),
+        }
+    }
+
+    fn encode(&self, msg: &Msg) -> Result<Vec<u8>> {
+        let data = msg.encode()?;
+        if data.len() > self.max_msg_size {
+            err!(InvalidInput, desc: "{} message too large {}", self.prefix, data.len())?;
+        }
+        Ok(data)
+    }
+}
+
+impl Peer {
+    fn to_uuid(&self) -> Option<Uuid> {
+        self.node.as_ref().map(|n| n.uuid).or(self.dialed)
+    }
+
+    // Peer that fails, or is too slow to drain its buffer, is marked as failed.
+    fn send(&mut self, data: &[u8]) -> Result<()> {
+        if self.failed {
+            err!(Disconnected, desc: "peer failed")?;
+        }
+        if self.wbuf.len().saturating_add(data.len()) > self.max_wbuf_size {
+            self.failed = true;
+            err!(Disconnected, desc: "peer write buffer full {}", self.wbuf.len())?;
+        }
+        self.wbuf.extend_from_slice(data);
+        let res = self.flush();
+        self.failed = res.is_err();
+        res
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        while !self.wbuf.is_empty() {
+            match self.conn.write(&self.wbuf) {
+                Ok(0) => err!(Disconnected, desc: "peer closed")?,
+                Ok(n) => {
+                    self.wbuf.drain(..n);
+                }
+                // not yet connected, or socket buffer is full.
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::NotConnected => break,
+                Err(err) => err!(Disconnected, try: Err(err), "peer write")?,
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, max_msg_size: usize) -> Result<Vec<Msg>> {
+        let mut buf = [0_u8; 4096];
+        loop {
+            match self.conn.read(&mut buf) {
+                Ok(0) => err!(Disconnected, desc: "peer closed")?,
+                Ok(n) => self.rbuf.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::NotConnected => break,
+                Err(err) => err!(Disconnected, try: Err(err), "peer read")?,
+            }
+        }
+
+        let mut msgs = vec![];
+        let mut offset = 0;
+        while self.rbuf.len() - offset >= 8 {
+            let data = &self.rbuf[offset..];
+            let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            if len > max_msg_size {
+                err!(InvalidInput, desc: "peer message too large {}", len)?;
+            }
+            match decode_record(data) {
+                Some((op, body, n)) => {
+                    msgs.push(Msg::decode(op, body)?);
+                    offset += n;
+                }
+                None if data.len() >= 8 + len => {
+                    err!(InvalidInput, desc: "corrupt peer message")?;
+                }
+                None => break,
+            }
+        }
+        self.rbuf.drain(..offset);
+
+        if offset > 0 {
+            self.last_seen = time::Instant::now();
+        }
+        Ok(msgs)
+    }
+}
+
+fn encode_node(node: &Node, body: &mut Vec<u8>) -> Result<()> {
+    let path = match node.path.to_str() {
+        Some(path) => path.to_string(),
+        None => err!(InvalidInput, desc: "node path not utf8 {:?}", node.path)?,
+    };
+    let cluster_address = node.cluster_address.map(|a| a.to_string()).unwrap_or_default();
+
+    body.extend_from_slice(node.uuid.as_bytes());
+    node.weight.encode_into(body)?;
+    path.encode_into(body)?;
+    node.mqtt_address.to_string().encode_into(body)?;
+    cluster_address.encode_into(body)?;
+
+    Ok(())
+}
+
+fn decode_node(body: &[u8], n: &mut usize) -> Result<Node> {
+    let uuid = match body.get(*n..*n + 16) {
+        Some(bytes) => Uuid::from_slice(bytes).unwrap(),
+        None => err!(InsufficientBytes, desc: "node uuid")?,
+    };
+    *n += 16;
+
+    let weight: u16 = decode_field(body, n)?;
+    let path: String = decode_field(body, n)?;
+    let mqtt_address: String = decode_field(body, n)?;
+    let cluster_address: String = decode_field(body, n)?;
+
+    let mqtt_address = parse_address(&mqtt_address)?;
+    let cluster_address = match cluster_address.as_str() {
+        "" => None,
+        addr => Some(parse_address(addr)?),
+    };
+
+    Ok(Node {
+        uuid,
+        path: path.into(),
+        weight,
+        mqtt_address,
+        cluster_address,
+    })
+}
+
+fn encode_topology(topology: &Topology, body: &mut Vec<u8>) -> Result<()> {
+    body.extend_from_slice(&topology.shard.to_be_bytes());
+    encode_node(&topology.master, body)?;
+    body.extend_from_slice(&u32::try_from(topology.replicas.len())?.to_be_bytes());
+    for node in topology.replicas.iter() {
+        encode_node(node, body)?;
+    }
+    Ok(())
+}
+
+fn decode_topology(body: &[u8], n: &mut usize) -> Result<Topology> {
+    let shard = decode_field(body, n)?;
+    let master = decode_node(body, n)?;
+    let count: u32 = decode_field(body, n)?;
+    let mut replicas = vec![];
+    for _ in 0..count {
+        replicas.push(decode_node(body, n)?);
+    }
+    Ok(Topology { shard, master, replicas })
+}
+
+fn encode_message(msg: &Message, body: &mut Vec<u8>) -> Result<()> {
+    match msg {
+        Message::LocalAck { shard_id, last_received_ack } => {
+            body.push(Msg::MSG_LOCAL_ACK);
+            shard_id.encode_into(body)?;
+            body.extend_from_slice(&last_received_ack.to_be_bytes());
+        }
+        Message::Packet {
+            client_id,
+            shard_id,
+            seqno,
+            packet_id,
+            subscriptions,
+            packet: v5::Packet::Publish(publish),
+        } => {
+            body.push(Msg::MSG_PACKET);
+            shard_id.encode_into(body)?;
+            body.extend_from_slice(&seqno.to_be_bytes());
+            packet_id.encode_into(body)?;
+            client_id.encode_into(body)?;
+            u32::try_from(subscriptions.len())?.encode_into(body)?;
+            for subscription in subscriptions.iter() {
+                encode_subscription(subscription, body)?;
+            }
+            publish.encode_into(body)?;
+        }
+        _ => err!(InvalidInput, desc: "message cannot be forwarded")?,
+    }
+    Ok(())
+}
+
+fn decode_message(body: &[u8], n: &mut usize) -> Result<Message> {
+    let msg = match decode_field::<u8>(body, n)? {
+        Msg::MSG_LOCAL_ACK => Message::LocalAck {
+            shard_id: decode_field(body, n)?,
+            last_received_ack: decode_u64(body, n)?,
+        },
+        Msg::MSG_PACKET => {
+            let shard_id = decode_field(body, n)?;
+            let seqno = decode_u64(body, n)?;
+            let packet_id = decode_field(body, n)?;
+            let client_id = decode_field(body, n)?;
+            let count: u32 = decode_field(body, n)?;
+            let mut subscriptions = vec![];
+            for _ in 0..count {
+                subscriptions.push(decode_subscription(body, n)?);
+            }
+            let publish: v5::Publish = decode_field(body, n)?;
+            Message::Packet {
+                client_id,
+                shard_id,
+                seqno,
+                packet_id,
+                subscriptions,
+                packet: v5::Packet::Publish(publish),
+            }
+        }
+        kind => err!(InvalidInput, desc: "invalid forwarded message {}", kind)?,
+    };
+    Ok(msg)
+}
+
+// Input authenticated by AUTH, refer to module documentation.
+fn auth_input(nonce: &[u8; 16], uuid: &Uuid) -> Vec<u8> {
+    let mut input = b"mqtr-cluster".to_vec();
+    input.extend_from_slice(nonce);
+    input.extend_from_slice(uuid.as_bytes());
+    input
+}
+
+fn auth_mac(secret: &str, nonce: &[u8; 16], uuid: &Uuid) -> [u8; 32] {
+    hmac_sha256::HMAC::mac(auth_input(nonce, uuid), secret)
+}
+
+fn parse_address(addr: &str) -> Result<net::SocketAddr> {
+    err!(InvalidInput, try: addr.parse::<net::SocketAddr>(), "address {:?}", addr)
+}
+
+#[cfg(test)]
+#[path = "membership_test.rs"]
+mod membership_test;
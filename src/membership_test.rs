@@ -0,0 +1,157 @@
+use super::*;
+
+use crate::fixtures::{client_id, new_subscription};
+use crate::TopicName;
+
+#[test]
+fn test_membership_msg() {
+    let node = |n: u16| Node {
+        uuid: Uuid::new_v4(),
+        path: format!("/zone-{}", n).into(),
+        weight: n,
+        mqtt_address: format!("127.0.0.1:{}", 1883 + n).parse().unwrap(),
+        cluster_address: Some(format!("127.0.0.1:{}", 7000 + n).parse().unwrap()),
+    };
+    let mut standalone = node(3);
+    standalone.cluster_address = None;
+    let (n1, n2) = (node(1), node(2));
+    let topology = vec![
+        Topology {
+            shard: 0,
+            master: n1.clone(),
+            replicas: vec![n2.clone()],
+        },
+        Topology { shard: 1, master: n2.clone(), replicas: vec![] },
+    ];
+    let publish = v5::Publish {
+        retain: false,
+        qos: v5::QoS::AtLeastOnce,
+        duplicate: false,
+        topic_name: TopicName::from("a/b".to_string()),
+        packet_id: Some(7),
+        properties: None,
+        payload: Some(b"hello".to_vec().into()),
+    };
+
+    let msgs = vec![
+        Msg::Hello { epoch: 10, node: n1.clone(), nonce: [7; 16] },
+        Msg::Auth { mac: [9; 32] },
+        Msg::View {
+            epoch: 11,
+            nodes: vec![n1.clone(), n2.clone(), standalone],
+            topology,
+        },
+        Msg::View { epoch: 12, nodes: vec![], topology: vec![] },
+        Msg::Ping { epoch: 12 },
+        Msg::Reset { shard: 3, seqno: 1, count: 2 },
+        Msg::Replicate {
+            shard: 3,
+            seqno: 2,
+            record: wal::Record::Remove { client_id: client_id("client-1") },
+        },
+        Msg::Forward {
+            shard: 1,
+            msgs: vec![
+                Message::Packet {
+                    client_id: client_id("client-1"),
+                    shard_id: 0,
+                    seqno: 3,
+                    packet_id: 7,
+                    subscriptions: vec![v5::Subscription {
+                        shard_id: 1,
+                        ..new_subscription("client-2", "a/#")
+                    }],
+                    packet: v5::Packet::Publish(publish),
+                },
+                Message::LocalAck { shard_id: 1, last_received_ack: 2 },
+            ],
+        },
+        Msg::Subscriptions {
+            shard: 0,
+            subscriptions: vec![
+                new_subscription("client-1", "a/b"),
+                new_subscription("client-3", "+/b"),
+            ],
+        },
+        Msg::Handover { shard: 3, seqno: 9 },
+        Msg::Ack { shard: 3, seqno: 8 },
+    ];
+    for msg in msgs.into_iter() {
+        let data = msg.encode().unwrap();
+        let (op, body, n) = decode_record(&data).unwrap();
+        assert_eq!(n, data.len());
+        let val = Msg::decode(op, body).unwrap();
+        match (&msg, &val) {
+            (Msg::Hello { node: a, .. }, Msg::Hello { node: b, .. }) => {
+                assert_eq!(a.path, b.path);
+                assert_eq!(a.weight, b.weight);
+                assert_eq!(a.mqtt_address, b.mqtt_address);
+                assert_eq!(a.cluster_address, b.cluster_address);
+            }
+            (Msg::View { nodes: a, .. }, Msg::View { nodes: b, .. }) => {
+                let addrs = |ns: &[Node]| -> Vec<_> {
+                    ns.iter().map(|n| (n.cluster_address, n.weight)).collect()
+                };
+                assert_eq!(addrs(a), addrs(b));
+            }
+            (
+                Msg::Subscriptions { subscriptions: a, .. },
+                Msg::Subscriptions { subscriptions: b, .. },
+            ) => {
+                let ids = |ss: &[v5::Subscription]| -> Vec<u32> {
+                    ss.iter().map(|s| s.shard_id).collect()
+                };
+                assert_eq!(ids(a), ids(b));
+            }
+            _ => (),
+        }
+        assert!(msg == val);
+    }
+
+    // only PUBLISH packets and LocalAck are forwarded.
+    let ack = Message::new_client_ack(v5::Packet::PingResp);
+    assert!(Msg::Forward { shard: 0, msgs: vec![ack] }.encode().is_err());
+
+    assert!(Msg::decode(11, &[]).is_err());
+    assert!(Msg::decode(Msg::OP_HELLO, &[0; 4]).is_err());
+}
+
+#[test]
+fn test_peer_wbuf_limit() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let conn = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    conn.set_nonblocking(true).unwrap();
+    // peer at the other end never reads.
+    let (_other, _) = listener.accept().unwrap();
+
+    let mut peer = Peer {
+        conn: TcpStream::from_std(conn),
+        node: None,
+        dialed: None,
+        hello: None,
+        nonce: [0; 16],
+        epoch: 0,
+        rbuf: Vec::default(),
+        wbuf: Vec::default(),
+        max_wbuf_size: 1024 * 1024,
+        failed: false,
+        last_seen: time::Instant::now(),
+    };
+
+    let data = vec![0xAB_u8; 128 * 1024];
+    let n = (0..1024).take_while(|_| peer.send(&data).is_ok()).count();
+    assert!(n < 1024);
+    assert!(peer.failed);
+    assert!(peer.wbuf.len() <= peer.max_wbuf_size);
+    assert!(peer.send(&data[..1]).is_err());
+}
+
+#[test]
+fn test_auth_mac() {
+    let (nonce, uuid) = (rand::random::<[u8; 16]>(), Uuid::new_v4());
+    let mac = auth_mac("secret", &nonce, &uuid);
+    assert!(hmac_sha256::HMAC::verify(auth_input(&nonce, &uuid), "secret", &mac));
+    assert!(!hmac_sha256::HMAC::verify(auth_input(&nonce, &uuid), "other", &mac));
+    let uuid2 = Uuid::new_v4();
+    assert!(!hmac_sha256::HMAC::verify(auth_input(&nonce, &uuid2), "secret", &mac));
+}
@@ -143,8 +143,20 @@ pub struct ClientOut {
     pub back_log: VecDeque<Message>,
 }
 
+impl Default for ClientOut {
+    fn default() -> ClientOut {
+        ClientOut {
+            seqno: 1,
+            index: BTreeMap::default(),
+            next_packet_id: 1,
+            back_log: VecDeque::default(),
+        }
+    }
+}
+
 impl ClientInp {
-    pub fn remove_session(&mut self, client_id: &ClientID) {
+    /// Remove un-acked messages received from `client_id`, return their seqno.
+    pub fn remove_session(&mut self, client_id: &ClientID) -> Vec<u64> {
         let mut remove_seqnos = Vec::new();
         for (seqno, msg) in self.unacks.iter() {
             if msg.as_client_id() == client_id {
@@ -152,14 +164,16 @@ impl ClientInp {
             }
         }
 
-        for seqno in remove_seqnos.into_iter() {
-            self.unacks.remove(&seqno);
+        for seqno in remove_seqnos.iter() {
+            self.unacks.remove(seqno);
         }
+
+        remove_seqnos
     }
 }
 
 /// Message is a unit of communication between shards hosted on the same node.
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Message {
     /// Message that is periodically published by a session to other local shards.
     LocalAck {
@@ -201,7 +215,17 @@ impl Message {
     /// Packet variant, shall panic otherwise.
     pub fn as_client_id(&self) -> &ClientID {
         match self {
-            Message::Packet { subscriptions, .. } => &subscriptions[0].client_id,
+            Message::Packet { client_id, .. } => client_id,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Return whether this message is routed to subscribing sessions, as apposed to
+    /// messages already converted via [Message::cout_publish]. Only applicable to
+    /// Packet variant, shall panic otherwise.
+    pub fn is_routed(&self) -> bool {
+        match self {
+            Message::Packet { subscriptions, .. } => !subscriptions.is_empty(),
             _ => unreachable!(),
         }
     }
@@ -209,7 +233,7 @@ impl Message {
     /// Convert this message into actual packets that can be send to subscribed clients.
     ///
     /// a. If there are multiple subscriptions, generate a publish-message for each
-    ///    subscription. TODO: there is scope for optimization.
+    ///    subscription, all of them sharing the same payload buffer.
     /// b. Use appropriate seqno/packet-id specific to this session.
     /// c. Adjust the qos based on server_qos and subscription_qos.
     /// d. Adjust the retain flag based on subscription's retain-as-published flag.
@@ -243,7 +267,14 @@ impl Message {
 
             let (seqno, packet_id) = sess.incr_cout_seqno();
 
-            publish.set_fixed_header(retain, qos, false).set_packet_id(packet_id);
+            publish.topic_name = sess.unmount_topic_name(&publish.topic_name);
+            publish.set_fixed_header(retain, qos, false);
+            match qos {
+                v5::QoS::AtMostOnce => publish.packet_id = None,
+                _ => {
+                    publish.set_packet_id(packet_id);
+                }
+            }
             publish.add_subscription_id(subscr.subscription_id);
 
             let msg = Message::Packet {
@@ -6,7 +6,8 @@ use std::{mem, net, time};
 
 use crate::packet::{MQTTRead, MQTTWrite};
 use crate::thread::{Rx, Thread, Threadable};
-use crate::{socket, AppTx, ClientID, Config, QueueStatus, Shard, Socket};
+use crate::Stream;
+use crate::{socket, AppTx, ClientID, Config, ConnGuard, QueueStatus, Shard, Socket};
 use crate::{Error, ErrorKind, Result};
 
 type ThreadRx = Rx<Request, Result<Response>>;
@@ -167,11 +168,12 @@ pub enum Response {
 
 pub struct AddConnectionArgs {
     pub client_id: ClientID,
-    pub conn: mio::net::TcpStream,
+    pub conn: Stream,
     pub addr: net::SocketAddr,
     pub upstream: socket::PktTx,
     pub downstream: socket::PktRx,
     pub client_max_packet_size: u32,
+    pub guard: ConnGuard,
 }
 
 // calls to interface with miot-thread, and shall wake the thread
@@ -420,7 +422,8 @@ impl Miot {
             packets: VecDeque::default(),
         };
         let (client_id, conn, addr) = (args.client_id.clone(), args.conn, args.addr);
-        let socket = socket::Socket { client_id, conn, addr, token, rd, wt };
+        let guard = args.guard;
+        let socket = socket::Socket { client_id, conn, addr, token, rd, wt, guard };
         conns.insert(args.client_id, socket);
 
         Response::Ok
@@ -0,0 +1,446 @@
+//! Module implement offline queues for persistent sessions.
+//!
+//! While the client of a persistent session is offline, QoS>0 PUBLISH messages
+//! matching its subscriptions are queued in an [OfflineQueue], held by the shard along
+//! with the session state. Queue is bounded by [Config::offline_queue_max_messages]
+//! and [Config::offline_queue_max_bytes], once the limit is reached messages are
+//! dropped as per [Config::offline_queue_overflow]. Messages whose
+//! `message_expiry_interval` has elapsed are trimmed from the queue.
+//!
+//! When the client reconnects with clean-start as ZERO, queued messages are delivered
+//! in the order they were received, without exceeding client's receive-maximum.
+//!
+//! When [Config::offline_queue_spill_dir] is configured, queued messages are written
+//! to a spill file, one for each queue, using the same record framing as
+//! [RetainLog][crate::RetainLog]. Spill files are scratch space, they are removed
+//! along with the queue and when the shard is spawned.
+//!
+//! For durable shards, every message queued and removed is journaled as
+//! [Record::Queued] and [Record::Dequeued], refer [OfflineQueue::take_journal], and
+//! appended to the session log. When the shard is re-spawned the queue is rebuilt from
+//! the log, refer [OfflineQueue::restore].
+
+use log::{error, warn};
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::{fs, mem, path};
+
+use crate::config::OverflowPolicy;
+use crate::store::{decode_record, encode_record};
+use crate::wal::Record;
+use crate::{v5, ClientID, Config, Message, PacketID};
+use crate::{Error, ErrorKind, Result};
+
+/// Type implement a bounded queue of routed messages, for an offline session.
+pub struct OfflineQueue {
+    prefix: String,
+    shard_id: u32,
+    client_id: ClientID,
+    max_messages: usize,
+    max_bytes: usize,
+    overflow: OverflowPolicy,
+    spill_dir: Option<path::PathBuf>,
+
+    entries: VecDeque<Entry>,
+    // sum of `Entry::size`, for all entries.
+    bytes: usize,
+    // messages were dropped under OverflowPolicy::Disconnect
+    overflowed: bool,
+    // created lazily, on the first message.
+    spill: Option<Spill>,
+    // index for the next queued message.
+    index: u64,
+    // records to be appended to the session log, None if shard is not durable.
+    journal: Option<Vec<Record>>,
+}
+
+struct Entry {
+    // index of the message in the queue, refer Record::Queued.
+    index: u64,
+    // seconds since UNIX_EPOCH, when the message was queued.
+    timestamp: u64,
+    // message-expiry-interval of the PUBLISH, in seconds.
+    expiry: Option<u32>,
+    size: usize,
+    value: Value,
+}
+
+enum Value {
+    Memory(Box<Message>),
+    // routing shard, and (offset, length) of the record in the spill file.
+    Disk(u32, u64, u64),
+}
+
+struct Spill {
+    loc: path::PathBuf,
+    file: fs::File,
+    size: u64,
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(&self.loc) {
+            error!("{:?} remove spill file: {}", self.loc, err);
+        }
+    }
+}
+
+impl OfflineQueue {
+    /// Create a new queue for `client_id`'s session hosted by shard `shard_id`.
+    /// Queue operations are journaled if `durable` is true.
+    pub fn new(
+        config: &Config,
+        shard_id: u32,
+        client_id: &ClientID,
+        durable: bool,
+    ) -> OfflineQueue {
+        OfflineQueue {
+            prefix: format!("offline:{}:{}", shard_id, **client_id),
+            shard_id,
+            client_id: client_id.clone(),
+            max_messages: config.offline_queue_max_messages() as usize,
+            max_bytes: config.offline_queue_max_bytes() as usize,
+            overflow: config.offline_queue_overflow(),
+            spill_dir: config.offline_queue_spill_dir().map(|d| d.to_path_buf()),
+
+            entries: VecDeque::default(),
+            bytes: 0,
+            overflowed: false,
+            spill: None,
+            index: 0,
+            journal: if durable { Some(Vec::default()) } else { None },
+        }
+    }
+
+    /// Remove spill files left behind by shard `shard_id`, from a previous run.
+    pub fn remove_spill_files(config: &Config, shard_id: u32) -> Result<()> {
+        let dir = match config.offline_queue_spill_dir() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        if !dir.exists() {
+            return Ok(());
+        }
+
+        let prefix = format!("shard-{}-", shard_id);
+        for entry in err!(IOError, try: fs::read_dir(dir), "read_dir {:?}", dir)? {
+            let loc = err!(IOError, try: entry, "read_dir {:?}", dir)?.path();
+            let name = loc.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if name.starts_with(&prefix) && name.ends_with(".queue") {
+                err!(IOError, try: fs::remove_file(&loc), "remove {:?}", loc)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the number of messages in the queue.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return the size of messages in the queue, in bytes.
+    pub fn to_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Return whether messages were dropped under [OverflowPolicy::Disconnect].
+    pub fn is_overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Clear the overflowed state, once the client is notified.
+    pub fn clear_overflowed(&mut self) {
+        self.overflowed = false
+    }
+
+    /// Take records journaled since the last call, to be appended to session log.
+    pub fn take_journal(&mut self) -> Vec<Record> {
+        match self.journal.as_mut() {
+            Some(journal) => mem::take(journal),
+            None => Vec::default(),
+        }
+    }
+
+    /// Return [Record::Queued] for every message in the queue, to checkpoint the
+    /// session log.
+    pub fn to_records(&self) -> Vec<Record> {
+        let mut records = Vec::default();
+        for entry in self.entries.iter() {
+            match self.load_message(&entry.value) {
+                Ok(msg) => records.extend(Record::from_queued(
+                    entry.index,
+                    entry.timestamp,
+                    &msg,
+                )),
+                Err(err) => error!("{} load spilled message: {}", self.prefix, err),
+            }
+        }
+        records
+    }
+
+    /// Restore a message replayed from the session log, queued as `index` at
+    /// `timestamp`. Restored messages are neither journaled nor subject to limits.
+    pub fn restore(&mut self, index: u64, timestamp: u64, msg: Message) {
+        let (size, expiry) = msg_size_expiry(&msg);
+        let value = self.new_value(msg);
+        self.entries.push_back(Entry { index, timestamp, expiry, size, value });
+        self.bytes += size;
+        self.index = self.index.max(index + 1);
+    }
+
+    /// Queue a routed message at `now`, seconds since UNIX_EPOCH. Subscriptions with
+    /// QoS-0 are ignored. Return the number of messages dropped, including `msg`.
+    pub fn push(&mut self, msg: Message, now: u64) -> usize {
+        let msg = match filter_qos0(msg) {
+            Some(msg) => msg,
+            None => return 0,
+        };
+        let (size, expiry) = msg_size_expiry(&msg);
+
+        let mut dropped = self.trim(now);
+        loop {
+            let full = self.entries.len() >= self.max_messages
+                || (self.bytes + size) > self.max_bytes;
+            match self.overflow {
+                _ if !full => break,
+                OverflowPolicy::DropOldest if !self.entries.is_empty() => {
+                    self.pop_entry();
+                    if self.entries.is_empty() {
+                        self.reset_spill();
+                    }
+                    dropped += 1;
+                }
+                OverflowPolicy::DropOldest | OverflowPolicy::DropNewest => {
+                    return dropped + 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    self.overflowed = true;
+                    return dropped + 1;
+                }
+            }
+        }
+
+        let index = self.index;
+        self.index += 1;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.extend(Record::from_queued(index, now, &msg));
+        }
+        let value = self.new_value(msg);
+        self.entries.push_back(Entry { index, timestamp: now, expiry, size, value });
+        self.bytes += size;
+
+        dropped
+    }
+
+    /// Pop the oldest message that has not expired at `now`. Message-expiry-interval
+    /// of the returned PUBLISH is reduced by the time it spent in the queue.
+    pub fn pop(&mut self, now: u64) -> Option<Message> {
+        loop {
+            let entry = self.pop_entry()?;
+            if entry.is_expired(now) {
+                continue;
+            }
+
+            let res = self.load_message(&entry.value);
+            if self.entries.is_empty() {
+                self.reset_spill();
+            }
+            let mut msg = match res {
+                Ok(msg) => msg,
+                Err(err) => {
+                    error!("{} load spilled message: {}", self.prefix, err);
+                    continue;
+                }
+            };
+            if let Some(expiry) = entry.expiry {
+                let elapsed = now.saturating_sub(entry.timestamp);
+                let expiry = u64::from(expiry).saturating_sub(elapsed) as u32;
+                if let Message::Packet { packet: v5::Packet::Publish(publ), .. } =
+                    &mut msg
+                {
+                    if let Some(props) = publ.properties.as_mut() {
+                        props.message_expiry_interval = Some(expiry);
+                    }
+                }
+            }
+            break Some(msg);
+        }
+    }
+
+    /// Remove messages that have expired at `now`, seconds since UNIX_EPOCH. Return
+    /// the number of messages removed.
+    pub fn trim(&mut self, now: u64) -> usize {
+        let n = self.entries.len();
+        let mut expired = vec![];
+        self.entries.retain(|entry| match entry.is_expired(now) {
+            true => {
+                expired.push(entry.index);
+                false
+            }
+            false => true,
+        });
+        expired.into_iter().for_each(|index| self.dequeued(index));
+        self.bytes = self.entries.iter().map(|e| e.size).sum();
+        if self.entries.is_empty() {
+            self.reset_spill();
+        }
+
+        n - self.entries.len()
+    }
+}
+
+impl OfflineQueue {
+    fn pop_entry(&mut self) -> Option<Entry> {
+        let entry = self.entries.pop_front()?;
+        self.bytes -= entry.size;
+        self.dequeued(entry.index);
+        Some(entry)
+    }
+
+    fn dequeued(&mut self, index: u64) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(Record::Dequeued { client_id: self.client_id.clone(), index });
+        }
+    }
+
+    fn new_value(&mut self, msg: Message) -> Value {
+        match self.spill_message(&msg) {
+            Ok(Some((fpos, len))) => Value::Disk(msg_shard_id(&msg), fpos, len),
+            Ok(None) => Value::Memory(Box::new(msg)),
+            Err(err) => {
+                error!("{} spill message: {}", self.prefix, err);
+                Value::Memory(Box::new(msg))
+            }
+        }
+    }
+
+    // Return (offset, length) of the record, None if spilling is not configured.
+    fn spill_message(&mut self, msg: &Message) -> Result<Option<(u64, u64)>> {
+        let dir = match &self.spill_dir {
+            Some(dir) => dir,
+            None => return Ok(None),
+        };
+
+        if self.spill.is_none() {
+            err!(IOError, try: fs::create_dir_all(dir), "create spill dir {:?}", dir)?;
+            let name = format!("shard-{}-{}.queue", self.shard_id, uuid::Uuid::new_v4());
+            let loc = dir.join(name);
+            let mut opts = fs::OpenOptions::new();
+            let file = opts.read(true).append(true).create(true).open(&loc);
+            let file = err!(IOError, try: file, "open spill file {:?}", loc)?;
+            self.spill = Some(Spill { loc, file, size: 0 });
+        }
+        let spill = self.spill.as_mut().unwrap();
+
+        let (op, body) = Record::from_unack(msg).unwrap().encode()?;
+        let data = encode_record(op, &body)?;
+        err!(IOError, try: spill.file.write_all(&data), "append {:?}", spill.loc)?;
+
+        let fpos = spill.size;
+        spill.size += data.len() as u64;
+        Ok(Some((fpos, data.len() as u64)))
+    }
+
+    fn load_message(&self, value: &Value) -> Result<Message> {
+        let (shard_id, fpos, len) = match value {
+            Value::Memory(msg) => return Ok(msg.as_ref().clone()),
+            Value::Disk(shard_id, fpos, len) => (*shard_id, *fpos, *len),
+        };
+        let spill = self.spill.as_ref().unwrap();
+
+        let mut data = vec![0; len as usize];
+        err!(IOError, try: spill.file.read_exact_at(&mut data, fpos), "{:?}", spill.loc)?;
+        let (op, body, _) = match decode_record(&data) {
+            Some(val) => val,
+            None => {
+                err!(InvalidInput, desc: "{:?} corrupt record at {}", spill.loc, fpos)?
+            }
+        };
+        match Record::decode(op, body)? {
+            Record::Unack { seqno, client_id, subscriptions, publish } => {
+                Ok(Message::Packet {
+                    client_id,
+                    shard_id,
+                    seqno,
+                    packet_id: PacketID::default(),
+                    subscriptions,
+                    packet: v5::Packet::Publish(publish),
+                })
+            }
+            _ => {
+                err!(InvalidInput, desc: "{:?} unexpected record at {}", spill.loc, fpos)
+            }
+        }
+    }
+
+    // Spill file is truncated once the queue is drained.
+    fn reset_spill(&mut self) {
+        if let Some(spill) = self.spill.as_mut() {
+            if spill.size > 0 {
+                match spill.file.set_len(0) {
+                    Ok(()) => spill.size = 0,
+                    Err(err) => warn!("{:?} truncate spill file: {}", spill.loc, err),
+                }
+            }
+        }
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        match self.expiry {
+            Some(secs) => now >= self.timestamp.saturating_add(secs.into()),
+            None => false,
+        }
+    }
+}
+
+// Return `msg` with only QoS>0 subscriptions, None if nothing is left to queue.
+fn filter_qos0(mut msg: Message) -> Option<Message> {
+    match &mut msg {
+        Message::Packet {
+            subscriptions, packet: v5::Packet::Publish(publ), ..
+        } => {
+            if publ.qos == v5::QoS::AtMostOnce {
+                return None;
+            }
+            subscriptions.retain(|s| s.qos != v5::QoS::AtMostOnce);
+            match subscriptions.is_empty() {
+                true => None,
+                false => Some(msg),
+            }
+        }
+        _ => None,
+    }
+}
+
+// Return (size, message-expiry-interval) of a routed PUBLISH.
+fn msg_size_expiry(msg: &Message) -> (usize, Option<u32>) {
+    match msg {
+        Message::Packet { packet: v5::Packet::Publish(publ), .. } => {
+            let size = publ.topic_name.len()
+                + publ.payload.as_ref().map(|p| p.len()).unwrap_or(0);
+            let expiry = publ.properties.as_ref().and_then(|p| p.message_expiry_interval);
+            (size, expiry)
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn msg_shard_id(msg: &Message) -> u32 {
+    match msg {
+        Message::Packet { shard_id, .. } => *shard_id,
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+#[path = "offline_test.rs"]
+mod offline_test;
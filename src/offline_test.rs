@@ -0,0 +1,178 @@
+use super::*;
+
+use crate::{TopicFilter, TopicName};
+
+fn new_message(seqno: u64, qos: v5::QoS, expiry: Option<u32>) -> Message {
+    let subscription = v5::Subscription {
+        topic_filter: TopicFilter::from("x/+".to_string()),
+        client_id: ClientID("sub".to_string()),
+        shard_id: 0,
+        subscription_id: None,
+        qos: v5::QoS::AtLeastOnce,
+        no_local: false,
+        retain_as_published: false,
+        retain_forward_rule: v5::RetainForwardRule::OnEverySubscribe,
+    };
+    let properties = expiry.map(|secs| v5::PublishProperties {
+        message_expiry_interval: Some(secs),
+        ..v5::PublishProperties::default()
+    });
+    let publish = v5::Publish {
+        retain: false,
+        qos,
+        duplicate: false,
+        topic_name: TopicName::from("x/1".to_string()),
+        packet_id: Some(1),
+        properties,
+        payload: Some(format!("{:06}", seqno).into_bytes().into()),
+    };
+    Message::Packet {
+        client_id: ClientID("pub".to_string()),
+        shard_id: 1,
+        seqno,
+        packet_id: PacketID::default(),
+        subscriptions: vec![subscription],
+        packet: v5::Packet::Publish(publish),
+    }
+}
+
+fn seqnos(queue: &mut OfflineQueue, now: u64) -> Vec<u64> {
+    let mut seqnos = vec![];
+    while let Some(msg) = queue.pop(now) {
+        match msg {
+            Message::Packet { seqno, shard_id: 1, .. } => seqnos.push(seqno),
+            _ => unreachable!(),
+        }
+    }
+    seqnos
+}
+
+#[test]
+fn test_offline_queue() {
+    let client_id = ClientID("sub".to_string());
+    let qos1 = v5::QoS::AtLeastOnce;
+    // every message is 9 bytes, topic-name and payload.
+    let mut config = Config {
+        offline_queue_max_messages: Some(4),
+        offline_queue_max_bytes: Some(27),
+        ..Config::default()
+    };
+
+    let mut queue = OfflineQueue::new(&config, 0, &client_id, false);
+    assert_eq!(queue.push(new_message(1, v5::QoS::AtMostOnce, None), 0), 0);
+    assert!(queue.is_empty());
+    for seqno in 1..=5 {
+        queue.push(new_message(seqno, qos1, None), 0);
+    }
+    assert_eq!((queue.len(), queue.to_bytes()), (3, 27));
+    assert_eq!(seqnos(&mut queue, 0), vec![3, 4, 5]);
+
+    config.offline_queue_overflow = Some(OverflowPolicy::DropNewest);
+    let mut queue = OfflineQueue::new(&config, 0, &client_id, false);
+    let dropped: usize = (1..=5).map(|n| queue.push(new_message(n, qos1, None), 0)).sum();
+    assert_eq!(dropped, 2);
+    assert!(!queue.is_overflowed());
+    assert_eq!(seqnos(&mut queue, 0), vec![1, 2, 3]);
+
+    config.offline_queue_overflow = Some(OverflowPolicy::Disconnect);
+    let mut queue = OfflineQueue::new(&config, 0, &client_id, false);
+    (1..=3).for_each(|n| assert_eq!(queue.push(new_message(n, qos1, None), 0), 0));
+    assert!(!queue.is_overflowed());
+    assert_eq!(queue.push(new_message(4, qos1, None), 0), 1);
+    assert!(queue.is_overflowed());
+
+    // expired messages are trimmed, and expiry is reduced for the rest.
+    let mut queue = OfflineQueue::new(&config, 0, &client_id, false);
+    queue.push(new_message(1, qos1, Some(10)), 100);
+    queue.push(new_message(2, qos1, Some(30)), 100);
+    queue.push(new_message(3, qos1, None), 100);
+    assert_eq!(queue.trim(110), 1);
+    match queue.pop(120) {
+        Some(Message::Packet { seqno: 2, packet: v5::Packet::Publish(publ), .. }) => {
+            let props = publ.properties.unwrap();
+            assert_eq!(props.message_expiry_interval, Some(10));
+        }
+        _ => panic!("unexpected message"),
+    }
+    assert_eq!(seqnos(&mut queue, 120), vec![3]);
+}
+
+#[test]
+fn test_offline_queue_spill() {
+    let dir = std::env::temp_dir().join(format!("offline-{}", uuid::Uuid::new_v4()));
+    let client_id = ClientID("sub".to_string());
+    let qos1 = v5::QoS::AtLeastOnce;
+    let config = Config {
+        offline_queue_max_messages: Some(3),
+        offline_queue_spill_dir: Some(dir.clone()),
+        ..Config::default()
+    };
+
+    let spill_files = || fs::read_dir(&dir).unwrap().count();
+    {
+        let mut queue = OfflineQueue::new(&config, 2, &client_id, false);
+        for seqno in 1..=4 {
+            queue.push(new_message(seqno, qos1, None), 0);
+        }
+        assert!(matches!(queue.entries[0].value, Value::Disk(1, _, _)));
+        assert_eq!(spill_files(), 1);
+        assert_eq!(seqnos(&mut queue, 0), vec![2, 3, 4]);
+        assert_eq!(queue.spill.as_ref().unwrap().size, 0);
+
+        queue.push(new_message(5, qos1, None), 0);
+        assert_eq!(seqnos(&mut queue, 0), vec![5]);
+    }
+    assert_eq!(spill_files(), 0);
+
+    fs::write(dir.join("shard-2-stale.queue"), b"stale").unwrap();
+    OfflineQueue::remove_spill_files(&config, 2).unwrap();
+    assert_eq!(spill_files(), 0);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_offline_queue_journal() {
+    let client_id = ClientID("sub".to_string());
+    let qos1 = v5::QoS::AtLeastOnce;
+    let config = Config {
+        offline_queue_max_messages: Some(3),
+        ..Config::default()
+    };
+
+    let mut queue = OfflineQueue::new(&config, 0, &client_id, true);
+    for seqno in 1..=4 {
+        queue.push(new_message(seqno, qos1, None), 0);
+    }
+    queue.push(new_message(5, qos1, Some(10)), 0);
+    queue.pop(0);
+    queue.trim(20);
+
+    let mut records = vec![Record::Session {
+        client_id: client_id.clone(),
+        session_expiry_interval: 3600,
+    }];
+    records.extend(queue.take_journal());
+    assert!(queue.take_journal().is_empty());
+    let n_dequeued =
+        records.iter().filter(|r| matches!(r, Record::Dequeued { .. })).count();
+    // two dropped as oldest, one popped and one expired.
+    assert_eq!(n_dequeued, 4);
+
+    let replay = crate::wal::Replay::from_records(0, records);
+    let queued: Vec<Record> = replay
+        .to_records()
+        .into_iter()
+        .filter(|r| matches!(r, Record::Queued { .. }))
+        .collect();
+    assert_eq!(queued, queue.to_records());
+
+    let mut restored = OfflineQueue::new(&config, 0, &client_id, true);
+    for (index, (timestamp, msg)) in replay.queued[&client_id].clone().into_iter() {
+        restored.restore(index, timestamp, msg);
+    }
+    assert!(restored.take_journal().is_empty());
+    assert_eq!(restored.to_records(), queue.to_records());
+    assert_eq!(restored.index, 4);
+    assert_eq!(seqnos(&mut restored, 20), vec![4]);
+}
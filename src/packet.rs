@@ -1,7 +1,7 @@
 use log::error;
-use std::{io, thread, time};
+use std::{io, mem, thread, time};
 
-use crate::{v5, Packetize, VarU32};
+use crate::{v5, Bytes, Packetize, Stream, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
 /// Type implement a state machine to asynchronously read from socket using [mio].
@@ -36,13 +36,27 @@ impl Default for MQTTRead {
 }
 
 impl MQTTRead {
+    /// Maximum size of fixed-header, 1 byte of packet-type and flags, and upto 4
+    /// bytes of remaining-length.
+    pub const HEADER_SIZE: usize = 5;
+
+    /// Read buffer is sized for the fixed-header and grown to the packet size after
+    /// the fixed-header is read.
     pub fn new(max_size: u32) -> MQTTRead {
         MQTTRead::Init {
-            data: Vec::with_capacity(max_size as usize),
+            data: Vec::with_capacity(Self::HEADER_SIZE),
             max_size: max_size as usize,
         }
     }
 
+    /// Start reading a packet whose first byte, `byte1`, is already read from the
+    /// stream.
+    pub fn from_byte1(max_size: u32, byte1: u8) -> MQTTRead {
+        let mut data = Vec::with_capacity(Self::HEADER_SIZE);
+        data.push(byte1);
+        MQTTRead::Header { byte1, data, max_size: max_size as usize }
+    }
+
     // return (self,would_block)
     // Disconnected, and implies a bad connection.
     // MalformedPacket, implies a DISCONNECT and socket close
@@ -50,34 +64,17 @@ impl MQTTRead {
     pub fn read<R: io::Read>(self, mut stream: R) -> Result<(Self, bool)> {
         use MQTTRead::{Fin, Header, Init, Remain};
 
-        let mut scratch = [0_u8; 5];
+        // fixed-header is read byte by byte, so that we never read beyond the
+        // current packet, bytes that follow belong to the next packet.
+        let mut scratch = [0_u8; 1];
         match self {
             Init { mut data, max_size } => match stream.read(&mut scratch) {
                 Ok(0) => err!(Disconnected, desc: "MQTTRead::Init"),
-                Ok(1) => {
+                Ok(_) => {
                     data.push(scratch[0]);
                     let byte1 = scratch[0];
                     Ok((MQTTRead::Header { byte1, data, max_size }, false))
                 }
-                Ok(n) => {
-                    data.extend_from_slice(&scratch[..n]);
-                    let byte1 = scratch[0];
-                    match scratch[1..].iter().skip_while(|b| **b > 0x80).next() {
-                        Some(_) => {
-                            let (remaining_len, m) = VarU32::decode(&scratch[1..])?;
-
-                            let pkt_len = 1 + m + (*remaining_len as usize);
-                            read_packet_limit(pkt_len, max_size)?;
-
-                            let fh = v5::FixedHeader { byte1, remaining_len };
-                            data.reserve(pkt_len);
-                            data.resize(pkt_len, 0);
-                            let start = n;
-                            Ok((MQTTRead::Remain { data, start, fh, max_size }, false))
-                        }
-                        None => Ok((MQTTRead::Header { byte1, data, max_size }, false)),
-                    }
-                }
                 Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
                     Ok((MQTTRead::Init { data, max_size }, true))
                 }
@@ -85,22 +82,39 @@ impl MQTTRead {
             },
             Header { byte1, mut data, max_size } => match stream.read(&mut scratch) {
                 Ok(0) => err!(Disconnected, desc:  "MQTTRead::Header"),
-                Ok(n) => {
-                    data.extend_from_slice(&scratch[..n]);
-                    let start = data.len();
-                    match scratch.into_iter().skip_while(|b| *b > 0x80).next() {
-                        Some(_) => {
-                            let (remaining_len, m) = VarU32::decode(&data[1..])?;
+                Ok(_) if scratch[0] & 0x80 > 0 => {
+                    data.push(scratch[0]);
+                    match data.len() {
+                        n if n < 5 => {
+                            Ok((MQTTRead::Header { byte1, data, max_size }, false))
+                        }
+                        _ => err!(
+                            MalformedPacket,
+                            code: MalformedPacket,
+                            "MQTTRead::Header remaining-length exceeds 4 bytes"
+                        ),
+                    }
+                }
+                Ok(_) => {
+                    data.push(scratch[0]);
+                    let (remaining_len, m) = VarU32::decode(&data[1..])?;
 
-                            let pkt_len = 1 + m + (*remaining_len as usize);
-                            read_packet_limit(pkt_len, max_size)?;
+                    let pkt_len = 1 + m + (*remaining_len as usize);
+                    read_packet_limit(pkt_len, max_size)?;
 
-                            let fh = v5::FixedHeader { byte1, remaining_len };
-                            data.reserve(pkt_len);
-                            data.resize(pkt_len, 0);
-                            Ok((MQTTRead::Remain { data, start, fh, max_size }, false))
+                    // reserved packet-type and flags, before buffering the packet.
+                    let fh = v5::FixedHeader { byte1, remaining_len };
+                    fh.validate()?;
+                    let start = data.len();
+                    data.reserve_exact(pkt_len.saturating_sub(start));
+                    data.resize(pkt_len, 0);
+                    match start >= pkt_len {
+                        // packets with ZERO remaining-length, like PINGREQ.
+                        true => Ok((MQTTRead::Fin { data, fh, max_size }, false)),
+                        false => {
+                            let state = MQTTRead::Remain { data, start, fh, max_size };
+                            Ok((state, false))
                         }
-                        None => Ok((MQTTRead::Header { byte1, data, max_size }, false)),
                     }
                 }
                 Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
@@ -134,7 +148,7 @@ impl MQTTRead {
 
     // MalformedPacket, implies a DISCONNECT and socket close
     // ProtocolError, implies DISCONNECT and socket close
-    pub fn parse(&self) -> Result<v5::Packet> {
+    pub fn parse(&mut self) -> Result<v5::Packet> {
         let (pkt, n, m) = match self {
             MQTTRead::Fin { data, fh, .. } => match fh.unwrap().0 {
                 v5::PacketType::Connect => {
@@ -146,7 +160,13 @@ impl MQTTRead {
                     (v5::Packet::ConnAck(pkt), n, data.len())
                 }
                 v5::PacketType::Publish => {
-                    let (pkt, n) = v5::Publish::decode(&data)?;
+                    // payload shall share the read-buffer, hand it over and start
+                    // the next packet with a fresh buffer. Buffer is trimmed to the
+                    // packet, since it is held as long as the payload is referred.
+                    let mut data = mem::take(data);
+                    data.shrink_to_fit();
+                    let data = Bytes::from(data);
+                    let (pkt, n) = v5::Publish::decode_bytes(&data)?;
                     (v5::Packet::Publish(pkt), n, data.len())
                 }
                 v5::PacketType::PubAck => {
@@ -219,20 +239,31 @@ impl MQTTRead {
     }
 }
 
+/// PUBLISH payloads of this size or more are not copied into the write buffer,
+/// instead they are written in-place using vectored writes.
+pub const VECTORED_PAYLOAD: usize = 1024;
+
 /// Type implement a state machine to asynchronously write to socket using [mio].
+///
+/// One or more packets are serialized into `data`. Large PUBLISH payloads are held
+/// in `payloads` as (offset, payload), where offset is the position in `data` after
+/// which the payload shall be written.
 pub enum MQTTWrite {
     None,
     Init {
         data: Vec<u8>,
+        payloads: Vec<(usize, Bytes)>,
         max_size: usize,
     },
     Remain {
         data: Vec<u8>,
+        payloads: Vec<(usize, Bytes)>,
         start: usize,
         max_size: usize,
     },
     Fin {
         data: Vec<u8>,
+        payloads: Vec<(usize, Bytes)>,
         max_size: usize,
     },
 }
@@ -245,9 +276,9 @@ impl Default for MQTTWrite {
 
 impl MQTTWrite {
     pub fn new(buf: &[u8], max_size: u32) -> MQTTWrite {
-        let mut data = Vec::with_capacity(max_size as usize);
-        data.extend_from_slice(buf);
-        MQTTWrite::Init { data, max_size: max_size as usize }
+        let data = Vec::default();
+        let (payloads, max_size) = (Vec::default(), max_size as usize);
+        MQTTWrite::Fin { data, payloads, max_size }.reset(buf)
     }
 
     // return (self,would_block)
@@ -256,67 +287,143 @@ impl MQTTWrite {
         use MQTTWrite::{Fin, Init, Remain};
 
         match self {
-            // silently ignore if the packet size is more that requested.
-            Init { data, max_size } if data.len() > max_size => {
-                // TODO: add skipped packets to connection metrics.
-                Ok((MQTTWrite::Fin { data, max_size }, false))
+            // nothing to write, say after a reset with no packets.
+            Init { data, payloads, max_size } if data.is_empty() => {
+                Ok((MQTTWrite::Fin { data, payloads, max_size }, false))
             }
-            Init { data, max_size } => match stream.write(&data) {
-                Ok(0) => err!(Disconnected, desc:  "MQTTWrite::Init"),
-                Ok(n) if n == data.len() => {
-                    Ok((MQTTWrite::Fin { data, max_size }, false))
-                }
-                Ok(n) if n < data.len() => {
-                    Ok((MQTTWrite::Remain { data, start: n, max_size }, false))
-                }
-                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
-                    Ok((MQTTWrite::Remain { data, start: 0, max_size }, false))
-                }
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    Ok((MQTTWrite::Remain { data, start: 0, max_size }, true))
-                }
-                Err(err) => err!(Disconnected, try: Err(err), "MQTTWrite::Init"),
-                Ok(_) => unreachable!(),
-            },
-            Remain { data, start, max_size } => match stream.write(&data[start..]) {
-                Ok(0) => err!(Disconnected, desc:  "MQTTWrite::Remain"),
-                Ok(n) if (start + n) == data.len() => {
-                    Ok((MQTTWrite::Fin { data, max_size }, false))
-                }
-                Ok(n) if (start + n) < data.len() => {
-                    let start = start + n;
-                    Ok((MQTTWrite::Remain { data, start, max_size }, false))
-                }
-                Err(err) if err.kind() == io::ErrorKind::Interrupted => {
-                    Ok((MQTTWrite::Remain { data, start, max_size }, false))
-                }
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    Ok((MQTTWrite::Remain { data, start, max_size }, true))
+            Init { data, payloads, max_size } => {
+                let state = MQTTWrite::Remain { data, payloads, start: 0, max_size };
+                state.write(stream)
+            }
+            Remain { data, payloads, start, max_size } => {
+                let len =
+                    data.len() + payloads.iter().map(|(_, p)| p.len()).sum::<usize>();
+                let res = {
+                    let slices = to_io_slices(&data, &payloads, start);
+                    stream.write_vectored(&slices)
+                };
+                match res {
+                    Ok(0) => err!(Disconnected, desc:  "MQTTWrite::Remain"),
+                    Ok(n) if (start + n) == len => {
+                        Ok((MQTTWrite::Fin { data, payloads, max_size }, false))
+                    }
+                    Ok(n) if (start + n) < len => {
+                        let start = start + n;
+                        Ok((MQTTWrite::Remain { data, payloads, start, max_size }, false))
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {
+                        Ok((MQTTWrite::Remain { data, payloads, start, max_size }, false))
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        Ok((MQTTWrite::Remain { data, payloads, start, max_size }, true))
+                    }
+                    Err(err) => err!(Disconnected, try: Err(err), "MQTTWrite::Remain"),
+                    Ok(_) => unreachable!(),
                 }
-                Err(err) => err!(Disconnected, try: Err(err), "MQTTWrite::Remain"),
-                Ok(_) => unreachable!(),
-            },
-            Fin { data, max_size } => Ok((MQTTWrite::Fin { data, max_size }, false)),
+            }
+            Fin { data, payloads, max_size } => {
+                Ok((MQTTWrite::Fin { data, payloads, max_size }, false))
+            }
             MQTTWrite::None => unreachable!(),
         }
     }
 
     pub fn reset(self, buf: &[u8]) -> Self {
         match self {
-            MQTTWrite::Fin { mut data, max_size } => {
+            MQTTWrite::Fin { mut data, mut payloads, max_size } => {
                 data.truncate(0);
-                data.extend_from_slice(buf);
-                MQTTWrite::Init { data, max_size }
+                payloads.clear();
+                // silently ignore if the packet size is more that requested.
+                // TODO: add skipped packets to connection metrics.
+                if buf.len() <= max_size {
+                    data.extend_from_slice(buf);
+                }
+                MQTTWrite::Init { data, payloads, max_size }
             }
             _ => unreachable!(),
         }
     }
+
+    /// Serialize `pkt` and append it to the packets yet to be written, applicable
+    /// only in Init state, that is, after a [MQTTWrite::reset]. Packets larger than
+    /// `max_size` are silently skipped.
+    pub fn encode_packet(&mut self, pkt: &v5::Packet) -> Result<()> {
+        let (data, payloads, max_size) = match self {
+            MQTTWrite::Init { data, payloads, max_size } => (data, payloads, *max_size),
+            _ => unreachable!(),
+        };
+
+        let (start, n_payloads) = (data.len(), payloads.len());
+        let res = match pkt {
+            v5::Packet::Publish(publ) => match publ.encode_header_into(data) {
+                Ok(Some(payload)) if payload.len() >= VECTORED_PAYLOAD => {
+                    payloads.push((data.len(), payload));
+                    Ok(())
+                }
+                Ok(Some(payload)) => {
+                    data.extend_from_slice(&payload);
+                    Ok(())
+                }
+                Ok(None) => Ok(()),
+                Err(err) => Err(err),
+            },
+            pkt => pkt.encode_into(data).map(|_| ()),
+        };
+
+        let size = (data.len() - start)
+            + payloads[n_payloads..].iter().map(|(_, p)| p.len()).sum::<usize>();
+        match res {
+            // silently ignore if the packet size is more that requested.
+            // TODO: add skipped packets to connection metrics.
+            Ok(()) if size > max_size => {
+                data.truncate(start);
+                payloads.truncate(n_payloads);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+            Err(err) => {
+                data.truncate(start);
+                payloads.truncate(n_payloads);
+                Err(err)
+            }
+        }
+    }
+}
+
+// Return io-slices, for data and payloads interleaved, skipping `start` bytes.
+fn to_io_slices<'a>(
+    data: &'a [u8],
+    payloads: &'a [(usize, Bytes)],
+    start: usize,
+) -> Vec<io::IoSlice<'a>> {
+    let mut segments: Vec<&[u8]> = Vec::with_capacity(payloads.len() * 2 + 1);
+    let mut off = 0;
+    for (at, payload) in payloads.iter() {
+        segments.push(&data[off..*at]);
+        segments.push(payload);
+        off = *at;
+    }
+    segments.push(&data[off..]);
+
+    let mut skip = start;
+    let mut slices = Vec::with_capacity(segments.len());
+    for seg in segments.into_iter() {
+        match seg.len() {
+            n if n <= skip => skip -= n,
+            _ => {
+                slices.push(io::IoSlice::new(&seg[skip..]));
+                skip = 0;
+            }
+        }
+    }
+
+    slices
 }
 
 pub fn send_disconnect(
     prefix: &str,
     code: v5::DisconnReasonCode,
-    conn: &mio::net::TcpStream,
+    conn: &Stream,
     timeout: time::Instant,
     max_size: u32,
 ) -> Result<()> {
@@ -334,7 +441,7 @@ pub fn send_disconnect(
         };
         packetw = val;
 
-        if would_block && timeout < time::Instant::now() {
+        if would_block && time::Instant::now() < timeout {
             thread::sleep(SLEEP_10MS);
         } else if would_block {
             break err!(
@@ -351,13 +458,24 @@ pub fn send_disconnect(
 pub fn send_connack(
     prefix: &str,
     code: v5::ConnackReasonCode,
-    conn: &mio::net::TcpStream,
+    conn: &Stream,
+    timeout: time::Instant,
+    max_size: u32,
+) -> Result<()> {
+    let cack = v5::ConnAck::from_reason_code(code);
+    send_connack_with(prefix, cack, conn, timeout, max_size)
+}
+
+/// Same as [send_connack], but send the supplied CONNACK packet, say with properties.
+pub fn send_connack_with(
+    prefix: &str,
+    cack: v5::ConnAck,
+    conn: &Stream,
     timeout: time::Instant,
     max_size: u32,
 ) -> Result<()> {
     use crate::SLEEP_10MS;
 
-    let cack = v5::ConnAck::from_reason_code(code);
     let mut packetw = MQTTWrite::new(cack.encode().unwrap().as_ref(), max_size);
     loop {
         let (val, would_block) = match packetw.write(conn) {
@@ -369,7 +487,7 @@ pub fn send_connack(
         };
         packetw = val;
 
-        if would_block && timeout < time::Instant::now() {
+        if would_block && time::Instant::now() < timeout {
             thread::sleep(SLEEP_10MS);
         } else if would_block {
             break err!(
@@ -395,3 +513,7 @@ fn read_packet_limit(pkt_len: usize, max_size: usize) -> Result<()> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+#[path = "packet_test.rs"]
+mod packet_test;
@@ -0,0 +1,136 @@
+use std::io;
+
+use super::*;
+use crate::TopicName;
+
+#[test]
+fn test_read_publish_zero_copy() {
+    let publ = v5::Publish {
+        retain: false,
+        qos: v5::QoS::AtLeastOnce,
+        duplicate: false,
+        topic_name: TopicName::from("a/b/c".to_string()),
+        packet_id: Some(1),
+        properties: None,
+        payload: Some(Bytes::from(vec![0xAB_u8; 1024 * 1024])),
+    };
+    let data = publ.encode().unwrap();
+
+    let mut stream: &[u8] = data.as_ref();
+    let mut packetr = MQTTRead::new(2 * 1024 * 1024);
+    let pkt = loop {
+        packetr = match packetr.read(&mut stream).unwrap() {
+            (mut packetr @ MQTTRead::Fin { .. }, _) => {
+                // read-buffer is sized for the packet, not for max_size.
+                if let MQTTRead::Fin { data, .. } = &packetr {
+                    assert_eq!(data.capacity(), data.len());
+                }
+                let pkt = packetr.parse().unwrap();
+                packetr = packetr.reset();
+                assert!(matches!(packetr, MQTTRead::Init { .. }));
+                break pkt;
+            }
+            (packetr, _) => packetr,
+        };
+    };
+
+    let payload = match &pkt {
+        v5::Packet::Publish(val) => {
+            assert_eq!(val, &publ);
+            val.payload.clone().unwrap()
+        }
+        pkt => panic!("unexpected {:?}", pkt.to_packet_type()),
+    };
+    // fan-out clones share the payload received from the stream.
+    let clones: Vec<v5::Packet> = (0..100).map(|_| pkt.clone()).collect();
+    assert_eq!(payload.strong_count(), 102);
+
+    std::mem::drop(clones);
+    assert_eq!(payload.strong_count(), 2);
+}
+
+#[test]
+fn test_read_reserved_header() {
+    // reserved QoS in PUBLISH and reserved packet-type.
+    for data in [[0x36_u8, 0x00], [0x00, 0x00]] {
+        let mut stream: &[u8] = &data;
+        let mut packetr = MQTTRead::new(1024);
+        let err = loop {
+            packetr = match packetr.read(&mut stream) {
+                Ok((MQTTRead::Fin { .. }, _)) => panic!("{:?} read as packet", data),
+                Ok((packetr, _)) => packetr,
+                Err(err) => break err,
+            };
+        };
+        assert_eq!(err.kind(), ErrorKind::MalformedPacket, "{:?}", data);
+    }
+}
+
+// Writer that accepts at most `limit` bytes per call.
+struct Limited {
+    data: Vec<u8>,
+    limit: usize,
+}
+
+impl io::Write for Limited {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.limit);
+        self.data.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_write_packets_vectored() {
+    let new_publish = |topic: &str, n: usize| {
+        v5::Packet::Publish(v5::Publish {
+            retain: false,
+            qos: v5::QoS::AtMostOnce,
+            duplicate: false,
+            topic_name: TopicName::from(topic.to_string()),
+            packet_id: None,
+            properties: None,
+            payload: Some(Bytes::from(vec![n as u8; n])),
+        })
+    };
+    let pkts = vec![
+        new_publish("a", 10),
+        new_publish("b", VECTORED_PAYLOAD * 4),
+        v5::Packet::PingResp,
+        new_publish("too/large", 16 * 1024),
+        new_publish("c", VECTORED_PAYLOAD),
+        v5::Packet::Disconnect(v5::Disconnect::new(
+            v5::DisconnReasonCode::NormalDisconnect,
+            None,
+        )),
+    ];
+
+    let mut pw = MQTTWrite::new(&[], 8 * 1024);
+    for pkt in pkts.iter() {
+        pw.encode_packet(pkt).unwrap();
+    }
+    match &pw {
+        MQTTWrite::Init { payloads, .. } => assert_eq!(payloads.len(), 2),
+        _ => unreachable!(),
+    }
+
+    let mut stream = Limited { data: Vec::default(), limit: 100 };
+    loop {
+        pw = match pw.write(&mut stream).unwrap() {
+            (MQTTWrite::Fin { .. }, _) => break,
+            (pw, _) => pw,
+        }
+    }
+
+    let mut expected: Vec<u8> = vec![];
+    for (i, pkt) in pkts.iter().enumerate() {
+        if i != 3 {
+            expected.extend_from_slice(pkt.encode().unwrap().as_ref());
+        }
+    }
+    assert_eq!(stream.data, expected);
+}
@@ -0,0 +1,226 @@
+//! Module implement PROXY protocol, version 1 and version 2, as specified by
+//! <https://www.haproxy.org/download/2.6/doc/proxy-protocol.txt>.
+//!
+//! Load balancers prefix the connection with a PROXY header carrying the original
+//! client address. Header is read before the MQTT CONNECT packet.
+
+use std::{io, net};
+
+use crate::{Error, ErrorKind, Result};
+
+/// Signature for version-2 header.
+pub const V2_SIGNATURE: [u8; 12] =
+    [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+/// Version-1 header can't be larger than this, including the CRLF.
+pub const V1_MAX_SIZE: usize = 107;
+/// Fixed portion of the version-2 header.
+pub const V2_HEADER_SIZE: usize = 16;
+
+/// Type capture the addresses carried in PROXY header.
+///
+/// Addresses are None for `PROXY UNKNOWN` in version-1, for LOCAL command and for
+/// address families other than TCP/IPv4 and TCP/IPv6 in version-2.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: Option<net::SocketAddr>,
+    pub destination: Option<net::SocketAddr>,
+}
+
+/// Type implement a state machine to asynchronously read PROXY header using [mio].
+///
+/// Bytes are read exactly up to the end of header, so that what follows on the
+/// stream is the MQTT CONNECT packet.
+#[derive(Default)]
+pub enum ProxyRead {
+    #[default]
+    None,
+    Init {
+        required: bool,
+    },
+    V1 {
+        data: Vec<u8>,
+    },
+    V2 {
+        data: Vec<u8>,
+        size: usize,
+    },
+    Fin {
+        header: ProxyHeader,
+    },
+    /// Connection did not start with a PROXY header, and header is not required.
+    /// `byte1` is the first byte of the MQTT packet already read from the stream.
+    Skip {
+        byte1: u8,
+    },
+}
+
+impl ProxyRead {
+    pub fn new(required: bool) -> ProxyRead {
+        ProxyRead::Init { required }
+    }
+
+    // return (self,would_block)
+    // Disconnected, and implies a bad connection.
+    // InvalidInput, implies a bad header, or a missing header when required.
+    pub fn read<R: io::Read>(self, mut stream: R) -> Result<(Self, bool)> {
+        use ProxyRead::{Fin, Init, Skip, V1, V2};
+
+        let mut scratch = [0_u8; 1];
+        match self {
+            Init { required } => match stream.read(&mut scratch) {
+                Ok(0) => err!(Disconnected, desc: "ProxyRead::Init"),
+                Ok(_) if scratch[0] == b'P' => {
+                    let data = scratch.to_vec();
+                    Ok((V1 { data }, false))
+                }
+                Ok(_) if scratch[0] == V2_SIGNATURE[0] => {
+                    let (data, size) = (scratch.to_vec(), V2_HEADER_SIZE);
+                    Ok((V2 { data, size }, false))
+                }
+                Ok(_) if required => {
+                    err!(InvalidInput, desc: "ProxyRead::Init missing PROXY header")
+                }
+                Ok(_) => Ok((Skip { byte1: scratch[0] }, false)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    Ok((Init { required }, true))
+                }
+                Err(err) => err!(Disconnected, try: Err(err), "ProxyRead::Init"),
+            },
+            // version-1 header is small, read byte by byte until CRLF.
+            V1 { mut data } => match stream.read(&mut scratch) {
+                Ok(0) => err!(Disconnected, desc: "ProxyRead::V1"),
+                Ok(_) => {
+                    data.push(scratch[0]);
+                    if data.ends_with(b"\r\n") {
+                        let header = parse_v1(&data)?;
+                        Ok((Fin { header }, false))
+                    } else if data.len() >= V1_MAX_SIZE {
+                        err!(InvalidInput, desc: "ProxyRead::V1 header too large")
+                    } else {
+                        Ok((V1 { data }, false))
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    Ok((V1 { data }, true))
+                }
+                Err(err) => err!(Disconnected, try: Err(err), "ProxyRead::V1"),
+            },
+            V2 { mut data, size } => {
+                let start = data.len();
+                data.resize(size, 0);
+                match stream.read(&mut data[start..]) {
+                    Ok(0) => err!(Disconnected, desc: "ProxyRead::V2"),
+                    Ok(n) if (start + n) == V2_HEADER_SIZE && size == V2_HEADER_SIZE => {
+                        if data[..12] != V2_SIGNATURE {
+                            err!(InvalidInput, desc: "ProxyRead::V2 bad signature")?;
+                        }
+                        let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+                        match len {
+                            0 => Ok((Fin { header: parse_v2(&data)? }, false)),
+                            len => Ok((V2 { data, size: size + len }, false)),
+                        }
+                    }
+                    Ok(n) if (start + n) == size => {
+                        Ok((Fin { header: parse_v2(&data)? }, false))
+                    }
+                    Ok(n) => {
+                        data.truncate(start + n);
+                        Ok((V2 { data, size }, false))
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        data.truncate(start);
+                        Ok((V2 { data, size }, true))
+                    }
+                    Err(err) => err!(Disconnected, try: Err(err), "ProxyRead::V2"),
+                }
+            }
+            Fin { header } => Ok((Fin { header }, false)),
+            Skip { byte1 } => Ok((Skip { byte1 }, false)),
+            ProxyRead::None => unreachable!(),
+        }
+    }
+}
+
+/// Parse version-1 header, `data` must include the trailing CRLF.
+pub fn parse_v1(data: &[u8]) -> Result<ProxyHeader> {
+    use std::str::from_utf8;
+
+    let line = match data.strip_suffix(b"\r\n") {
+        Some(line) => err!(InvalidInput, try: from_utf8(line), "PROXY v1 not ascii")?,
+        None => err!(InvalidInput, desc: "PROXY v1 missing CRLF")?,
+    };
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, sport, dport] => {
+            let src: net::IpAddr = err!(InvalidInput, try: src.parse(), "PROXY v1 src")?;
+            let dst: net::IpAddr = err!(InvalidInput, try: dst.parse(), "PROXY v1 dst")?;
+            let sport: u16 = err!(InvalidInput, try: sport.parse(), "PROXY v1 sport")?;
+            let dport: u16 = err!(InvalidInput, try: dport.parse(), "PROXY v1 dport")?;
+            match (*proto, src, dst) {
+                ("TCP4", net::IpAddr::V4(_), net::IpAddr::V4(_)) => (),
+                ("TCP6", net::IpAddr::V6(_), net::IpAddr::V6(_)) => (),
+                (proto, _, _) => {
+                    err!(InvalidInput, desc: "PROXY v1 address mismatch for {}", proto)?
+                }
+            }
+            Ok(ProxyHeader {
+                source: Some((src, sport).into()),
+                destination: Some((dst, dport).into()),
+            })
+        }
+        _ => err!(InvalidInput, desc: "PROXY v1 bad header {:?}", line),
+    }
+}
+
+/// Parse version-2 header, `data` must contain the complete header.
+pub fn parse_v2(data: &[u8]) -> Result<ProxyHeader> {
+    if data.len() < V2_HEADER_SIZE || data[..12] != V2_SIGNATURE {
+        err!(InvalidInput, desc: "PROXY v2 bad signature")?;
+    }
+
+    let (ver_cmd, fam) = (data[12], data[13]);
+    let len = u16::from_be_bytes([data[14], data[15]]) as usize;
+    if (ver_cmd >> 4) != 2 {
+        err!(InvalidInput, desc: "PROXY v2 bad version {}", ver_cmd >> 4)?;
+    } else if data.len() != (V2_HEADER_SIZE + len) {
+        err!(InvalidInput, desc: "PROXY v2 bad length {}", len)?;
+    }
+
+    let addrs = &data[V2_HEADER_SIZE..];
+    match (ver_cmd & 0x0F, fam) {
+        // LOCAL command, connection established by proxy itself.
+        (0x0, _) => Ok(ProxyHeader::default()),
+        // PROXY command, TCP over IPv4
+        (0x1, 0x11) if addrs.len() >= 12 => {
+            let src = net::Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            let dst = net::Ipv4Addr::new(addrs[4], addrs[5], addrs[6], addrs[7]);
+            let sport = u16::from_be_bytes([addrs[8], addrs[9]]);
+            let dport = u16::from_be_bytes([addrs[10], addrs[11]]);
+            Ok(ProxyHeader {
+                source: Some((src, sport).into()),
+                destination: Some((dst, dport).into()),
+            })
+        }
+        // PROXY command, TCP over IPv6
+        (0x1, 0x21) if addrs.len() >= 36 => {
+            let src = net::Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).unwrap());
+            let dst = net::Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[16..32]).unwrap());
+            let sport = u16::from_be_bytes([addrs[32], addrs[33]]);
+            let dport = u16::from_be_bytes([addrs[34], addrs[35]]);
+            Ok(ProxyHeader {
+                source: Some((src, sport).into()),
+                destination: Some((dst, dport).into()),
+            })
+        }
+        (0x1, 0x11 | 0x21) => err!(InvalidInput, desc: "PROXY v2 short address block"),
+        // PROXY command, other families are accepted but addresses are ignored.
+        (0x1, _) => Ok(ProxyHeader::default()),
+        (cmd, _) => err!(InvalidInput, desc: "PROXY v2 bad command {}", cmd),
+    }
+}
+
+#[cfg(test)]
+#[path = "proxy_test.rs"]
+mod proxy_test;
@@ -0,0 +1,140 @@
+use std::io;
+
+use crate::ErrorKind;
+
+use super::*;
+
+// Reader that returns one byte for every read call, and WouldBlock on alternate
+// calls, to exercise the state machine.
+struct Trickle {
+    data: Vec<u8>,
+    off: usize,
+    block: bool,
+}
+
+impl io::Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.block = !self.block;
+        if self.block {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "trickle"));
+        }
+        match self.data.get(self.off) {
+            Some(byte) if !buf.is_empty() => {
+                buf[0] = *byte;
+                self.off += 1;
+                Ok(1)
+            }
+            _ => Ok(0),
+        }
+    }
+}
+
+fn read_all(data: &[u8], required: bool) -> (Result<ProxyRead>, usize) {
+    let mut stream = Trickle { data: data.to_vec(), off: 0, block: false };
+    let mut pr = ProxyRead::new(required);
+    loop {
+        pr = match pr.read(&mut stream) {
+            Ok((pr @ ProxyRead::Fin { .. }, _)) => break (Ok(pr), stream.off),
+            Ok((pr @ ProxyRead::Skip { .. }, _)) => break (Ok(pr), stream.off),
+            Ok((pr, _would_block)) => pr,
+            Err(err) => break (Err(err), stream.off),
+        }
+    }
+}
+
+fn v2_header(cmd: u8, fam: u8, addrs: &[u8]) -> Vec<u8> {
+    let mut data = V2_SIGNATURE.to_vec();
+    data.push(0x20 | cmd);
+    data.push(fam);
+    data.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+    data.extend_from_slice(addrs);
+    data
+}
+
+#[test]
+fn test_parse_v1() {
+    let header = parse_v1(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n").unwrap();
+    assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+    assert_eq!(header.destination, Some("192.168.0.11:1883".parse().unwrap()));
+
+    let header = parse_v1(b"PROXY TCP6 ::1 fe80::1 56324 1883\r\n").unwrap();
+    assert_eq!(header.source, Some("[::1]:56324".parse().unwrap()));
+    assert_eq!(header.destination, Some("[fe80::1]:1883".parse().unwrap()));
+
+    let header = parse_v1(b"PROXY UNKNOWN\r\n").unwrap();
+    assert_eq!(header, ProxyHeader::default());
+
+    for data in [
+        &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883"[..],
+        &b"PROXY TCP4 ::1 192.168.0.11 56324 1883\r\n"[..],
+        &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n"[..],
+        &b"PROXY TCP5 192.168.0.1 192.168.0.11 56324 1883\r\n"[..],
+        &b"PROXY TCP4 192.168.0.1 192.168.0.11 65536 1883\r\n"[..],
+    ] {
+        let err = parse_v1(data).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", data);
+    }
+}
+
+#[test]
+fn test_parse_v2() {
+    let addrs = [127, 0, 0, 1, 10, 0, 0, 1, 0x1F, 0x90, 0x07, 0x5B];
+    let header = parse_v2(&v2_header(0x1, 0x11, &addrs)).unwrap();
+    assert_eq!(header.source, Some("127.0.0.1:8080".parse().unwrap()));
+    assert_eq!(header.destination, Some("10.0.0.1:1883".parse().unwrap()));
+
+    let mut addrs = vec![0; 36];
+    addrs[15] = 1;
+    addrs[31] = 2;
+    addrs[32..].copy_from_slice(&[0x1F, 0x90, 0x07, 0x5B]);
+    let header = parse_v2(&v2_header(0x1, 0x21, &addrs)).unwrap();
+    assert_eq!(header.source, Some("[::1]:8080".parse().unwrap()));
+    assert_eq!(header.destination, Some("[::2]:1883".parse().unwrap()));
+
+    let header = parse_v2(&v2_header(0x0, 0x00, &[])).unwrap();
+    assert_eq!(header, ProxyHeader::default());
+
+    let err = parse_v2(&v2_header(0x1, 0x11, &[127, 0, 0, 1])).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    let err = parse_v2(&v2_header(0x2, 0x11, &[])).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn test_proxy_read() {
+    let connect = [0x10_u8, 0x00];
+
+    let mut data = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 1883\r\n".to_vec();
+    let n = data.len();
+    data.extend_from_slice(&connect);
+    match read_all(&data, true) {
+        (Ok(ProxyRead::Fin { header }), off) => {
+            assert_eq!(header.source, Some("192.168.0.1:56324".parse().unwrap()));
+            assert_eq!(off, n);
+        }
+        (res, _) => panic!("unexpected {:?}", res.err()),
+    }
+
+    let addrs = [127, 0, 0, 1, 10, 0, 0, 1, 0x1F, 0x90, 0x07, 0x5B];
+    let mut data = v2_header(0x1, 0x11, &addrs);
+    let n = data.len();
+    data.extend_from_slice(&connect);
+    match read_all(&data, true) {
+        (Ok(ProxyRead::Fin { header }), off) => {
+            assert_eq!(header.source, Some("127.0.0.1:8080".parse().unwrap()));
+            assert_eq!(off, n);
+        }
+        (res, _) => panic!("unexpected {:?}", res.err()),
+    }
+
+    match read_all(&connect, false) {
+        (Ok(ProxyRead::Skip { byte1: 0x10 }), 1) => (),
+        (res, _) => panic!("unexpected {:?}", res.err()),
+    }
+
+    let err = read_all(&connect, true).0.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+    let err = read_all(&[b'P'; V1_MAX_SIZE + 1], true).0.err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+}
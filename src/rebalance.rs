@@ -27,14 +27,33 @@
 //! gracefull or fail-over, a new Topology shall be created that involves minimum
 //! shard migration.
 //!
+//! **Replica placement**
+//!
+//! With [Algorithm::Replicated], replicas are placed for each master node, so that all
+//! the shards mastered by a node share the same replica nodes. Replica nodes are picked
+//! one at a time, preferring:
+//!
+//! * Nodes whose [Node::path] share the shortest prefix with the master and the
+//!   replicas already picked, say, `/zone-a/rack-1` is preferred over `/zone-b/rack-1`
+//!   for a master in `/zone-b/rack-2`. This way a shard survives the failure of a
+//!   zone, as long as there are more zones than its master and replicas.
+//! * Nodes with the least load, that is number of master and replica shards hosted by
+//!   the node, relative to its weight.
+//!
 //! **Shard-Migration**
 //!
 //! * Migration of master shard from one node to another.
 //! * Migration of replica shard from one node not another.
 //! * Demotion of master shard as replica-shard.
-//! * Promotion of replica-shard as master-shard.
+//! * Promotion of replica-shard as master-shard, refer [promote_replicas]. Replicas
+//!   lost along with the failed node are replaced, refer [top_up_replicas].
+
+use uuid::Uuid;
+
+use std::collections::BTreeMap;
 
-use crate::{Config, Node};
+use crate::{util, Config, ConsistentHash, Node};
+use crate::{Error, ErrorKind, Result};
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct Topology {
@@ -53,30 +72,41 @@ impl Rebalancer {
     /// Clients are mapped to shards using ClientID, so that a client will always
     /// map to the same shard no matter where or when it is computed.
     pub fn session_partition<U: AsRef<[u8]>>(id: &U, num_shards: u32) -> u32 {
-        let id: &[u8] = id.as_ref();
-        let hash = cityhash_rs::cityhash_110_128(id);
-        let hash = (hash & 0xFFFFFFFFFFFFFFFF) ^ ((hash >> 64) & 0xFFFFFFFFFFFFFFFF);
-        let hash = ((hash & 0xFFFFFFFF) ^ ((hash >> 32) & 0xFFFFFFFF)) as u32;
-        hash & (num_shards - 1)
+        util::cityhash_u32(id.as_ref()) & (num_shards - 1)
     }
 
     /// Rebalance topology for supplied set of nodes. Subsequently use
     /// [diff_topology] passing in the old and new topology to identify the migrating
-    /// shards.
-    pub fn rebalance(&self, nodes: &[Node], old: Vec<Topology>) -> Vec<Topology> {
+    /// shards. Fail if `nodes` is empty or any of the nodes has ZERO weight.
+    pub fn rebalance(&self, nodes: &[Node], old: Vec<Topology>) -> Result<Vec<Topology>> {
         self.algo.rebalance(&self.config, nodes, old)
     }
 }
 
 pub enum Algorithm {
     SingleNode,
+    /// Assign master shards to nodes using [ConsistentHash], by node's weight.
+    ConsistentHash,
+    /// Same as [Algorithm::ConsistentHash], and assign [Config::num_replicas] replicas
+    /// for each shard, refer to module documentation.
+    Replicated,
 }
 
 impl Algorithm {
-    fn rebalance(&self, c: &Config, nodes: &[Node], _: Vec<Topology>) -> Vec<Topology> {
-        match self {
+    fn rebalance(
+        &self,
+        c: &Config,
+        nodes: &[Node],
+        _: Vec<Topology>,
+    ) -> Result<Vec<Topology>> {
+        let topology = match self {
             Algorithm::SingleNode => {
-                let node = &nodes[0];
+                let node = match nodes.first() {
+                    Some(node) => node,
+                    None => {
+                        err!(InvalidInput, desc: "Atleast one node must be in cluster")?
+                    }
+                };
                 (0..c.num_shards())
                     .map(|shard| Topology {
                         shard,
@@ -85,13 +115,95 @@ impl Algorithm {
                     })
                     .collect()
             }
+            Algorithm::ConsistentHash => {
+                let ch = ConsistentHash::from_nodes(nodes)?;
+                let nodes: BTreeMap<Uuid, &Node> =
+                    nodes.iter().map(|n| (n.uuid, n)).collect();
+                (0..c.num_shards())
+                    .map(|shard| Topology {
+                        shard,
+                        master: nodes[&ch.hash_to_node(shard_hash(shard))].clone(),
+                        replicas: Vec::new(),
+                    })
+                    .collect()
+            }
+            Algorithm::Replicated => {
+                let mut topology =
+                    Algorithm::ConsistentHash.rebalance(c, nodes, Vec::new())?;
+                place_replicas(c, nodes, &mut topology);
+                topology
+            }
+        };
+
+        Ok(topology)
+    }
+}
+
+fn place_replicas(c: &Config, nodes: &[Node], topology: &mut [Topology]) {
+    let num_replicas = (c.num_replicas() as usize).min(nodes.len().saturating_sub(1));
+
+    // number of master and replica shards hosted by each node.
+    let mut load: BTreeMap<Uuid, usize> = nodes.iter().map(|n| (n.uuid, 0)).collect();
+    for t in topology.iter() {
+        *load.get_mut(&t.master.uuid).unwrap() += 1;
+    }
+
+    // place replicas for heavier masters first, so that lighter ones fill the gaps.
+    let mut masters: Vec<(&Node, usize)> = nodes
+        .iter()
+        .map(|n| (n, load[&n.uuid]))
+        .filter(|(_, count)| *count > 0)
+        .collect();
+    masters.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.uuid.cmp(&b.0.uuid)));
+
+    let mut replicas: BTreeMap<Uuid, Vec<Node>> = BTreeMap::new();
+    for (master, count) in masters.into_iter() {
+        let mut group: Vec<&Node> = vec![master];
+        for _ in 0..num_replicas {
+            let node = nodes
+                .iter()
+                .filter(|n| !group.contains(n))
+                .min_by(|a, b| {
+                    // compare weighed load, after taking up this master's shards.
+                    let (la, lb) = (load[&a.uuid] + count, load[&b.uuid] + count);
+                    let (la, lb) = (la as u64, lb as u64);
+                    let (wa, wb) = (a.weight as u64, b.weight as u64);
+                    affinity(a, &group)
+                        .cmp(&affinity(b, &group))
+                        .then((la * wb).cmp(&(lb * wa)))
+                        .then(a.uuid.cmp(&b.uuid))
+                })
+                .unwrap();
+            *load.get_mut(&node.uuid).unwrap() += count;
+            group.push(node);
         }
+        let group = group[1..].iter().map(|n| (*n).clone()).collect();
+        replicas.insert(master.uuid, group);
     }
+
+    for t in topology.iter_mut() {
+        t.replicas = replicas[&t.master.uuid].clone();
+    }
+}
+
+// Return the longest path prefix `node` shares with any of the `group`, lower the
+// value farther is the node from the group's failure domain.
+fn affinity(node: &Node, group: &[&Node]) -> usize {
+    let prefix = |n: &Node| {
+        let iter = node.path.components().zip(n.path.components());
+        iter.take_while(|(a, b)| a == b).count()
+    };
+    group.iter().map(|n| prefix(n)).max().unwrap_or(0)
+}
+
+// Place shard on the hash circle, shard numbers are hashed so that they are spread
+// across the circle.
+fn shard_hash(shard: u32) -> u32 {
+    util::cityhash_u32(&shard.to_be_bytes())
 }
 
 /// Compare the old and new topology to identify the migrating shards. For each
 /// migrating shards, there shall be an entry in the returned list.
-#[allow(dead_code)]
 pub fn diff_topology(olds: &[Topology], news: &[Topology]) -> Vec<(Topology, Topology)> {
     let mut olds = olds.to_vec();
     olds.sort_by_key(|x| x.shard);
@@ -113,6 +225,77 @@ pub fn diff_topology(olds: &[Topology], news: &[Topology]) -> Vec<(Topology, Top
     diffs
 }
 
+/// Fail-over shards whose master is not in `nodes`, by promoting the first replica
+/// that is in `nodes` as the new master, refer [crate::replica]. Replicas that are
+/// not in `nodes` are dropped, and shards without a live replica are left as is.
+///
+/// Outcome does not depend on the order in which nodes have failed, hence nodes that
+/// have missed an intermediate membership view still agree on the topology.
+pub fn promote_replicas(topology: &[Topology], nodes: &[Node]) -> Vec<Topology> {
+    topology
+        .iter()
+        .map(|t| {
+            let hosts = std::iter::once(&t.master).chain(t.replicas.iter());
+            let mut hosts = hosts.filter(|n| nodes.contains(n)).cloned();
+            match hosts.next() {
+                Some(master) => {
+                    Topology { shard: t.shard, master, replicas: hosts.collect() }
+                }
+                None => t.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Add replicas to shards that have less than [Config::num_replicas], say after
+/// [promote_replicas], picking from `nodes` the same way as [Algorithm::Replicated].
+/// Existing master and replicas are left as is, and so are shards whose master is
+/// not in `nodes`.
+pub fn top_up_replicas(
+    c: &Config,
+    topology: &[Topology],
+    nodes: &[Node],
+) -> Vec<Topology> {
+    let num_replicas = (c.num_replicas() as usize).min(nodes.len().saturating_sub(1));
+
+    // number of master and replica shards hosted by each node.
+    let mut load: BTreeMap<Uuid, usize> = nodes.iter().map(|n| (n.uuid, 0)).collect();
+    for t in topology.iter() {
+        for n in std::iter::once(&t.master).chain(t.replicas.iter()) {
+            load.entry(n.uuid).and_modify(|count| *count += 1);
+        }
+    }
+
+    let mut topology = topology.to_vec();
+    for t in topology.iter_mut().filter(|t| nodes.contains(&t.master)) {
+        while t.replicas.len() < num_replicas {
+            let group: Vec<&Node> =
+                std::iter::once(&t.master).chain(t.replicas.iter()).collect();
+            let node = nodes
+                .iter()
+                .filter(|n| !group.contains(n))
+                .min_by(|a, b| {
+                    let (la, lb) = (load[&a.uuid] as u64, load[&b.uuid] as u64);
+                    let (wa, wb) = (a.weight as u64, b.weight as u64);
+                    affinity(a, &group)
+                        .cmp(&affinity(b, &group))
+                        .then((la * wb).cmp(&(lb * wa)))
+                        .then(a.uuid.cmp(&b.uuid))
+                })
+                .cloned();
+            match node {
+                Some(node) => {
+                    *load.get_mut(&node.uuid).unwrap() += 1;
+                    t.replicas.push(node);
+                }
+                None => break,
+            }
+        }
+    }
+
+    topology
+}
+
 #[cfg(test)]
 #[path = "rebalance_test.rs"]
 mod rebalance_test;
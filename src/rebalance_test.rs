@@ -31,3 +31,203 @@ fn test_session_to_shard() {
         (sd / (mean as f32)) * 100.0
     );
 }
+
+#[test]
+fn test_rebalance_consistent_hash() {
+    let config = Config { num_shards: Some(1024), ..Config::default() };
+    let rebalancer = Rebalancer { config, algo: Algorithm::ConsistentHash };
+    let nodes: Vec<Node> = (0..5)
+        .map(|i| Node {
+            uuid: uuid::Uuid::new_v4(),
+            weight: 1 + i,
+            ..Node::default()
+        })
+        .collect();
+    let count = |topology: &[Topology], node: &Node| {
+        topology.iter().filter(|t| &t.master == node).count()
+    };
+
+    let old = rebalancer.rebalance(&nodes[..4], Vec::new()).unwrap();
+    assert_eq!(old.len(), 1024);
+    assert!(nodes[..4].iter().all(|n| count(&old, n) > 0));
+    assert!(count(&old, &nodes[3]) > count(&old, &nodes[0]));
+    // topology is computed with ZERO knowledge, order of nodes doesn't matter.
+    let mut reversed = nodes[..4].to_vec();
+    reversed.reverse();
+    assert!(rebalancer.rebalance(&reversed, Vec::new()).unwrap() == old);
+
+    // adding a node moves shards only to the new node.
+    let new = rebalancer.rebalance(&nodes, old.clone()).unwrap();
+    let diffs = diff_topology(&old, &new);
+    assert_eq!(diffs.len(), count(&new, &nodes[4]));
+    assert!(diffs.iter().all(|(_, new)| new.master == nodes[4]));
+
+    // removing a node moves only the shards hosted by that node.
+    let new = rebalancer.rebalance(&nodes[1..4], old.clone()).unwrap();
+    let diffs = diff_topology(&old, &new);
+    assert_eq!(diffs.len(), count(&old, &nodes[0]));
+    assert!(diffs.iter().all(|(old, _)| old.master == nodes[0]));
+}
+
+#[test]
+fn test_rebalance_replicas() {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    let zone = |node: &Node| node.path.iter().nth(1).map(|z| z.to_os_string());
+    // 3 zones, with 2 racks in each zone, and one heavy node in zone-0.
+    let nodes: Vec<Node> = (0..6)
+        .map(|i| Node {
+            uuid: uuid::Uuid::new_v4(),
+            path: format!("/zone-{}/rack-{}", i % 3, i / 3).into(),
+            weight: if i == 0 { 2 } else { 1 },
+            ..Node::default()
+        })
+        .collect();
+
+    for num_replicas in [1, 2] {
+        let config = Config {
+            num_shards: Some(256),
+            num_replicas: Some(num_replicas),
+            ..Config::default()
+        };
+        let rebalancer = Rebalancer { config, algo: Algorithm::Replicated };
+        let topology = rebalancer.rebalance(&nodes, Vec::new()).unwrap();
+
+        let mut layouts: BTreeMap<uuid::Uuid, Vec<uuid::Uuid>> = BTreeMap::new();
+        let mut load: BTreeMap<uuid::Uuid, usize> = BTreeMap::new();
+        for t in topology.iter() {
+            assert_eq!(t.replicas.len(), num_replicas as usize);
+            // master and replicas are spread across zones, any one zone can fail.
+            let mut zones = BTreeSet::new();
+            zones.insert(zone(&t.master));
+            t.replicas.iter().for_each(|n| assert!(zones.insert(zone(n))));
+
+            // all shards on a master share the same replicas.
+            let replicas: Vec<uuid::Uuid> = t.replicas.iter().map(|n| n.uuid).collect();
+            let layout = layouts.entry(t.master.uuid).or_insert_with(|| replicas.clone());
+            assert_eq!(layout, &replicas);
+
+            for node in std::iter::once(&t.master).chain(t.replicas.iter()) {
+                *load.entry(node.uuid).or_default() += 1;
+            }
+        }
+
+        // with a replica in every zone, load on each zone is fixed. Else weighed
+        // load is balanced, upto a master's group that moves as a single unit.
+        if num_replicas == 1 {
+            let loads: Vec<f64> = nodes
+                .iter()
+                .map(|n| (load[&n.uuid] as f64) / (n.weight as f64))
+                .collect();
+            let (min, max) = loads
+                .iter()
+                .fold((f64::MAX, 0.0_f64), |(a, b), l| (a.min(*l), b.max(*l)));
+            let group = nodes
+                .iter()
+                .map(|n| topology.iter().filter(|t| t.master == *n).count())
+                .max()
+                .unwrap();
+            assert!(max - min <= group as f64, "{:?} {}", loads, group);
+        }
+    }
+
+    // replicas are capped by the number of nodes, and fall back to nearer nodes.
+    let config = Config { num_replicas: Some(3), ..Config::default() };
+    let rebalancer = Rebalancer { config, algo: Algorithm::Replicated };
+    let same_zone = vec![nodes[0].clone(), nodes[3].clone()];
+    let topology = rebalancer.rebalance(&same_zone, Vec::new()).unwrap();
+    assert!(topology.iter().all(|t| t.replicas.len() == 1 && t.replicas[0] != t.master));
+
+    // rebalance fails on an empty cluster or a node with ZERO weight.
+    for algo in [Algorithm::SingleNode, Algorithm::ConsistentHash, Algorithm::Replicated]
+    {
+        let rebalancer = Rebalancer { config: Config::default(), algo };
+        assert!(rebalancer.rebalance(&[], Vec::new()).is_err());
+    }
+    let zero = vec![nodes[0].clone(), Node { weight: 0, ..nodes[1].clone() }];
+    assert!(rebalancer.rebalance(&zero, Vec::new()).is_err());
+}
+
+#[test]
+fn test_promote_replicas() {
+    let config = Config {
+        num_shards: Some(64),
+        num_replicas: Some(2),
+        ..Config::default()
+    };
+    let rebalancer = Rebalancer { config, algo: Algorithm::Replicated };
+    let nodes: Vec<Node> = (0..4)
+        .map(|i| Node {
+            uuid: uuid::Uuid::new_v4(),
+            path: format!("/zone-{}", i).into(),
+            ..Node::default()
+        })
+        .collect();
+    let topology = rebalancer.rebalance(&nodes, Vec::new()).unwrap();
+
+    // first live replica is promoted, shards on live masters are untouched.
+    let live = &nodes[1..];
+    let promoted = promote_replicas(&topology, live);
+    for (old, new) in topology.iter().zip(promoted.iter()) {
+        assert_eq!(old.shard, new.shard);
+        if old.master == nodes[0] {
+            assert!(new.master == old.replicas[0]);
+            assert!(new.replicas == old.replicas[1..]);
+        } else {
+            assert!(new.master == old.master);
+            assert!(!new.replicas.contains(&nodes[0]));
+        }
+    }
+
+    // failing nodes one after another is same as failing them together.
+    let a = promote_replicas(&promote_replicas(&topology, live), &nodes[2..]);
+    let b = promote_replicas(&topology, &nodes[2..]);
+    assert!(a == b);
+    assert!(a.iter().all(|t| nodes[2..].contains(&t.master)));
+
+    // shard without a live replica stays with its failed master.
+    let c = promote_replicas(&topology, &[]);
+    assert!(c == topology);
+}
+
+#[test]
+fn test_top_up_replicas() {
+    let config = Config {
+        num_shards: Some(64),
+        num_replicas: Some(2),
+        ..Config::default()
+    };
+    let rebalancer = Rebalancer {
+        config: config.clone(),
+        algo: Algorithm::Replicated,
+    };
+    let nodes: Vec<Node> = (0..5)
+        .map(|i| Node {
+            uuid: uuid::Uuid::new_v4(),
+            path: format!("/zone-{}", i).into(),
+            ..Node::default()
+        })
+        .collect();
+    let topology = rebalancer.rebalance(&nodes, Vec::new()).unwrap();
+
+    // promoted shards get back their replicas, on live nodes.
+    let live = &nodes[1..];
+    let promoted = promote_replicas(&topology, live);
+    let topped = top_up_replicas(&config, &promoted, live);
+    for (old, new) in promoted.iter().zip(topped.iter()) {
+        assert!(new.master == old.master);
+        assert!(new.replicas[..old.replicas.len()] == old.replicas[..]);
+        assert_eq!(new.replicas.len(), 2);
+        assert!(!new.replicas.contains(&new.master));
+        assert!(new.replicas.iter().all(|n| live.contains(n)));
+        assert!(new.replicas[0] != new.replicas[1]);
+    }
+    // complete topology is left as is.
+    assert!(top_up_replicas(&config, &topped, live) == topped);
+
+    // replicas are capped by the number of nodes.
+    let live = &nodes[3..];
+    let topped = top_up_replicas(&config, &promote_replicas(&topology, live), live);
+    let mut iter = topped.iter().filter(|t| live.contains(&t.master));
+    assert!(iter.all(|t| t.replicas.len() == 1 && live.contains(&t.replicas[0])));
+}
@@ -0,0 +1,169 @@
+//! Module implement replication of shard state from master shard to its replicas.
+//!
+//! In a multi-node cluster, every shard is mastered by one node and replicated on
+//! [Config::num_replicas][crate::Config::num_replicas] other nodes, refer
+//! [Topology][crate::rebalance::Topology]. Master shard streams the changes to its
+//! session state, the same [Record]s that are written to its session log, refer
+//! [crate::wal], to the cluster thread. Cluster thread in turn sends them to the
+//! replica nodes, over the inter-node connection, refer [crate::membership].
+//!
+//! * Records are numbered by the master node, with a seqno for each shard.
+//! * RESET, carrying the next seqno, is followed by records capturing the live state
+//!   of the master shard. It is sent when the master shard is spawned, and whenever
+//!   a node joins or re-joins the cluster, since records sent to a disconnected node
+//!   are lost.
+//! * Replica node applies the records in seqno order into a [Replica]. A missing
+//!   seqno marks the replica as stale, and subsequent records are ignored until the
+//!   next RESET.
+//! * Replica node acknowledges the seqno of the last record applied, back to the
+//!   master, once the live state sent with RESET is applied, refer [Replica::to_ack].
+//!
+//! **Fail-over**
+//!
+//! When a node fails, every node promotes the first live replica of the shards
+//! mastered by the failed node as their new master, refer
+//! [promote_replicas][crate::rebalance::promote_replicas]. Since all shards on a node
+//! share the same replicas, all of them are promoted to the same node. Promoted
+//! node spawns the shards from their [Replica], much like a restart from the session
+//! log, and clients reconnecting to the new master resume their sessions.
+//!
+//! * Replicas are promoted only by a membership view with a majority of the
+//!   configured nodes, refer [crate::membership].
+//! * Stale replica, or a shard without replica, is not promoted, since its sessions
+//!   would be lost. Shard is unavailable until its replica is RESET.
+//! * PUBACK, SUBACK and UNSUBACK are held until at least one replica has
+//!   acknowledged the records sent by the master shard up to then, hence
+//!   acknowledged changes survive the failure of the master node, as long as that
+//!   replica survives it. A shard without live replicas acknowledges clients right
+//!   away. Messages routed to offline sessions in other shards are replicated by
+//!   those shards, PUBACK does not wait for them.
+//!
+//! **Rebalance**
+//!
+//! When a shard migrates to another node, say a node that has joined the cluster, the
+//! old master closes the shard and replicates its live state and its final records to
+//! the new master as well, followed by HANDOVER carrying the next seqno. New master
+//! spawns the shard from its [Replica], same as fail-over, refer [crate::membership].
+//! If the shard is not replicated, that is with ZERO
+//! [Config::num_replicas][crate::Config::num_replicas], new master spawns the shard
+//! without sessions.
+//!
+//! Messages queued for offline sessions, refer [crate::offline], are replicated as
+//! [Record::Queued] and [Record::Dequeued], and re-queued on the promoted shard.
+
+use std::collections::BTreeSet;
+
+use crate::wal::{Record, Replay};
+use crate::ClientID;
+use crate::{Error, ErrorKind, Result};
+
+/// Type implement the replicated state of a single shard, refer to module
+/// documentation.
+pub struct Replica {
+    shard_id: u32,
+    /// Seqno of the next record.
+    seqno: u64,
+    /// Seqno following the records that capture the live state, sent along with
+    /// RESET.
+    reset_till: u64,
+    /// A record was missed, wait for the next RESET.
+    stale: bool,
+    /// Sessions whose clients are connected with the master shard.
+    online: BTreeSet<ClientID>,
+    replay: Replay,
+}
+
+impl Replica {
+    /// Create an empty replica on RESET, expecting `seqno` as the next record,
+    /// followed by `count` records capturing the live state of the master shard.
+    pub fn new(shard_id: u32, seqno: u64, count: u64) -> Replica {
+        Replica {
+            shard_id,
+            seqno,
+            reset_till: seqno.saturating_add(count),
+            stale: false,
+            online: BTreeSet::default(),
+            replay: Replay::default(),
+        }
+    }
+
+    /// Return whether a record was missed since the last RESET.
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// Return the seqno of the last record applied, to acknowledge the master. None
+    /// if the replica is stale, or if the live state sent with RESET is not yet
+    /// applied.
+    pub fn to_ack(&self) -> Option<u64> {
+        match self.stale || self.seqno < self.reset_till {
+            true => None,
+            false => Some(self.seqno.saturating_sub(1)),
+        }
+    }
+
+    /// Apply `record` numbered as `seqno`, `now` is in seconds since UNIX_EPOCH.
+    /// Return error if a record was missed, there after the replica is stale.
+    pub fn apply(&mut self, seqno: u64, record: Record, now: u64) -> Result<()> {
+        if self.stale {
+            return Ok(());
+        } else if seqno != self.seqno {
+            self.stale = true;
+            err!(
+                InvalidInput,
+                desc: "replica shard {} expected seqno {} got {}",
+                self.shard_id,
+                self.seqno,
+                seqno
+            )?;
+        }
+        self.seqno = seqno.saturating_add(1);
+
+        match &record {
+            Record::Session { client_id, .. } => {
+                self.online.insert(client_id.clone());
+            }
+            Record::Offline { client_id, .. } | Record::Remove { client_id } => {
+                self.online.remove(client_id);
+            }
+            _ => (),
+        }
+        self.replay.apply(self.shard_id, record, now);
+
+        Ok(())
+    }
+
+    /// Check HANDOVER from the old master, `seqno` being the next record it would
+    /// have sent. Return error if a record was missed, there after the replica is
+    /// stale.
+    pub fn handover(&mut self, seqno: u64) -> Result<()> {
+        if self.stale || seqno == self.seqno {
+            return Ok(());
+        }
+        self.stale = true;
+        err!(
+            InvalidInput,
+            desc: "replica shard {} handover expected seqno {} got {}",
+            self.shard_id,
+            self.seqno,
+            seqno
+        )
+    }
+
+    /// Return the replicated state, to spawn the shard as the new master. Clients
+    /// connected with the failed master go offline at `now`.
+    pub fn into_replay(self, now: u64) -> Replay {
+        let mut replay = self.replay;
+        for client_id in self.online.iter() {
+            if let Some(state) = replay.sessions.get_mut(client_id) {
+                state.offline_at = now;
+            }
+        }
+        replay.sessions.retain(|_, state| !state.is_expired(now));
+        replay
+    }
+}
+
+#[cfg(test)]
+#[path = "replica_test.rs"]
+mod replica_test;
@@ -0,0 +1,56 @@
+use super::*;
+
+use crate::fixtures::{client_id, new_subscription};
+
+#[test]
+fn test_replica() {
+    let (a, b) = (client_id("a"), client_id("b"));
+    let records = vec![
+        Record::Session { client_id: a.clone(), session_expiry_interval: 60 },
+        Record::Subscribe { subscription: new_subscription("a", "x/+") },
+        Record::Session { client_id: b.clone(), session_expiry_interval: 60 },
+        Record::Offline { client_id: b.clone(), timestamp: 100 },
+    ];
+
+    // last record is acknowledged only after the live state sent with RESET.
+    let mut replica = Replica::new(5, 10, 4);
+    for (seqno, record) in (10..).zip(records) {
+        assert_eq!(replica.to_ack(), None);
+        replica.apply(seqno, record, 100).unwrap();
+    }
+    assert_eq!(replica.to_ack(), Some(13));
+
+    // missing record marks the replica as stale, and later records are ignored.
+    let record = Record::Remove { client_id: a.clone() };
+    assert!(replica.apply(15, record.clone(), 100).is_err());
+    assert!(replica.is_stale());
+    assert!(replica.apply(16, record, 100).is_ok());
+    assert_eq!(replica.to_ack(), None);
+
+    // online client goes offline on promotion, offline client keeps its timestamp.
+    let replay = replica.into_replay(130);
+    assert_eq!(replay.sessions.len(), 2);
+    let state = &replay.sessions[&a];
+    assert_eq!(state.offline_at, 130);
+    assert_eq!(state.subscriptions.values().next().unwrap().shard_id, 5);
+    assert_eq!(replay.sessions[&b].offline_at, 100);
+
+    // expired sessions are not promoted.
+    let mut replica = Replica::new(5, 1, 0);
+    let record = Record::Session { client_id: b.clone(), session_expiry_interval: 60 };
+    replica.apply(1, record, 100).unwrap();
+    replica.apply(2, Record::Offline { client_id: b, timestamp: 100 }, 100).unwrap();
+    assert!(replica.into_replay(200).sessions.is_empty());
+
+    // handover with a seqno beyond the last applied record marks the replica stale.
+    let mut replica = Replica::new(5, 1, 0);
+    let record = Record::Session {
+        client_id: client_id("c"),
+        session_expiry_interval: 60,
+    };
+    replica.apply(1, record, 100).unwrap();
+    assert!(replica.handover(2).is_ok());
+    assert!(!replica.is_stale());
+    assert!(replica.handover(3).is_err());
+    assert!(replica.is_stale());
+}
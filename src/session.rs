@@ -1,12 +1,14 @@
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 
-use std::collections::{BTreeMap, VecDeque};
-use std::{cmp, net};
+use std::collections::BTreeMap;
+use std::{cmp, mem, net};
 
-use crate::{message, v5};
+use crate::{message, store, v5, wal};
 use crate::{ClientID, Config, PacketID, SubscribedTrie, TopicFilter, TopicName};
 use crate::{Error, ErrorKind, ReasonCode, Result};
-use crate::{KeepAlive, Message, PktRx, PktTx, QueueStatus, Shard};
+use crate::{
+    KeepAlive, Message, OfflineQueue, PeerCred, PktRx, PktTx, QueueStatus, Shard,
+};
 
 type Messages = Vec<Message>;
 type Packets = Vec<v5::Packet>;
@@ -18,34 +20,18 @@ type QueuePkt = QueueStatus<v5::Packet>;
 // *Will Message*
 //
 // TODO The Will Message MUST be published after the Network Connection is subsequently
-//      closed and either the Will Delay Interval has elapsed or the Session ends,
-//      unless the Will Message has been deleted by the Server on receipt of a
-//      DISCONNECT packet with Reason Code 0x00 (Normal disconnection) or a new
-//      Network Connection for the ClientID is opened before the Will Delay Interval
-//      has elapsed.
-//
-//      Situations in which the Will Message is published include, but are not
-//      limited to:
-//      * An I/O error or network failure detected by the Server.
-//      * The Client fails to communicate within the Keep Alive time.
-//      * The Client closes the Network Connection without first sending a DISCONNECT
-//        packet with a Reason Code 0x00 (Normal disconnection).
-//      * The Server closes the Network Connection without first receiving a
-//        DISCONNECT packet with a Reason Code 0x00 (Normal disconnection).
-// TODO The Will Message MUST be removed from the stored Session State in the Server
-//      once it has been published or the Server has received a DISCONNECT packet
-//      with a Reason Code of 0x00 (Normal disconnection) from the Client.
+//      closed and either the Will Delay Interval has elapsed or the Session ends.
+//      Will Delay Interval is not yet supported, Will Message is published as soon
+//      as the Network Connection is closed without a DISCONNECT packet with Reason
+//      Code 0x00 (Normal disconnection).
 // TODO In the case of a Server shutdown or failure, the Server MAY defer publication
 //      of Will Messages until a subsequent restart. If this happens, there might be
 //      a delay between the time the Server experienced failure and when the Will
 //      Message is published.
-// TODO If the Will Flag is set to 0, then the Will QoS MUST be set to 0 (0x00)
-//      If the Will Flag is set to 1, the value of Will QoS can be 0 (0x00),
-//      1 (0x01), or 2 (0x02) [MQTT-3.1.2-12]. A value of 3 (0x03) is a Malformed Packet.
 //
 // *Session Reconnect/Restart*
 //
-// TODO: `session_expiry_interval`.
+// TODO: `session_expiry_interval` from DISCONNECT properties.
 // TODO: For restart, try seqno handshake between broker/client during CONNECT/CONNACK.
 //       seqno, can be exchanged via user-property.
 //
@@ -79,6 +65,14 @@ pub struct SessionArgs {
     pub shard_id: u32,
     pub miot_tx: PktTx,
     pub session_rx: PktRx,
+    pub peer_cred: Option<PeerCred>,
+    pub mount_point: Option<String>,
+    // Log changes to session state, refer [crate::wal], applicable only for
+    // persistent sessions.
+    pub durable: bool,
+    // Shard is replicated, refer [crate::replica], acknowledgements are held until
+    // a replica has applied the changes.
+    pub replicated: bool,
 }
 
 /// Type implement the session for every connected client.
@@ -95,6 +89,11 @@ pub struct Session {
     client_topic_alias_max: Option<u16>,
     session_expiry_interval: Option<u32>,
     config: Config,
+    // Mount-point of the listener this client connected through, refer
+    // [crate::ConfigListener::mount_point].
+    mount_point: Option<String>,
+    // Credentials of the peer process, for clients connected via unix transport.
+    peer_cred: Option<PeerCred>,
 
     // Outbound channel to Miot thread.
     miot_tx: PktTx,
@@ -102,7 +101,6 @@ pub struct Session {
     session_rx: PktRx,
 
     // MQTT Will-Delay-Publish
-    #[allow(dead_code)]
     will_message: Option<WillMessage>,
     // MQTT Keep alive between client and broker.
     keep_alive: KeepAlive,
@@ -122,11 +120,80 @@ pub struct Session {
     subscriptions: BTreeMap<TopicFilter, v5::Subscription>,
     // Manages out-bound messages to client.
     cout: message::ClientOut,
+    // Changes to session state, yet to be written to shard's session log.
+    journal: Option<Vec<wal::Record>>,
+    // Messages queued while the client was offline, yet to be delivered.
+    queue: Option<OfflineQueue>,
+    // Offline queue has overflowed, client shall be disconnected with QuotaExceeded.
+    quota_exceeded: bool,
+    // PUBACK, SUBACK and UNSUBACK held until a replica has applied the changes to
+    // session state, along with the seqno of the last record sent by then, None if
+    // the shard is not replicated.
+    held_acks: Option<Vec<(Option<u64>, Message)>>,
 }
 
 pub struct SessionStats;
 
-#[allow(dead_code)]
+/// Type implement the state of a persistent session, held by the shard while the
+/// client is offline, and rebuilt from the session log after a restart.
+pub struct SessionState {
+    pub client_id: ClientID,
+    pub session_expiry_interval: u32,
+    /// Seconds since UNIX_EPOCH, when the client went offline.
+    pub offline_at: u64,
+    pub subscriptions: BTreeMap<TopicFilter, v5::Subscription>,
+    pub cout: message::ClientOut,
+    /// Messages routed to this session while the client is offline, refer
+    /// [crate::offline].
+    pub queue: Option<OfflineQueue>,
+}
+
+impl SessionState {
+    pub fn new(client_id: ClientID, offline_at: u64) -> SessionState {
+        SessionState {
+            client_id,
+            session_expiry_interval: 0,
+            offline_at,
+            subscriptions: BTreeMap::default(),
+            cout: message::ClientOut::default(),
+            queue: None,
+        }
+    }
+
+    /// Return whether the session has expired at `now`, seconds since UNIX_EPOCH.
+    /// Session expiry interval of 0xFFFFFFFF means the session never expires.
+    pub fn is_expired(&self, now: u64) -> bool {
+        match self.session_expiry_interval {
+            u32::MAX => false,
+            secs => now >= self.offline_at.saturating_add(secs.into()),
+        }
+    }
+
+    pub fn remove_topic_filters(&self, topic_filters: &mut SubscribedTrie) {
+        for (topic_filter, value) in self.subscriptions.iter() {
+            topic_filters.unsubscribe(topic_filter, value);
+        }
+    }
+
+    /// Return the records to checkpoint this session in shard's session log.
+    pub fn to_records(&self) -> Vec<wal::Record> {
+        let mut records = to_records(
+            &self.client_id,
+            self.session_expiry_interval,
+            &self.subscriptions,
+            &self.cout,
+        );
+        records.push(wal::Record::Offline {
+            client_id: self.client_id.clone(),
+            timestamp: self.offline_at,
+        });
+        if let Some(queue) = self.queue.as_ref() {
+            records.extend(queue.to_records());
+        }
+        records
+    }
+}
+
 struct WillMessage {
     retain: bool,
     qos: v5::QoS,
@@ -137,27 +204,29 @@ struct WillMessage {
 
 impl Session {
     pub fn start(args: SessionArgs, config: Config, pkt: &v5::Connect) -> Session {
-        let cout = message::ClientOut {
-            seqno: 1,
-            index: BTreeMap::default(),
-            next_packet_id: 1,
-            back_log: VecDeque::default(),
-        };
+        let cout = message::ClientOut::default();
 
         let (_clean_start, wflag, qos, retain) = pkt.flags.unwrap();
         let will_message = match wflag {
             true => Some(WillMessage {
                 retain,
                 qos,
-                properties: pkt.payload.will_properties.clone().unwrap(),
+                properties: pkt.payload.will_properties.clone().unwrap_or_default(),
                 topic: pkt.payload.will_topic.clone().unwrap(),
                 payload: pkt.payload.will_payload.clone().unwrap(),
             }),
             false => None,
         };
 
-        let prefix = format!("session:{}", args.addr);
+        let prefix = match &args.peer_cred {
+            Some(peer_cred) => format!("session:{}:{}", peer_cred, args.addr),
+            None => format!("session:{}", args.addr),
+        };
         let sei = config.mqtt_session_expiry_interval(pkt.session_expiry_interval());
+        let journal = match sei {
+            Some(secs) if secs > 0 && args.durable => Some(Vec::default()),
+            _ => None,
+        };
         Session {
             client_id: args.client_id,
             prefix: prefix,
@@ -166,6 +235,8 @@ impl Session {
             client_topic_alias_max: pkt.topic_alias_max(),
             session_expiry_interval: sei,
             config: config.clone(),
+            mount_point: args.mount_point,
+            peer_cred: args.peer_cred,
 
             miot_tx: args.miot_tx,
             session_rx: args.session_rx,
@@ -179,14 +250,23 @@ impl Session {
             qos2: Vec::default(),
             subscriptions: BTreeMap::default(),
             cout,
+            journal,
+            queue: None,
+            quota_exceeded: false,
+            held_acks: args.replicated.then(Vec::default),
         }
     }
 
     pub fn success_ack(&mut self, pkt: &v5::Connect, _shard: &Shard) -> v5::ConnAck {
+        // absence of maximum-qos in CONNACK means QoS-2 is supported.
+        let maximum_qos = match self.config.mqtt_maximum_qos().try_into().unwrap() {
+            v5::QoS::ExactlyOnce => None,
+            qos => Some(qos),
+        };
         let mut props = v5::ConnAckProperties {
             session_expiry_interval: self.session_expiry_interval,
             receive_maximum: Some(self.config.mqtt_receive_maximum()),
-            maximum_qos: Some(self.config.mqtt_maximum_qos().try_into().unwrap()),
+            maximum_qos,
             retain_available: Some(self.config.mqtt_retain_available()),
             max_packet_size: Some(self.config.mqtt_max_packet_size()),
             assigned_client_identifier: None,
@@ -211,6 +291,100 @@ impl Session {
         std::mem::drop(self);
         SessionStats
     }
+
+    /// Return whether session state shall be retained after the client goes offline,
+    /// that is, `session_expiry_interval` is non-ZERO.
+    pub fn is_persistent(&self) -> bool {
+        matches!(self.session_expiry_interval, Some(secs) if secs > 0)
+    }
+
+    /// Close the session and return its state, to be held by the shard while the
+    /// client is offline.
+    pub fn detach(mut self) -> SessionState {
+        // acknowledgements are specific to the network connection.
+        self.cout.back_log.retain(|msg| matches!(msg, Message::Packet { .. }));
+
+        SessionState {
+            client_id: self.client_id.clone(),
+            session_expiry_interval: self.session_expiry_interval.unwrap_or(0),
+            offline_at: store::unix_secs(),
+            subscriptions: mem::take(&mut self.subscriptions),
+            cout: mem::take(&mut self.cout),
+            queue: self.queue.take(),
+        }
+    }
+
+    /// Resume the session from `state`, when client reconnects with clean-start as
+    /// ZERO. Subscriptions in `state` are expected to be already in shard's
+    /// topic-filters.
+    pub fn resume(&mut self, state: SessionState) {
+        let SessionState { subscriptions, mut cout, queue, .. } = state;
+
+        self.subscriptions = subscriptions;
+        match queue {
+            // queued messages are held back, client is disconnected with
+            // QuotaExceeded and shall receive them on its next connection.
+            Some(mut queue) if queue.is_overflowed() => {
+                warn!(
+                    "{} offline queue overflowed, {} messages",
+                    self.prefix,
+                    queue.len()
+                );
+                queue.clear_overflowed();
+                self.queue = Some(queue);
+                self.quota_exceeded = true;
+            }
+            Some(queue) if !queue.is_empty() => self.queue = Some(queue),
+            Some(_) | None => (),
+        }
+
+        // re-send un-acknowledged PUBLISH, with their original packet-id and with
+        // DUP flag set [MQTT-4.4.0-1], [MQTT-4.4.0-2].
+        let mut inflight: Vec<Message> =
+            mem::take(&mut cout.index).into_values().collect();
+        inflight.sort_by_key(|msg| match msg {
+            Message::Packet { seqno, .. } => *seqno,
+            _ => 0,
+        });
+        for mut msg in inflight.into_iter() {
+            if let Message::Packet { packet: v5::Packet::Publish(publ), .. } = &mut msg {
+                publ.duplicate = true;
+            }
+            self.cout.back_log.push_back(msg);
+        }
+        self.cout.back_log.extend(cout.back_log);
+        self.cout.seqno = cout.seqno;
+        self.cout.next_packet_id = cout.next_packet_id;
+    }
+
+    /// Return changes to session state since the last call, to be written to shard's
+    /// session log.
+    pub fn take_journal(&mut self) -> Vec<wal::Record> {
+        match self.journal.as_mut() {
+            Some(journal) => mem::take(journal),
+            None => Vec::new(),
+        }
+    }
+
+    /// Return the records to checkpoint this session in shard's session log, None
+    /// if the session is not logged.
+    pub fn to_records(&self) -> Option<Vec<wal::Record>> {
+        self.journal.as_ref()?;
+
+        let sei = self.session_expiry_interval.unwrap_or(0);
+        let mut records =
+            to_records(&self.client_id, sei, &self.subscriptions, &self.cout);
+        if let Some(queue) = self.queue.as_ref() {
+            records.extend(queue.to_records());
+        }
+        Some(records)
+    }
+
+    fn log_record(&mut self, record: wal::Record) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push(record)
+        }
+    }
 }
 
 impl Session {
@@ -230,6 +404,15 @@ impl Session {
     pub fn route_packets(&mut self, shard: &mut Shard) -> Result<QueuePkt> {
         let rc_disconnected = QueueStatus::Disconnected(Vec::new());
 
+        if mem::take(&mut self.quota_exceeded) {
+            err!(
+                ProtocolError,
+                code: QuotaExceeded,
+                "{} offline queue overflowed",
+                self.prefix
+            )?;
+        }
+
         let mut down_status = self.session_rx.try_recvs(&self.prefix);
         match down_status.take_values() {
             pkts if pkts.len() == 0 => {
@@ -276,19 +459,35 @@ impl Session {
         // PUBLISH, PUBLISH-ack lead to message routing.
 
         let msgs = match pkt {
-            v5::Packet::PingReq => vec![Message::new_client_ack(v5::Packet::PingReq)],
+            v5::Packet::PingReq => vec![Message::new_client_ack(v5::Packet::PingResp)],
             v5::Packet::Publish(publish) => self.do_publish(shard, publish)?,
             v5::Packet::Subscribe(sub) => self.do_subscribe(shard, sub)?,
-            v5::Packet::UnSubscribe(_unsub) => todo!(),
-            v5::Packet::PubAck(_puback) => todo!(),
-            v5::Packet::PubRec(_puback) => todo!(),
-            v5::Packet::PubRel(_puback) => todo!(),
-            v5::Packet::PubComp(_puback) => todo!(),
-            v5::Packet::Disconnect(_disconn) => {
-                // TODO: handle disconnect packet, its header and properties.
+            v5::Packet::UnSubscribe(unsub) => self.do_unsubscribe(shard, unsub)?,
+            v5::Packet::PubAck(puback) => self.do_puback(puback)?,
+            v5::Packet::PubRec(_) | v5::Packet::PubRel(_) | v5::Packet::PubComp(_) => {
+                // QoS-2 is not supported, refer [Config::mqtt_maximum_qos].
+                err!(
+                    ProtocolError,
+                    code: ProtocolError,
+                    "{} packet type {:?} without QoS-2 support",
+                    self.prefix,
+                    pkt.to_packet_type()
+                )?
+            }
+            v5::Packet::Disconnect(disconn) => {
+                // TODO: handle disconnect properties.
+                if disconn.code != v5::DisconnReasonCode::DiconnectWillMessage {
+                    // Will Message is discarded on normal disconnection [MQTT-3.14.4-3]
+                    self.will_message = None;
+                }
                 err!(Disconnected, code: Success, "{} client disconnect", self.prefix)?
             }
-            v5::Packet::Auth(_auth) => todo!(),
+            v5::Packet::Auth(_) => err!(
+                ProtocolError,
+                code: ProtocolError,
+                "{} AUTH without authentication method",
+                self.prefix
+            )?,
 
             // CONNECT, CONNACK, SUBACK, UNSUBACK, PINGRESP all lead to errors.
             v5::Packet::Connect(_) => err!(
@@ -316,37 +515,70 @@ impl Session {
         Ok(msgs)
     }
 
-    fn do_publish(&mut self, shard: &mut Shard, publ: v5::Publish) -> Result<Messages> {
-        // TODO: However, as the Server is permitted to map the Topic Name to another
-        //       name, it might not be the same as the Topic Name in the original
-        //       PUBLISH packet.
-
+    fn do_publish(
+        &mut self,
+        shard: &mut Shard,
+        mut publ: v5::Publish,
+    ) -> Result<Messages> {
         if self.is_duplicate(&publ) {
             return Ok(Vec::new());
         }
-        self.book_retain(shard, &publ)?;
         self.book_qos(&publ)?;
+        // Topic Name is mapped to the listener's mount-point, so that it might not be
+        // the same as the Topic Name in the original PUBLISH packet.
         let topic_name = self.publish_topic_name(&publ)?;
-        let subscrs = shard.match_subscribers(self, &topic_name);
+        publ.topic_name = self.mount_topic_name(topic_name);
+        // topic-alias is specific to this connection.
+        if let Some(props) = publ.properties.as_mut() {
+            props.topic_alias = None;
+        }
+
+        match self.route_publish(shard, publ.clone())? {
+            // nothing to wait for, acknowledge right away.
+            0 if publ.qos == v5::QoS::AtLeastOnce => {
+                self.unbook_qos(&publ);
+                let code = ReasonCode::NoMatchingSubscribers;
+                Ok(vec![Message::new_client_ack(new_puback(&publ, code))])
+            }
+            _ => Ok(Vec::new()),
+        }
 
+        // TODO: handle `message_expiry_interval`
+    }
+
+    // return the number of clients `publ` is routed to.
+    fn route_publish(&mut self, shard: &mut Shard, publ: v5::Publish) -> Result<usize> {
+        self.book_retain(shard, &publ)?;
+        let subscrs = shard.match_subscribers(self, &publ.topic_name);
+
+        let mut n = 0;
         for (_match_client_id, subscrs) in subscrs.into_iter() {
             if subscrs.len() == 0 {
                 continue;
             }
 
             shard.route_to_client(self, subscrs, publ.clone());
-
-            // TODO: handle retain_as_published.
-            // TODO: handle retain_forward_rule
+            n += 1;
         }
 
-        // TODO: handle `message_expiry_interval`
+        Ok(n)
+    }
+
+    fn do_puback(&mut self, puback: v5::Pub) -> Result<Messages> {
+        match self.cout.index.remove(&puback.packet_id) {
+            Some(_msg) => self.log_record(wal::Record::Acked {
+                client_id: self.client_id.clone(),
+                packet_id: puback.packet_id,
+            }),
+            None => debug!("{} puback for unknown {}", self.prefix, puback.packet_id),
+        }
 
         Ok(Vec::new())
     }
 
     // return suback and retained-messages if any.
     fn do_subscribe(&mut self, shard: &Shard, sub: v5::Subscribe) -> Result<Messages> {
+        let mut retains = Vec::new();
         let subscription_id: Option<u32> = match &sub.properties {
             Some(props) => props.subscription_id.clone().map(|x| *x),
             None => None,
@@ -355,8 +587,9 @@ impl Session {
         let mut return_codes = Vec::with_capacity(sub.filters.len());
         for filter in sub.filters.iter() {
             let (rfr, retain_as_published, no_local, qos) = filter.opt.unwrap();
+            let topic_filter = self.mount_topic_filter(filter.topic_filter.clone());
             let subscription = v5::Subscription {
-                topic_filter: filter.topic_filter.clone(),
+                topic_filter: topic_filter.clone(),
 
                 client_id: self.client_id.clone(),
                 shard_id: shard.shard_id,
@@ -364,21 +597,58 @@ impl Session {
                 qos,
                 no_local,
                 retain_as_published,
-                retain_forward_rule: rfr,
+                retain_forward_rule: rfr.clone(),
             };
 
-            shard
-                .as_topic_filters()
-                .subscribe(&filter.topic_filter, subscription.clone());
-            self.subscriptions.insert(filter.topic_filter.clone(), subscription);
+            let topic_filters = shard.as_topic_filters();
+            let exists = match self
+                .subscriptions
+                .insert(topic_filter.clone(), subscription.clone())
+            {
+                // replace the existing subscription [MQTT-3.8.4-3].
+                Some(old) => {
+                    topic_filters.unsubscribe(&topic_filter, &old);
+                    true
+                }
+                None => false,
+            };
+            topic_filters.subscribe(&topic_filter, subscription.clone());
+            self.log_record(wal::Record::Subscribe {
+                subscription: subscription.clone(),
+            });
 
             let server_qos = v5::QoS::try_from(self.config.mqtt_maximum_qos()).unwrap();
-            let rc = match cmp::max(server_qos, qos) {
+            let rc = match cmp::min(server_qos, qos) {
                 v5::QoS::AtMostOnce => v5::SubAckReasonCode::QoS0,
                 v5::QoS::AtLeastOnce => v5::SubAckReasonCode::QoS1,
                 v5::QoS::ExactlyOnce => v5::SubAckReasonCode::QoS2,
             };
-            return_codes.push(rc)
+            return_codes.push(rc);
+
+            // When a new Non‑shared Subscription is made, the last retained message, if
+            // any, on each matching topic name is sent to the Client as directed by the
+            // Retain Handling Subscription Option. These messages are sent with the
+            // RETAIN flag set to 1. Which retained messages are sent is controlled by
+            // the Retain Handling Subscription Option. At the time of the Subscription:
+            //
+            // * If Retain Handling is set to 0 the Server MUST send the retained
+            //   messages matching the Topic Filter of the subscription to the Client
+            //   [MQTT-3.3.1-9].
+            // * If Retain Handling is set to 1 then if the subscription did not already
+            //   exist, the Server MUST send all retained message matching the Topic
+            //   Filter of the subscription to the Client, and if the subscription did
+            //   exist the Server MUST NOT send the retained messages. [MQTT-3.3.1-10].
+            // * If Retain Handling is set to 2, the Server MUST NOT send the retained
+            //   messages [MQTT-3.3.1-11].
+            let retain = match rfr {
+                _ if topic_filter.starts_with("$share/") => false,
+                v5::RetainForwardRule::OnEverySubscribe => true,
+                v5::RetainForwardRule::OnNewSubscribe => !exists,
+                v5::RetainForwardRule::Never => false,
+            };
+            if retain {
+                retains.extend(self.retained_messages(shard, subscription));
+            }
         }
 
         let sub_ack = v5::SubAck {
@@ -387,22 +657,101 @@ impl Session {
             return_codes,
         };
 
-        // When a new Non‑shared Subscription is made, the last retained message, if any,
-        // on each matching topic name is sent to the Client as directed by the
-        // Retain Handling Subscription Option. These messages are sent with the RETAIN
-        // flag set to 1. Which retained messages are sent is controlled by the Retain
-        // Handling Subscription Option. At the time of the Subscription:
-        //
-        // * If Retain Handling is set to 0 the Server MUST send the retained messages
-        //   matching the Topic Filter of the subscription to the Client [MQTT-3.3.1-9].
-        // * If Retain Handling is set to 1 then if the subscription did not already exist,
-        //   the Server MUST send all retained message matching the Topic Filter of the
-        //   subscription to the Client, and if the subscription did exist the Server
-        //   MUST NOT send the retained messages. [MQTT-3.3.1-10].
-        // * If Retain Handling is set to 2, the Server MUST NOT send the retained
-        //   messages [MQTT-3.3.1-11].
-
-        Ok(vec![Message::ClientAck { packet: v5::Packet::SubAck(sub_ack) }])
+        let mut msgs = vec![Message::ClientAck { packet: v5::Packet::SubAck(sub_ack) }];
+        msgs.extend(retains);
+        Ok(msgs)
+    }
+
+    // return unsuback.
+    fn do_unsubscribe(
+        &mut self,
+        shard: &Shard,
+        unsub: v5::UnSubscribe,
+    ) -> Result<Messages> {
+        let mut return_codes = Vec::with_capacity(unsub.filters.len());
+        for topic_filter in unsub.filters.iter() {
+            let topic_filter = self.mount_topic_filter(topic_filter.clone());
+            let rc = match self.subscriptions.remove(&topic_filter) {
+                Some(subscription) => {
+                    shard.as_topic_filters().unsubscribe(&topic_filter, &subscription);
+                    self.log_record(wal::Record::Unsubscribe {
+                        client_id: self.client_id.clone(),
+                        topic_filter,
+                    });
+                    v5::UnsubAckReasonCode::QoS0
+                }
+                None => v5::UnsubAckReasonCode::NoSubscriptionExisted,
+            };
+            return_codes.push(rc);
+        }
+
+        let unsub_ack = v5::UnsubAck {
+            packet_id: unsub.packet_id,
+            properties: None,
+            return_codes,
+        };
+
+        Ok(vec![Message::ClientAck { packet: v5::Packet::UnsubAck(unsub_ack) }])
+    }
+
+    // return retained messages matching `subscription`, ready to be sent to client.
+    fn retained_messages(&mut self, shard: &Shard, subscr: v5::Subscription) -> Messages {
+        let retained =
+            shard.as_retained_messages().match_topic_filter(&subscr.topic_filter);
+
+        let mut msgs = Vec::with_capacity(retained.len());
+        for publ in retained.into_iter() {
+            let msg = Message::Packet {
+                client_id: self.client_id.clone(),
+                shard_id: shard.shard_id,
+                seqno: 0,
+                packet_id: 0,
+                subscriptions: vec![subscr.clone()],
+                packet: v5::Packet::Publish(publ),
+            };
+            for mut msg in msg.cout_publish(self).into_iter() {
+                if let Message::Packet { packet: v5::Packet::Publish(publ), .. } =
+                    &mut msg
+                {
+                    publ.retain = true;
+                }
+                msgs.push(msg);
+            }
+        }
+
+        msgs
+    }
+
+    /// Publish the Will Message, if any, this is called after the network connection
+    /// is closed [MQTT-3.1.2-8].
+    pub fn publish_will(&mut self, shard: &mut Shard) -> Result<()> {
+        let will = match self.will_message.take() {
+            Some(will) => will,
+            None => return Ok(()),
+        };
+
+        let props = will.properties;
+        let properties = v5::PublishProperties {
+            payload_format_indicator: props.payload_format_indicator,
+            message_expiry_interval: props.message_expiry_interval,
+            response_topic: props.response_topic,
+            correlation_data: props.correlation_data,
+            content_type: props.content_type,
+            user_properties: props.user_properties,
+            ..v5::PublishProperties::default()
+        };
+        let publ = v5::Publish {
+            retain: will.retain,
+            qos: will.qos,
+            duplicate: false,
+            topic_name: self.mount_topic_name(will.topic),
+            packet_id: None,
+            properties: Some(properties),
+            payload: Some(will.payload.into()),
+        };
+
+        self.route_publish(shard, publ)?;
+        Ok(())
     }
 }
 
@@ -415,9 +764,34 @@ impl Session {
     pub fn in_messages(&mut self, msgs: Vec<Message>) -> QueueStatus<Message> {
         for msg in msgs.into_iter() {
             match msg {
+                msg @ Message::ClientAck { .. } if is_held(&msg) => {
+                    match self.held_acks.as_mut() {
+                        Some(acks) => acks.push((None, msg)),
+                        None => self.cout.back_log.push_back(msg),
+                    }
+                }
                 msg @ Message::ClientAck { .. } => {
                     self.cout.back_log.push_back(msg);
                 }
+                // already converted for this session, like retained messages.
+                msg @ Message::Packet { .. } if !msg.is_routed() => {
+                    self.cout.back_log.push_back(msg);
+                }
+                // preserve the order with messages queued while the client was
+                // offline.
+                msg @ Message::Packet { .. } if self.queue.is_some() => {
+                    let queue = self.queue.as_mut().unwrap();
+                    let dropped = queue.push(msg, store::unix_secs());
+                    for record in queue.take_journal().into_iter() {
+                        self.log_record(record)
+                    }
+                    if dropped > 0 {
+                        warn!(
+                            "{} offline queue dropped {} messages",
+                            self.prefix, dropped
+                        );
+                    }
+                }
                 msg @ Message::Packet { .. } => msg
                     .cout_publish(self)
                     .into_iter()
@@ -444,6 +818,8 @@ impl Session {
             return QueueStatus::Block(Vec::new());
         }
 
+        self.drain_queue();
+
         let mut miot_tx = self.miot_tx.clone(); // when dropped miot thread woken up.
 
         let mut msgs: Vec<Message> = self.cout.back_log.drain(..).collect();
@@ -452,27 +828,116 @@ impl Session {
 
         let mut status = miot_tx.try_sends(&self.prefix, pkts);
 
-        let ok_msgs = msgs.split_off(msgs.len() - status.take_values().len());
-        // book `ok_msgs` as inflight messages
-        for msg in ok_msgs.into_iter() {
+        // `status` carries the packets that could not be sent, in the same order.
+        let rem_msgs = msgs.split_off(msgs.len() - status.take_values().len());
+        // book sent QoS-1,2 messages as inflight messages
+        for msg in msgs.into_iter() {
             match &msg {
-                Message::Packet { packet_id, .. } => {
+                Message::Packet {
+                    seqno,
+                    packet_id,
+                    packet: v5::Packet::Publish(publ),
+                    ..
+                } if publ.qos != v5::QoS::AtMostOnce => {
+                    if self.journal.is_some() {
+                        self.log_record(wal::Record::Inflight {
+                            client_id: self.client_id.clone(),
+                            seqno: *seqno,
+                            packet_id: *packet_id,
+                            publish: publ.clone(),
+                        });
+                    }
                     self.cout.index.insert(*packet_id, msg);
                 }
+                Message::Packet { .. } => (),
                 Message::ClientAck { .. } => (),
                 Message::LocalAck { .. } => (),
             }
         }
         // remaining messages, if any.
-        for msg in msgs.into_iter() {
+        for msg in rem_msgs.into_iter() {
             self.cout.back_log.push_back(msg);
         }
 
         status
     }
 
+    // MQTT v5 does not allow re-sending PUBLISH on the same connection [MQTT-4.4.0-1],
+    // only retry messages held back by client's receive-maximum.
     pub fn retry_publish(&mut self) {
-        todo!()
+        if !self.cout.back_log.is_empty() || self.queue.is_some() {
+            self.flush_messages();
+        }
+    }
+
+    // Move messages from offline queue to back_log, as long as the inflight QoS>0
+    // PUBLISH are within client's receive-maximum.
+    fn drain_queue(&mut self) {
+        let mut queue = match self.queue.take() {
+            Some(queue) if self.quota_exceeded => {
+                self.queue = Some(queue);
+                return;
+            }
+            Some(queue) => queue,
+            None => return,
+        };
+
+        let is_inflight = |msg: &Message| match msg {
+            Message::Packet { packet: v5::Packet::Publish(publ), .. } => {
+                publ.qos != v5::QoS::AtMostOnce
+            }
+            _ => false,
+        };
+        let mut n = self.cout.index.len()
+            + self.cout.back_log.iter().filter(|msg| is_inflight(msg)).count();
+
+        let now = store::unix_secs();
+        while n < usize::from(self.client_receive_maximum) {
+            match queue.pop(now) {
+                Some(msg) => {
+                    for msg in msg.cout_publish(self).into_iter() {
+                        n += usize::from(is_inflight(&msg));
+                        self.cout.back_log.push_back(msg);
+                    }
+                }
+                None => break,
+            }
+        }
+        for record in queue.take_journal().into_iter() {
+            self.log_record(record)
+        }
+
+        if !queue.is_empty() {
+            self.queue = Some(queue);
+        }
+    }
+
+    /// Send the held acknowledgements whose changes to session state are applied by a
+    /// replica, that is records up to seqno `acked`. Acknowledgements held since the
+    /// last call wait for the records sent so far, up to seqno `sent`. Shall be called
+    /// after the shard has sent the changes to its replicas, refer [crate::replica].
+    pub fn release_acks(&mut self, sent: u64, acked: u64) {
+        let acks = match self.held_acks.as_mut() {
+            Some(acks) if !acks.is_empty() => acks,
+            Some(_) | None => return,
+        };
+
+        for (seqno, _) in acks.iter_mut().filter(|(seqno, _)| seqno.is_none()) {
+            *seqno = Some(sent);
+        }
+        let n = acks.iter().take_while(|(seqno, _)| *seqno <= Some(acked)).count();
+        if n > 0 {
+            self.cout.back_log.extend(acks.drain(..n).map(|(_, msg)| msg));
+            self.flush_messages();
+        }
+    }
+
+    /// Send PUBACK for client's QoS-1 `publ`, once it is routed to all subscribers.
+    pub fn ack_publish(&mut self, publ: &v5::Publish) {
+        if self.unbook_qos(publ) {
+            let packet = new_puback(publ, ReasonCode::Success);
+            self.in_messages(vec![Message::new_client_ack(packet)]);
+        }
     }
 }
 
@@ -533,7 +998,7 @@ impl Session {
             v5::QoS::ExactlyOnce => &mut self.qos2,
         };
 
-        let packet_id = publ.packet_id.unwrap();
+        let packet_id = publ.packet_id.unwrap_or(0);
         match qos_vec.binary_search(&packet_id) {
             Ok(off) => {
                 qos_vec.remove(off);
@@ -625,6 +1090,32 @@ impl Session {
         (seqno, packet_id)
     }
 
+    /// Strip this session's mount-point from `topic_name`, for PUBLISH packets sent
+    /// to the client.
+    pub fn unmount_topic_name(&self, topic_name: &TopicName) -> TopicName {
+        match self.mount_point.as_ref() {
+            Some(mp) => match topic_name.strip_prefix(mp.as_str()) {
+                Some(name) => TopicName::from(name.to_string()),
+                None => topic_name.clone(),
+            },
+            None => topic_name.clone(),
+        }
+    }
+
+    fn mount_topic_name(&self, topic_name: TopicName) -> TopicName {
+        match self.mount_point.as_ref() {
+            Some(mp) => TopicName::from(format!("{}{}", mp, topic_name.as_str())),
+            None => topic_name,
+        }
+    }
+
+    fn mount_topic_filter(&self, topic_filter: TopicFilter) -> TopicFilter {
+        match self.mount_point.as_ref() {
+            Some(mp) => TopicFilter::from(format!("{}{}", mp, topic_filter.as_str())),
+            None => topic_filter,
+        }
+    }
+
     #[inline]
     pub fn as_config(&self) -> &Config {
         &self.config
@@ -634,4 +1125,60 @@ impl Session {
     pub fn as_client_id(&self) -> &ClientID {
         &self.client_id
     }
+
+    /// Return the peer credentials for clients connected via unix transport, can be
+    /// used to authenticate the client.
+    #[inline]
+    pub fn as_peer_cred(&self) -> Option<&PeerCred> {
+        self.peer_cred.as_ref()
+    }
+}
+
+// Records that capture the session's subscriptions and its inflight messages.
+fn to_records(
+    client_id: &ClientID,
+    session_expiry_interval: u32,
+    subscriptions: &BTreeMap<TopicFilter, v5::Subscription>,
+    cout: &message::ClientOut,
+) -> Vec<wal::Record> {
+    let mut records = vec![wal::Record::Session {
+        client_id: client_id.clone(),
+        session_expiry_interval,
+    }];
+    for subscription in subscriptions.values() {
+        records.push(wal::Record::Subscribe { subscription: subscription.clone() });
+    }
+    for msg in cout.index.values() {
+        if let Message::Packet {
+            seqno,
+            packet_id,
+            packet: v5::Packet::Publish(publish),
+            ..
+        } = msg
+        {
+            records.push(wal::Record::Inflight {
+                client_id: client_id.clone(),
+                seqno: *seqno,
+                packet_id: *packet_id,
+                publish: publish.clone(),
+            });
+        }
+    }
+    records
+}
+
+fn new_puback(publ: &v5::Publish, code: ReasonCode) -> v5::Packet {
+    v5::Packet::PubAck(v5::Pub {
+        packet_type: v5::PacketType::PubAck,
+        packet_id: publ.packet_id.unwrap_or(0),
+        code,
+        properties: None,
+    })
+}
+
+// Acknowledgements for changes to session state, held for replicated shards.
+fn is_held(msg: &Message) -> bool {
+    use v5::Packet::{PubAck, SubAck, UnsubAck};
+
+    matches!(msg, Message::ClientAck { packet: PubAck(_) | SubAck(_) | UnsubAck(_) })
 }
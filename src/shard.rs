@@ -1,13 +1,17 @@
 use log::{debug, error, info, trace, warn};
 use uuid::Uuid;
 
-use std::{cmp, collections::BTreeMap, mem, net, sync::Arc};
+use std::collections::{btree_map, BTreeMap, BTreeSet};
+use std::{cmp, mem, net, sync::Arc};
 
+use crate::session::SessionState;
 use crate::thread::{Rx, Thread, Threadable, Tx};
-use crate::{message, session, socket, v5};
-use crate::{AppTx, ClientID, Config, RetainedTrie, Session, Shardable, SubscribedTrie};
+use crate::wal::{self, SessionLog};
+use crate::{message, session, socket, store, v5};
+use crate::{AppTx, ClientID, Config, ConfigListener, ConnGuard, Shardable};
 use crate::{Cluster, Flusher, Message, Miot, MsgRx, QueueStatus, Socket, TopicName};
 use crate::{Error, ErrorKind, ReasonCode, Result};
+use crate::{OfflineQueue, PeerCred, RetainedTrie, Session, Stream, SubscribedTrie};
 
 type ThreadRx = Rx<Request, Result<Response>>;
 type QueueReq = crate::thread::QueueReq<Request, Result<Response>>;
@@ -36,6 +40,8 @@ pub enum Inner {
     Tx(Arc<mio::Waker>, Tx<Request, Result<Response>>),
     // Held by all Shard threads.
     MsgTx(Arc<mio::Waker>, message::MsgTx),
+    // Held by all Shard threads, for shards mastered by other nodes.
+    Remote(Box<Cluster>),
     // Thread.
     Main(RunLoop),
     // Held by Cluster, replacing both Handle and Main.
@@ -66,6 +72,21 @@ pub struct RunLoop {
     /// Collection of sessions and corresponding clients managed by this shard. Shall be
     /// dropped after close_wait call, when the thread returns, will be empty.
     sessions: BTreeMap<ClientID, Session>,
+    /// Persistent sessions whose clients are offline, they are removed when the
+    /// session expires.
+    offline_sessions: BTreeMap<ClientID, SessionState>,
+    /// Write-ahead log of session state, refer [Config::session_store_dir].
+    session_log: Option<SessionLog>,
+    /// Changes to session state, yet to be sent to the replicas of this shard, None
+    /// if the shard is not replicated, refer [crate::replica].
+    replica_log: Option<Vec<wal::Record>>,
+    /// Seqno of the next record sent to the replicas.
+    replica_seqno: u64,
+    /// Seqno of the last record acknowledged by any of the replicas.
+    replica_acked: u64,
+    /// Seconds since UNIX_EPOCH, when `offline_sessions` were last checked for
+    /// expiry.
+    expiry_scan: u64,
     /// Every incoming PUBLISH from a local-session will be indexed with its incoming
     /// shard_id, and Message::Packet::seqno. A Periodic Message::LocalAck shall be
     /// sent to other shards.
@@ -135,6 +156,7 @@ impl Drop for Shard {
             Inner::Handle(_hndl) => info!("{} drop ...", self.prefix),
             Inner::Tx(_waker, _tx) => info!("{} drop ...", self.prefix),
             Inner::MsgTx(_waker, _tx) => info!("{} drop ...", self.prefix),
+            Inner::Remote(_cluster) => info!("{} drop ...", self.prefix),
             Inner::Main(_run_loop) => info!("{} drop ...", self.prefix),
             Inner::Close(_fin_state) => info!("{} drop ...", self.prefix),
         }
@@ -146,6 +168,11 @@ pub struct SpawnArgs {
     pub flusher: Flusher,
     pub topic_filters: SubscribedTrie,
     pub retained_messages: RetainedTrie,
+    /// Stream changes to session state to the replicas of this shard.
+    pub replicated: bool,
+    /// Session state replicated from the failed master, for shard promoted on
+    /// fail-over, refer [crate::replica].
+    pub replay: Option<wal::Replay>,
 }
 
 impl Shard {
@@ -182,11 +209,27 @@ impl Shard {
             let size = self.config.mqtt_pkt_batch_size() * num_shards;
             message::msg_channel(self.shard_id, size as usize, Arc::clone(&waker))
         };
-        let cinp = message::ClientInp {
+        let (session_log, mut replay) =
+            self.load_sessions(&args.topic_filters, args.replay)?;
+        OfflineQueue::remove_spill_files(&self.config, self.shard_id)?;
+        let durable = session_log.is_some() || args.replicated;
+        self.restore_queues(&mut replay, durable);
+        let mut cinp = message::ClientInp {
             seqno: 1,
             unacks: BTreeMap::default(),
             timestamp: BTreeMap::default(),
         };
+        // un-acked messages are routed again, refer handle_set_shard_queues.
+        for (seqno, msg) in replay.unacks.into_iter() {
+            let shard_id = match &msg {
+                Message::Packet { subscriptions, .. } => subscriptions[0].shard_id,
+                _ => unreachable!(),
+            };
+            cinp.seqno = seqno.saturating_add(1);
+            cinp.timestamp.insert(shard_id, (seqno, 0));
+            cinp.unacks.insert(seqno, msg);
+        }
+
         let mut shard = Shard {
             name: format!("{}-shard-main", self.config.name),
             shard_id: self.shard_id,
@@ -201,6 +244,12 @@ impl Shard {
                 miot: Miot::default(),
 
                 sessions: BTreeMap::default(),
+                offline_sessions: replay.sessions,
+                session_log,
+                replica_log: args.replicated.then(Vec::default),
+                replica_seqno: 1,
+                replica_acked: 0,
+                expiry_scan: 0,
                 state: ShardState { cinp },
                 ack_timestamp: BTreeMap::default(),
                 shard_back_log: BTreeMap::default(),
@@ -243,6 +292,63 @@ impl Shard {
         Ok(shard)
     }
 
+    // Replay the session log, if configured, subscribe the topic-filters of replayed
+    // sessions and checkpoint the log. Shard promoted on fail-over shall use the
+    // `replicated` state, instead of replaying its session log.
+    fn load_sessions(
+        &self,
+        topic_filters: &SubscribedTrie,
+        replicated: Option<wal::Replay>,
+    ) -> Result<(Option<SessionLog>, wal::Replay)> {
+        let mut session_log = SessionLog::from_config(&self.config, self.shard_id)?;
+
+        let (replay, from) = match (replicated, session_log.as_mut()) {
+            (Some(replay), _) => (replay, "replica".to_string()),
+            (None, Some(session_log)) => {
+                let records = session_log.load()?;
+                let replay = wal::Replay::from_records(self.shard_id, records);
+                (replay, format!("{:?}", session_log.to_location()))
+            }
+            (None, None) => return Ok((None, wal::Replay::default())),
+        };
+        if let Some(session_log) = session_log.as_mut() {
+            session_log.checkpoint(&replay.to_records())?;
+        }
+
+        for state in replay.sessions.values() {
+            for (topic_filter, subscription) in state.subscriptions.iter() {
+                topic_filters.subscribe(topic_filter, subscription.clone());
+            }
+        }
+
+        info!(
+            "{} loaded sessions:{} unacks:{} queued:{} from {}",
+            self.prefix,
+            replay.sessions.len(),
+            replay.unacks.len(),
+            replay.queued.len(),
+            from
+        );
+
+        Ok((session_log, replay))
+    }
+
+    // Rebuild offline queues, refer [crate::offline], for replayed sessions.
+    fn restore_queues(&self, replay: &mut wal::Replay, durable: bool) {
+        for (client_id, queued) in mem::take(&mut replay.queued).into_iter() {
+            let state = match replay.sessions.get_mut(&client_id) {
+                Some(state) => state,
+                None => continue,
+            };
+            let mut queue =
+                OfflineQueue::new(&self.config, self.shard_id, &client_id, durable);
+            for (index, (timestamp, msg)) in queued.into_iter() {
+                queue.restore(index, timestamp, msg);
+            }
+            state.queue = Some(queue);
+        }
+    }
+
     pub fn to_tx(&self) -> Self {
         trace!("{} cloning tx ...", self.prefix);
 
@@ -266,6 +372,22 @@ impl Shard {
         shard
     }
 
+    /// Create a message queue for shard `shard_id` mastered by another node in a
+    /// multi-node cluster. Messages sent on this queue are forwarded to that node via
+    /// `cluster`, refer [Cluster::forward].
+    pub fn new_remote(config: Config, shard_id: u32, cluster: Cluster) -> Shard {
+        let mut shard = Shard {
+            name: format!("{}-shard-remote", config.name),
+            shard_id,
+            uuid: Uuid::new_v4(),
+            prefix: String::default(),
+            config,
+            inner: Inner::Remote(Box::new(cluster)),
+        };
+        shard.prefix = shard.prefix();
+        shard
+    }
+
     pub fn to_msg_tx(&self) -> Self {
         trace!("{} cloning tx ...", self.prefix);
 
@@ -273,6 +395,10 @@ impl Shard {
             Inner::Handle(Handle { waker, msg_tx, .. }) => {
                 Inner::MsgTx(Arc::clone(waker), msg_tx.clone())
             }
+            Inner::MsgTx(waker, msg_tx) => {
+                Inner::MsgTx(Arc::clone(waker), msg_tx.clone())
+            }
+            Inner::Remote(cluster) => Inner::Remote(Box::new(cluster.to_tx())),
             _ => unreachable!(),
         };
 
@@ -292,9 +418,12 @@ impl Shard {
 pub enum Request {
     SetMiot(Miot, MsgRx),
     SetShardQueues(BTreeMap<u32, Shard>),
-    AddSession(AddSessionArgs),
+    AddSession(Box<AddSessionArgs>),
+    ImportSessions(Vec<v5::Subscription>),
     FlushConnection { socket: Socket, err: Error },
     SendMessages { msgs: Vec<Message> },
+    Resync,
+    ReplicaAck { seqno: u64 },
     Close,
 }
 
@@ -303,9 +432,12 @@ pub enum Response {
 }
 
 pub struct AddSessionArgs {
-    pub conn: mio::net::TcpStream,
+    pub conn: Stream,
     pub addr: net::SocketAddr,
+    pub peer_cred: Option<PeerCred>,
     pub pkt: v5::Connect,
+    pub listener: ConfigListener,
+    pub guard: ConnGuard,
 }
 
 // calls to interface with shard-thread.
@@ -333,7 +465,21 @@ impl Shard {
     pub fn add_session(&self, args: AddSessionArgs) -> Result<()> {
         match &self.inner {
             Inner::Handle(Handle { thrd, .. }) => {
-                let req = Request::AddSession(args);
+                let req = Request::AddSession(Box::new(args));
+                match thrd.request(req)?? {
+                    Response::Ok => Ok(()),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Import `subscriptions` from a snapshot, refer [crate::snapshot], as offline
+    /// sessions hosted by this shard.
+    pub fn import_sessions(&self, subscriptions: Vec<v5::Subscription>) -> Result<()> {
+        match &self.inner {
+            Inner::Handle(Handle { thrd, .. }) => {
+                let req = Request::ImportSessions(subscriptions);
                 match thrd.request(req)?? {
                     Response::Ok => Ok(()),
                 }
@@ -353,9 +499,35 @@ impl Shard {
         }
     }
 
+    /// Send the live state of this shard to its replicas, refer [crate::replica].
+    pub fn resync(&self) -> Result<()> {
+        match &self.inner {
+            Inner::Handle(Handle { thrd, .. }) => thrd.post(Request::Resync),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Replica has applied the records sent by this shard, up to `seqno`, refer
+    /// [crate::replica].
+    pub fn replica_ack(&self, seqno: u64) -> Result<()> {
+        match &self.inner {
+            Inner::Handle(Handle { thrd, .. }) => {
+                thrd.post(Request::ReplicaAck { seqno })
+            }
+            _ => unreachable!(),
+        }
+    }
+
     pub fn send_messages(&mut self, msgs: Vec<Message>) -> QueueStatus<Message> {
         match &mut self.inner {
             Inner::MsgTx(_waker, msg_tx) => msg_tx.try_sends(msgs),
+            Inner::Remote(cluster) => match cluster.forward(self.shard_id, msgs) {
+                Ok(()) => QueueStatus::Ok(Vec::new()),
+                Err(err) => {
+                    error!("{} forward: {}", self.prefix, err);
+                    QueueStatus::Disconnected(Vec::new())
+                }
+            },
             _ => unreachable!(),
         }
     }
@@ -429,6 +601,11 @@ impl Threadable for Shard {
             self.clear_unacks();
             self.retry_publish();
 
+            self.expire_sessions();
+            self.flush_session_log();
+            // acknowledge clients once the changes are applied by a replica.
+            self.release_acks();
+
             // wake up miot every time shard wakes up
             self.as_miot().wake()
         }
@@ -487,9 +664,23 @@ impl Shard {
                     let resp = self.handle_add_session(req);
                     err!(IPCFail, try: tx.send(Ok(resp))).ok();
                 }
+                (req @ ImportSessions(_), Some(tx)) => {
+                    let resp = self.handle_import_sessions(req);
+                    err!(IPCFail, try: tx.send(Ok(resp))).ok();
+                }
+                // shard is already closed, connection is dropped with the socket.
+                (FlushConnection { .. }, None) if closed => (),
                 (req @ FlushConnection { .. }, None) => {
                     self.handle_flush_connection(req);
                 }
+                (Resync, None) if closed => (),
+                (req @ Resync, None) => {
+                    self.handle_resync(req);
+                }
+                (ReplicaAck { .. }, None) if closed => (),
+                (req @ ReplicaAck { .. }, None) => {
+                    self.handle_replica_ack(req);
+                }
                 (req @ Close, Some(tx)) => {
                     let resp = self.handle_close(req);
                     err!(IPCFail, try: tx.send(Ok(resp))).ok();
@@ -508,12 +699,24 @@ impl Shard {
     // For each session, convert incoming packets to messages and route them to other
     // sessions/bridges.
     fn route_packets(&mut self) {
-        let mut inner = mem::replace(&mut self.inner, Inner::Init);
+        for (client_id, err) in self.route_sessions() {
+            let RunLoop { miot, .. } = match &mut self.inner {
+                Inner::Main(run_loop) => run_loop,
+                _ => unreachable!(),
+            };
 
-        let RunLoop { sessions, .. } = match &mut inner {
-            Inner::Main(run_loop) => run_loop,
-            _ => unreachable!(),
-        };
+            if let Some(socket) = allow_panic!(&self, miot.remove_connection(&client_id))
+            {
+                let req = Request::FlushConnection { socket, err };
+                self.handle_flush_connection(req);
+            }
+        }
+    }
+
+    // Route packets received by each session, return the sessions that have failed.
+    fn route_sessions(&mut self) -> Vec<(ClientID, Error)> {
+        // sessions are moved out, so that they can route packets via `self`.
+        let mut sessions = mem::take(self.as_mut_sessions());
 
         let mut failed_sessions = Vec::new();
         for (client_id, session) in sessions.iter_mut() {
@@ -533,21 +736,9 @@ impl Shard {
             }
         }
 
-        mem::drop(sessions);
-        let _init = mem::replace(&mut self.inner, inner);
-
-        for (client_id, err) in failed_sessions {
-            let RunLoop { miot, .. } = match &mut self.inner {
-                Inner::Main(run_loop) => run_loop,
-                _ => unreachable!(),
-            };
+        let _empty = mem::replace(self.as_mut_sessions(), sessions);
 
-            if let Some(socket) = allow_panic!(&self, miot.remove_connection(&client_id))
-            {
-                let req = Request::FlushConnection { socket, err };
-                self.handle_flush_connection(req);
-            }
-        }
+        failed_sessions
     }
 
     // Flush outgoing messages from this shard to other shards. Messages were booked
@@ -560,7 +751,15 @@ impl Shard {
 
         let back_log = mem::replace(shard_back_log, BTreeMap::default());
         for (shard_id, msgs) in back_log.into_iter() {
-            let shard = shard_queues.get_mut(&shard_id).unwrap();
+            // shard is not wired up yet, like when its master is being promoted on
+            // fail-over, hold the messages until the next SetShardQueues.
+            let shard = match shard_queues.get_mut(&shard_id) {
+                Some(shard) => shard,
+                None => {
+                    shard_back_log.insert(shard_id, msgs);
+                    continue;
+                }
+            };
 
             let mut status = shard.send_messages(msgs);
             // re-index the remaining messages, may be the other shard is busy.
@@ -588,12 +787,14 @@ impl Shard {
                 Message::LocalAck { shard_id, last_received_ack } => {
                     self.book_local_ack(*shard_id, *last_received_ack)
                 }
-                Message::Packet { client_id, shard_id, seqno, .. } => {
+                Message::Packet { shard_id, seqno, subscriptions, .. } => {
                     self.as_mut_ack_timestamps().insert(*shard_id, *seqno);
-                    match session_msgs.get_mut(client_id) {
+                    // messages are routed to the subscribing session.
+                    let client_id = subscriptions[0].client_id.clone();
+                    match session_msgs.get_mut(&client_id) {
                         Some(msgs) => msgs.push(msg),
                         None => {
-                            session_msgs.insert(client_id.clone(), vec![msg]);
+                            session_msgs.insert(client_id, vec![msg]);
                         }
                     }
                 }
@@ -610,7 +811,7 @@ impl Shard {
                     QueueStatus::Disconnected(_) => disconnecteds.push(client_id),
                     _ => (),
                 },
-                None => warn!("{} msg-rx, session {} is gone", self.prefix, *client_id),
+                None => self.queue_offline(&client_id, msgs),
             }
         }
 
@@ -633,6 +834,35 @@ impl Shard {
         status
     }
 
+    // Queue messages for an offline session, if present, refer [crate::offline].
+    fn queue_offline(&mut self, client_id: &ClientID, msgs: Vec<Message>) {
+        let durable = self.is_durable();
+        let RunLoop { offline_sessions, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let state = match offline_sessions.get_mut(client_id) {
+            Some(state) => state,
+            None => {
+                warn!("{} msg-rx, session {} is gone", self.prefix, **client_id);
+                return;
+            }
+        };
+        let queue = state.queue.get_or_insert_with(|| {
+            OfflineQueue::new(&self.config, self.shard_id, client_id, durable)
+        });
+
+        let now = store::unix_secs();
+        let dropped: usize = msgs.into_iter().map(|msg| queue.push(msg, now)).sum();
+        if dropped > 0 {
+            warn!("{} offline queue {} dropped {}", self.prefix, **client_id, dropped);
+        }
+
+        let records = queue.take_journal();
+        self.append_session_log(records);
+    }
+
     // flush the booked messages down stream
     fn flush_messages(&mut self) {
         let RunLoop { sessions, .. } = match &mut self.inner {
@@ -653,8 +883,9 @@ impl Shard {
 
         let ack_timestamp = mem::replace(ack_timestamp, BTreeMap::default());
 
+        // LocalAck is sent back to the routing shard, carrying this shard's id.
         for (shard_id, last_received_ack) in ack_timestamp.into_iter() {
-            let msg = Message::LocalAck { shard_id, last_received_ack };
+            let msg = Message::LocalAck { shard_id: self.shard_id, last_received_ack };
             match shard_back_log.get_mut(&shard_id) {
                 Some(msgs) => msgs.push(msg),
                 None => {
@@ -665,11 +896,185 @@ impl Shard {
     }
 
     fn clear_unacks(&mut self) {
-        for msg in self.remove_unacks().into_iter() {
-            match msg {
-                Message::Packet { .. } => todo!(), // send back publish ack.
+        let msgs = self.remove_unacks();
+
+        let RunLoop { sessions, state, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        for msg in msgs.into_iter() {
+            let (client_id, publ) = match msg {
+                Message::Packet {
+                    client_id, packet: v5::Packet::Publish(publ), ..
+                } => (client_id, publ),
+                _ => unreachable!(),
+            };
+            // same PUBLISH can be routed to more than one shard, send back publish
+            // ack only after all the shards have acknowledged.
+            let pending = state.cinp.unacks.values().any(|msg| match msg {
+                Message::Packet {
+                    client_id: cid,
+                    packet: v5::Packet::Publish(p),
+                    ..
+                } => cid == &client_id && p.packet_id == publ.packet_id,
+                _ => false,
+            });
+            match sessions.get_mut(&client_id) {
+                Some(session) if !pending => session.ack_publish(&publ),
+                Some(_) | None => (),
+            }
+        }
+    }
+
+    // Remove offline sessions that have expired, checked at most once a second.
+    fn expire_sessions(&mut self) {
+        let now = store::unix_secs();
+
+        let RunLoop { offline_sessions, topic_filters, expiry_scan, .. } =
+            match &mut self.inner {
+                Inner::Main(run_loop) => run_loop,
                 _ => unreachable!(),
             };
+        if *expiry_scan == now {
+            return;
+        }
+        *expiry_scan = now;
+
+        let mut records = vec![];
+        for state in offline_sessions.values_mut() {
+            if let Some(queue) = state.queue.as_mut() {
+                queue.trim(now);
+                records.extend(queue.take_journal());
+            }
+        }
+
+        let expired: Vec<ClientID> = offline_sessions
+            .iter()
+            .filter(|(_, state)| state.is_expired(now))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+
+        for client_id in expired.into_iter() {
+            if let Some(state) = offline_sessions.remove(&client_id) {
+                debug!("{} session {} expired", self.prefix, *client_id);
+                state.remove_topic_filters(topic_filters);
+                records.push(wal::Record::Remove { client_id });
+            }
+        }
+
+        self.append_session_log(records);
+    }
+
+    // Write changes to session state into the session log, and checkpoint the log if
+    // it has out-grown its previous checkpoint. Same changes are sent to replicas.
+    fn flush_session_log(&mut self) {
+        let mut records = vec![];
+        for session in self.as_mut_sessions().values_mut() {
+            records.extend(session.take_journal());
+        }
+        self.append_session_log(records);
+
+        let checkpoint = match self.as_session_log() {
+            Some(session_log) => session_log.need_checkpoint(),
+            None => false,
+        };
+        let records = match checkpoint {
+            true => self.to_checkpoint(),
+            false => Vec::new(),
+        };
+
+        let RunLoop { session_log, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        if let Some(session_log) = session_log.as_mut() {
+            if let Err(err) = session_log.flush() {
+                error!("{} session log: {}", self.prefix, err);
+            }
+            if checkpoint {
+                if let Err(err) = session_log.checkpoint(&records) {
+                    error!("{} session log checkpoint: {}", self.prefix, err);
+                }
+            }
+        }
+
+        self.flush_replica_log();
+    }
+
+    // Send changes to session state to the replicas, via cluster.
+    fn flush_replica_log(&mut self) {
+        let replica_log = match &mut self.inner {
+            Inner::Main(RunLoop { replica_log, .. }) => replica_log,
+            _ => unreachable!(),
+        };
+        let records = match replica_log.as_mut() {
+            Some(records) if !records.is_empty() => mem::take(records),
+            Some(_) | None => return,
+        };
+        self.replicate(false, records);
+    }
+
+    // Send `records` to the replicas, numbered from the next seqno.
+    fn replicate(&mut self, reset: bool, records: Vec<wal::Record>) {
+        let RunLoop { cluster, replica_seqno, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let seqno = *replica_seqno;
+        *replica_seqno += u64::try_from(records.len()).unwrap();
+        if let Err(err) = cluster.replicate(self.shard_id, seqno, reset, records) {
+            error!("{} replicate: {}", self.prefix, err);
+        }
+    }
+
+    // Return the records capturing the live state of this shard.
+    fn to_checkpoint(&self) -> Vec<wal::Record> {
+        let RunLoop { sessions, offline_sessions, state, .. } = match &self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let mut records = vec![];
+        for session in sessions.values() {
+            records.extend(session.to_records().unwrap_or_default());
+        }
+        for offline in offline_sessions.values() {
+            records.extend(offline.to_records());
+        }
+        for msg in state.cinp.unacks.values() {
+            records.extend(wal::Record::from_unack(msg));
+        }
+        records
+    }
+
+    fn append_session_log(&mut self, records: Vec<wal::Record>) {
+        let RunLoop { session_log, replica_log, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        if let Some(session_log) = session_log.as_mut() {
+            if let Err(err) = session_log.append(&records) {
+                error!("{} session log: {}", self.prefix, err);
+            }
+        }
+        if let Some(replica_log) = replica_log.as_mut() {
+            replica_log.extend(records);
+        }
+    }
+
+    // Acknowledgements held since the last call wait for the records sent so far.
+    fn release_acks(&mut self) {
+        let RunLoop { sessions, replica_seqno, replica_acked, .. } = match &mut self.inner
+        {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        for session in sessions.values_mut() {
+            session.release_acks(*replica_seqno - 1, *replica_acked);
         }
     }
 
@@ -697,21 +1102,44 @@ impl Shard {
             _ => unreachable!(),
         };
 
+        // shards promoted on fail-over are wired up again, un-acked messages are
+        // already routed.
+        let rewire = !run_loop.shard_queues.is_empty();
         run_loop.shard_queues = shard_queues;
+        if rewire {
+            return Response::Ok;
+        }
+
+        // route the un-acked messages, replayed from session log, once again.
+        for msg in run_loop.state.cinp.unacks.values() {
+            let shard_id = match msg {
+                Message::Packet { subscriptions, .. } => subscriptions[0].shard_id,
+                _ => unreachable!(),
+            };
+            match run_loop.shard_back_log.get_mut(&shard_id) {
+                Some(msgs) => msgs.push(msg.clone()),
+                None => {
+                    run_loop.shard_back_log.insert(shard_id, vec![msg.clone()]);
+                }
+            }
+        }
+
         Response::Ok
     }
 
     fn handle_add_session(&mut self, req: Request) -> Response {
         use crate::{miot::AddConnectionArgs, session::SessionArgs};
 
-        let AddSessionArgs { conn, addr, pkt } = match req {
-            Request::AddSession(args) => args,
+        let AddSessionArgs { conn, addr, peer_cred, pkt, listener, guard } = match req {
+            Request::AddSession(args) => *args,
             _ => unreachable!(),
         };
 
         let client_id = ClientID::from_connect(&pkt.payload.client_id);
+        let (clean_start, ..) = pkt.flags.unwrap();
 
-        // TODO: handle pkt.flags.clean_start here.
+        self.take_over_session(&client_id, addr);
+        let state = self.take_offline_session(&client_id, clean_start);
 
         // start the session here
         let (mut session, upstream, downstream) = {
@@ -733,48 +1161,44 @@ impl Shard {
                 shard_id: self.shard_id,
                 miot_tx,
                 session_rx,
+                peer_cred,
+                mount_point: listener.mount_point().map(|mp| mp.to_string()),
+                durable: self.is_durable(),
+                replicated: self.is_replicated(),
             };
-            (Session::start(args, self.config.clone(), &pkt), upstream, downstream)
+            let config = self.config.for_listener(&listener);
+            (Session::start(args, config, &pkt), upstream, downstream)
         };
 
         // send back the connection acknowledgment CONNACK here.
-        let packet = v5::Packet::ConnAck(session.success_ack(&pkt, self));
-        session.in_messages(vec![Message::new_client_ack(packet)]);
+        let mut connack = session.success_ack(&pkt, self);
+        if state.is_some() {
+            connack.set_session_present();
+        }
+        session.in_messages(vec![Message::new_client_ack(v5::Packet::ConnAck(connack))]);
         match session.flush_messages() {
             QueueStatus::Disconnected(_) | QueueStatus::Block(_) => {
                 error!("{} fail to send CONNACK in add_session", self.prefix);
+                if let Some(state) = state {
+                    self.as_mut_offline_sessions().insert(client_id, state);
+                }
                 return Response::Ok;
             }
             QueueStatus::Ok(_) => (),
         }
 
-        // add_connection further down shall wake miot-thread.
-        let RunLoop { sessions, miot, topic_filters, .. } = match &mut self.inner {
-            Inner::Main(run_loop) => run_loop,
-            _ => unreachable!(),
-        };
-        // nuke existing session, if already present for this client_id
-        if let Some(_) = sessions.get(&client_id) {
-            if let Some(mut session) = sessions.remove(&client_id) {
-                // TODO: should we remove topic_filters or SessionTakenOver ?
-                session.remove_topic_filters(topic_filters);
-                session.close();
-            };
-            if let Some(socket) = allow_panic!(self, miot.remove_connection(&client_id)) {
-                let err: Result<()> = err!(
-                    SessionTakenOver,
-                    code: SessionTakenOver,
-                    "{} client {}",
-                    self.prefix,
-                    addr
-                );
-                let arg = Request::FlushConnection { socket, err: err.unwrap_err() };
-
-                mem::drop(sessions);
-                mem::drop(miot);
-                mem::drop(topic_filters);
-                self.handle_flush_connection(arg);
+        let resumed = state.is_some();
+        if let Some(state) = state {
+            session.resume(state);
+        }
+        match session.to_records() {
+            Some(records) => self.append_session_log(records),
+            // session resumed by a client that is no more persistent.
+            None if resumed => {
+                let client_id = client_id.clone();
+                self.append_session_log(vec![wal::Record::Remove { client_id }]);
             }
+            None => (),
         }
 
         // add_connection further down shall wake miot-thread.
@@ -791,6 +1215,7 @@ impl Shard {
                 upstream,
                 downstream,
                 client_max_packet_size: session.client_max_packet_size(),
+                guard,
             };
             allow_panic!(&self, miot.add_connection(args));
         }
@@ -800,6 +1225,65 @@ impl Shard {
         Response::Ok
     }
 
+    // Close the existing session, if already present for this client_id, persistent
+    // session is retained as offline session.
+    fn take_over_session(&mut self, client_id: &ClientID, addr: net::SocketAddr) {
+        let RunLoop {
+            sessions, offline_sessions, miot, topic_filters, ..
+        } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let (mut records, mut socket) = (vec![], None);
+        if let Some(mut session) = sessions.remove(client_id) {
+            records = session.take_journal();
+            if session.is_persistent() {
+                offline_sessions.insert(client_id.clone(), session.detach());
+            } else {
+                session.remove_topic_filters(topic_filters);
+                session.close();
+            }
+            socket = allow_panic!(self, miot.remove_connection(client_id));
+        }
+
+        self.append_session_log(records);
+        if let Some(socket) = socket {
+            let err: Result<()> = err!(
+                SessionTakenOver,
+                code: SessionTakenOver,
+                "{} client {}",
+                self.prefix,
+                addr
+            );
+            let arg = Request::FlushConnection { socket, err: err.unwrap_err() };
+            self.handle_flush_connection(arg);
+        }
+    }
+
+    // Return the offline session for `client_id`, if any. On `clean_start` the
+    // offline session is discarded [MQTT-3.1.2-4].
+    fn take_offline_session(
+        &mut self,
+        client_id: &ClientID,
+        clean_start: bool,
+    ) -> Option<SessionState> {
+        let RunLoop { offline_sessions, topic_filters, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+
+        let state = offline_sessions.remove(client_id)?;
+        if clean_start {
+            state.remove_topic_filters(topic_filters);
+            let client_id = client_id.clone();
+            self.append_session_log(vec![wal::Record::Remove { client_id }]);
+            None
+        } else {
+            Some(state)
+        }
+    }
+
     fn handle_flush_connection(&mut self, req: Request) -> Response {
         use crate::flush::FlushConnectionArgs;
 
@@ -808,28 +1292,138 @@ impl Shard {
             _ => unreachable!(),
         };
 
+        self.close_session(&socket.client_id);
+
+        let RunLoop { flusher, .. } = match &mut self.inner {
+            Inner::Main(run_loop) => run_loop,
+            _ => unreachable!(),
+        };
+        let args = FlushConnectionArgs { socket, err: Some(err) };
+        allow_panic!(&self, flusher.flush_connection(args));
+
+        Response::Ok
+    }
+
+    // Remove the session for `client_id`, if present, and publish its will message.
+    // Persistent session is retained as offline session.
+    fn close_session(&mut self, client_id: &ClientID) {
         let session = {
             let RunLoop { sessions, .. } = match &mut self.inner {
                 Inner::Main(run_loop) => run_loop,
                 _ => unreachable!(),
             };
-            sessions.remove(&socket.client_id)
+            sessions.remove(client_id)
         };
         match session {
             Some(mut session) => {
+                // packets received before the socket was closed, including DISCONNECT.
+                session.route_packets(self).ok();
+                if let Err(err) = session.publish_will(self) {
+                    error!("{} publish will message: {}", self.prefix, err);
+                }
                 self.remove_session(&session);
-                session.remove_topic_filters(self.as_mut_topic_filters());
-                session.close();
+
+                let mut records = session.take_journal();
+                if session.is_persistent() {
+                    let state = session.detach();
+                    let timestamp = state.offline_at;
+                    let client_id = client_id.clone();
+                    self.as_mut_offline_sessions().insert(client_id.clone(), state);
+                    records.push(wal::Record::Offline { client_id, timestamp });
+                } else {
+                    session.remove_topic_filters(self.as_mut_topic_filters());
+                    session.close();
+                }
+                self.append_session_log(records);
             }
             None => (),
         }
+    }
 
-        let RunLoop { flusher, .. } = match &mut self.inner {
+    // Subscriptions of a session that is online are ignored, its client is already
+    // managing them. Imported sessions expire as per
+    // [Config::mqtt_session_expiry_interval], never if that is not configured.
+    fn handle_import_sessions(&mut self, req: Request) -> Response {
+        let subscriptions = match req {
+            Request::ImportSessions(subscriptions) => subscriptions,
+            _ => unreachable!(),
+        };
+
+        let now = store::unix_secs();
+        let sei = self.config.mqtt_session_expiry_interval(None).unwrap_or(u32::MAX);
+        let RunLoop { sessions, offline_sessions, topic_filters, .. } =
+            match &mut self.inner {
+                Inner::Main(run_loop) => run_loop,
+                _ => unreachable!(),
+            };
+
+        let mut imported = BTreeSet::default();
+        for subscription in subscriptions.into_iter() {
+            let client_id = subscription.client_id.clone();
+            if sessions.contains_key(&client_id) {
+                warn!("{} import, session {} is online", self.prefix, *client_id);
+                continue;
+            }
+
+            let state = offline_sessions.entry(client_id.clone()).or_insert_with(|| {
+                let mut state = SessionState::new(client_id.clone(), now);
+                state.session_expiry_interval = sei;
+                state
+            });
+            let topic_filter = subscription.topic_filter.clone();
+            if let btree_map::Entry::Vacant(entry) =
+                state.subscriptions.entry(topic_filter)
+            {
+                topic_filters.subscribe(entry.key(), subscription.clone());
+                entry.insert(subscription);
+                imported.insert(client_id);
+            }
+        }
+
+        let mut records = vec![];
+        for client_id in imported.iter() {
+            records.extend(offline_sessions[client_id].to_records());
+        }
+        info!("{} imported sessions:{}", self.prefix, imported.len());
+        self.append_session_log(records);
+
+        Response::Ok
+    }
+
+    // Send pending changes, followed by the live state of this shard, to replicas.
+    fn handle_resync(&mut self, _req: Request) -> Response {
+        let replicated = match &self.inner {
+            Inner::Main(RunLoop { replica_log, .. }) => replica_log.is_some(),
+            _ => unreachable!(),
+        };
+        if !replicated {
+            return Response::Ok;
+        }
+
+        self.flush_session_log();
+        let records = self.to_checkpoint();
+        debug!("{} resync replicas with {} records", self.prefix, records.len());
+        self.replicate(true, records);
+
+        Response::Ok
+    }
+
+    fn handle_replica_ack(&mut self, req: Request) -> Response {
+        let seqno = match req {
+            Request::ReplicaAck { seqno } => seqno,
+            _ => unreachable!(),
+        };
+
+        let RunLoop { replica_seqno, replica_acked, .. } = match &mut self.inner {
             Inner::Main(run_loop) => run_loop,
             _ => unreachable!(),
         };
-        let args = FlushConnectionArgs { socket, err: Some(err) };
-        allow_panic!(&self, flusher.flush_connection(args));
+        // ack from the replica of an earlier master of this shard, refer membership.
+        if seqno >= *replica_seqno {
+            warn!("{} replica ack {} beyond seqno {}", self.prefix, seqno, replica_seqno);
+        } else {
+            *replica_acked = cmp::max(*replica_acked, seqno);
+        }
 
         Response::Ok
     }
@@ -844,6 +1438,34 @@ impl Shard {
 
         let miot = mem::replace(&mut run_loop.miot, Miot::default()).close_wait();
 
+        let mut records = vec![];
+        if run_loop.session_log.is_some() || run_loop.replica_log.is_some() {
+            let timestamp = store::unix_secs();
+            for (client_id, session) in run_loop.sessions.iter_mut() {
+                records.extend(session.take_journal());
+                if session.to_records().is_some() {
+                    let client_id = client_id.clone();
+                    records.push(wal::Record::Offline { client_id, timestamp });
+                }
+            }
+        }
+        if let Some(mut session_log) = run_loop.session_log.take() {
+            match session_log.append(&records).and_then(|_| session_log.sync()) {
+                Ok(()) => (),
+                Err(err) => error!("{} session log: {}", self.prefix, err),
+            }
+        }
+        // replicas, and the new master of a migrated shard, see the clients go offline.
+        if let Some(mut replica_log) = run_loop.replica_log.take() {
+            replica_log.extend(records);
+            let seqno = run_loop.replica_seqno;
+            if let Err(err) =
+                run_loop.cluster.replicate(self.shard_id, seqno, false, replica_log)
+            {
+                error!("{} replicate: {}", self.prefix, err);
+            }
+        }
+
         mem::drop(run_loop.poll);
         mem::drop(run_loop.waker);
         mem::drop(run_loop.cluster);
@@ -865,6 +1487,131 @@ impl Shard {
     }
 }
 
+// Drive a shard in-memory, without spawning the shard, miot and flusher threads.
+#[cfg(any(feature = "fuzzy", test))]
+impl Shard {
+    /// Create a shard in main-state, along with its message queue. Sessions added to
+    /// this shard shall route messages to each other via the same shard. `cluster`
+    /// is typically created via [Cluster::new_in_memory].
+    pub(crate) fn new_in_memory(
+        config: Config,
+        cluster: Cluster,
+        app_tx: AppTx,
+    ) -> Result<(Shard, MsgRx)> {
+        let poll = mio::Poll::new()?;
+        let waker = Arc::new(mio::Waker::new(poll.registry(), Self::WAKE_TOKEN)?);
+
+        let size = config.mqtt_pkt_batch_size() as usize;
+        let (msg_tx, msg_rx) = message::msg_channel(0, size, Arc::clone(&waker));
+
+        let mut shard = Shard {
+            name: format!("{}-shard-memory", config.name),
+            shard_id: 0,
+            uuid: Uuid::new_v4(),
+            prefix: String::default(),
+            config: config.clone(),
+            inner: Inner::Main(RunLoop {
+                poll,
+                waker: Arc::clone(&waker),
+                cluster: Box::new(cluster),
+                flusher: Flusher::default(),
+                miot: Miot::default(),
+
+                sessions: BTreeMap::default(),
+                offline_sessions: BTreeMap::default(),
+                session_log: None,
+                replica_log: None,
+                replica_seqno: 1,
+                replica_acked: 0,
+                expiry_scan: 0,
+                state: ShardState {
+                    cinp: message::ClientInp {
+                        seqno: 1,
+                        unacks: BTreeMap::default(),
+                        timestamp: BTreeMap::default(),
+                    },
+                },
+                ack_timestamp: BTreeMap::default(),
+                shard_back_log: BTreeMap::default(),
+
+                shard_queues: BTreeMap::default(),
+                topic_filters: SubscribedTrie::default(),
+                retained_messages: RetainedTrie::default(),
+
+                app_tx,
+            }),
+        };
+        shard.prefix = shard.prefix();
+
+        let mut queue = Shard::default();
+        queue.inner = Inner::MsgTx(waker, msg_tx);
+        match &mut shard.inner {
+            Inner::Main(run_loop) => run_loop.shard_queues.insert(0, queue),
+            _ => unreachable!(),
+        };
+
+        Ok((shard, msg_rx))
+    }
+
+    /// Start a session for `pkt`, return the queue to send packets to the session
+    /// and the queue to receive packets from the session.
+    pub(crate) fn add_session_in_memory(
+        &mut self,
+        addr: net::SocketAddr,
+        pkt: &v5::Connect,
+    ) -> (socket::PktTx, socket::PktRx) {
+        use crate::session::SessionArgs;
+
+        let client_id = ClientID::from_connect(&pkt.payload.client_id);
+        let size = self.config.mqtt_pkt_batch_size() as usize;
+        let (upstream, session_rx) = socket::pkt_channel(0, size, self.to_waker());
+        let (miot_tx, downstream) = socket::pkt_channel(0, size, self.to_waker());
+
+        let args = SessionArgs {
+            addr,
+            client_id: client_id.clone(),
+            shard_id: self.shard_id,
+            miot_tx,
+            session_rx,
+            peer_cred: None,
+            mount_point: None,
+            durable: false,
+            replicated: false,
+        };
+        let mut session = Session::start(args, self.config.clone(), pkt);
+
+        let packet = v5::Packet::ConnAck(session.success_ack(pkt, self));
+        session.in_messages(vec![Message::new_client_ack(packet)]);
+        session.flush_messages();
+
+        self.close_session(&client_id);
+        self.as_mut_sessions().insert(client_id, session);
+
+        (upstream, downstream)
+    }
+
+    /// Run one iteration of the shard's main-loop, return the sessions that have
+    /// failed and are closed.
+    pub(crate) fn run_in_memory(&mut self, msg_rx: &MsgRx) -> Vec<(ClientID, Error)> {
+        let failed_sessions = self.route_sessions();
+        for (client_id, _) in failed_sessions.iter() {
+            self.close_session(client_id);
+        }
+        self.flush_to_shards();
+
+        self.in_messages(msg_rx);
+        self.flush_messages();
+        self.ack_messages();
+
+        self.clear_unacks();
+        self.retry_publish();
+
+        self.expire_sessions();
+
+        failed_sessions
+    }
+}
+
 // sub-functions that work for handling incoming publish
 impl Shard {
     pub fn match_subscribers(
@@ -964,15 +1711,15 @@ impl Shard {
             _ => unreachable!(),
         };
 
-        match state.cinp.timestamp.get_mut(&shard_id) {
-            Some((_, last_received_ack)) => {
-                *last_received_ack = last_received;
-            }
-            None => unreachable!(),
+        // QoS-0 messages are not tracked in `timestamp`.
+        if let Some((_, last_received_ack)) = state.cinp.timestamp.get_mut(&shard_id) {
+            *last_received_ack = last_received;
         }
     }
 
     fn insert_unacks(&mut self, shard_id: u32, seqno: u64, msg: &Message) {
+        self.append_session_log(wal::Record::from_unack(msg).into_iter().collect());
+
         let RunLoop { state, .. } = match &mut self.inner {
             Inner::Main(run_loop) => run_loop,
             _ => unreachable!(),
@@ -993,33 +1740,33 @@ impl Shard {
             _ => unreachable!(),
         };
 
-        let mut last_received_ack = u64::MAX;
-        for (_, (_, last_received)) in state.cinp.timestamp.iter() {
-            last_received_ack = cmp::min(last_received_ack, *last_received);
-        }
+        // seqno starts from 1, an un-acked shard shall have its last_received_ack as 0.
+        let acked_seqnos = state
+            .cinp
+            .unacks
+            .iter()
+            .filter_map(|(seqno, msg)| {
+                let shard_id = match msg {
+                    Message::Packet { subscriptions, .. } => subscriptions[0].shard_id,
+                    _ => unreachable!(),
+                };
+                match state.cinp.timestamp.get(&shard_id) {
+                    Some((_, last_received_ack)) if seqno <= last_received_ack => {
+                        Some(*seqno)
+                    }
+                    _ => None,
+                }
+            })
+            .collect::<Vec<u64>>();
 
         let mut msgs = Vec::new();
-        match last_received_ack {
-            u64::MAX => (),
-            last_received_ack => {
-                let acked_seqnos = state
-                    .cinp
-                    .unacks
-                    .iter()
-                    .filter_map(|(seqno, _)| {
-                        if seqno < &last_received_ack {
-                            Some(*seqno)
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<u64>>();
-                for seqno in acked_seqnos.into_iter() {
-                    msgs.push(state.cinp.unacks.remove(&seqno).unwrap());
-                }
-            }
+        for seqno in acked_seqnos.iter() {
+            msgs.push(state.cinp.unacks.remove(seqno).unwrap());
         }
 
+        let records = acked_seqnos.into_iter().map(|seqno| wal::Record::Routed { seqno });
+        self.append_session_log(records.collect());
+
         msgs
     }
 
@@ -1029,7 +1776,9 @@ impl Shard {
             _ => unreachable!(),
         };
 
-        state.cinp.remove_session(session.as_client_id())
+        let seqnos = state.cinp.remove_session(session.as_client_id());
+        let records = seqnos.into_iter().map(|seqno| wal::Record::Routed { seqno });
+        self.append_session_log(records.collect());
     }
 }
 
@@ -1087,6 +1836,37 @@ impl Shard {
         }
     }
 
+    pub fn as_mut_offline_sessions(&mut self) -> &mut BTreeMap<ClientID, SessionState> {
+        match &mut self.inner {
+            Inner::Main(RunLoop { offline_sessions, .. }) => offline_sessions,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Return whether the changes to persistent sessions are logged, or replicated.
+    pub fn is_durable(&self) -> bool {
+        match &self.inner {
+            Inner::Main(RunLoop { session_log, replica_log, .. }) => {
+                session_log.is_some() || replica_log.is_some()
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn is_replicated(&self) -> bool {
+        match &self.inner {
+            Inner::Main(RunLoop { replica_log, .. }) => replica_log.is_some(),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn as_session_log(&self) -> Option<&SessionLog> {
+        match &self.inner {
+            Inner::Main(RunLoop { session_log, .. }) => session_log.as_ref(),
+            _ => unreachable!(),
+        }
+    }
+
     pub fn as_mut_ack_timestamps(&mut self) -> &mut BTreeMap<u32, u64> {
         match &mut self.inner {
             Inner::Main(RunLoop { ack_timestamp, .. }) => ack_timestamp,
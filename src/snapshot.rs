@@ -0,0 +1,164 @@
+//! Module implement snapshot of subscriptions and retained messages.
+//!
+//! [Cluster::export_snapshot][crate::Cluster::export_snapshot] captures the
+//! [SubscribedTrie] and [RetainedTrie] into a [Snapshot], that can be saved to a file,
+//! inspected offline and loaded back into a fresh cluster using
+//! [Cluster::import_snapshot][crate::Cluster::import_snapshot]. Tries are MVCC,
+//! hence each trie is captured as of a single version, without blocking the traffic.
+//!
+//! Snapshot does not carry session state, refer [crate::wal], and message expiry
+//! for retained messages starts afresh when imported. Imported subscriptions are
+//! hosted as offline sessions by the shard owning the client, clients can resume
+//! them by connecting with clean-start as ZERO. In a multi-node cluster, each node
+//! imports the subscriptions for the shards it masters.
+//!
+//! **File format**
+//!
+//! ```text
+//! | magic: "MQTRSNAP" | version: u32 | records ... |
+//! ```
+//!
+//! followed by records that use the same framing as [RetainLog][crate::RetainLog].
+//! First record is INFO, carrying the timestamp, in seconds since UNIX_EPOCH, at
+//! which the snapshot was taken. It is followed by a SUBSCRIPTION record for every
+//! subscription, a RETAIN record, carrying the PUBLISH packet, for every retained
+//! message, and an END record carrying the number of subscriptions and retained
+//! messages. All integers are in big-endian.
+
+use std::io::Write;
+use std::{fs, path};
+
+use crate::store::{decode_record, encode_record, unix_secs};
+use crate::wal::{decode_field, decode_subscription, decode_u64, encode_subscription};
+use crate::{v5, Packetize, RetainedTrie, SubscribedTrie};
+use crate::{Error, ErrorKind, Result};
+
+/// Type implement a point-in-time copy of subscriptions and retained messages.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Snapshot {
+    /// Seconds since UNIX_EPOCH, when the snapshot was taken.
+    pub timestamp: u64,
+    pub subscriptions: Vec<v5::Subscription>,
+    pub retained: Vec<v5::Publish>,
+}
+
+impl Snapshot {
+    /// Current version of the snapshot file format.
+    pub const VERSION: u32 = 1;
+
+    const MAGIC: &'static [u8; 8] = b"MQTRSNAP";
+
+    const OP_INFO: u8 = 1;
+    const OP_SUBSCRIPTION: u8 = 2;
+    const OP_RETAIN: u8 = 3;
+    const OP_END: u8 = 4;
+
+    /// Capture the current version of `topic_filters` and `retained_messages`.
+    pub fn new(topic_filters: &SubscribedTrie, retained: &RetainedTrie) -> Snapshot {
+        Snapshot {
+            timestamp: unix_secs(),
+            subscriptions: topic_filters.to_values(),
+            retained: retained.to_values(),
+        }
+    }
+
+    /// Save snapshot to file `loc`, file is replaced atomically.
+    pub fn save<P>(&self, loc: P) -> Result<()>
+    where
+        P: AsRef<path::Path>,
+    {
+        let loc: &path::Path = loc.as_ref();
+        let tmp_loc = loc.with_extension("tmp");
+
+        let data = self.encode()?;
+        {
+            let mut tmp =
+                err!(IOError, try: fs::File::create(&tmp_loc), "{:?}", tmp_loc)?;
+            err!(IOError, try: tmp.write_all(&data), "write {:?}", tmp_loc)?;
+            err!(IOError, try: tmp.sync_all(), "fsync {:?}", tmp_loc)?;
+        }
+        err!(IOError, try: fs::rename(&tmp_loc, loc), "rename {:?}", tmp_loc)?;
+
+        Ok(())
+    }
+
+    /// Load snapshot from file `loc`.
+    pub fn load<P>(loc: P) -> Result<Snapshot>
+    where
+        P: AsRef<path::Path>,
+    {
+        let loc: &path::Path = loc.as_ref();
+        let data = err!(IOError, try: fs::read(loc), "read {:?}", loc)?;
+        Snapshot::decode(&data)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = Self::MAGIC.to_vec();
+        data.extend_from_slice(&Self::VERSION.to_be_bytes());
+
+        let body = self.timestamp.to_be_bytes();
+        data.extend(encode_record(Self::OP_INFO, &body)?);
+        for subscr in self.subscriptions.iter() {
+            let mut body = vec![];
+            encode_subscription(subscr, &mut body)?;
+            data.extend(encode_record(Self::OP_SUBSCRIPTION, &body)?);
+        }
+        for publish in self.retained.iter() {
+            let body = publish.encode()?;
+            data.extend(encode_record(Self::OP_RETAIN, body.as_ref())?);
+        }
+        let mut body = vec![];
+        body.extend_from_slice(&(self.subscriptions.len() as u64).to_be_bytes());
+        body.extend_from_slice(&(self.retained.len() as u64).to_be_bytes());
+        data.extend(encode_record(Self::OP_END, &body)?);
+
+        Ok(data)
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Snapshot> {
+        let n = Self::MAGIC.len();
+        if data.len() < (n + 4) || &data[..n] != Self::MAGIC {
+            err!(InvalidInput, desc: "not a snapshot file")?;
+        }
+        let version = u32::from_be_bytes(data[n..n + 4].try_into().unwrap());
+        if version != Self::VERSION {
+            err!(InvalidInput, desc: "unsupported snapshot version {}", version)?;
+        }
+
+        let mut val = Snapshot::default();
+        let mut offset = n + 4;
+        loop {
+            let (op, body, m) = match decode_record(&data[offset..]) {
+                Some(val) => val,
+                None => err!(InvalidInput, desc: "corrupt snapshot at {}", offset)?,
+            };
+            offset += m;
+
+            let mut n = 0;
+            match op {
+                Self::OP_INFO => val.timestamp = decode_u64(body, &mut n)?,
+                Self::OP_SUBSCRIPTION => {
+                    val.subscriptions.push(decode_subscription(body, &mut n)?)
+                }
+                Self::OP_RETAIN => val.retained.push(decode_field(body, &mut n)?),
+                Self::OP_END => {
+                    let subscriptions = decode_u64(body, &mut n)? as usize;
+                    let retained = decode_u64(body, &mut n)? as usize;
+                    if subscriptions != val.subscriptions.len()
+                        || retained != val.retained.len()
+                    {
+                        err!(InvalidInput, desc: "incomplete snapshot")?;
+                    }
+                    break;
+                }
+                op => err!(InvalidInput, desc: "invalid snapshot op {}", op)?,
+            }
+        }
+
+        Ok(val)
+    }
+}
+
+#[cfg(test)]
+#[path = "snapshot_test.rs"]
+mod snapshot_test;
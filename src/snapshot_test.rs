@@ -0,0 +1,48 @@
+use super::*;
+
+use crate::fixtures::{new_retained, new_subscription};
+use crate::TopicName;
+
+#[test]
+fn test_snapshot() {
+    let topic_filters = SubscribedTrie::default();
+    let retained = RetainedTrie::default();
+    for (id, filter) in [("a", "x/+"), ("b", "x/#"), ("a", "$SYS/y")].iter() {
+        // non-default options, to cover their encoding.
+        let subscr = v5::Subscription {
+            shard_id: 3,
+            subscription_id: Some(7),
+            no_local: false,
+            retain_as_published: true,
+            ..new_subscription(id, filter)
+        };
+        topic_filters.subscribe(&subscr.topic_filter.clone(), subscr);
+    }
+    for topic in ["x/1", "x/2/3", "$SYS/y"].iter() {
+        retained
+            .set(&TopicName::from(topic.to_string()), new_retained(topic, topic, None));
+    }
+
+    let snapshot = Snapshot::new(&topic_filters, &retained);
+    // later changes are not visible in the snapshot.
+    retained.set(&TopicName::from("x/4".to_string()), new_retained("x/4", "x/4", None));
+    assert_eq!(snapshot.subscriptions.len(), 3);
+    assert_eq!(snapshot.retained.len(), 3);
+
+    let data = snapshot.encode().unwrap();
+    assert_eq!(Snapshot::decode(&data).unwrap(), snapshot);
+
+    let dir = std::env::temp_dir().join(format!("snapshot-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    let loc = dir.join("state.snap");
+    snapshot.save(&loc).unwrap();
+    assert_eq!(Snapshot::load(&loc).unwrap(), snapshot);
+    fs::remove_dir_all(&dir).unwrap();
+
+    // truncated, and unsupported version.
+    assert!(Snapshot::decode(&data[..data.len() - 1]).is_err());
+    let mut data = data;
+    data[11] = 2;
+    assert!(Snapshot::decode(&data).is_err());
+    assert!(Snapshot::decode(b"MQTR").is_err());
+}
@@ -4,7 +4,7 @@ use std::sync::{mpsc, Arc};
 use std::{collections::VecDeque, mem, net, time};
 
 use crate::packet::{MQTTRead, MQTTWrite};
-use crate::{v5, ClientID, Config, Packetize, QueueStatus};
+use crate::{v5, ClientID, Config, ConnGuard, QueueStatus, Stream};
 use crate::{ErrorKind, Result};
 
 pub type QueuePkt = QueueStatus<v5::Packet>;
@@ -85,11 +85,13 @@ impl PktRx {
 /// Type encapsulates the socket connection and associated data-structures.
 pub struct Socket {
     pub client_id: ClientID,
-    pub conn: mio::net::TcpStream,
+    pub conn: Stream,
     pub addr: net::SocketAddr,
     pub token: mio::Token,
     pub rd: Source,
     pub wt: Sink,
+    /// Accounts this connection against its listener's `max_connections`.
+    pub guard: ConnGuard,
 }
 
 pub struct Source {
@@ -111,15 +113,15 @@ pub struct Sink {
 impl Socket {
     pub fn read_elapsed(&self) -> bool {
         match &self.rd.timeout {
-            Some(timeout) if timeout > &time::Instant::now() => true,
-            Some(_) | None => false,
+            Some(timeout) => timeout <= &time::Instant::now(),
+            None => false,
         }
     }
 
     pub fn write_elapsed(&self) -> bool {
         match &self.wt.timeout {
-            Some(timeout) if timeout > &time::Instant::now() => true,
-            Some(_) | None => false,
+            Some(timeout) => timeout <= &time::Instant::now(),
+            None => false,
         }
     }
 
@@ -187,6 +189,11 @@ impl Socket {
         };
 
         let status = match &pr {
+            // idle connection, keep-alive shall take care of dead clients.
+            Init { data, .. } if data.is_empty() => {
+                self.set_read_timeout(false, timeout);
+                QueueStatus::Block(Vec::new())
+            }
             Init { .. } | Header { .. } | Remain { .. } if !self.read_elapsed() => {
                 trace!("{} read retrying", prefix);
                 self.set_read_timeout(true, timeout);
@@ -247,36 +254,27 @@ impl Socket {
 
     // QueueStatus shall not carry any packets
     pub fn flush_packets(&mut self, prefix: &str, config: &Config) -> QueuePkt {
-        let mut pw = mem::replace(&mut self.wt.pw, MQTTWrite::default());
-        let mut iter = {
-            let packets = self.wt.packets.drain(..).collect::<Vec<v5::Packet>>();
-            packets.into_iter()
-        };
-        let res = loop {
+        loop {
             match self.write_packet(prefix, config) {
                 QueueStatus::Ok(_) => (),
                 res @ QueueStatus::Block(_) => break res,
                 res @ QueueStatus::Disconnected(_) => break res,
             }
-            if let Some(packet) = iter.next() {
-                let blob = match packet.encode() {
-                    Ok(blob) => blob,
-                    Err(err) => {
-                        let pt = packet.to_packet_type();
-                        error!("{} skipping packet {:?} : {}", prefix, pt, err);
-                        continue;
-                    }
-                };
-                pw = pw.reset(blob.as_ref());
-            } else {
+            if self.wt.packets.is_empty() {
                 break QueueStatus::Ok(Vec::new());
             }
-        };
 
-        self.wt.packets.extend(iter);
-        let _pw_none = mem::replace(&mut self.wt.pw, pw);
-
-        res
+            // previous batch is fully written, serialize all pending packets into
+            // the same write-buffer, to be written as the next batch.
+            let mut pw = mem::replace(&mut self.wt.pw, MQTTWrite::default()).reset(&[]);
+            for packet in self.wt.packets.drain(..) {
+                if let Err(err) = pw.encode_packet(&packet) {
+                    let pt = packet.to_packet_type();
+                    error!("{} skipping packet {:?} : {}", prefix, pt, err);
+                }
+            }
+            let _pw_none = mem::replace(&mut self.wt.pw, pw);
+        }
     }
 
     // QueueStatus shall not carry any packets
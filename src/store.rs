@@ -0,0 +1,410 @@
+//! Module implement persistent store for retained messages.
+//!
+//! [Cluster][crate::Cluster] writes every set/reset of a retained message through
+//! a [RetainStore], and loads them back when the cluster is spawned, so that
+//! retained messages survive a restart. [RetainLog] is the default implementation,
+//! enabled by configuring [Config::retain_store_dir].
+//!
+//! **Log format**
+//!
+//! [RetainLog] is an append-only file, `retained.log`, made up of records:
+//!
+//! ```text
+//! | length: u32 | crc32: u32 | op: u8 | body ... |
+//! ```
+//!
+//! `length` is the number of bytes following the crc, and `crc32` is computed over
+//! those bytes. Body for `op` SET is the timestamp, in seconds since UNIX_EPOCH, at
+//! which the message was retained, followed by the PUBLISH packet. Body for `op`
+//! RESET is the topic-name. All integers are in big-endian. A partially written
+//! record, at the tail of the log, is truncated while loading the log. A record
+//! whose crc does not match, anywhere else in the log, fails the load.
+//!
+//! Records that are overwritten by a later SET or RESET are garbage. When garbage
+//! out-grows the live records, the log is compacted by re-writing the live records
+//! into a new file and atomically renaming it over the old one.
+
+use log::{error, info, warn};
+
+use std::collections::BTreeMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::{fs, path, time};
+
+use crate::config::FsyncPolicy;
+use crate::{crc, v5, Config, Packetize, TopicName};
+use crate::{Error, ErrorKind, Result};
+
+/// Retained message along with the time at which it was retained.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetainRecord {
+    /// Retained PUBLISH message.
+    pub publish: v5::Publish,
+    /// Seconds since UNIX_EPOCH, when `publish` was retained.
+    pub timestamp: u64,
+}
+
+impl RetainRecord {
+    pub fn new(publish: v5::Publish) -> RetainRecord {
+        RetainRecord { publish, timestamp: unix_secs() }
+    }
+
+    /// Return the retained message, with its message-expiry-interval adjusted for
+    /// the time elapsed since it was retained. Return None if message has expired.
+    pub fn to_publish(&self) -> Option<v5::Publish> {
+        let mut publish = self.publish.clone();
+
+        let elapsed = unix_secs().saturating_sub(self.timestamp);
+        let expiry = publish.properties.as_mut().map(|p| &mut p.message_expiry_interval);
+        match expiry {
+            Some(Some(secs)) if u64::from(*secs) <= elapsed => None,
+            Some(Some(secs)) => {
+                *secs -= elapsed as u32;
+                Some(publish)
+            }
+            _ => Some(publish),
+        }
+    }
+}
+
+/// Trait to be implemented by persistent store for retained messages.
+///
+/// Methods are called from the cluster thread. Errors are logged by the cluster,
+/// and the in-memory copy of retained messages continue to serve the clients.
+pub trait RetainStore: Send {
+    /// Load all retained messages from the store. Called once, when the cluster is
+    /// spawned.
+    fn load(&mut self) -> Result<Vec<RetainRecord>>;
+
+    /// Persist a retained message, replacing the previous one for the same
+    /// topic-name, if any.
+    fn set(&mut self, record: &RetainRecord) -> Result<()>;
+
+    /// Remove the retained message for `topic_name`.
+    fn reset(&mut self, topic_name: &TopicName) -> Result<()>;
+
+    /// Called periodically by the cluster, to persist pending writes as per the
+    /// fsync policy and to compact the store, if required.
+    fn flush(&mut self) -> Result<()>;
+
+    /// Persist all pending writes, called when the cluster is closed.
+    fn sync(&mut self) -> Result<()>;
+}
+
+/// Return the configured store for retained messages, if any.
+pub fn from_config(config: &Config) -> Result<Option<Box<dyn RetainStore>>> {
+    match config.retain_store_dir() {
+        Some(dir) => Ok(Some(Box::new(RetainLog::open(config, dir)?))),
+        None => Ok(None),
+    }
+}
+
+/// Append-only log of retained messages, refer to module documentation for
+/// details.
+pub struct RetainLog {
+    loc: path::PathBuf,
+    file: fs::File,
+    fsync: FsyncPolicy,
+    fsync_interval: time::Duration,
+    last_fsync: time::Instant,
+    dirty: bool,
+
+    // size of the log file, in bytes.
+    size: u64,
+    // (offset, length) of live SET records, indexed by topic-name.
+    live: BTreeMap<TopicName, (u64, u64)>,
+    // total size of live records.
+    live_size: u64,
+}
+
+impl RetainLog {
+    /// Name of the log file under [Config::retain_store_dir].
+    pub const FILE_NAME: &'static str = "retained.log";
+    /// Compaction is not attempted, if the log is smaller than this size.
+    pub const COMPACT_MIN_SIZE: u64 = 1024 * 1024;
+
+    const OP_SET: u8 = 1;
+    const OP_RESET: u8 = 2;
+
+    /// Open the log under directory `dir`, create the directory and log file if
+    /// they are missing.
+    pub fn open<P>(config: &Config, dir: P) -> Result<RetainLog>
+    where
+        P: AsRef<path::Path>,
+    {
+        let dir: &path::Path = dir.as_ref();
+        err!(IOError, try: fs::create_dir_all(dir), "create retain store {:?}", dir)?;
+
+        let loc = dir.join(Self::FILE_NAME);
+        let file = Self::open_file(&loc)?;
+        let size = err!(IOError, try: file.metadata(), "metadata {:?}", loc)?.len();
+
+        let val = RetainLog {
+            loc,
+            file,
+            fsync: config.retain_store_fsync(),
+            fsync_interval: time::Duration::from_secs(
+                config.retain_store_fsync_interval().into(),
+            ),
+            last_fsync: time::Instant::now(),
+            dirty: false,
+
+            size,
+            live: BTreeMap::default(),
+            live_size: 0,
+        };
+
+        Ok(val)
+    }
+
+    /// Return the location of the log file.
+    pub fn to_location(&self) -> path::PathBuf {
+        self.loc.clone()
+    }
+
+    /// Return the size of the log file, in bytes.
+    pub fn to_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Re-write live records into a new log file, dropping the garbage.
+    pub fn compact(&mut self) -> Result<()> {
+        let tmp_loc = self.loc.with_extension("compact");
+
+        let mut live = BTreeMap::default();
+        let mut size = 0;
+        {
+            let mut old =
+                err!(IOError, try: fs::File::open(&self.loc), "{:?}", self.loc)?;
+            let mut tmp =
+                err!(IOError, try: fs::File::create(&tmp_loc), "{:?}", tmp_loc)?;
+
+            let mut buf = vec![];
+            for (topic_name, (offset, len)) in self.live.iter() {
+                buf.resize(*len as usize, 0);
+                err!(IOError, try: old.seek(SeekFrom::Start(*offset)))?;
+                err!(IOError, try: old.read_exact(&mut buf), "read {:?}", self.loc)?;
+                err!(IOError, try: tmp.write_all(&buf), "write {:?}", tmp_loc)?;
+
+                live.insert(topic_name.clone(), (size, *len));
+                size += len;
+            }
+            err!(IOError, try: tmp.sync_all(), "fsync {:?}", tmp_loc)?;
+        }
+
+        err!(IOError, try: fs::rename(&tmp_loc, &self.loc), "rename {:?}", tmp_loc)?;
+        if let Some(dir) = self.loc.parent() {
+            err!(IOError, try: fs::File::open(dir).and_then(|d| d.sync_all()))?;
+        }
+
+        info!(
+            "{:?} compacted {} bytes to {} bytes, {} messages",
+            self.loc,
+            self.size,
+            size,
+            live.len()
+        );
+
+        self.file = Self::open_file(&self.loc)?;
+        self.size = size;
+        self.live_size = size;
+        self.live = live;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+impl RetainStore for RetainLog {
+    fn load(&mut self) -> Result<Vec<RetainRecord>> {
+        let data = err!(IOError, try: fs::read(&self.loc), "read {:?}", self.loc)?;
+
+        let mut records: BTreeMap<TopicName, RetainRecord> = BTreeMap::default();
+        self.live.clear();
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let (op, body, n) = match decode_record(&data[offset..]) {
+                Some(val) => val,
+                None if !is_partial_record(&data[offset..]) => err!(
+                    InvalidInput,
+                    desc: "{:?} corrupt record at {}/{}",
+                    self.loc,
+                    offset,
+                    data.len()
+                )?,
+                None => {
+                    warn!(
+                        "{:?} truncating partial record at {}/{}",
+                        self.loc,
+                        offset,
+                        data.len()
+                    );
+                    err!(IOError, try: self.file.set_len(offset as u64))?;
+                    err!(IOError, try: self.file.sync_all(), "fsync {:?}", self.loc)?;
+                    break;
+                }
+            };
+
+            match op {
+                Self::OP_SET => {
+                    let timestamp = match body.get(..8) {
+                        Some(bytes) => u64::from_be_bytes(bytes.try_into().unwrap()),
+                        None => err!(InvalidInput, desc: "{:?} SET record", self.loc)?,
+                    };
+                    let (publish, _) = v5::Publish::decode(&body[8..])?;
+                    let topic_name = publish.topic_name.clone();
+                    self.live.insert(topic_name.clone(), (offset as u64, n as u64));
+                    records.insert(topic_name, RetainRecord { publish, timestamp });
+                }
+                Self::OP_RESET => {
+                    let (topic_name, _) = String::decode(body)?;
+                    let topic_name = TopicName::from(topic_name);
+                    self.live.remove(&topic_name);
+                    records.remove(&topic_name);
+                }
+                op => err!(InvalidInput, desc: "{:?} invalid op {}", self.loc, op)?,
+            }
+            offset += n;
+        }
+
+        self.size = offset as u64;
+        self.live_size = self.live.values().map(|(_, len)| len).sum();
+
+        Ok(records.into_values().collect())
+    }
+
+    fn set(&mut self, record: &RetainRecord) -> Result<()> {
+        let mut body = record.timestamp.to_be_bytes().to_vec();
+        record.publish.encode_into(&mut body)?;
+
+        let (offset, len) = self.append(Self::OP_SET, &body)?;
+
+        let topic_name = record.publish.topic_name.clone();
+        if let Some((_, old_len)) = self.live.insert(topic_name, (offset, len)) {
+            self.live_size -= old_len;
+        }
+        self.live_size += len;
+
+        Ok(())
+    }
+
+    fn reset(&mut self, topic_name: &TopicName) -> Result<()> {
+        if let Some((_, len)) = self.live.remove(topic_name) {
+            self.live_size -= len;
+            let body = topic_name.encode()?;
+            self.append(Self::OP_RESET, body.as_ref())?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let interval = matches!(self.fsync, FsyncPolicy::Interval);
+        if self.dirty && interval && self.last_fsync.elapsed() >= self.fsync_interval {
+            self.sync()?;
+        }
+
+        if self.size >= Self::COMPACT_MIN_SIZE && self.size > (self.live_size * 2) {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            err!(IOError, try: self.file.sync_data(), "fsync {:?}", self.loc)?;
+            self.dirty = false;
+        }
+        self.last_fsync = time::Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Drop for RetainLog {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            error!("{:?} sync on drop: {}", self.loc, err);
+        }
+    }
+}
+
+impl RetainLog {
+    fn open_file(loc: &path::Path) -> Result<fs::File> {
+        let mut opts = fs::OpenOptions::new();
+        let file = opts.read(true).append(true).create(true).open(loc);
+        err!(IOError, try: file, "open retain log {:?}", loc)
+    }
+
+    // append record, return (offset, length) of the record.
+    fn append(&mut self, op: u8, body: &[u8]) -> Result<(u64, u64)> {
+        let data = encode_record(op, body)?;
+
+        err!(IOError, try: self.file.write_all(&data), "append {:?}", self.loc)?;
+        self.dirty = true;
+        if let FsyncPolicy::Always = self.fsync {
+            self.sync()?;
+        }
+
+        let offset = self.size;
+        self.size += data.len() as u64;
+        Ok((offset, data.len() as u64))
+    }
+}
+
+const HEADER_SIZE: usize = 8;
+
+// Frame `op` and `body` into a log record, refer to module documentation.
+pub(crate) fn encode_record(op: u8, body: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(body.len() + 1)?;
+
+    let mut data = Vec::with_capacity(HEADER_SIZE + body.len() + 1);
+    data.extend_from_slice(&len.to_be_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.push(op);
+    data.extend_from_slice(body);
+    let crc = crc::crc32(&data[HEADER_SIZE..]);
+    data[4..8].copy_from_slice(&crc.to_be_bytes());
+
+    Ok(data)
+}
+
+// return true if `data` is shorter than the record it starts with, that is, the
+// record was partially written.
+pub(crate) fn is_partial_record(data: &[u8]) -> bool {
+    match data.get(..4) {
+        Some(bytes) => {
+            let len = u32::from_be_bytes(bytes.try_into().unwrap()) as usize;
+            data.len() < HEADER_SIZE.saturating_add(len)
+        }
+        None => true,
+    }
+}
+
+// return (op, body, record-length), None if record is partial or corrupt.
+pub(crate) fn decode_record(data: &[u8]) -> Option<(u8, &[u8], usize)> {
+    if data.len() < HEADER_SIZE {
+        return None;
+    }
+
+    let len = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    match data.get(HEADER_SIZE..HEADER_SIZE + len) {
+        Some(rec) if len > 0 && crc::crc32(rec) == crc => {
+            Some((rec[0], &rec[1..], HEADER_SIZE + len))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn unix_secs() -> u64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+#[path = "store_test.rs"]
+mod store_test;
@@ -0,0 +1,121 @@
+use super::*;
+
+use crate::fixtures::{new_retained, temp_dir};
+
+fn load_sorted(config: &Config, dir: &path::Path) -> Vec<RetainRecord> {
+    let mut log = RetainLog::open(config, dir).unwrap();
+    let mut records = log.load().unwrap();
+    records.sort_by(|a, b| a.publish.topic_name.cmp(&b.publish.topic_name));
+    records
+}
+
+#[test]
+fn test_retain_log() {
+    let dir = temp_dir("test_retain_log");
+    let config = Config {
+        retain_store_fsync: Some(FsyncPolicy::Always),
+        ..Config::default()
+    };
+
+    let (a, b, c) = (
+        RetainRecord::new(new_retained("a/b", "one", None)),
+        RetainRecord::new(new_retained("a/c", "two", Some(3600))),
+        RetainRecord::new(new_retained("b", "three", None)),
+    );
+    {
+        let mut log = RetainLog::open(&config, &dir).unwrap();
+        assert_eq!(log.load().unwrap(), vec![]);
+        log.set(&a).unwrap();
+        log.set(&b).unwrap();
+        log.set(&c).unwrap();
+        log.reset(&c.publish.topic_name).unwrap();
+        // reset on a missing topic is a no-op.
+        log.reset(&TopicName::from("x".to_string())).unwrap();
+    }
+    assert_eq!(load_sorted(&config, &dir), vec![a.clone(), b.clone()]);
+
+    // overwrite `a` and leave a partial record at the tail.
+    let a = RetainRecord::new(new_retained("a/b", "four", None));
+    let size = {
+        let mut log = RetainLog::open(&config, &dir).unwrap();
+        log.load().unwrap();
+        log.set(&a).unwrap();
+        log.to_size()
+    };
+    let loc = dir.join(RetainLog::FILE_NAME);
+    {
+        let mut file = fs::OpenOptions::new().append(true).open(&loc).unwrap();
+        file.write_all(&[0, 0, 0, 100, 1, 2, 3, 4, 1]).unwrap();
+    }
+    assert_eq!(load_sorted(&config, &dir), vec![a, b]);
+    assert_eq!(fs::metadata(&loc).unwrap().len(), size);
+
+    // corrupt a record in the middle of the log, load shall fail without truncating.
+    let mut data = fs::read(&loc).unwrap();
+    data[HEADER_SIZE + 1] ^= 0xFF;
+    fs::write(&loc, &data).unwrap();
+    assert!(RetainLog::open(&config, &dir).unwrap().load().is_err());
+    assert_eq!(fs::metadata(&loc).unwrap().len(), size);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_retain_log_compact() {
+    let dir = temp_dir("test_retain_log_compact");
+    let config = Config::default();
+
+    let mut records = vec![];
+    let size = {
+        let mut log = RetainLog::open(&config, &dir).unwrap();
+        for i in 0..1000 {
+            let topic = format!("topic/{}", i % 10);
+            let record = RetainRecord::new(new_retained(&topic, &i.to_string(), None));
+            log.set(&record).unwrap();
+            if i >= 990 {
+                records.push(record);
+            }
+        }
+        log.reset(&TopicName::from("topic/0".to_string())).unwrap();
+        records.remove(0);
+
+        let size = log.to_size();
+        log.compact().unwrap();
+        assert!(log.to_size() * 50 < size, "{} {}", log.to_size(), size);
+
+        // log is usable after compaction.
+        let record = RetainRecord::new(new_retained("topic/0", "new", None));
+        log.set(&record).unwrap();
+        records.insert(0, record);
+        log.to_size()
+    };
+
+    assert_eq!(load_sorted(&config, &dir), records);
+    assert_eq!(fs::metadata(dir.join(RetainLog::FILE_NAME)).unwrap().len(), size);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_retain_record_expiry() {
+    let now = unix_secs();
+
+    let record = RetainRecord {
+        publish: new_retained("a", "b", None),
+        timestamp: 0,
+    };
+    assert_eq!(record.to_publish(), Some(record.publish.clone()));
+
+    let record = RetainRecord {
+        publish: new_retained("a", "b", Some(5)),
+        timestamp: now - 10,
+    };
+    assert_eq!(record.to_publish(), None);
+
+    let record = RetainRecord {
+        publish: new_retained("a", "b", Some(100)),
+        timestamp: now - 10,
+    };
+    let expiry = record.to_publish().unwrap().properties.unwrap().message_expiry_interval;
+    assert!(matches!(expiry, Some(secs) if secs <= 90 && secs >= 89), "{:?}", expiry);
+}
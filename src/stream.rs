@@ -0,0 +1,196 @@
+use std::{fmt, io, net};
+
+use crate::{TlsStream, WsStream};
+
+/// Type abstracts the transport for a client connection, TCP, TLS, WebSocket or
+/// Unix-domain socket.
+///
+/// Implements [io::Read], [io::Write] and [mio::event::Source] by dispatching to the
+/// underlying stream.
+pub enum Stream {
+    Tcp(mio::net::TcpStream),
+    Tls(Box<TlsStream>),
+    Ws(Box<WsStream>),
+    Unix(mio::net::UnixStream),
+}
+
+/// Type captures the credentials of a peer process connected via Unix-domain socket,
+/// read using `SO_PEERCRED`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeerCred {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: i32,
+}
+
+impl fmt::Display for PeerCred {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uid-{}:pid-{}", self.uid, self.pid)
+    }
+}
+
+impl From<mio::net::TcpStream> for Stream {
+    fn from(conn: mio::net::TcpStream) -> Stream {
+        Stream::Tcp(conn)
+    }
+}
+
+impl From<mio::net::UnixStream> for Stream {
+    fn from(conn: mio::net::UnixStream) -> Stream {
+        Stream::Unix(conn)
+    }
+}
+
+impl From<TlsStream> for Stream {
+    fn from(conn: TlsStream) -> Stream {
+        Stream::Tls(Box::new(conn))
+    }
+}
+
+impl From<WsStream> for Stream {
+    fn from(conn: WsStream) -> Stream {
+        Stream::Ws(Box::new(conn))
+    }
+}
+
+impl Stream {
+    /// Unix-domain socket peers don't have an IP address, they are identified with
+    /// the unspecified address and `seqno` as port, where ever a [net::SocketAddr]
+    /// is expected. `seqno` shall be unique across active connections on a listener.
+    pub fn unix_peer_addr(seqno: u16) -> net::SocketAddr {
+        (net::Ipv4Addr::UNSPECIFIED, seqno).into()
+    }
+
+    /// Return the peer's credentials, applicable only for Unix-domain socket.
+    pub fn peer_cred(&self) -> io::Result<Option<PeerCred>> {
+        match self {
+            Stream::Tcp(_) | Stream::Tls(_) | Stream::Ws(_) => Ok(None),
+            Stream::Unix(conn) => Ok(Some(peer_cred(conn)?)),
+        }
+    }
+}
+
+impl io::Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl io::Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(conn) => (&*conn).read(buf),
+            Stream::Tls(conn) => (&**conn).read(buf),
+            Stream::Ws(conn) => (&**conn).read(buf),
+            Stream::Unix(conn) => (&*conn).read(buf),
+        }
+    }
+}
+
+impl io::Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        (&*self).write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl io::Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(conn) => (&*conn).write(buf),
+            Stream::Tls(conn) => (&**conn).write(buf),
+            Stream::Ws(conn) => (&**conn).write(buf),
+            Stream::Unix(conn) => (&*conn).write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(conn) => (&*conn).write_vectored(bufs),
+            Stream::Tls(conn) => (&**conn).write_vectored(bufs),
+            Stream::Ws(conn) => (&**conn).write_vectored(bufs),
+            Stream::Unix(conn) => (&*conn).write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(conn) => (&*conn).flush(),
+            Stream::Tls(conn) => (&**conn).flush(),
+            Stream::Ws(conn) => (&**conn).flush(),
+            Stream::Unix(conn) => (&*conn).flush(),
+        }
+    }
+}
+
+impl mio::event::Source for Stream {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(conn) => conn.register(registry, token, interests),
+            Stream::Tls(conn) => conn.register(registry, token, interests),
+            Stream::Ws(conn) => conn.register(registry, token, interests),
+            Stream::Unix(conn) => conn.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(conn) => conn.reregister(registry, token, interests),
+            Stream::Tls(conn) => conn.reregister(registry, token, interests),
+            Stream::Ws(conn) => conn.reregister(registry, token, interests),
+            Stream::Unix(conn) => conn.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        match self {
+            Stream::Tcp(conn) => conn.deregister(registry),
+            Stream::Tls(conn) => conn.deregister(registry),
+            Stream::Ws(conn) => conn.deregister(registry),
+            Stream::Unix(conn) => conn.deregister(registry),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_cred(conn: &mio::net::UnixStream) -> io::Result<PeerCred> {
+    use std::{mem, os::unix::io::AsRawFd};
+
+    let mut ucred = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            conn.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut ucred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match rc {
+        0 => Ok(PeerCred { uid: ucred.uid, gid: ucred.gid, pid: ucred.pid }),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_cred(_conn: &mio::net::UnixStream) -> io::Result<PeerCred> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_PEERCRED not supported"))
+}
@@ -288,18 +288,21 @@ pub fn pending_requests<Q, R>(prefix: &str, rx: &Rx<Q, R>, max: usize) -> QueueR
 /// Return (requests, disconnected), uses blocking `recv`. For nond-blocking version
 /// use pending_requests.
 pub fn get_requests<Q, R>(prefix: &str, rx: &Rx<Q, R>, max: usize) -> QueueReq<Q, R> {
-    let mut reqs = Vec::with_capacity(max);
-    loop {
-        match rx.recv() {
-            Ok(req) if reqs.len() < max => reqs.push(req),
-            Ok(req) => {
-                reqs.push(req);
-                break QueueReq::Ok(reqs);
-            }
-            Err(mpsc::RecvError) => {
-                warn!("{} req-channel disconnected ...", prefix);
-                break QueueReq::Disconnected(reqs);
-            }
+    // block for the first request, and gather the rest without blocking.
+    let req = match rx.recv() {
+        Ok(req) => req,
+        Err(mpsc::RecvError) => {
+            warn!("{} req-channel disconnected ...", prefix);
+            return QueueReq::Disconnected(Vec::default());
         }
+    };
+
+    let mut status = pending_requests(prefix, rx, max.saturating_sub(1));
+    let mut reqs = vec![req];
+    reqs.extend(status.take_values());
+    match status {
+        QueueReq::Ok(_) => QueueReq::Ok(reqs),
+        QueueReq::Block(_) => QueueReq::Block(reqs),
+        QueueReq::Disconnected(_) => QueueReq::Disconnected(reqs),
     }
 }
@@ -129,6 +129,8 @@ impl Ticker {
 }
 
 pub enum Request {
+    AddShard(Box<Shard>),
+    RemoveShard(u32),
     Close,
 }
 
@@ -138,6 +140,22 @@ pub enum Response {
 
 // calls to interface with ticker-thread.
 impl Ticker {
+    /// Wake up `shard` along with other shards, say a shard spawned on fail-over.
+    pub fn add_shard(&self, shard: Shard) -> Result<()> {
+        match &self.inner {
+            Inner::Handle(thrd) => thrd.post(Request::AddShard(Box::new(shard))),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Stop waking up shard `shard_id`, say a shard migrated to another node.
+    pub fn remove_shard(&self, shard_id: u32) -> Result<()> {
+        match &self.inner {
+            Inner::Handle(thrd) => thrd.post(Request::RemoveShard(shard_id)),
+            _ => unreachable!(),
+        }
+    }
+
     pub fn close_wait(mut self) -> Ticker {
         let inner = mem::replace(&mut self.inner, Inner::Init);
         match inner {
@@ -145,6 +163,8 @@ impl Ticker {
                 thrd.request(Request::Close).ok();
                 thrd.close_wait()
             }
+            // cluster closed before ticker was spawned.
+            Inner::Init => self,
             _ => unreachable!(),
         }
     }
@@ -158,8 +178,17 @@ impl Threadable for Ticker {
         'outer: loop {
             thread::sleep(SLEEP_10MS);
 
+            let RunLoop { cluster, ticker_count, shards, .. } = match &mut self.inner {
+                Inner::Main(run_loop) => run_loop,
+                _ => unreachable!(),
+            };
+
             loop {
                 match rx.try_recv() {
+                    Ok((Request::AddShard(shard), None)) => shards.push(*shard),
+                    Ok((Request::RemoveShard(shard_id), None)) => {
+                        shards.retain(|shard| shard.shard_id != shard_id)
+                    }
                     Ok((Request::Close, Some(tx))) => {
                         err!(IPCFail, try: tx.send(Ok(Response::Ok))).ok();
                         break 'outer;
@@ -170,11 +199,6 @@ impl Threadable for Ticker {
                 }
             }
 
-            let RunLoop { cluster, ticker_count, shards, .. } = match &mut self.inner {
-                Inner::Main(run_loop) => run_loop,
-                _ => unreachable!(),
-            };
-
             *ticker_count += 1;
 
             cluster.wake();
@@ -0,0 +1,176 @@
+//! Module implement TLS transport for client connections, refer
+//! [Transport::Tls][crate::Transport::Tls].
+//!
+//! TLS handshake is driven in-band, while reading the first bytes from the
+//! connection, so that listener threads handle it along with the CONNECT handshake,
+//! within the same connect-timeout. Records are encrypted as they are written, and
+//! records that can't be written without blocking are kept with the connection. They
+//! are written before any more data is accepted from the caller.
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+use std::{cell::RefCell, io, io::Read, path, sync::Arc};
+
+use crate::{Error, ErrorKind, Result};
+
+/// Type implement a server side TLS connection, over a non-blocking TCP stream.
+pub struct TlsStream {
+    conn: mio::net::TcpStream,
+    // reads and writes are made via shared reference, refer [crate::Stream].
+    tls: RefCell<rustls::ServerConnection>,
+}
+
+impl TlsStream {
+    /// Wrap an accepted TCP connection, TLS handshake shall happen as and when data
+    /// is read from the connection.
+    pub fn new(
+        conn: mio::net::TcpStream,
+        config: Arc<rustls::ServerConfig>,
+    ) -> io::Result<TlsStream> {
+        let tls = match rustls::ServerConnection::new(config) {
+            Ok(tls) => tls,
+            Err(err) => return Err(io::Error::other(err)),
+        };
+        Ok(TlsStream { conn, tls: RefCell::new(tls) })
+    }
+
+    fn read_plain(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut tls = self.tls.borrow_mut();
+        loop {
+            // handshake messages and alerts, from previous calls.
+            match write_tls(&mut tls, &self.conn) {
+                Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+                _ => (),
+            }
+
+            match tls.reader().read(buf) {
+                Ok(n) => break Ok(n),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => break Err(err),
+            }
+
+            match tls.read_tls(&mut &self.conn) {
+                Ok(0) => break Ok(0),
+                Ok(_) => (),
+                Err(err) => break Err(err),
+            }
+            if let Err(err) = tls.process_new_packets() {
+                // send the alert, if any, before closing the connection.
+                write_tls(&mut tls, &self.conn).ok();
+                break Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+
+    fn write_plain<F>(&self, write: F) -> io::Result<usize>
+    where
+        F: FnOnce(&mut rustls::ServerConnection) -> io::Result<usize>,
+    {
+        let mut tls = self.tls.borrow_mut();
+        write_tls(&mut tls, &self.conn)?;
+
+        let n = write(&mut tls)?;
+        match write_tls(&mut tls, &self.conn) {
+            Ok(()) => Ok(n),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl io::Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_plain(buf)
+    }
+}
+
+impl io::Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_plain(|tls| tls.writer().write(buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.write_plain(|tls| tls.writer().write_vectored(bufs))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        write_tls(&mut self.tls.borrow_mut(), &self.conn)
+    }
+}
+
+impl mio::event::Source for TlsStream {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.conn.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.conn.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        self.conn.deregister(registry)
+    }
+}
+
+/// Load certificate chain from `cert` and private key from `key`, both PEM files,
+/// and return the server configuration for TLS listeners.
+pub fn server_config(
+    cert: &path::Path,
+    key: &path::Path,
+) -> Result<Arc<rustls::ServerConfig>> {
+    let certs: Vec<CertificateDer<'static>> = err!(
+        InvalidInput,
+        try: CertificateDer::pem_file_iter(cert).and_then(|iter| iter.collect()),
+        "tls certificate {:?}",
+        cert
+    )?;
+    if certs.is_empty() {
+        err!(InvalidInput, desc: "tls certificate missing in {:?}", cert)?;
+    }
+    let key = err!(
+        InvalidInput,
+        try: PrivateKeyDer::from_pem_file(key),
+        "tls private key {:?}",
+        key
+    )?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = err!(
+        InvalidInput,
+        try: rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions(),
+        "tls protocol versions"
+    )?;
+    let config = err!(
+        InvalidInput,
+        try: builder.with_no_client_auth().with_single_cert(certs, key),
+        "tls certificate {:?}",
+        cert
+    )?;
+
+    Ok(Arc::new(config))
+}
+
+// Write encrypted records, if any, return WouldBlock if they are not written in full.
+fn write_tls(
+    tls: &mut rustls::ServerConnection,
+    mut conn: &mio::net::TcpStream,
+) -> io::Result<()> {
+    while tls.wants_write() {
+        if tls.write_tls(&mut conn)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::WriteZero));
+        }
+    }
+    Ok(())
+}
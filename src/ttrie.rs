@@ -71,6 +71,16 @@ impl SubscribedTrie {
 
         matches
     }
+
+    /// Return all subscriptions in the trie, as of this call. Concurrent updates
+    /// are not blocked, and not visible in the returned list.
+    pub fn to_values(&self) -> Vec<Subscription> {
+        let root = Arc::clone(&self.inner.read().root);
+
+        let mut acc = vec![];
+        root.collect_values(&mut acc);
+        acc
+    }
 }
 
 impl SubscribedTrie {
@@ -161,7 +171,8 @@ impl RetainedTrie {
         self.do_remove(key)
     }
 
-    pub fn match_topic_filter<'b, K>(&self, key: &'b K) -> Option<v5::Publish>
+    /// Return all retained messages whose topic-name match the topic-filter `key`.
+    pub fn match_topic_filter<'b, K>(&self, key: &'b K) -> Vec<v5::Publish>
     where
         K: IterTopicPath<'b>,
     {
@@ -174,20 +185,27 @@ impl RetainedTrie {
 
         stats.lookups = stats.lookups.saturating_add(1);
 
-        let res = match root.match_topic(in_levels, false) {
-            Some(mut vals) => {
-                assert!(vals.len() == 1);
-                stats.hits = stats.hits.saturating_add(1);
-                Some(vals.remove(0))
-            }
-            None => None,
-        };
+        let mut res = vec![];
+        root.match_filter(in_levels, &mut res);
+        if !res.is_empty() {
+            stats.hits = stats.hits.saturating_add(1);
+        }
 
         let inner = Inner { stats, root: Arc::clone(&root) };
         *self.inner.write() = Arc::new(inner);
 
         res
     }
+
+    /// Return all retained messages in the trie, as of this call. Concurrent updates
+    /// are not blocked, and not visible in the returned list.
+    pub fn to_values(&self) -> Vec<v5::Publish> {
+        let root = Arc::clone(&self.inner.read().root);
+
+        let mut acc = vec![];
+        root.collect_values(&mut acc);
+        acc
+    }
 }
 
 impl RetainedTrie {
@@ -558,6 +576,75 @@ impl<V> Node<V> {
     }
 }
 
+impl<V> Node<V> {
+    // Match topic-filter levels, `in_levels`, against the topic-names in this trie,
+    // used for retained-messages where the trie is keyed by topic-name.
+    fn match_filter<'a, I>(&self, mut in_levels: I, acc: &mut Vec<V>)
+    where
+        I: Iterator<Item = &'a str> + Clone,
+        V: Clone,
+    {
+        let in_level = match in_levels.next() {
+            Some(in_level) => in_level,
+            None => {
+                if let Node::Child { values, .. } = self {
+                    acc.extend(values.iter().cloned())
+                }
+                return;
+            }
+        };
+
+        let children = match self {
+            Node::Root { children } => children,
+            Node::Child { children, .. } => children,
+        };
+        let is_root = matches!(self, Node::Root { .. });
+
+        match in_level {
+            "#" | "+" => {
+                // MQTT Spec. 4.7: The Server MUST NOT match Topic Filters starting
+                // with a wildcard character (# or +) with Topic Names beginning with
+                // a $ character.
+                let children = children
+                    .iter()
+                    .filter(|child| !(is_root && child.as_name().starts_with('$')));
+                for child in children {
+                    if in_level == "#" {
+                        child.collect_values(acc);
+                    } else {
+                        child.match_filter(in_levels.clone(), acc);
+                    }
+                }
+                // "#" also matches the parent level.
+                if let ("#", Node::Child { values, .. }) = (in_level, self) {
+                    acc.extend(values.iter().cloned())
+                }
+            }
+            in_level => {
+                if let Ok(off) = children.binary_search_by_key(&in_level, |n| n.as_name())
+                {
+                    children[off].match_filter(in_levels, acc)
+                }
+            }
+        }
+    }
+
+    fn collect_values(&self, acc: &mut Vec<V>)
+    where
+        V: Clone,
+    {
+        match self {
+            Node::Root { children } => {
+                children.iter().for_each(|child| child.collect_values(acc))
+            }
+            Node::Child { children, values, .. } => {
+                acc.extend(values.iter().cloned());
+                children.iter().for_each(|child| child.collect_values(acc))
+            }
+        }
+    }
+}
+
 // (level_match, multi_level_match)
 // input key must have be already validated !!
 fn match_level(in_lvl: &str, trie_level: &str) -> (bool, bool) {
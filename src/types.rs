@@ -1,9 +1,10 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
-#[cfg(any(feature = "fuzzy", test))]
-use std::result;
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
-use std::ops::{Deref, DerefMut};
+use std::ops::{self, Deref, DerefMut};
+use std::{fmt, result, sync::Arc};
 
 use crate::util::{self, advance};
 use crate::{Error, ErrorKind, ReasonCode, Result};
@@ -20,6 +21,7 @@ use crate::{IterTopicPath, Packetize};
 
 /// Enumeration of different MQTT Protocol version.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 pub enum MqttProtocol {
     V4 = 4,
@@ -71,8 +73,87 @@ impl AsRef<[u8]> for Blob {
     }
 }
 
+/// Type implement a shared, reference counted, read-only byte-buffer.
+///
+/// Cloning and slicing a Bytes value does not copy the underlying buffer, used for
+/// PUBLISH payload so that fan-out to subscribers share the same bytes received from
+/// the publisher.
+#[derive(Clone, Default)]
+pub struct Bytes {
+    data: Arc<Vec<u8>>,
+    start: usize,
+    end: usize,
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self.deref()
+    }
+}
+
+impl PartialEq for Bytes {
+    fn eq(&self, other: &Bytes) -> bool {
+        self.deref() == other.deref()
+    }
+}
+
+impl Eq for Bytes {}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        self.deref().fmt(f)
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Bytes {
+        let end = data.len();
+        Bytes { data: Arc::new(data), start: 0, end }
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(data: &[u8]) -> Bytes {
+        Bytes::from(data.to_vec())
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(data: String) -> Bytes {
+        Bytes::from(data.into_bytes())
+    }
+}
+
+impl Bytes {
+    /// Return a new Bytes value for `range` within this value, sharing the
+    /// underlying buffer. Panics if `range` is out of bounds.
+    pub fn slice(&self, range: ops::Range<usize>) -> Bytes {
+        assert!(range.start <= range.end && range.end <= self.len());
+        Bytes {
+            data: Arc::clone(&self.data),
+            start: self.start + range.start,
+            end: self.start + range.end,
+        }
+    }
+
+    /// Return the number of values sharing the underlying buffer.
+    pub fn strong_count(&self) -> usize {
+        Arc::strong_count(&self.data)
+    }
+}
+
 /// Type client-id implements a unique ID defined by MQTT specification.
 #[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct ClientID(pub String);
 
 impl Deref for ClientID {
@@ -115,8 +196,25 @@ impl ClientID {
     }
 }
 
+impl Packetize for ClientID {
+    fn decode<T: AsRef<[u8]>>(stream: T) -> Result<(Self, usize)> {
+        let (val, n) = String::decode(stream)?;
+        Ok((ClientID(val), n))
+    }
+
+    fn encode(&self) -> Result<Blob> {
+        self.0.encode()
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        self.0.encoded_len()
+    }
+}
+
 /// Type implement topic-name defined by MQTT specification.
 #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct TopicName(String);
 
 impl Deref for TopicName {
@@ -167,12 +265,10 @@ impl<'a> Arbitrary<'a> for TopicName {
             levels.join("/").chars().filter(|ch| !matches!(ch, '#' | '+' | '\u{0}')),
         );
 
-        s = loop {
-            if s.len() > 0 {
-                break s;
-            }
-            s = uns.choose(&string_choice)?.to_string();
-        };
+        // choose from non-empty strings, exhausted `uns` shall keep choosing the first.
+        if s.len() == 0 {
+            s = uns.choose(&string_choice[1..])?.to_string();
+        }
 
         Ok(s.into())
     }
@@ -193,6 +289,10 @@ impl Packetize for TopicName {
         self.validate()?;
         self.0.encode()
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        self.0.encoded_len()
+    }
 }
 
 impl<'a> IterTopicPath<'a> for TopicName {
@@ -220,6 +320,8 @@ impl TopicName {
 
 /// Type implement topic-filter defined by MQTT specification.
 #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct TopicFilter(String);
 
 impl Deref for TopicFilter {
@@ -257,6 +359,10 @@ impl Packetize for TopicFilter {
         self.validate()?;
         self.0.encode()
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        self.0.encoded_len()
+    }
 }
 
 impl<'a> IterTopicPath<'a> for TopicFilter {
@@ -299,12 +405,10 @@ impl<'a> Arbitrary<'a> for TopicFilter {
             levels.join("/").chars().filter(|ch| !matches!(ch, '\u{0}')),
         );
 
-        s = loop {
-            if s.len() > 0 {
-                break s;
-            }
-            s = uns.choose(&string_choice)?.to_string();
-        };
+        // choose from non-empty strings, exhausted `uns` shall keep choosing the first.
+        if s.len() == 0 {
+            s = uns.choose(&string_choice[1..])?.to_string();
+        }
 
         Ok(s.into())
     }
@@ -345,6 +449,8 @@ impl TopicFilter {
 /// o/p u32   : 0bwww_wwww_zzz_zzzz_yyy_yyyy_xxx_xxxx
 /// ```
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct VarU32(pub u32);
 
 #[cfg(any(feature = "fuzzy", test))]
@@ -412,6 +518,16 @@ impl Packetize for VarU32 {
 
         Ok(Blob::Small { data, size })
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        match self.0 {
+            val if val < 128 => Ok(1),
+            val if val < 16_384 => Ok(2),
+            val if val < 2_097_152 => Ok(3),
+            val if val <= *VarU32::MAX => Ok(4),
+            val => err!(ProtocolError, desc: "VarU32::encode({})", val),
+        }
+    }
 }
 
 impl VarU32 {
@@ -468,6 +584,10 @@ impl Packetize for UserProperty {
             Ok(Blob::Large { data })
         }
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        Ok(self.0.encoded_len()? + self.1.encoded_len()?)
+    }
 }
 
 impl Packetize for u8 {
@@ -489,6 +609,10 @@ impl Packetize for u8 {
 
         Ok(blob)
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        Ok(1)
+    }
 }
 
 impl Packetize for u16 {
@@ -510,6 +634,10 @@ impl Packetize for u16 {
 
         Ok(blob)
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        Ok(2)
+    }
 }
 
 impl Packetize for u32 {
@@ -531,6 +659,10 @@ impl Packetize for u32 {
 
         Ok(blob)
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        Ok(4)
+    }
 }
 
 impl Packetize for String {
@@ -581,6 +713,15 @@ impl Packetize for String {
             }
         }
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        match self.len() {
+            n if n > (u16::MAX as usize) => {
+                err!(ProtocolError, desc: "String::encode too large {:?}", n)
+            }
+            n => Ok(2 + n),
+        }
+    }
 }
 
 impl Packetize for Vec<u8> {
@@ -608,4 +749,13 @@ impl Packetize for Vec<u8> {
             }
         }
     }
+
+    fn encoded_len(&self) -> Result<usize> {
+        match self.len() {
+            n if n > (u16::MAX as usize) => {
+                err!(ProtocolError, desc: "Vector::encode({})", n)
+            }
+            n => Ok(2 + n),
+        }
+    }
 }
@@ -1,3 +1,5 @@
+use std::net;
+
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
 // TODO: validate whether this is what the specification means.
@@ -33,6 +35,20 @@ pub fn ceil_power_of_2(n: u32) -> u64 {
     }
 }
 
+/// Return 128-bit cityhash of `data` folded into 32-bits. Used for hashing keys that
+/// must map the same on every node and every build, like ClientID and shard numbers.
+pub fn cityhash_u32(data: &[u8]) -> u32 {
+    let hash = cityhash_rs::cityhash_110_128(data);
+    let hash = (hash & 0xFFFFFFFFFFFFFFFF) ^ ((hash >> 64) & 0xFFFFFFFFFFFFFFFF);
+    ((hash & 0xFFFFFFFF) ^ ((hash >> 32) & 0xFFFFFFFF)) as u32
+}
+
+/// Return client address with IPv4-mapped IPv6 address, as accepted on dual-stack
+/// listeners, converted to plain IPv4 address.
+pub fn canonical_addr(addr: net::SocketAddr) -> net::SocketAddr {
+    net::SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
 #[cfg(test)]
 #[path = "util_test.rs"]
 mod util_test;
@@ -59,3 +59,15 @@ fn test_ceil_power_of_2() {
         }
     }
 }
+
+#[test]
+fn test_canonical_addr() {
+    let addr: net::SocketAddr = "[::ffff:10.1.2.3]:1883".parse().unwrap();
+    assert_eq!(canonical_addr(addr), "10.1.2.3:1883".parse().unwrap());
+
+    let addr: net::SocketAddr = "[2001:db8::1]:1883".parse().unwrap();
+    assert_eq!(canonical_addr(addr), addr);
+
+    let addr: net::SocketAddr = "10.1.2.3:1883".parse().unwrap();
+    assert_eq!(canonical_addr(addr), addr);
+}
@@ -1,10 +1,13 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(any(feature = "fuzzy", test))]
 use std::result;
 
-use crate::v5::{FixedHeader, PacketType, Property, PropertyType};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType};
 use crate::{util::advance, Blob, Packetize, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -13,6 +16,7 @@ const PP: &'static str = "Packet::Auth";
 /// Error codes allowed in AUTH packet.
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum AuthReasonCode {
     Success = 0x00,
@@ -28,13 +32,16 @@ impl TryFrom<u8> for AuthReasonCode {
             0x00 => Ok(AuthReasonCode::Success),
             0x18 => Ok(AuthReasonCode::ContinueAuthentication),
             0x19 => Ok(AuthReasonCode::ReAuthenticate),
-            val => err!(ProtocolError, code: ProtocolError, "{} reason-code {}", PP, val),
+            val => {
+                err!(MalformedPacket, code: MalformedPacket, "{} reason-code {}", PP, val)
+            }
         }
     }
 }
 
 /// AUTH packet
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct Auth {
     pub code: AuthReasonCode,
     pub properties: Option<AuthProperties>,
@@ -75,36 +82,68 @@ impl Packetize for Auth {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_fixed_header;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
-        data.extend_from_slice((self.code as u8).encode()?.as_ref());
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
+
+        packet_len(self.remaining_len()?)
+    }
+}
+
+impl Auth {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new(PacketType::Auth, remlen)?.encode_into(data)?;
+        (self.code as u8).encode_into(data)?;
         if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
+            properties.encode_into(data)?;
         } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
+            VarU32(0).encode_into(data)?;
         }
 
-        let fh = FixedHeader::new(PacketType::Auth, VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        Ok(1 + props)
     }
-}
 
-impl Auth {
     fn validate(&self) -> Result<()> {
+        // authentication-method is mandatory for continue/re-authenticate.
+        if self.code != AuthReasonCode::Success && self.properties.is_none() {
+            err!(ProtocolError, code: ProtocolError, "{} missing auth-method", PP)?
+        }
+
         Ok(())
     }
 }
 
 /// Collection of MQTT properties allowed in AUTH packet
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct AuthProperties {
     /// Property::AuthenticationMethod
     pub authentication_method: String,
     /// Property::AuthenticationData
+    #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::bytes"))]
     pub authentication_data: Vec<u8>,
     /// Property::ReasonString
     pub reason_string: Option<String>,
@@ -145,7 +184,7 @@ impl Packetize for AuthProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check = PropertyCheck::new(PP, PropertyScope::Packet(PacketType::Auth));
         let mut props = AuthProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -158,10 +197,7 @@ impl Packetize for AuthProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 AuthenticationMethod(val) => authentication_method = Some(val),
@@ -169,7 +205,7 @@ impl Packetize for AuthProperties {
                 ReasonString(val) => props.reason_string = Some(val),
                 UserProp(val) => props.user_properties.push(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop {:?}", PP, pt)?
                 }
             };
         }
@@ -189,10 +225,15 @@ impl Packetize for AuthProperties {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(data, AuthenticationMethod, self.authentication_method);
         enc_prop!(data, AuthenticationData, &self.authentication_data);
         enc_prop!(opt: data, ReasonString, &self.reason_string);
@@ -201,8 +242,29 @@ impl Packetize for AuthProperties {
             enc_prop!(data, UserProp, uprop)
         }
 
-        let data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
 
-        Ok(Blob::Large { data })
+        property_len(self.props_len()?)
+    }
+}
+
+impl AuthProperties {
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(n, AuthenticationMethod, self.authentication_method);
+        prop_len!(n, AuthenticationData, &self.authentication_data);
+        prop_len!(opt: n, ReasonString, &self.reason_string);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop)
+        }
+
+        Ok(n)
     }
 }
@@ -1,7 +1,11 @@
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
+
 use std::ops::{Deref, DerefMut};
 
 use crate::util::advance;
-use crate::v5::{FixedHeader, Property, PropertyType, QoS};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType, QoS};
 use crate::{Blob, Packetize, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -9,6 +13,8 @@ const PP: &'static str = "Packet::ConnAck";
 
 /// Flags carried in CONNACK packet.
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct ConnackFlags(pub u8);
 
 impl Deref for ConnackFlags {
@@ -37,14 +43,13 @@ impl Packetize for ConnackFlags {
 
         let (flags, n) = dec_field!(u8, stream, 0);
         let flags = ConnackFlags(flags);
-        flags.unwrap()?;
 
         flags.validate()?;
         Ok((flags, n))
     }
 
     fn encode(&self) -> Result<Blob> {
-        self.unwrap()?;
+        self.validate()?;
         self.0.encode()
     }
 }
@@ -58,20 +63,22 @@ impl ConnackFlags {
 
     /// Return `session_present` flag.
     pub fn unwrap(&self) -> Result<bool> {
-        if (self.0 & 0b_1111_1110) > 0 {
-            err!(MalformedPacket, code: MalformedPacket, "{} flags {:?}", PP, self.0)?;
-        }
-
+        self.validate()?;
         Ok(self.0 & (*Self::SESSION_PRESENT) > 0)
     }
 
     fn validate(&self) -> Result<()> {
+        if (self.0 & 0b_1111_1110) > 0 {
+            err!(MalformedPacket, code: MalformedPacket, "{} flags {:?}", PP, self.0)?;
+        }
+
         Ok(())
     }
 }
 
 /// Error codes allowed in CONNACK packet.
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum ConnackReasonCode {
     Success = 0x00,
@@ -134,6 +141,7 @@ impl TryFrom<u8> for ConnackReasonCode {
 
 /// CONNACK packet
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct ConnAck {
     pub flags: ConnackFlags,
     pub code: ConnackReasonCode,
@@ -141,6 +149,34 @@ pub struct ConnAck {
 }
 
 impl ConnAck {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new(PacketType::ConnAck, remlen)?.encode_into(data)?;
+        self.flags.0.encode_into(data)?;
+        (self.code as u8).encode_into(data)?;
+        if let Some(properties) = &self.properties {
+            properties.encode_unchecked_into(data)?;
+        } else {
+            VarU32(0).encode_into(data)?;
+        }
+
+        Ok(data.len() - start)
+    }
+
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        // flags and reason-code.
+        Ok(1 + 1 + props)
+    }
+
     pub fn new_success(ps: Option<ConnAckProperties>) -> ConnAck {
         ConnAck {
             flags: ConnackFlags::default(),
@@ -178,33 +214,46 @@ impl Packetize for ConnAck {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::{insert_fixed_header, PacketType};
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
-        data.extend_from_slice((*self.flags).encode()?.as_ref());
-        data.extend_from_slice((self.code as u8).encode()?.as_ref());
-        if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
-        } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
-        }
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
-        let fh = FixedHeader::new(PacketType::ConnAck, VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
 
-        Ok(Blob::Large { data })
+        packet_len(self.remaining_len()?)
     }
 }
 
 impl ConnAck {
     fn validate(&self) -> Result<()> {
+        self.flags.validate()?;
+        if let Some(properties) = &self.properties {
+            properties.validate()?;
+        }
+        if self.code != ConnackReasonCode::Success && (*self.flags & 0b_0000_0001) > 0 {
+            err!(
+                ProtocolError,
+                code: ProtocolError,
+                "{} session_present for {:?}",
+                PP,
+                self.code
+            )?
+        }
+
         Ok(())
     }
 }
 
 /// Collection of MQTT properties allowed in CONNACK packet
 #[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct ConnAckProperties {
     pub session_expiry_interval: Option<u32>,
     pub receive_maximum: Option<u16>,
@@ -221,6 +270,7 @@ pub struct ConnAckProperties {
     pub response_information: Option<String>,
     pub server_reference: Option<String>,
     pub authentication_method: Option<String>,
+    #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::opt_bytes"))]
     pub authentication_data: Option<Vec<u8>>,
     pub user_properties: Vec<UserProperty>,
 }
@@ -231,7 +281,8 @@ impl Packetize for ConnAckProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check =
+            PropertyCheck::new(PP, PropertyScope::Packet(PacketType::ConnAck));
         let mut props = ConnAckProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -242,16 +293,10 @@ impl Packetize for ConnAckProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 SessionExpiryInterval(val) => props.session_expiry_interval = Some(val),
-                ReceiveMaximum(0) => {
-                    err!(ProtocolError, code: ProtocolError, "{} receive_maximum:0", PP)?;
-                }
                 ReceiveMaximum(val) => props.receive_maximum = Some(val),
                 MaximumQoS(val) => props.maximum_qos = Some(val),
                 RetainAvailable(0) => props.retain_available = Some(false),
@@ -262,9 +307,6 @@ impl Packetize for ConnAckProperties {
                     "retain-available invalid {:?}",
                     val
                 )?,
-                MaximumPacketSize(0) => {
-                    err!(ProtocolError, code: ProtocolError, "{} max_packet_size:0", PP)?;
-                }
                 MaximumPacketSize(val) => props.max_packet_size = Some(val),
                 AssignedClientIdentifier(val) => {
                     props.assigned_client_identifier = Some(val);
@@ -314,19 +356,40 @@ impl Packetize for ConnAckProperties {
                 AuthenticationMethod(val) => props.authentication_method = Some(val),
                 AuthenticationData(val) => props.authentication_data = Some(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop {:?}", PP, pt)?
                 }
             };
         }
 
+        props.validate()?;
         Ok((props, n))
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
+    }
+}
+
+impl ConnAckProperties {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, SessionExpiryInterval, self.session_expiry_interval);
         enc_prop!(opt: data, ReceiveMaximum, self.receive_maximum);
         match &self.maximum_qos {
@@ -363,17 +426,70 @@ impl Packetize for ConnAckProperties {
             enc_prop!(data, UserProp, uprop)
         }
 
-        let data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
+
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
 
-        Ok(Blob::Large { data })
+        prop_len!(opt: n, SessionExpiryInterval, self.session_expiry_interval);
+        prop_len!(opt: n, ReceiveMaximum, self.receive_maximum);
+        if let Some(val) = &self.maximum_qos {
+            prop_len!(n, MaximumQoS, u8::from(*val));
+        }
+        if let Some(val) = self.retain_available {
+            let val: u8 = if val { 1 } else { 0 };
+            prop_len!(n, RetainAvailable, val);
+        }
+        prop_len!(opt: n, MaximumPacketSize, self.max_packet_size);
+        prop_len!(opt: n, AssignedClientIdentifier, &self.assigned_client_identifier);
+        prop_len!(opt: n, TopicAliasMaximum, self.topic_alias_max);
+        prop_len!(opt: n, ReasonString, &self.reason_string);
+        if let Some(val) = self.wildcard_subscription_available {
+            let val: u8 = if val { 1 } else { 0 };
+            prop_len!(n, WildcardSubscriptionAvailable, val);
+        }
+        if let Some(val) = self.subscription_identifiers_available {
+            let val: u8 = if val { 1 } else { 0 };
+            prop_len!(n, SubscriptionIdentifierAvailable, val);
+        }
+        if let Some(val) = self.shared_subscription_available {
+            let val: u8 = if val { 1 } else { 0 };
+            prop_len!(n, SharedSubscriptionAvailable, val);
+        }
+        prop_len!(opt: n, ServerKeepAlive, self.server_keep_alive);
+        prop_len!(opt: n, ResponseInformation, &self.response_information);
+        prop_len!(opt: n, ServerReference, &self.server_reference);
+        prop_len!(opt: n, AuthenticationMethod, &self.authentication_method);
+        prop_len!(opt: n, AuthenticationData, &self.authentication_data);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop)
+        }
+
+        Ok(n)
     }
-}
 
-impl ConnAckProperties {
     pub const RECEIVE_MAXIMUM: u16 = 65_535_u16;
     pub const MAXIMUM_QOS: QoS = QoS::ExactlyOnce;
     pub const TOPIC_ALIAS_MAXIMUM: u16 = 0_u16;
 
+    fn validate(&self) -> Result<()> {
+        use crate::v5::validate::check_non_zero;
+
+        check_non_zero(PP, PropertyType::ReceiveMaximum, self.receive_maximum)?;
+        check_non_zero(PP, PropertyType::MaximumPacketSize, self.max_packet_size)?;
+        if let Some(QoS::ExactlyOnce) = self.maximum_qos {
+            err!(ProtocolError, code: ProtocolError, "{} maximum_qos:2", PP)?
+        }
+        if self.authentication_data.is_some() && self.authentication_method.is_none() {
+            err!(ProtocolError, code: ProtocolError, "{} auth-data without method", PP)?
+        }
+
+        Ok(())
+    }
+
     /// Use this method to confirm to MQTT specification's default.
     /// DEFAULT: [Self::MAXIMUM_QOS]
     pub fn maximum_qos(&self) -> QoS {
@@ -1,5 +1,7 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 use std::ops::{Deref, DerefMut};
 
@@ -7,7 +9,8 @@ use std::ops::{Deref, DerefMut};
 use std::result;
 
 use crate::util::advance;
-use crate::v5::{FixedHeader, PayloadFormat, Property, PropertyType, QoS, UserProperty};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{PayloadFormat, Property, PropertyType, QoS, UserProperty};
 use crate::{Blob, ClientID, Config, MqttProtocol, Packetize, TopicName, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -15,6 +18,8 @@ const PP: &'static str = "Packet::Connect";
 
 /// Flags carried by CONNECT packet
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct ConnectFlags(pub u8);
 
 impl Deref for ConnectFlags {
@@ -97,7 +102,7 @@ impl ConnectFlags {
     pub fn unwrap(&self) -> (bool, bool, QoS, bool) {
         let clean_start: bool = (self.0 & Self::CLEAN_START.0) > 0;
         let will_flag: bool = (self.0 & Self::WILL_FLAG.0) > 0;
-        let will_qos: QoS = (self.0 & Self::WILL_QOS_MASK >> 3).try_into().unwrap();
+        let will_qos: QoS = ((self.0 & Self::WILL_QOS_MASK) >> 3).try_into().unwrap();
         let will_retain: bool = (self.0 & Self::WILL_RETAIN.0) > 0;
 
         (clean_start, will_flag, will_qos, will_retain)
@@ -120,12 +125,24 @@ impl ConnectFlags {
             err!(MalformedPacket, code: MalformedPacket, "connect-flag resrvd bit is 1")?;
         }
 
+        QoS::try_from((self.0 & Self::WILL_QOS_MASK) >> 3)?;
+        let will_bits = Self::WILL_QOS_MASK | Self::WILL_RETAIN.0;
+        if !self.is_will_flag() && (self.0 & will_bits) > 0 {
+            err!(
+                MalformedPacket,
+                code: MalformedPacket,
+                "connect-flag will-qos/will-retain without will-flag 0x{:x}",
+                self.0
+            )?;
+        }
+
         Ok(())
     }
 }
 
 /// CONNECT packet
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct Connect {
     pub protocol_name: String,
     pub protocol_version: MqttProtocol,
@@ -229,49 +246,102 @@ impl Packetize for Connect {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::{insert_fixed_header, PacketType};
+        let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
         self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
-        let mut data = Vec::with_capacity(64);
-        data.extend_from_slice(self.protocol_name.encode()?.as_ref());
-        data.extend_from_slice(u8::from(self.protocol_version).encode()?.as_ref());
-        data.extend_from_slice((*self.flags).encode()?.as_ref());
-        data.extend_from_slice(self.keep_alive.encode()?.as_ref());
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
+
+        packet_len(self.remaining_len()?)
+    }
+}
+
+impl Connect {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        use crate::v5::PacketType;
+
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new(PacketType::Connect, remlen)?.encode_into(data)?;
+        self.protocol_name.encode_into(data)?;
+        u8::from(self.protocol_version).encode_into(data)?;
+        (*self.flags).encode_into(data)?;
+        self.keep_alive.encode_into(data)?;
         if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
+            properties.encode_unchecked_into(data)?;
         } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
+            VarU32(0).encode_into(data)?;
         }
 
         // payload
-        data.extend_from_slice((*self.payload.client_id).encode()?.as_ref());
-        if let Some(will_properties) = &self.payload.will_properties {
-            data.extend_from_slice(will_properties.encode()?.as_ref());
-        } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
+        (*self.payload.client_id).encode_into(data)?;
+        match &self.payload.will_properties {
+            Some(will_properties) => {
+                will_properties.encode_into(data)?;
+            }
+            // will-properties are present only when will-flag is set.
+            None if self.flags.is_will_flag() => {
+                VarU32(0).encode_into(data)?;
+            }
+            None => (),
         }
         if let Some(will_topic) = &self.payload.will_topic {
-            data.extend_from_slice(will_topic.encode()?.as_ref());
+            (**will_topic).encode_into(data)?;
         }
         if let Some(will_payload) = &self.payload.will_payload {
-            data.extend_from_slice(will_payload.encode()?.as_ref());
+            will_payload.encode_into(data)?;
         }
         if let Some(user_name) = &self.payload.user_name {
-            data.extend_from_slice(user_name.encode()?.as_ref());
+            user_name.encode_into(data)?;
         }
         if let Some(password) = &self.payload.password {
-            data.extend_from_slice(password.encode()?.as_ref());
+            password.encode_into(data)?;
         }
 
-        let fh = FixedHeader::new(PacketType::Connect, VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        let mut n = self.protocol_name.encoded_len()? + 1 + 1 + 2 + props;
+
+        // payload
+        n += self.payload.client_id.encoded_len()?;
+        match &self.payload.will_properties {
+            Some(will_properties) => n += will_properties.encoded_len()?,
+            None if self.flags.is_will_flag() => n += 1,
+            None => (),
+        }
+        if let Some(will_topic) = &self.payload.will_topic {
+            n += will_topic.encoded_len()?;
+        }
+        if let Some(will_payload) = &self.payload.will_payload {
+            n += will_payload.encoded_len()?;
+        }
+        if let Some(user_name) = &self.payload.user_name {
+            n += user_name.encoded_len()?;
+        }
+        if let Some(password) = &self.payload.password {
+            n += password.encoded_len()?;
+        }
+
+        Ok(n)
     }
-}
 
-impl Connect {
     fn validate(&self) -> Result<()> {
         if self.protocol_name != "MQTT" {
             err!(
@@ -293,11 +363,17 @@ impl Connect {
         };
 
         self.flags.validate()?;
+        if let Some(properties) = &self.properties {
+            properties.validate()?;
+        }
 
-        let flags = *self.flags;
-        QoS::try_from((flags & ConnectFlags::WILL_QOS_MASK) >> 3)?;
-        if (flags & *ConnectFlags::WILL_FLAG) > 0 {
-            // NOTE: Spec says that properites and payload MUST be specified
+        let pld = &self.payload;
+        if let Some(will_topic) = &pld.will_topic {
+            will_topic.validate()?;
+        }
+        if self.flags.is_will_flag() {
+            // NOTE: Spec says that properites and payload MUST be specified, zero
+            // length will-properties are decoded as None.
             if self.payload.will_topic.is_none() {
                 err!(
                     MalformedPacket,
@@ -305,13 +381,6 @@ impl Connect {
                     "{} missing will-topic",
                     PP
                 )?;
-            } else if self.payload.will_properties.is_none() {
-                err!(
-                    MalformedPacket,
-                    code: MalformedPacket,
-                    "{} missing will-properties",
-                    PP
-                )?;
             } else if self.payload.will_payload.is_none() {
                 err!(
                     MalformedPacket,
@@ -320,9 +389,19 @@ impl Connect {
                     PP
                 )?;
             }
+        } else if pld.will_properties.is_some()
+            || pld.will_topic.is_some()
+            || pld.will_payload.is_some()
+        {
+            err!(MalformedPacket, code: MalformedPacket, "{} will without will-flag", PP)?;
+        }
+        if self.flags.is_username() != pld.user_name.is_some() {
+            err!(MalformedPacket, code: MalformedPacket, "{} user-name mismatch", PP)?;
+        }
+        if self.flags.is_password() != pld.password.is_some() {
+            err!(MalformedPacket, code: MalformedPacket, "{} password mismatch", PP)?;
         }
 
-        let pld = &self.payload;
         if let Some(true) = pld.will_properties.as_ref().map(|p| p.is_utf8()) {
             if let Err(err) = std::str::from_utf8(pld.will_payload.as_ref().unwrap()) {
                 err!(
@@ -369,6 +448,7 @@ impl Connect {
 
 /// Collection of MQTT properties allowed in CONNECT packet
 #[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct ConnectProperties {
     pub session_expiry_interval: Option<u32>, // 0=disable, 0xFFFFFFFF=indefinite
     pub receive_maximum: Option<u16>,         // default=65535, can't be ZERO
@@ -377,6 +457,7 @@ pub struct ConnectProperties {
     pub request_response_info: Option<bool>,
     pub request_problem_info: Option<bool>,
     pub authentication_method: Option<String>,
+    #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::opt_bytes"))]
     pub authentication_data: Option<Vec<u8>>,
     pub user_properties: Vec<UserProperty>,
 }
@@ -416,7 +497,8 @@ impl Packetize for ConnectProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check =
+            PropertyCheck::new(PP, PropertyScope::Packet(PacketType::Connect));
         let mut props = ConnectProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -427,20 +509,11 @@ impl Packetize for ConnectProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 SessionExpiryInterval(val) => props.session_expiry_interval = Some(val),
-                ReceiveMaximum(0) => {
-                    err!(ProtocolError, code: ProtocolError, "{} receive_maximum:0", PP)?;
-                }
                 ReceiveMaximum(val) => props.receive_maximum = Some(val),
-                MaximumPacketSize(0) => {
-                    err!(ProtocolError, code: ProtocolError, "{} max_packet_size:0", PP)?;
-                }
                 MaximumPacketSize(val) => props.max_packet_size = Some(val),
                 TopicAliasMaximum(val) => props.topic_alias_max = Some(val),
                 RequestResponseInformation(0) => {
@@ -467,19 +540,40 @@ impl Packetize for ConnectProperties {
                 AuthenticationMethod(val) => props.authentication_method = Some(val),
                 AuthenticationData(val) => props.authentication_data = Some(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop {:?}", PP, pt)?
                 }
             }
         }
 
+        props.validate()?;
         Ok((props, n))
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
 
+        property_len(self.props_len()?)
+    }
+}
+
+impl ConnectProperties {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, SessionExpiryInterval, self.session_expiry_interval);
         enc_prop!(opt: data, ReceiveMaximum, self.receive_maximum);
         enc_prop!(opt: data, MaximumPacketSize, self.max_packet_size);
@@ -493,22 +587,56 @@ impl Packetize for ConnectProperties {
             enc_prop!(data, RequestProblemInformation, val);
         }
         enc_prop!(opt: data, AuthenticationMethod, &self.authentication_method);
-        enc_prop!(opt: data, AuthenticationData, &self.authentication_method);
+        enc_prop!(opt: data, AuthenticationData, &self.authentication_data);
 
         for uprop in self.user_properties.iter() {
             enc_prop!(data, UserProp, uprop)
         }
 
-        data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(opt: n, SessionExpiryInterval, self.session_expiry_interval);
+        prop_len!(opt: n, ReceiveMaximum, self.receive_maximum);
+        prop_len!(opt: n, MaximumPacketSize, self.max_packet_size);
+        prop_len!(opt: n, TopicAliasMaximum, self.topic_alias_max);
+        if let Some(val) = self.request_response_info {
+            let val: u8 = if val { 1 } else { 0 };
+            prop_len!(n, RequestResponseInformation, val);
+        }
+        if let Some(val) = self.request_problem_info {
+            let val: u8 = if val { 1 } else { 0 };
+            prop_len!(n, RequestProblemInformation, val);
+        }
+        prop_len!(opt: n, AuthenticationMethod, &self.authentication_method);
+        prop_len!(opt: n, AuthenticationData, &self.authentication_data);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop)
+        }
+
+        Ok(n)
     }
-}
 
-impl ConnectProperties {
     pub const RECEIVE_MAXIMUM: u16 = 65535;
     pub const TOPIC_ALIAS_MAXIMUM: u16 = 0;
 
+    fn validate(&self) -> Result<()> {
+        use crate::v5::validate::check_non_zero;
+
+        check_non_zero(PP, PropertyType::ReceiveMaximum, self.receive_maximum)?;
+        check_non_zero(PP, PropertyType::MaximumPacketSize, self.max_packet_size)?;
+        if self.authentication_data.is_some() && self.authentication_method.is_none() {
+            err!(ProtocolError, code: ProtocolError, "{} auth-data without method", PP)?
+        }
+
+        Ok(())
+    }
+
     pub fn session_expiry_interval(&self) -> Option<u32> {
         self.session_expiry_interval
     }
@@ -539,12 +667,14 @@ impl ConnectProperties {
 
 /// Will Property carried in [ConnectPayload]
 #[derive(Clone, Default, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct WillProperties {
     pub will_delay_interval: Option<u32>,
     pub payload_format_indicator: PayloadFormat, // default=PayloadFormat::Binary
     pub message_expiry_interval: Option<u32>,
     pub content_type: Option<String>,
     pub response_topic: Option<TopicName>,
+    #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::opt_bytes"))]
     pub correlation_data: Option<Vec<u8>>,
     pub user_properties: Vec<UserProperty>,
 }
@@ -583,7 +713,7 @@ impl Packetize for WillProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check = PropertyCheck::new(PP, PropertyScope::Will);
         let mut wps = WillProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -594,16 +724,7 @@ impl Packetize for WillProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(
-                    ProtocolError,
-                    code: ProtocolError,
-                    "{} repeat prop in will-message {:?}",
-                    PP,
-                    pt
-                )?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 WillDelayInterval(val) => wps.will_delay_interval = Some(val),
@@ -616,8 +737,8 @@ impl Packetize for WillProperties {
                 CorrelationData(val) => wps.correlation_data = Some(val),
                 UserProp(val) => wps.user_properties.push(val),
                 _ => err!(
-                    ProtocolError,
-                    code: ProtocolError,
+                    MalformedPacket,
+                    code: MalformedPacket,
                     "{} bad prop in will-message {:?}",
                     PP,
                     pt
@@ -629,10 +750,15 @@ impl Packetize for WillProperties {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
 
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, WillDelayInterval, self.will_delay_interval);
         if self.payload_format_indicator.is_utf8() {
             let val = u8::from(PayloadFormat::Utf8);
@@ -647,13 +773,38 @@ impl Packetize for WillProperties {
             enc_prop!(data, UserProp, uprop);
         }
 
-        let data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
     }
 }
 
 impl WillProperties {
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(opt: n, WillDelayInterval, self.will_delay_interval);
+        if self.payload_format_indicator.is_utf8() {
+            let val = u8::from(PayloadFormat::Utf8);
+            prop_len!(n, PayloadFormatIndicator, val);
+        }
+        prop_len!(opt: n, MessageExpiryInterval, self.message_expiry_interval);
+        prop_len!(opt: n, ContentType, &self.content_type);
+        prop_len!(opt: n, ResponseTopic, &self.response_topic);
+        prop_len!(opt: n, CorrelationData, &self.correlation_data);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop);
+        }
+
+        Ok(n)
+    }
+
     pub const WILL_DELAY_INTERVAL: u32 = 0;
 
     pub fn will_delay_interval(&self) -> u32 {
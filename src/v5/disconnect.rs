@@ -1,11 +1,14 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(any(feature = "fuzzy", test))]
 use std::result;
 
 use crate::util::advance;
-use crate::v5::{FixedHeader, Property, PropertyType};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType};
 use crate::{Blob, Packetize, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -15,6 +18,7 @@ const PP: &'static str = "Packet::Disconnect";
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum DisconnReasonCode {
     NormalDisconnect = 0x00,
     DiconnectWillMessage = 0x04,
@@ -25,6 +29,7 @@ pub enum DisconnReasonCode {
     NotAuthorized = 0x87,
     ServerBusy = 0x89,
     ServerShutdown = 0x8B,
+    BadAuthenticationMethod = 0x8C,
     KeepAliveTimeout = 0x8D,
     SessionTakenOver = 0x8E,
     InvalidTopicFilter = 0x8F,
@@ -61,6 +66,7 @@ impl TryFrom<u8> for DisconnReasonCode {
             0x87 => Ok(DisconnReasonCode::NotAuthorized),
             0x89 => Ok(DisconnReasonCode::ServerBusy),
             0x8B => Ok(DisconnReasonCode::ServerShutdown),
+            0x8C => Ok(DisconnReasonCode::BadAuthenticationMethod),
             0x8D => Ok(DisconnReasonCode::KeepAliveTimeout),
             0x8E => Ok(DisconnReasonCode::SessionTakenOver),
             0x8F => Ok(DisconnReasonCode::InvalidTopicFilter),
@@ -96,6 +102,7 @@ impl TryFrom<u8> for DisconnReasonCode {
 
 /// DISCONNECT Packet
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct Disconnect {
     pub code: DisconnReasonCode,
     pub properties: Option<DisconnProperties>,
@@ -114,6 +121,32 @@ impl<'a> Arbitrary<'a> for Disconnect {
 }
 
 impl Disconnect {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new(PacketType::Disconnect, remlen)?.encode_into(data)?;
+        (self.code as u8).encode_into(data)?;
+        if let Some(properties) = &self.properties {
+            properties.encode_into(data)?;
+        } else {
+            VarU32(0).encode_into(data)?;
+        }
+
+        Ok(data.len() - start)
+    }
+
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        Ok(1 + props)
+    }
+
     pub fn new(code: DisconnReasonCode, props: Option<DisconnProperties>) -> Disconnect {
         Disconnect { code, properties: props }
     }
@@ -160,23 +193,20 @@ impl Packetize for Disconnect {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::{insert_fixed_header, PacketType::Disconnect};
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
-        data.extend_from_slice((self.code as u8).encode()?.as_ref());
-        if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
-        } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
-        }
-
-        let fh = FixedHeader::new(Disconnect, VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
-        // println!("Disconnect encoded {:?}", data);
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
 
-        Ok(Blob::Large { data })
+        packet_len(self.remaining_len()?)
     }
 }
 
@@ -188,6 +218,7 @@ impl Disconnect {
 
 /// Collection of MQTT properties allowed in DISCONNECT packet
 #[derive(Clone, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct DisconnProperties {
     pub session_expiry_interval: Option<u32>,
     pub reason_string: Option<String>,
@@ -232,7 +263,8 @@ impl Packetize for DisconnProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check =
+            PropertyCheck::new(PP, PropertyScope::Packet(PacketType::Disconnect));
         let mut props = DisconnProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -243,10 +275,7 @@ impl Packetize for DisconnProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 SessionExpiryInterval(val) => props.session_expiry_interval = Some(val),
@@ -254,7 +283,7 @@ impl Packetize for DisconnProperties {
                 ServerReference(val) => props.server_reference = Some(val),
                 UserProp(val) => props.user_properties.push(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop, {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop, {:?}", PP, pt)?
                 }
             };
         }
@@ -263,10 +292,15 @@ impl Packetize for DisconnProperties {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
 
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, SessionExpiryInterval, self.session_expiry_interval);
         enc_prop!(opt: data, ReasonString, &self.reason_string);
         enc_prop!(opt: data, ServerReference, &self.server_reference);
@@ -275,13 +309,32 @@ impl Packetize for DisconnProperties {
             enc_prop!(data, UserProp, uprop)
         }
 
-        let data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
     }
 }
 
 impl DisconnProperties {
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(opt: n, SessionExpiryInterval, self.session_expiry_interval);
+        prop_len!(opt: n, ReasonString, &self.reason_string);
+        prop_len!(opt: n, ServerReference, &self.server_reference);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop)
+        }
+
+        Ok(n)
+    }
+
     #[cfg(any(feature = "fuzzy", test))]
     pub fn is_empty(&mut self) -> bool {
         self.session_expiry_interval.is_none()
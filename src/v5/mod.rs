@@ -2,6 +2,8 @@
 
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 use std::cmp;
 #[cfg(any(feature = "fuzzy", test))]
@@ -11,9 +13,6 @@ use crate::util::advance;
 use crate::{Blob, ClientID, Packetize, TopicFilter, TopicName, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
-// TODO: review all v5::* code to check error-kind, must either be MalformedPacket or
-//       ProtocolError.
-
 /// MQTT packetization, decode a single field.
 macro_rules! dec_field {
     ($type:ty, $stream:expr, $n:expr; $($pred:tt)*) => {{
@@ -53,8 +52,8 @@ macro_rules! dec_props {
                         (Some(properties), $n + r)
                     } else {
                         err!(
-                            ProtocolError,
-                            code: ProtocolError,
+                            MalformedPacket,
+                            code: MalformedPacket,
                             "property len mismatching {}",
                             r
                         )?
@@ -75,8 +74,8 @@ macro_rules! dec_props {
                     (Some(properties), $n + r)
                 } else {
                     err!(
-                        ProtocolError,
-                        code: ProtocolError,
+                        MalformedPacket,
+                        code: MalformedPacket,
                         "property len mismatching {}",
                         r
                     )?
@@ -104,6 +103,20 @@ macro_rules! enc_prop {
 }
 pub(crate) use enc_prop;
 
+/// Same as [enc_prop], instead of encoding the property add its encoded length to `$n`.
+macro_rules! prop_len {
+    (opt: $n:ident, $varn:ident, $($val:tt)*) => {{
+        if let Some(val) = $($val)* {
+            $n += VarU32(PropertyType::$varn as u32).encoded_len()?;
+            $n += val.encoded_len()?;
+        }
+    }};
+    ($n:ident, $varn:ident, $($val:tt)*) => {{
+        $n += VarU32(PropertyType::$varn as u32).encoded_len()?;
+        $n += ($($val)*).encoded_len()?;
+    }};
+}
+
 mod auth;
 mod connack;
 mod connect;
@@ -115,6 +128,10 @@ mod sub;
 mod suback;
 mod unsub;
 mod unsuback;
+mod validate;
+
+#[cfg(feature = "v5-serde")]
+pub mod serde_util;
 
 pub use auth::{Auth, AuthProperties, AuthReasonCode};
 pub use connack::{ConnAck, ConnAckProperties, ConnackFlags, ConnackReasonCode};
@@ -129,9 +146,10 @@ pub use sub::{Subscribe, SubscribeFilter, SubscribeProperties, SubscriptionOpt};
 pub use suback::{SubAck, SubAckProperties, SubAckReasonCode};
 pub use unsub::{UnSubscribe, UnSubscribeProperties};
 pub use unsuback::{UnsubAck, UnsubAckProperties, UnsubAckReasonCode};
+pub use validate::PropertyScope;
 
 /// Type captures an active subscription by client.
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct Subscription {
     /// Uniquely identifies this subscription for the subscribing client. Within entire
     /// cluster, `(client_id, topic_filter)` is uqniue.
@@ -186,6 +204,7 @@ impl Ord for Subscription {
 /// MQTT packet type
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum PacketType {
     Connect = 1,
     ConnAck = 2,
@@ -255,6 +274,7 @@ impl From<PacketType> for u8 {
 
 /// Enumeration of all possible MQTT packets, its header, fields, properties, payload.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum Packet {
     Connect(Connect),
     ConnAck(ConnAck),
@@ -361,6 +381,46 @@ impl Packetize for Packet {
             Packet::Auth(pkt) => pkt.encode(),
         }
     }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        match self {
+            Packet::Connect(pkt) => pkt.encode_into(data),
+            Packet::ConnAck(pkt) => pkt.encode_into(data),
+            Packet::Publish(pkt) => pkt.encode_into(data),
+            Packet::PubAck(pkt) => pkt.encode_into(data),
+            Packet::PubRec(pkt) => pkt.encode_into(data),
+            Packet::PubRel(pkt) => pkt.encode_into(data),
+            Packet::PubComp(pkt) => pkt.encode_into(data),
+            Packet::Subscribe(pkt) => pkt.encode_into(data),
+            Packet::SubAck(pkt) => pkt.encode_into(data),
+            Packet::UnSubscribe(pkt) => pkt.encode_into(data),
+            Packet::UnsubAck(pkt) => pkt.encode_into(data),
+            Packet::PingReq => PingReq.encode_into(data),
+            Packet::PingResp => PingResp.encode_into(data),
+            Packet::Disconnect(pkt) => pkt.encode_into(data),
+            Packet::Auth(pkt) => pkt.encode_into(data),
+        }
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        match self {
+            Packet::Connect(pkt) => pkt.encoded_len(),
+            Packet::ConnAck(pkt) => pkt.encoded_len(),
+            Packet::Publish(pkt) => pkt.encoded_len(),
+            Packet::PubAck(pkt) => pkt.encoded_len(),
+            Packet::PubRec(pkt) => pkt.encoded_len(),
+            Packet::PubRel(pkt) => pkt.encoded_len(),
+            Packet::PubComp(pkt) => pkt.encoded_len(),
+            Packet::Subscribe(pkt) => pkt.encoded_len(),
+            Packet::SubAck(pkt) => pkt.encoded_len(),
+            Packet::UnSubscribe(pkt) => pkt.encoded_len(),
+            Packet::UnsubAck(pkt) => pkt.encoded_len(),
+            Packet::PingReq => PingReq.encoded_len(),
+            Packet::PingResp => PingResp.encoded_len(),
+            Packet::Disconnect(pkt) => pkt.encoded_len(),
+            Packet::Auth(pkt) => pkt.encoded_len(),
+        }
+    }
 }
 
 impl Packet {
@@ -383,11 +443,38 @@ impl Packet {
             Packet::Auth(_) => PacketType::Auth,
         }
     }
+
+    /// Same as [Packetize::encode], except that the packet is not validated against
+    /// the specification. Meant for tools, like `mqcl encode`, that craft malformed
+    /// packets for testing. Fields that cannot be framed, say a string longer than
+    /// 64KB, still fail.
+    pub fn encode_unchecked(&self) -> Result<Blob> {
+        let mut data = Vec::with_capacity(64);
+        match self {
+            Packet::Connect(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::ConnAck(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::Publish(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::PubAck(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::PubRec(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::PubRel(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::PubComp(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::Subscribe(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::SubAck(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::UnSubscribe(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::UnsubAck(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::PingReq => PingReq.encode_into(&mut data)?,
+            Packet::PingResp => PingResp.encode_into(&mut data)?,
+            Packet::Disconnect(pkt) => pkt.encode_unchecked_into(&mut data)?,
+            Packet::Auth(pkt) => pkt.encode_unchecked_into(&mut data)?,
+        };
+        Ok(Blob::Large { data })
+    }
 }
 
 /// Quality of service
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum QoS {
     AtMostOnce = 0,
     AtLeastOnce = 1,
@@ -432,6 +519,7 @@ impl From<QoS> for u8 {
 /// http://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Figure_2.2_-
 /// ```
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct FixedHeader {
     /// First byte of the stream. Used to identify packet types and several flags
     pub byte1: u8,
@@ -629,42 +717,36 @@ impl FixedHeader {
         Ok(val)
     }
 
+    /// Validate reserved flag bits in byte-1, refer to _Table 2-2_ in the
+    /// specification. Flags in PUBLISH packet are validated by [Publish].
     pub fn validate(&self) -> Result<()> {
         use PacketType::*;
-        use QoS::{AtLeastOnce, AtMostOnce};
 
         let _qos = QoS::try_from((self.byte1 & Self::HDR_QOS) >> 1)?;
-        let _pkt_type = PacketType::try_from((self.byte1 & Self::HDR_PKT_TYPE) >> 4)?;
-
-        let (pkt_type, retain, qos, dup) = self.unwrap();
-        match pkt_type {
-            Connect if qos == AtMostOnce && !retain && !dup => Ok(()),
-            ConnAck if qos == AtMostOnce && !retain && !dup => Ok(()),
-            Publish => Ok(()),
-            PubAck if qos == AtMostOnce && !retain && !dup => Ok(()),
-            PubRec if qos == AtMostOnce && !retain && !dup => Ok(()),
-            PubRel if qos == AtLeastOnce && !retain && !dup => Ok(()),
-            PubComp if qos == AtMostOnce && !retain && !dup => Ok(()),
-            Subscribe if qos == AtLeastOnce && !retain && !dup => Ok(()),
-            SubAck if qos == AtMostOnce && !retain && !dup => Ok(()),
-            UnSubscribe if qos == AtLeastOnce && !retain && !dup => Ok(()),
-            UnsubAck if qos == AtMostOnce && !retain && !dup => Ok(()),
-            Disconnect if qos == AtMostOnce && !retain && !dup => Ok(()),
-            Auth if qos == AtMostOnce && !retain && !dup => Ok(()),
-            _ if retain || dup || qos != QoS::AtMostOnce => err!(
+        let pkt_type = PacketType::try_from((self.byte1 & Self::HDR_PKT_TYPE) >> 4)?;
+
+        let flags = match pkt_type {
+            Publish => return Ok(()),
+            PubRel | Subscribe | UnSubscribe => 0b_0010,
+            _ => 0b_0000,
+        };
+        if (self.byte1 & 0b_1111) != flags {
+            err!(
                 MalformedPacket,
                 code: MalformedPacket,
                 "FixedHeader invalid flags byte1:0x{:x}",
                 self.byte1
-            ),
-            _ => Ok(()),
+            )?
         }
+
+        Ok(())
     }
 }
 
 /// Enumerated list of all property types defined in MQTT spec.
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum PropertyType {
     PayloadFormatIndicator = 1,
     MessageExpiryInterval = 2,
@@ -743,18 +825,25 @@ impl TryFrom<u32> for PropertyType {
 
 /// Enumeration of property and its value that are allowed in a MQTT packet.
 #[derive(Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum Property {
     PayloadFormatIndicator(u8),
     MessageExpiryInterval(u32),
     ContentType(String),
     ResponseTopic(TopicName),
-    CorrelationData(Vec<u8>),
+    CorrelationData(
+        #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::bytes"))]
+        Vec<u8>,
+    ),
     SubscriptionIdentifier(VarU32),
     SessionExpiryInterval(u32),
     AssignedClientIdentifier(String),
     ServerKeepAlive(u16),
     AuthenticationMethod(String),
-    AuthenticationData(Vec<u8>),
+    AuthenticationData(
+        #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::bytes"))]
+        Vec<u8>,
+    ),
     RequestProblemInformation(u8),
     WillDelayInterval(u32),
     RequestResponseInformation(u8),
@@ -968,9 +1057,15 @@ impl Packetize for Property {
     }
 
     fn encode(&self) -> Result<Blob> {
+        let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
         use Property::*;
 
-        let mut data = Vec::with_capacity(64);
+        let start = data.len();
         match self {
             PayloadFormatIndicator(val) => enc_prop!(data, PayloadFormatIndicator, val),
             MessageExpiryInterval(val) => enc_prop!(data, MessageExpiryInterval, val),
@@ -1013,7 +1108,7 @@ impl Packetize for Property {
             }
         };
 
-        Ok(Blob::Large { data })
+        Ok(data.len() - start)
     }
 }
 
@@ -1058,6 +1153,7 @@ impl Property {
 /// Possible payload values for PayloadFormatIndicator property.
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum PayloadFormat {
     Binary = 0,
     Utf8 = 1,
@@ -1107,37 +1203,16 @@ impl PayloadFormat {
     }
 }
 
-fn insert_fixed_header(fh: FixedHeader, mut data: Vec<u8>) -> Result<Vec<u8>> {
-    let a = data.len();
-
-    let fh_blob = fh.encode()?;
-    let fh_bytes = fh_blob.as_ref();
-    let n = fh_bytes.len();
-
-    data.extend_from_slice(fh_bytes);
-    data.copy_within(..a, n);
-    (&mut data[..n]).copy_from_slice(fh_bytes);
-
-    Ok(data)
+// Return the length of a packet, fixed-header included, whose remaining-length is `n`.
+fn packet_len(n: usize) -> Result<usize> {
+    Ok(1 + VarU32(u32::try_from(n)?).encoded_len()? + n)
 }
 
-fn insert_property_len(n: usize, mut data: Vec<u8>) -> Result<Vec<u8>> {
-    let a = data.len();
-
-    let n = u32::try_from(n)?;
-
-    let blob = VarU32(n).encode()?;
-    let bytes = blob.as_ref();
-    let m = bytes.len();
-
-    data.extend_from_slice(bytes);
-    data.copy_within(..a, m);
-    (&mut data[..m]).copy_from_slice(bytes);
-
-    Ok(data)
+// Return the length of properties, property-length included, whose length is `n`.
+fn property_len(n: usize) -> Result<usize> {
+    Ok(VarU32(u32::try_from(n)?).encoded_len()? + n)
 }
 
-//TODO
-//#[cfg(any(feature = "fuzzy", test))]
-//#[path = "mod_fuzzy.rs"]
-//mod mod_fuzzy;
+#[cfg(any(feature = "fuzzy", test))]
+#[path = "mod_fuzzy.rs"]
+mod mod_fuzzy;
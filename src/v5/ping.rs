@@ -1,5 +1,7 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::Arbitrary;
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 use crate::v5::{FixedHeader, PacketType};
 use crate::Result;
@@ -8,6 +10,7 @@ use crate::{Blob, Packetize, VarU32};
 /// PINGREQ Packet
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct PingReq;
 
 impl Packetize for PingReq {
@@ -23,7 +26,7 @@ impl Packetize for PingReq {
     fn encode(&self) -> Result<Blob> {
         let mut data = [0_u8; 32];
 
-        let fh = FixedHeader::new(PacketType::PingResp, VarU32(0))?;
+        let fh = FixedHeader::new(PacketType::PingReq, VarU32(0))?;
         data[..2].copy_from_slice(fh.encode()?.as_ref());
 
         Ok(Blob::Small { data, size: 2 })
@@ -33,6 +36,7 @@ impl Packetize for PingReq {
 /// PINGRESP Packet
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct PingResp;
 
 impl Packetize for PingResp {
@@ -48,7 +52,7 @@ impl Packetize for PingResp {
     fn encode(&self) -> Result<Blob> {
         let mut data = [0_u8; 32];
 
-        let fh = FixedHeader::new(PacketType::PingReq, VarU32(0))?;
+        let fh = FixedHeader::new(PacketType::PingResp, VarU32(0))?;
         data[..2].copy_from_slice(fh.encode()?.as_ref());
 
         Ok(Blob::Small { data, size: 2 })
@@ -1,17 +1,23 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(any(feature = "fuzzy", test))]
 use std::result;
 
 use crate::util::advance;
-use crate::v5::{FixedHeader, PacketType, Property, PropertyType};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType};
 use crate::{Blob, Packetize, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
+const PP: &str = "Packet::Pub";
+
 /// Error codes allowed in PUBACK packet
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum PubAckReasonCode {
     Success = 0x00,
@@ -29,6 +35,7 @@ pub enum PubAckReasonCode {
 /// Error codes allowed in PUBREC packet
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum PubRecReasonCode {
     Success = 0x00,
@@ -46,6 +53,7 @@ pub enum PubRecReasonCode {
 /// Error codes allowed in PUBREL packet
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum PubRelReasonCode {
     Success = 0x00,
@@ -55,6 +63,7 @@ pub enum PubRelReasonCode {
 /// Error codes allowed in PUBCOMP packet
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum PubCompReasonCode {
     Success = 0x00,
@@ -63,6 +72,7 @@ pub enum PubCompReasonCode {
 
 /// PUBACK, PUBREC, PUBREL, PUBCOMP packets
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct Pub {
     pub packet_type: PacketType,
     pub packet_id: u16,
@@ -145,19 +155,30 @@ impl Packetize for Pub {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_fixed_header;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
-        data.extend_from_slice(self.packet_id.encode()?.as_ref());
-        data.extend_from_slice((self.code as u8).encode()?.as_ref());
-        if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
-        } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
-        }
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
 
-        let remlen = VarU32(data.len().try_into()?);
+        packet_len(self.remaining_len()?)
+    }
+}
+
+impl Pub {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
         let fh = match self.packet_type {
             PacketType::PubAck => FixedHeader::new(PacketType::PubAck, remlen)?,
             PacketType::PubRel => FixedHeader::new_pubrel(remlen)?,
@@ -165,15 +186,27 @@ impl Packetize for Pub {
             PacketType::PubComp => FixedHeader::new(PacketType::PubComp, remlen)?,
             packet_type => err!(ProtocolError, desc: "packet_type {:?}", packet_type)?,
         };
-        data = insert_fixed_header(fh, data)?;
+        fh.encode_into(data)?;
+        self.packet_id.encode_into(data)?;
+        (self.code as u8).encode_into(data)?;
+        if let Some(properties) = &self.properties {
+            properties.encode_into(data)?;
+        } else {
+            VarU32(0).encode_into(data)?;
+        }
 
-        // println!("Pub::encode {:?}", data);
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        Ok(2 + 1 + props)
     }
-}
 
-impl Pub {
     #[cfg(any(feature = "fuzzy", test))]
     pub fn normalize(&mut self) {
         if let Some(props) = &mut self.properties {
@@ -184,28 +217,19 @@ impl Pub {
     }
 
     fn validate(&self) -> Result<()> {
-        let invalid_code = match self.packet_type {
-            PacketType::PubAck | PacketType::PubRec => match self.code {
-                ReasonCode::Success => false,
-                ReasonCode::NoMatchingSubscribers => false,
-                ReasonCode::UnspecifiedError => false,
-                ReasonCode::ImplementationError => false,
-                ReasonCode::NotAuthorized => false,
-                ReasonCode::TopicNameInvalid => false,
-                ReasonCode::PacketIdInuse => false,
-                ReasonCode::PacketIdNotFound => false,
-                ReasonCode::QuotaExceeded => false,
-                ReasonCode::PayloadFormatInvalid => false,
-                _ => true,
-            },
-            PacketType::PubRel | PacketType::PubComp => match self.code {
-                ReasonCode::Success => false,
-                ReasonCode::PacketIdNotFound => false,
-                _ => true,
-            },
-            _ => unreachable!(),
-        };
-        if invalid_code {
+        match self.packet_type {
+            PacketType::PubAck
+            | PacketType::PubRec
+            | PacketType::PubRel
+            | PacketType::PubComp => (),
+            pkt_type => {
+                err!(ProtocolError, code: ProtocolError, "{} {:?}", PP, pkt_type)?
+            }
+        }
+        if self.packet_id == 0 {
+            err!(ProtocolError, code: ProtocolError, "{} packet_id is ZERO", PP)?
+        }
+        if !self.code.is_allowed(self.packet_type) {
             err!(MalformedPacket, code: MalformedPacket, "invalid code {:?}", self.code)?
         }
 
@@ -215,6 +239,7 @@ impl Pub {
 
 /// Collection of MQTT properties in PUBACK, PUBREC, PUBREL, PUBCOMP packets
 #[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct PubProperties {
     /// Property::ReasonString
     pub reason_string: Option<String>,
@@ -249,7 +274,7 @@ impl Packetize for PubProperties {
     fn decode<T: AsRef<[u8]>>(stream: T) -> Result<(Self, usize)> {
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check = PropertyCheck::new(PP, PropertyScope::Packet(PacketType::PubAck));
         let mut props = PubProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -260,17 +285,14 @@ impl Packetize for PubProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "duplicate property {:?}", pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 Property::ReasonString(val) => props.reason_string = Some(val),
                 Property::UserProp(val) => props.user_properties.push(val),
                 _ => err!(
-                    ProtocolError,
-                    code: ProtocolError,
+                    MalformedPacket,
+                    code: MalformedPacket,
                     "{:?} found in puback properties",
                     pt
                 )?,
@@ -281,23 +303,45 @@ impl Packetize for PubProperties {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
 
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, ReasonString, &self.reason_string);
 
         for uprop in self.user_properties.iter() {
             enc_prop!(data, UserProp, uprop)
         }
 
-        let data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
     }
 }
 
 impl PubProperties {
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(opt: n, ReasonString, &self.reason_string);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop)
+        }
+
+        Ok(n)
+    }
+
     #[cfg(any(feature = "fuzzy", test))]
     pub fn is_empty(&mut self) -> bool {
         self.reason_string.is_none() && self.user_properties.len() == 0
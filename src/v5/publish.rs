@@ -1,8 +1,14 @@
-use std::{cmp, fmt, result};
+#[cfg(any(feature = "fuzzy", test))]
+use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
+
+use std::{cmp, fmt, ops, result};
 
 use crate::util::advance;
-use crate::v5::{FixedHeader, PayloadFormat, Property, PropertyType, QoS};
-use crate::{Blob, Packetize, TopicName, UserProperty, VarU32};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{PayloadFormat, Property, PropertyType, QoS};
+use crate::{Blob, Bytes, Packetize, TopicName, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
 const PP: &'static str = "Packet::Publish";
@@ -16,7 +22,7 @@ pub struct Publish {
     pub topic_name: TopicName,
     pub packet_id: Option<u16>,
     pub properties: Option<PublishProperties>,
-    pub payload: Option<Vec<u8>>,
+    pub payload: Option<Bytes>,
 }
 
 impl fmt::Display for Publish {
@@ -26,6 +32,35 @@ impl fmt::Display for Publish {
     }
 }
 
+#[cfg(any(feature = "fuzzy", test))]
+impl<'a> Arbitrary<'a> for Publish {
+    fn arbitrary(uns: &mut Unstructured<'a>) -> result::Result<Self, ArbitraryError> {
+        let qos: QoS = uns.arbitrary()?;
+        let packet_id = match qos {
+            QoS::AtMostOnce => None,
+            _ => Some(cmp::max(uns.arbitrary::<u16>()?, 1)),
+        };
+        let payload = match uns.arbitrary::<u8>()? % 3 {
+            0 => None,
+            1 => Some(Bytes::from(b"hello world".to_vec())),
+            2 => Some(Bytes::from(uns.arbitrary::<Vec<u8>>()?)),
+            _ => unreachable!(),
+        };
+
+        let val = Publish {
+            retain: uns.arbitrary()?,
+            qos,
+            duplicate: uns.arbitrary()?,
+            topic_name: uns.arbitrary()?,
+            packet_id,
+            properties: uns.arbitrary()?,
+            payload,
+        };
+
+        Ok(val)
+    }
+}
+
 impl PartialOrd for Publish {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         self.topic_name.partial_cmp(&other.topic_name)
@@ -41,12 +76,96 @@ impl Ord for Publish {
 impl Packetize for Publish {
     fn decode<T: AsRef<[u8]>>(stream: T) -> Result<(Self, usize)> {
         let stream: &[u8] = stream.as_ref();
+        Publish::decode_with(stream, |range| Bytes::from(&stream[range]))
+    }
+
+    fn encode(&self) -> Result<Blob> {
+        let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
+
+        packet_len(self.remaining_len()?)
+    }
+}
+
+impl Publish {
+    /// Decode PUBLISH packet from `stream`, payload shall share the underlying
+    /// buffer of `stream` without copying.
+    pub fn decode_bytes(stream: &Bytes) -> Result<(Self, usize)> {
+        Publish::decode_with(stream, |range| stream.slice(range))
+    }
+
+    /// Same as [Packetize::encode_into], except that payload is not copied into
+    /// `data`, it is returned so that caller can write it along with `data`.
+    pub fn encode_header_into(&self, data: &mut Vec<u8>) -> Result<Option<Bytes>> {
+        self.validate()?;
+        self.encode_header_unchecked_into(data)
+    }
 
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+        if let Some(payload) = self.encode_header_unchecked_into(data)? {
+            data.extend_from_slice(&payload);
+        }
+        Ok(data.len() - start)
+    }
+
+    fn encode_header_unchecked_into(&self, data: &mut Vec<u8>) -> Result<Option<Bytes>> {
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        let fh = FixedHeader::new_publish(self.retain, self.qos, self.duplicate, remlen)?;
+        fh.encode_into(data)?;
+
+        // topic-name can be empty when topic-alias is used.
+        (*self.topic_name).encode_into(data)?;
+        if let Some(packet_id) = self.packet_id {
+            packet_id.encode_into(data)?;
+        }
+        if let Some(properties) = &self.properties {
+            properties.encode_unchecked_into(data)?;
+        } else {
+            VarU32(0).encode_into(data)?;
+        }
+
+        Ok(self.payload.clone())
+    }
+
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let mut n = (*self.topic_name).encoded_len()?;
+        if self.packet_id.is_some() {
+            n += 2;
+        }
+        n += match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        n += self.payload.as_ref().map(|p| p.len()).unwrap_or(0);
+
+        Ok(n)
+    }
+
+    fn decode_with<F>(stream: &[u8], to_payload: F) -> Result<(Self, usize)>
+    where
+        F: FnOnce(ops::Range<usize>) -> Bytes,
+    {
         let (fh, fh_len) = dec_field!(FixedHeader, stream, 0);
         fh.validate()?;
         let (_, retain, qos, duplicate) = fh.unwrap();
 
-        let (topic_name, n) = dec_field!(TopicName, stream, fh_len);
+        // topic-name can be empty when topic-alias is used.
+        let (topic_name, n) = dec_field!(String, stream, fh_len);
+        let topic_name = TopicName::from(topic_name);
         let (packet_id, n) = dec_field!(
             u16,
             stream,
@@ -57,7 +176,7 @@ impl Packetize for Publish {
 
         let (payload, n) = match fh_len + usize::try_from(*fh.remaining_len)? {
             m if m == n => (None, n),
-            m if m <= stream.len() => (Some(stream[n..m].to_vec()), m),
+            m if n < m && m <= stream.len() => (Some(to_payload(n..m)), m),
             m => err!(MalformedPacket, code: MalformedPacket, "{} in payload {}", PP, m)?,
         };
 
@@ -75,37 +194,6 @@ impl Packetize for Publish {
         Ok((val, n))
     }
 
-    fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_fixed_header;
-
-        let mut data = Vec::with_capacity(64);
-
-        data.extend_from_slice(self.topic_name.encode()?.as_ref());
-        if let Some(packet_id) = self.packet_id {
-            data.extend_from_slice(packet_id.encode()?.as_ref());
-        }
-        if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
-        } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
-        }
-        if let Some(payload) = &self.payload {
-            data.extend_from_slice(payload)
-        }
-
-        let fh = FixedHeader::new_publish(
-            self.retain,
-            self.qos,
-            self.duplicate,
-            VarU32(data.len().try_into()?),
-        )?;
-        data = insert_fixed_header(fh, data)?;
-
-        Ok(Blob::Large { data })
-    }
-}
-
-impl Publish {
     pub fn set_fixed_header(&mut self, retain: bool, qos: QoS, dup: bool) -> &mut Self {
         self.retain = retain;
         self.qos = qos;
@@ -141,6 +229,12 @@ impl Publish {
                 "{} DUP is set for QoS-0",
                 PP
             )?,
+            QoS::AtMostOnce if self.packet_id.is_some() => err!(
+                MalformedPacket,
+                code: MalformedPacket,
+                "{} packet_id is set for QoS-0",
+                PP
+            )?,
             QoS::AtLeastOnce | QoS::ExactlyOnce if self.packet_id.is_none() => err!(
                 MalformedPacket,
                 code: MalformedPacket,
@@ -148,9 +242,26 @@ impl Publish {
                 PP,
                 self.qos
             )?,
+            QoS::AtLeastOnce | QoS::ExactlyOnce if self.packet_id == Some(0) => {
+                err!(ProtocolError, code: ProtocolError, "{} packet_id is ZERO", PP)?
+            }
             _ => (),
         }
 
+        match self.topic_alias() {
+            None if self.topic_name.is_empty() => err!(
+                ProtocolError,
+                code: ProtocolError,
+                "{} empty topic_name without topic-alias",
+                PP
+            )?,
+            Some(_) if self.topic_name.is_empty() => (),
+            _ => self.topic_name.validate()?,
+        }
+        if let Some(properties) = &self.properties {
+            properties.validate()?;
+        }
+
         if let (Some(payload), Some(true)) =
             (self.payload.as_ref(), self.properties.as_ref().map(|p| p.is_payload_utf8()))
         {
@@ -182,24 +293,64 @@ impl Publish {
 
 /// Collection of MQTT properties allowed in PUBLISH packet
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct PublishProperties {
     pub payload_format_indicator: PayloadFormat, // default=PayloadFormat::Binary
     pub message_expiry_interval: Option<u32>,
     pub topic_alias: Option<u16>,
     pub response_topic: Option<TopicName>,
+    #[cfg_attr(feature = "v5-serde", serde(with = "crate::v5::serde_util::opt_bytes"))]
     pub correlation_data: Option<Vec<u8>>,
     pub subscribtion_identifier: Vec<VarU32>,
     pub content_type: Option<String>,
     pub user_properties: Vec<UserProperty>,
 }
 
+#[cfg(any(feature = "fuzzy", test))]
+impl<'a> Arbitrary<'a> for PublishProperties {
+    fn arbitrary(uns: &mut Unstructured<'a>) -> result::Result<Self, ArbitraryError> {
+        use crate::types;
+
+        let ct_choice: Vec<String> =
+            vec!["", "text/plain"].into_iter().map(|s| s.to_string()).collect();
+        let content_type = match uns.arbitrary::<u8>()? % 2 {
+            0 => Some(uns.choose(&ct_choice)?.to_string()),
+            1 => None,
+            _ => unreachable!(),
+        };
+        let topic_alias = match uns.arbitrary::<Option<u16>>()? {
+            Some(0) => Some(1),
+            val => val,
+        };
+
+        let n_sub_ids = uns.arbitrary::<usize>()? % 2;
+        let n_user_props = uns.arbitrary::<usize>()? % 4;
+        let val = PublishProperties {
+            payload_format_indicator: uns.arbitrary()?,
+            message_expiry_interval: uns.arbitrary()?,
+            topic_alias,
+            response_topic: uns.arbitrary()?,
+            correlation_data: uns.arbitrary()?,
+            subscribtion_identifier: (0..n_sub_ids)
+                .map(|_| uns.arbitrary())
+                .collect::<result::Result<Vec<VarU32>, ArbitraryError>>(
+            )?,
+            content_type,
+            user_properties: types::valid_user_props(uns, n_user_props)?,
+        };
+
+        Ok(val)
+    }
+}
+
 impl Packetize for PublishProperties {
     fn decode<T: AsRef<[u8]>>(stream: T) -> Result<(Self, usize)> {
         use crate::v5::Property::*;
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check =
+            PropertyCheck::new(PP, PropertyScope::Packet(PacketType::Publish));
         let mut props = PublishProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -209,21 +360,14 @@ impl Packetize for PublishProperties {
             let (property, m) = dec_field!(Property, stream, n);
             n = m;
 
-            let dup_ok = [PropertyType::UserProp, PropertyType::SubscriptionIdentifier];
             let pt = property.to_property_type();
-            if dup_ok.contains(&pt) && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 PayloadFormatIndicator(val) => {
                     props.payload_format_indicator = val.try_into()?;
                 }
                 MessageExpiryInterval(val) => props.message_expiry_interval = Some(val),
-                TopicAlias(0) => {
-                    err!(ProtocolError, code: ProtocolError, "{} topic-alias=ZERO", PP)?
-                }
                 TopicAlias(val) => props.topic_alias = Some(val),
                 ResponseTopic(val) => props.response_topic = Some(val),
                 CorrelationData(val) => props.correlation_data = Some(val),
@@ -231,19 +375,40 @@ impl Packetize for PublishProperties {
                 ContentType(val) => props.content_type = Some(val),
                 UserProp(val) => props.user_properties.push(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop {:?}", PP, pt)?
                 }
             }
         }
 
+        props.validate()?;
         Ok((props, n))
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
+    }
+}
+
+impl PublishProperties {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         if self.payload_format_indicator.is_utf8() {
             let val = u8::from(PayloadFormat::Utf8);
             enc_prop!(data, PayloadFormatIndicator, val);
@@ -261,13 +426,44 @@ impl Packetize for PublishProperties {
             enc_prop!(data, UserProp, uprop);
         }
 
-        data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        if self.payload_format_indicator.is_utf8() {
+            let val = u8::from(PayloadFormat::Utf8);
+            prop_len!(n, PayloadFormatIndicator, val);
+        }
+        prop_len!(opt: n, MessageExpiryInterval, self.message_expiry_interval);
+        prop_len!(opt: n, TopicAlias, self.topic_alias);
+        prop_len!(opt: n, ResponseTopic, &self.response_topic);
+        prop_len!(opt: n, CorrelationData, &self.correlation_data);
+        prop_len!(opt: n, ContentType, &self.content_type);
+
+        for subid in self.subscribtion_identifier.iter() {
+            prop_len!(n, SubscriptionIdentifier, subid);
+        }
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop);
+        }
+
+        Ok(n)
+    }
+
+    fn validate(&self) -> Result<()> {
+        use crate::v5::validate::check_non_zero;
+
+        check_non_zero(PP, PropertyType::TopicAlias, self.topic_alias)?;
+        for id in self.subscribtion_identifier.iter() {
+            check_non_zero(PP, PropertyType::SubscriptionIdentifier, Some(**id))?;
+        }
+
+        Ok(())
     }
-}
 
-impl PublishProperties {
     fn is_payload_utf8(&self) -> bool {
         self.payload_format_indicator.is_utf8()
     }
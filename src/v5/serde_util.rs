@@ -0,0 +1,209 @@
+//! Serde support for MQTT v5 packets, enabled via `v5-serde` feature.
+//!
+//! Binary fields, like correlation-data and authentication-data, are serialized as
+//! base64 strings. PUBLISH payload and will-payload are serialized as plain string
+//! when payload-format-indicator is [PayloadFormat::Utf8], otherwise as base64.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+use std::{result, str};
+
+use crate::v5::WillProperties;
+use crate::v5::{ConnectPayload, PayloadFormat, Publish, PublishProperties, QoS};
+use crate::{ClientID, TopicName};
+
+/// Use as `#[serde(with = "...")]` for `Vec<u8>` fields.
+pub mod bytes {
+    use super::*;
+
+    pub fn serialize<S>(val: &[u8], s: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(&STANDARD.encode(val))
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> result::Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        STANDARD.decode(String::deserialize(d)?).map_err(de::Error::custom)
+    }
+}
+
+/// Use as `#[serde(with = "...")]` for `Option<Vec<u8>>` fields.
+pub mod opt_bytes {
+    use super::*;
+
+    pub fn serialize<S>(val: &Option<Vec<u8>>, s: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match val {
+            Some(val) => s.serialize_some(&STANDARD.encode(val)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(d: D) -> result::Result<Option<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        match Option::<String>::deserialize(d)? {
+            Some(val) => Ok(Some(STANDARD.decode(val).map_err(de::Error::custom)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Publish")]
+struct PublishDef {
+    retain: bool,
+    qos: QoS,
+    duplicate: bool,
+    topic_name: TopicName,
+    packet_id: Option<u16>,
+    properties: Option<PublishProperties>,
+    payload: Option<String>,
+}
+
+impl Serialize for Publish {
+    fn serialize<S>(&self, s: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let format = self.properties.as_ref().map(|p| p.payload_format_indicator);
+        let payload = match &self.payload {
+            Some(payload) => Some(to_payload_str(format, payload)?),
+            None => None,
+        };
+        let val = PublishDef {
+            retain: self.retain,
+            qos: self.qos,
+            duplicate: self.duplicate,
+            topic_name: self.topic_name.clone(),
+            packet_id: self.packet_id,
+            properties: self.properties.clone(),
+            payload,
+        };
+        val.serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for Publish {
+    fn deserialize<D>(d: D) -> result::Result<Publish, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let val = PublishDef::deserialize(d)?;
+
+        let format = val.properties.as_ref().map(|p| p.payload_format_indicator);
+        let payload = match val.payload {
+            Some(payload) => Some(from_payload_str(format, payload)?.into()),
+            None => None,
+        };
+        let publ = Publish {
+            retain: val.retain,
+            qos: val.qos,
+            duplicate: val.duplicate,
+            topic_name: val.topic_name,
+            packet_id: val.packet_id,
+            properties: val.properties,
+            payload,
+        };
+        Ok(publ)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "ConnectPayload")]
+struct ConnectPayloadDef {
+    client_id: ClientID,
+    will_properties: Option<WillProperties>,
+    will_topic: Option<TopicName>,
+    will_payload: Option<String>,
+    user_name: Option<String>,
+    password: Option<String>,
+}
+
+impl Serialize for ConnectPayload {
+    fn serialize<S>(&self, s: S) -> result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let format = self.will_properties.as_ref().map(|p| p.payload_format_indicator);
+        let will_payload = match &self.will_payload {
+            Some(payload) => Some(to_payload_str(format, payload)?),
+            None => None,
+        };
+        let val = ConnectPayloadDef {
+            client_id: self.client_id.clone(),
+            will_properties: self.will_properties.clone(),
+            will_topic: self.will_topic.clone(),
+            will_payload,
+            user_name: self.user_name.clone(),
+            password: self.password.clone(),
+        };
+        val.serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for ConnectPayload {
+    fn deserialize<D>(d: D) -> result::Result<ConnectPayload, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let val = ConnectPayloadDef::deserialize(d)?;
+
+        let format = val.will_properties.as_ref().map(|p| p.payload_format_indicator);
+        let will_payload = match val.will_payload {
+            Some(payload) => Some(from_payload_str(format, payload)?),
+            None => None,
+        };
+        let payload = ConnectPayload {
+            client_id: val.client_id,
+            will_properties: val.will_properties,
+            will_topic: val.will_topic,
+            will_payload,
+            user_name: val.user_name,
+            password: val.password,
+        };
+        Ok(payload)
+    }
+}
+
+// Missing payload-format-indicator is same as PayloadFormat::Binary.
+fn to_payload_str<E>(
+    format: Option<PayloadFormat>,
+    payload: &[u8],
+) -> result::Result<String, E>
+where
+    E: ser::Error,
+{
+    match format.unwrap_or_default() {
+        PayloadFormat::Utf8 => match str::from_utf8(payload) {
+            Ok(val) => Ok(val.to_string()),
+            Err(err) => Err(E::custom(format!("payload-format is utf8, {}", err))),
+        },
+        PayloadFormat::Binary => Ok(STANDARD.encode(payload)),
+    }
+}
+
+fn from_payload_str<E>(
+    format: Option<PayloadFormat>,
+    val: String,
+) -> result::Result<Vec<u8>, E>
+where
+    E: de::Error,
+{
+    match format.unwrap_or_default() {
+        PayloadFormat::Utf8 => Ok(val.into_bytes()),
+        PayloadFormat::Binary => STANDARD.decode(val).map_err(E::custom),
+    }
+}
+
+#[cfg(test)]
+#[path = "serde_util_test.rs"]
+mod serde_util_test;
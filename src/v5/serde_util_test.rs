@@ -0,0 +1,77 @@
+use arbitrary::{Arbitrary, Unstructured};
+use rand::{prelude::random, rngs::StdRng, Rng, SeedableRng};
+
+use super::*;
+use crate::v5::{Auth, Connect, Disconnect, Packet, Pub, SubAck, Subscribe};
+use crate::v5::{UnSubscribe, UnsubAck};
+
+fn new_publish(format: PayloadFormat, payload: &[u8]) -> Publish {
+    let props = PublishProperties {
+        payload_format_indicator: format,
+        correlation_data: Some(vec![0xde, 0xad, 0xbe, 0xef]),
+        ..PublishProperties::default()
+    };
+    Publish {
+        retain: false,
+        qos: QoS::AtLeastOnce,
+        duplicate: false,
+        topic_name: TopicName::from("a/b".to_string()),
+        packet_id: Some(10),
+        properties: Some(props),
+        payload: Some(payload.into()),
+    }
+}
+
+#[test]
+fn test_publish_payload_format() {
+    let publ = new_publish(PayloadFormat::Utf8, "hello world".as_bytes());
+    let val = serde_json::to_value(&publ).unwrap();
+    assert_eq!(val["payload"], "hello world");
+    assert_eq!(val["properties"]["correlation_data"], "3q2+7w==");
+    assert_eq!(serde_json::from_value::<Publish>(val).unwrap(), publ);
+
+    let publ = new_publish(PayloadFormat::Binary, &[0xff, 0x00, 0x10]);
+    let val = serde_json::to_value(&publ).unwrap();
+    assert_eq!(val["payload"], "/wAQ");
+    assert_eq!(serde_json::from_value::<Publish>(val).unwrap(), publ);
+
+    let publ = new_publish(PayloadFormat::Utf8, &[0xff, 0x00, 0x10]);
+    assert!(serde_json::to_value(&publ).is_err());
+}
+
+#[test]
+fn test_packet_serde() {
+    let seed: u64 = random();
+    println!("test_packet_serde seed:{}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let mut n_packets = 0;
+    for _ in 0..1000 {
+        let bytes: Vec<u8> = (0..1024).map(|_| rng.gen()).collect();
+        let mut uns = Unstructured::new(&bytes);
+        let pkt = match rng.gen::<u8>() % 8 {
+            0 => Connect::arbitrary(&mut uns).map(Packet::Connect),
+            1 => Pub::arbitrary(&mut uns).map(Packet::PubAck),
+            2 => Subscribe::arbitrary(&mut uns).map(Packet::Subscribe),
+            3 => SubAck::arbitrary(&mut uns).map(Packet::SubAck),
+            4 => UnSubscribe::arbitrary(&mut uns).map(Packet::UnSubscribe),
+            5 => UnsubAck::arbitrary(&mut uns).map(Packet::UnsubAck),
+            6 => Disconnect::arbitrary(&mut uns).map(Packet::Disconnect),
+            7 => Auth::arbitrary(&mut uns).map(Packet::Auth),
+            _ => unreachable!(),
+        };
+        let pkt = match pkt {
+            Ok(pkt) => pkt,
+            Err(_) => continue,
+        };
+        // utf8 payload-format with non-utf8 payload cannot be serialized.
+        let text = match serde_json::to_string(&pkt) {
+            Ok(text) => text,
+            Err(_) => continue,
+        };
+        assert_eq!(serde_json::from_str::<Packet>(&text).unwrap(), pkt, "{}", text);
+        n_packets += 1;
+    }
+    println!("test_packet_serde n_packets:{}", n_packets);
+    assert!(n_packets > 0);
+}
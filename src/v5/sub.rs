@@ -1,10 +1,13 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(any(feature = "fuzzy", test))]
 use std::result;
 
-use crate::v5::{FixedHeader, Property, PropertyType, QoS};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType, QoS};
 use crate::{util::advance, Blob, Packetize, TopicFilter, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -12,6 +15,8 @@ const PP: &'static str = "Packet::Subscribe";
 
 /// Subscription options carried in SUBSCRIBE Packet
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "v5-serde", serde(transparent))]
 pub struct SubscriptionOpt(u8);
 
 #[cfg(any(feature = "fuzzy", test))]
@@ -65,7 +70,7 @@ impl SubscriptionOpt {
         let nl: bool = (self.0 & Self::NO_LOCAL) > 0;
         let rap: bool = (self.0 & Self::RETAIN_AS_PUBLISHED) > 0;
         (
-            RetainForwardRule::try_from((self.0 >> 4) & Self::RETAIN_HANDLING).unwrap(),
+            RetainForwardRule::try_from((self.0 & Self::RETAIN_HANDLING) >> 4).unwrap(),
             rap,
             nl,
             qos,
@@ -73,6 +78,25 @@ impl SubscriptionOpt {
     }
 
     fn validate(&self) -> Result<()> {
+        if (self.0 & Self::RESERVED) > 0 {
+            err!(
+                MalformedPacket,
+                code: MalformedPacket,
+                "{} sub-opt reserved bit != 0 0x{:x}",
+                PP,
+                self.0
+            )?
+        } else if ((self.0 & Self::RETAIN_HANDLING) >> 4) == 3 {
+            err!(
+                MalformedPacket,
+                code: MalformedPacket,
+                "{} invalid retain handling 0x{:x}",
+                PP,
+                self.0
+            )?
+        }
+        QoS::try_from(self.0 & Self::MAXIMUM_QOS)?;
+
         Ok(())
     }
 }
@@ -80,6 +104,7 @@ impl SubscriptionOpt {
 /// RetainForwardRule part of Subscription option defined by MQTT spec.
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub enum RetainForwardRule {
     OnEverySubscribe = 0,
     OnNewSubscribe = 1,
@@ -113,6 +138,7 @@ impl From<RetainForwardRule> for u8 {
 
 /// SUBSCRIBE Packet
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct Subscribe {
     pub packet_id: u16,
     pub properties: Option<SubscribeProperties>,
@@ -148,9 +174,9 @@ impl Packetize for Subscribe {
         let (properties, n) = dec_props!(SubscribeProperties, stream, n);
         let (payload, n) = match fh_len + usize::try_from(*fh.remaining_len)? {
             m if m == n => {
-                err!(MalformedPacket, code: MalformedPacket, "{} in payload {}", PP, m)?
+                err!(ProtocolError, code: ProtocolError, "{} in payload {}", PP, m)?
             }
-            m if m <= stream.len() => (&stream[n..m], m),
+            m if n <= m && m <= stream.len() => (&stream[n..m], m),
             m => err!(MalformedPacket, code: MalformedPacket, "{} in payload {}", PP, m)?,
         };
 
@@ -170,31 +196,58 @@ impl Packetize for Subscribe {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_fixed_header;
+        let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
         self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
-        let mut data = Vec::with_capacity(64);
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
 
-        data.extend_from_slice(self.packet_id.encode()?.as_ref());
+        packet_len(self.remaining_len()?)
+    }
+}
+
+impl Subscribe {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new_subscribe(remlen)?.encode_into(data)?;
+        self.packet_id.encode_into(data)?;
         if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
+            properties.encode_unchecked_into(data)?;
         } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
+            VarU32(0).encode_into(data)?;
         }
 
         for filter in self.filters.iter() {
-            data.extend_from_slice(filter.encode()?.as_ref());
+            filter.encode_unchecked_into(data)?;
         }
 
-        let fh = FixedHeader::new_subscribe(VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        let mut n = 2 + props;
+        for filter in self.filters.iter() {
+            n += filter.encoded_len()?;
+        }
+        Ok(n)
     }
-}
 
-impl Subscribe {
     #[cfg(any(feature = "fuzzy", test))]
     pub fn normalize(&mut self) {
         if let Some(props) = &mut self.properties {
@@ -205,29 +258,18 @@ impl Subscribe {
     }
 
     fn validate(&self) -> Result<()> {
+        if self.packet_id == 0 {
+            err!(ProtocolError, code: ProtocolError, "{} packet_id is ZERO", PP)?
+        }
         if self.filters.len() == 0 {
             err!(ProtocolError, code: ProtocolError, "{} missing topic filter", PP)?
         }
 
+        if let Some(properties) = &self.properties {
+            properties.validate()?;
+        }
         for filter in self.filters.iter() {
-            if (filter.opt.0 & SubscriptionOpt::RESERVED) > 0 {
-                err!(
-                    MalformedPacket,
-                    code: MalformedPacket,
-                    "{} sub-opt reserved bit != 0 0x{:x}",
-                    PP,
-                    filter.opt.0
-                )?
-            } else if ((filter.opt.0 & SubscriptionOpt::RETAIN_HANDLING) >> 4) == 3 {
-                err!(
-                    MalformedPacket,
-                    code: MalformedPacket,
-                    "{} invalid retain handling 0x{:x}",
-                    PP,
-                    filter.opt.0
-                )?
-            }
-            QoS::try_from(filter.opt.0 & SubscriptionOpt::MAXIMUM_QOS)?;
+            filter.validate()?;
         }
 
         Ok(())
@@ -236,6 +278,7 @@ impl Subscribe {
 
 /// Collection of MQTT properties allowed in SUBSCRIBE packet
 #[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct SubscribeProperties {
     pub subscription_id: Option<VarU32>,
     pub user_properties: Vec<UserProperty>,
@@ -262,7 +305,8 @@ impl Packetize for SubscribeProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check =
+            PropertyCheck::new(PP, PropertyScope::Packet(PacketType::Subscribe));
         let mut props = SubscribeProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -273,44 +317,75 @@ impl Packetize for SubscribeProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
-                SubscriptionIdentifier(VarU32(0)) => {
-                    err!(ProtocolError, code: ProtocolError, "{} subcr_ide:0", PP)?;
-                }
                 SubscriptionIdentifier(val) => props.subscription_id = Some(val),
                 UserProp(val) => props.user_properties.push(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop {:?}", PP, pt)?
                 }
             }
         }
 
+        props.validate()?;
         Ok((props, n))
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
+    }
+}
+
+impl SubscribeProperties {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, SubscriptionIdentifier, self.subscription_id);
 
         for uprop in self.user_properties.iter() {
             enc_prop!(data, UserProp, uprop);
         }
 
-        data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(opt: n, SubscriptionIdentifier, self.subscription_id);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop);
+        }
+
+        Ok(n)
+    }
+
+    fn validate(&self) -> Result<()> {
+        use crate::v5::validate::check_non_zero;
+
+        let id = self.subscription_id.map(|id| *id);
+        check_non_zero(PP, PropertyType::SubscriptionIdentifier, id)
     }
-}
 
-impl SubscribeProperties {
     #[cfg(any(feature = "fuzzy", test))]
     pub fn is_empty(&mut self) -> bool {
         self.subscription_id.is_none() && self.user_properties.len() == 0
@@ -319,6 +394,7 @@ impl SubscribeProperties {
 
 /// SubscribeFilter defined in the SUBSCRIBE packet's payload.
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct SubscribeFilter {
     pub topic_filter: TopicFilter,
     pub opt: SubscriptionOpt,
@@ -351,16 +427,48 @@ impl Packetize for SubscribeFilter {
 
     fn encode(&self) -> Result<Blob> {
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
-        data.extend_from_slice(self.topic_filter.encode()?.as_ref());
-        data.extend_from_slice(self.opt.encode()?.as_ref());
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
 
-        Ok(Blob::Large { data })
+    fn encoded_len(&self) -> Result<usize> {
+        // topic-filter and subscription-options.
+        Ok(self.topic_filter.encoded_len()? + 1)
     }
 }
 
 impl SubscribeFilter {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        (*self.topic_filter).encode_into(data)?;
+        self.opt.0.encode_into(data)?;
+
+        Ok(data.len() - start)
+    }
+
     fn validate(&self) -> Result<()> {
+        self.topic_filter.validate()?;
+        self.opt.validate()?;
+
+        let (_, _, no_local, _) = self.opt.unwrap();
+        if no_local && self.topic_filter.starts_with("$share/") {
+            err!(
+                ProtocolError,
+                code: ProtocolError,
+                "{} no-local on shared subscription {:?}",
+                PP,
+                self.topic_filter
+            )?
+        }
+
         Ok(())
     }
 }
@@ -1,11 +1,14 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(any(feature = "fuzzy", test))]
 use std::result;
 
 use crate::util::advance;
-use crate::v5::{FixedHeader, PacketType, Property, PropertyType};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType};
 use crate::{Blob, Packetize, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -14,6 +17,7 @@ const PP: &'static str = "Packet::SubAck";
 /// Error codes allowed in SUBACK packet.
 #[cfg_attr(any(feature = "fuzzy", test), derive(Arbitrary))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 #[repr(u8)]
 pub enum SubAckReasonCode {
     QoS0 = 0x0,
@@ -62,6 +66,7 @@ impl TryFrom<u8> for SubAckReasonCode {
 
 /// SUBACK Packet
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct SubAck {
     pub packet_id: u16,
     pub properties: Option<SubAckProperties>,
@@ -100,7 +105,7 @@ impl Packetize for SubAck {
             m if m == n => {
                 err!(MalformedPacket, code: MalformedPacket, "{} no payload", PP)?
             }
-            m if m <= stream.len() => (stream[n..m].to_vec(), m),
+            m if n <= m && m <= stream.len() => (stream[n..m].to_vec(), m),
             m => err!(MalformedPacket, code: MalformedPacket, "{} in payload {}", PP, m)?,
         };
 
@@ -116,28 +121,53 @@ impl Packetize for SubAck {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_fixed_header;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
+
+        packet_len(self.remaining_len()?)
+    }
+}
 
-        data.extend_from_slice(self.packet_id.encode()?.as_ref());
+impl SubAck {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new(PacketType::SubAck, remlen)?.encode_into(data)?;
+        self.packet_id.encode_into(data)?;
         if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
+            properties.encode_into(data)?;
         } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
+            VarU32(0).encode_into(data)?;
         }
-        for code in self.return_codes.clone().into_iter() {
-            data.push(code as u8)
+        for code in self.return_codes.iter() {
+            data.push(*code as u8)
         }
 
-        let fh = FixedHeader::new(PacketType::SubAck, VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        Ok(2 + props + self.return_codes.len())
     }
-}
 
-impl SubAck {
     #[cfg(any(feature = "fuzzy", test))]
     pub fn normalize(&mut self) {
         if let Some(props) = &mut self.properties {
@@ -148,12 +178,17 @@ impl SubAck {
     }
 
     fn validate(&self) -> Result<()> {
+        if self.return_codes.is_empty() {
+            err!(MalformedPacket, code: MalformedPacket, "{} no payload", PP)?
+        }
+
         Ok(())
     }
 }
 
 /// Collection of MQTT properties allowed in SUBACK packet
 #[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct SubAckProperties {
     pub reason_string: Option<String>,
     pub user_properties: Vec<UserProperty>,
@@ -186,7 +221,7 @@ impl Packetize for SubAckProperties {
     fn decode<T: AsRef<[u8]>>(stream: T) -> Result<(Self, usize)> {
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check = PropertyCheck::new(PP, PropertyScope::Packet(PacketType::SubAck));
         let mut props = SubAckProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -197,16 +232,13 @@ impl Packetize for SubAckProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 Property::ReasonString(val) => props.reason_string = Some(val),
                 Property::UserProp(val) => props.user_properties.push(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop, {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop, {:?}", PP, pt)?
                 }
             };
         }
@@ -215,23 +247,45 @@ impl Packetize for SubAckProperties {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
 
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         enc_prop!(opt: data, ReasonString, &self.reason_string);
 
         for uprop in self.user_properties.iter() {
             enc_prop!(data, UserProp, uprop)
         }
 
-        let data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
+
+        property_len(self.props_len()?)
     }
 }
 
 impl SubAckProperties {
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        prop_len!(opt: n, ReasonString, &self.reason_string);
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop)
+        }
+
+        Ok(n)
+    }
+
     #[cfg(any(feature = "fuzzy", test))]
     pub fn is_empty(&mut self) -> bool {
         self.reason_string.is_none() && self.user_properties.len() == 0
@@ -1,10 +1,13 @@
 #[cfg(any(feature = "fuzzy", test))]
 use arbitrary::{Arbitrary, Error as ArbitraryError, Unstructured};
+#[cfg(feature = "v5-serde")]
+use serde::{Deserialize, Serialize};
 
 #[cfg(any(feature = "fuzzy", test))]
 use std::result;
 
-use crate::v5::{FixedHeader, Property, PropertyType};
+use crate::v5::{validate::PropertyCheck, FixedHeader, PacketType, PropertyScope};
+use crate::v5::{Property, PropertyType};
 use crate::{util::advance, Blob, Packetize, TopicFilter, UserProperty, VarU32};
 use crate::{Error, ErrorKind, ReasonCode, Result};
 
@@ -12,6 +15,7 @@ const PP: &'static str = "Packet::UnSubscribe";
 
 /// UNSUBSCRIBE Packet
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct UnSubscribe {
     pub packet_id: u16,
     pub properties: Option<UnSubscribeProperties>,
@@ -49,8 +53,8 @@ impl Packetize for UnSubscribe {
             m if m == n => {
                 err!(ProtocolError, code: ProtocolError, "{} in payload {}", PP, m)?
             }
-            m if m <= stream.len() => (&stream[n..m], m),
-            m => err!(ProtocolError, code: ProtocolError, "{} in payload {}", PP, m)?,
+            m if n <= m && m <= stream.len() => (&stream[n..m], m),
+            m => err!(MalformedPacket, code: MalformedPacket, "{} in payload {}", PP, m)?,
         };
 
         // Assuming that each entry in payload will take up 32 bytes.
@@ -69,29 +73,58 @@ impl Packetize for UnSubscribe {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_fixed_header;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
+
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        self.validate()?;
+        self.encode_unchecked_into(data)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::packet_len;
+
+        packet_len(self.remaining_len()?)
+    }
+}
 
-        data.extend_from_slice(self.packet_id.encode()?.as_ref());
+impl UnSubscribe {
+    // Same as [Packetize::encode_into], without validating the packet, refer to
+    // [Packet::encode_unchecked].
+    pub(crate) fn encode_unchecked_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        let remlen = VarU32(self.remaining_len()?.try_into()?);
+        FixedHeader::new_unsubscribe(remlen)?.encode_into(data)?;
+        self.packet_id.encode_into(data)?;
         if let Some(properties) = &self.properties {
-            data.extend_from_slice(properties.encode()?.as_ref());
+            properties.encode_into(data)?;
         } else {
-            data.extend_from_slice(VarU32(0).encode()?.as_ref());
+            VarU32(0).encode_into(data)?;
         }
 
         for filter in self.filters.iter() {
-            data.extend_from_slice(filter.encode()?.as_ref());
+            (**filter).encode_into(data)?;
         }
 
-        let fh = FixedHeader::new_unsubscribe(VarU32(data.len().try_into()?))?;
-        data = insert_fixed_header(fh, data)?;
+        Ok(data.len() - start)
+    }
 
-        Ok(Blob::Large { data })
+    // Return the length of the packet, fixed-header excluded.
+    fn remaining_len(&self) -> Result<usize> {
+        let props = match &self.properties {
+            Some(properties) => properties.encoded_len()?,
+            None => 1,
+        };
+        let mut n = 2 + props;
+        for filter in self.filters.iter() {
+            n += filter.encoded_len()?;
+        }
+        Ok(n)
     }
-}
 
-impl UnSubscribe {
     #[cfg(any(feature = "fuzzy", test))]
     pub fn normalize(&mut self) {
         if let Some(props) = &mut self.properties {
@@ -102,9 +135,15 @@ impl UnSubscribe {
     }
 
     fn validate(&self) -> Result<()> {
+        if self.packet_id == 0 {
+            err!(ProtocolError, code: ProtocolError, "{} packet_id is ZERO", PP)?
+        }
         if self.filters.len() == 0 {
             err!(ProtocolError, code: ProtocolError, "{} topic filter missing", PP)?
         }
+        for filter in self.filters.iter() {
+            filter.validate()?;
+        }
 
         Ok(())
     }
@@ -112,6 +151,7 @@ impl UnSubscribe {
 
 /// Collection of MQTT properties allowed in UNSUBSCRIBE packet
 #[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "v5-serde", derive(Serialize, Deserialize))]
 pub struct UnSubscribeProperties {
     pub user_properties: Vec<UserProperty>,
 }
@@ -131,6 +171,17 @@ impl<'a> Arbitrary<'a> for UnSubscribeProperties {
 }
 
 impl UnSubscribeProperties {
+    // Return the length of properties, property-length excluded.
+    fn props_len(&self) -> Result<usize> {
+        let mut n = 0;
+
+        for uprop in self.user_properties.iter() {
+            prop_len!(n, UserProp, uprop);
+        }
+
+        Ok(n)
+    }
+
     #[cfg(any(feature = "fuzzy", test))]
     pub fn is_empty(&mut self) -> bool {
         self.user_properties.len() == 0
@@ -143,7 +194,8 @@ impl Packetize for UnSubscribeProperties {
 
         let stream: &[u8] = stream.as_ref();
 
-        let mut dups = [false; 256];
+        let mut check =
+            PropertyCheck::new(PP, PropertyScope::Packet(PacketType::UnSubscribe));
         let mut props = UnSubscribeProperties::default();
 
         let (len, mut n) = dec_field!(VarU32, stream, 0);
@@ -154,15 +206,12 @@ impl Packetize for UnSubscribeProperties {
             n = m;
 
             let pt = property.to_property_type();
-            if pt != PropertyType::UserProp && dups[pt as usize] {
-                err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", PP, pt)?
-            }
-            dups[pt as usize] = true;
+            check.check(pt)?;
 
             match property {
                 UserProp(val) => props.user_properties.push(val),
                 _ => {
-                    err!(ProtocolError, code: ProtocolError, "{} bad prop {:?}", PP, pt)?
+                    err!(MalformedPacket, code: MalformedPacket, "{} bad prop {:?}", PP, pt)?
                 }
             }
         }
@@ -171,16 +220,25 @@ impl Packetize for UnSubscribeProperties {
     }
 
     fn encode(&self) -> Result<Blob> {
-        use crate::v5::insert_property_len;
-
         let mut data = Vec::with_capacity(64);
+        self.encode_into(&mut data)?;
+        Ok(Blob::Large { data })
+    }
 
+    fn encode_into(&self, data: &mut Vec<u8>) -> Result<usize> {
+        let start = data.len();
+
+        VarU32(self.props_len()?.try_into()?).encode_into(data)?;
         for uprop in self.user_properties.iter() {
             enc_prop!(data, UserProp, uprop);
         }
 
-        data = insert_property_len(data.len(), data)?;
+        Ok(data.len() - start)
+    }
+
+    fn encoded_len(&self) -> Result<usize> {
+        use crate::v5::property_len;
 
-        Ok(Blob::Large { data })
+        property_len(self.props_len()?)
     }
 }
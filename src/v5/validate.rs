@@ -0,0 +1,204 @@
+//! Module implement validation rules, defined by MQTT v5 specification, that are
+//! common to all packets.
+//!
+//! * Properties allowed in each packet, refer to _Table 2-4_ in the specification.
+//! * Properties that may appear only once in a packet.
+//! * Reason codes allowed in each packet, refer to _Table 2-6_ in the specification.
+//!
+//! Packet specific rules, like reserved flag bits and invalid field values, are
+//! implemented by the packet's own `validate()` method. Either way, violations are
+//! reported as `MalformedPacket` or `ProtocolError`, as prescribed by the
+//! specification, and are checked while decoding as well as while encoding.
+
+use crate::v5::{PacketType, PropertyType};
+use crate::{Error, ErrorKind, ReasonCode, Result};
+
+/// Scope within which a property is carried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyScope {
+    /// Properties carried in the variable header of a packet.
+    Packet(PacketType),
+    /// Will-properties carried in the CONNECT payload.
+    Will,
+}
+
+impl PropertyType {
+    /// Return whether this property is allowed within `scope`.
+    pub fn is_allowed(&self, scope: PropertyScope) -> bool {
+        use PacketType as P;
+        use PropertyScope::{Packet, Will};
+        use PropertyType::*;
+
+        match self {
+            PayloadFormatIndicator
+            | MessageExpiryInterval
+            | ContentType
+            | ResponseTopic
+            | CorrelationData => {
+                matches!(scope, Packet(P::Publish) | Will)
+            }
+            SubscriptionIdentifier => {
+                matches!(scope, Packet(P::Publish) | Packet(P::Subscribe))
+            }
+            SessionExpiryInterval => matches!(
+                scope,
+                Packet(P::Connect) | Packet(P::ConnAck) | Packet(P::Disconnect)
+            ),
+            AssignedClientIdentifier
+            | ServerKeepAlive
+            | ResponseInformation
+            | MaximumQoS
+            | RetainAvailable
+            | WildcardSubscriptionAvailable
+            | SubscriptionIdentifierAvailable
+            | SharedSubscriptionAvailable => matches!(scope, Packet(P::ConnAck)),
+            AuthenticationMethod | AuthenticationData => {
+                matches!(scope, Packet(P::Connect) | Packet(P::ConnAck) | Packet(P::Auth))
+            }
+            RequestProblemInformation | RequestResponseInformation => {
+                matches!(scope, Packet(P::Connect))
+            }
+            WillDelayInterval => matches!(scope, Will),
+            ServerReference => {
+                matches!(scope, Packet(P::ConnAck) | Packet(P::Disconnect))
+            }
+            ReasonString => matches!(
+                scope,
+                Packet(P::ConnAck)
+                    | Packet(P::PubAck)
+                    | Packet(P::PubRec)
+                    | Packet(P::PubRel)
+                    | Packet(P::PubComp)
+                    | Packet(P::SubAck)
+                    | Packet(P::UnsubAck)
+                    | Packet(P::Disconnect)
+                    | Packet(P::Auth)
+            ),
+            ReceiveMaximum | TopicAliasMaximum | MaximumPacketSize => {
+                matches!(scope, Packet(P::Connect) | Packet(P::ConnAck))
+            }
+            TopicAlias => matches!(scope, Packet(P::Publish)),
+            UserProp => !matches!(scope, Packet(P::PingReq) | Packet(P::PingResp)),
+        }
+    }
+
+    /// Return whether this property can appear more than once within `scope`.
+    pub fn is_repeatable(&self, scope: PropertyScope) -> bool {
+        match self {
+            PropertyType::UserProp => true,
+            PropertyType::SubscriptionIdentifier => {
+                scope == PropertyScope::Packet(PacketType::Publish)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl ReasonCode {
+    /// Return whether this reason code is allowed in packet of type `pkt_type`.
+    pub fn is_allowed(&self, pkt_type: PacketType) -> bool {
+        use PacketType::*;
+        use ReasonCode as R;
+
+        match self {
+            R::Success => !matches!(
+                pkt_type,
+                Connect | Publish | Subscribe | UnSubscribe | PingReq | PingResp
+            ),
+            R::QoS1 | R::QoS2 => matches!(pkt_type, SubAck),
+            R::DiconnectWillMessage => matches!(pkt_type, Disconnect),
+            R::NoMatchingSubscribers => matches!(pkt_type, PubAck | PubRec),
+            R::NoSubscriptionExisted => matches!(pkt_type, UnsubAck),
+            R::ContinueAuthentication | R::ReAuthenticate => matches!(pkt_type, Auth),
+            R::UnspecifiedError | R::ImplementationError | R::NotAuthorized => {
+                matches!(
+                    pkt_type,
+                    ConnAck | PubAck | PubRec | SubAck | UnsubAck | Disconnect
+                )
+            }
+            R::MalformedPacket
+            | R::ProtocolError
+            | R::ServerBusy
+            | R::BadAuthenticationMethod
+            | R::PacketTooLarge
+            | R::RetainNotSupported
+            | R::QoSNotSupported
+            | R::UseAnotherServer
+            | R::ServerMoved
+            | R::ExceedConnectionRate => matches!(pkt_type, ConnAck | Disconnect),
+            R::UnsupportedProtocolVersion
+            | R::InvalidClientID
+            | R::BadLogin
+            | R::ServerNotAvailable
+            | R::Banned => matches!(pkt_type, ConnAck),
+            R::ServerShutdown
+            | R::KeepAliveTimeout
+            | R::SessionTakenOver
+            | R::ExceededReceiveMaximum
+            | R::TopicAliasInvalid
+            | R::ExceedMessageRate
+            | R::AdminAction
+            | R::ExceedMaximumConnectTime => matches!(pkt_type, Disconnect),
+            R::InvalidTopicFilter => matches!(pkt_type, SubAck | UnsubAck | Disconnect),
+            R::TopicNameInvalid | R::PayloadFormatInvalid => {
+                matches!(pkt_type, ConnAck | PubAck | PubRec | Disconnect)
+            }
+            R::PacketIdInuse => matches!(pkt_type, PubAck | PubRec | SubAck | UnsubAck),
+            R::PacketIdNotFound => matches!(pkt_type, PubRel | PubComp),
+            R::QuotaExceeded => {
+                matches!(pkt_type, ConnAck | PubAck | PubRec | SubAck | Disconnect)
+            }
+            R::UnsupportedSharedSubscription
+            | R::SubscriptionIdNotSupported
+            | R::WildcardSubscriptionsNotSupported => {
+                matches!(pkt_type, SubAck | Disconnect)
+            }
+        }
+    }
+}
+
+/// Check decoded properties against the property matrix, one property at a time.
+pub(crate) struct PropertyCheck {
+    pp: &'static str,
+    scope: PropertyScope,
+    seen: [bool; 64],
+}
+
+impl PropertyCheck {
+    pub fn new(pp: &'static str, scope: PropertyScope) -> PropertyCheck {
+        PropertyCheck { pp, scope, seen: [false; 64] }
+    }
+
+    pub fn check(&mut self, pt: PropertyType) -> Result<()> {
+        if !pt.is_allowed(self.scope) {
+            err!(
+                MalformedPacket,
+                code: MalformedPacket,
+                "{} prop {:?} not allowed in {:?}",
+                self.pp,
+                pt,
+                self.scope
+            )?
+        } else if self.seen[pt as usize] && !pt.is_repeatable(self.scope) {
+            err!(ProtocolError, code: ProtocolError, "{} repeat prop {:?}", self.pp, pt)?
+        }
+        self.seen[pt as usize] = true;
+
+        Ok(())
+    }
+}
+
+/// Properties with ZERO value, where ZERO is forbidden by the specification.
+pub(crate) fn check_non_zero<T>(pp: &str, pt: PropertyType, val: Option<T>) -> Result<()>
+where
+    T: Into<u64>,
+{
+    match val.map(|val| val.into()) {
+        Some(0) => err!(ProtocolError, code: ProtocolError, "{} {:?} is ZERO", pp, pt),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+#[path = "validate_test.rs"]
+mod validate_test;
@@ -0,0 +1,131 @@
+use super::*;
+use crate::v5::{self, Property, QoS};
+use crate::{Packetize, TopicFilter, TopicName, VarU32};
+
+fn encode_props(props: &[Property]) -> Vec<u8> {
+    let mut data = vec![];
+    for prop in props.iter() {
+        data.extend_from_slice(prop.encode().unwrap().as_ref());
+    }
+    let mut out = VarU32(data.len() as u32).encode().unwrap().as_ref().to_vec();
+    out.extend_from_slice(&data);
+    out
+}
+
+#[test]
+fn test_reason_code_table() {
+    use PacketType::*;
+
+    for val in 0..=255_u8 {
+        let code = ReasonCode::try_from(val).ok();
+        let allowed = |pkt_type| code.map(|c| c.is_allowed(pkt_type)).unwrap_or(false);
+
+        assert_eq!(
+            v5::ConnackReasonCode::try_from(val).is_ok(),
+            allowed(ConnAck),
+            "{}",
+            val
+        );
+        assert_eq!(
+            v5::SubAckReasonCode::try_from(val).is_ok(),
+            allowed(SubAck),
+            "{}",
+            val
+        );
+        assert_eq!(
+            v5::UnsubAckReasonCode::try_from(val).is_ok(),
+            allowed(UnsubAck),
+            "{}",
+            val
+        );
+        assert_eq!(
+            v5::DisconnReasonCode::try_from(val).is_ok(),
+            allowed(Disconnect),
+            "{}",
+            val
+        );
+        assert_eq!(v5::AuthReasonCode::try_from(val).is_ok(), allowed(Auth), "{}", val);
+    }
+}
+
+#[test]
+fn test_property_check() {
+    // not allowed in CONNACK.
+    let data = encode_props(&[Property::TopicAlias(10)]);
+    let err = v5::ConnAckProperties::decode(&data).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MalformedPacket);
+    assert_eq!(err.code(), ReasonCode::MalformedPacket);
+
+    // allowed only once.
+    let data =
+        encode_props(&[Property::ReceiveMaximum(10), Property::ReceiveMaximum(10)]);
+    let err = v5::ConnAckProperties::decode(&data).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ProtocolError);
+    assert_eq!(err.code(), ReasonCode::ProtocolError);
+
+    // subscription-identifier can repeat in PUBLISH, not in SUBSCRIBE.
+    let ids = [
+        Property::SubscriptionIdentifier(VarU32(1)),
+        Property::SubscriptionIdentifier(VarU32(2)),
+    ];
+    let data = encode_props(&ids);
+    let (props, _) = v5::PublishProperties::decode(&data).unwrap();
+    assert_eq!(props.subscribtion_identifier, vec![VarU32(1), VarU32(2)]);
+    let err = v5::SubscribeProperties::decode(&data).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ProtocolError);
+
+    // will-delay-interval is allowed only in will-properties.
+    let data = encode_props(&[Property::WillDelayInterval(10)]);
+    assert!(v5::WillProperties::decode(&data).is_ok());
+    let err = v5::PublishProperties::decode(&data).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::MalformedPacket);
+}
+
+#[test]
+fn test_encode_validate() {
+    let mut publ = v5::Publish {
+        retain: false,
+        qos: QoS::AtMostOnce,
+        duplicate: false,
+        topic_name: TopicName::from("a/b".to_string()),
+        packet_id: Some(1),
+        properties: None,
+        payload: None,
+    };
+    assert_eq!(publ.encode().unwrap_err().kind(), ErrorKind::MalformedPacket);
+    publ.packet_id = None;
+    publ.encode().unwrap();
+
+    publ.topic_name = TopicName::from("".to_string());
+    assert_eq!(publ.encode().unwrap_err().kind(), ErrorKind::ProtocolError);
+    publ.properties =
+        Some(v5::PublishProperties { topic_alias: Some(1), ..Default::default() });
+    let data = publ.encode().unwrap();
+    assert_eq!(v5::Publish::decode(&data).unwrap().0, publ);
+
+    let opt = v5::SubscriptionOpt::new(
+        v5::RetainForwardRule::Never,
+        false,
+        true,
+        QoS::AtLeastOnce,
+    );
+    let sub = v5::Subscribe {
+        packet_id: 1,
+        properties: None,
+        filters: vec![v5::SubscribeFilter {
+            topic_filter: TopicFilter::from("$share/g/a/b".to_string()),
+            opt,
+        }],
+    };
+    assert_eq!(sub.encode().unwrap_err().kind(), ErrorKind::ProtocolError);
+
+    // unchecked encoding crafts the malformed packets, rejected while decoding.
+    publ.topic_name = TopicName::from("a/b".to_string());
+    publ.properties = None;
+    publ.duplicate = true;
+    for pkt in [v5::Packet::Publish(publ), v5::Packet::Subscribe(sub)] {
+        let data = pkt.encode_unchecked().unwrap();
+        assert_eq!(pkt.encoded_len().unwrap(), data.as_ref().len());
+        assert!(v5::Packet::decode(data.as_ref()).is_err());
+    }
+}
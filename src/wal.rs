@@ -0,0 +1,751 @@
+//! Module implement write-ahead log for session state hosted by a shard.
+//!
+//! When [Config::session_store_dir] is configured, every [Shard][crate::Shard] logs
+//! the changes to its persistent sessions, that is sessions whose
+//! `session_expiry_interval` is non-ZERO, into a [SessionLog]:
+//!
+//! * Creation and removal of sessions, and when a client goes offline.
+//! * SUBSCRIBE and UNSUBSCRIBE, refer [Record::Subscribe], [Record::Unsubscribe].
+//! * Inflight QoS-1 PUBLISH sent to the client, until it is acknowledged with PUBACK,
+//!   refer [crate::message::ClientOut].
+//! * QoS-1 PUBLISH received from clients, until it is acknowledged by the subscribing
+//!   shard, refer [crate::message::ClientInp].
+//! * PUBLISH queued for offline sessions, until it is delivered, dropped or expired,
+//!   refer [crate::offline].
+//!
+//! When a shard is spawned, it replays the log to rebuild its sessions, as offline
+//! sessions along with their offline queues, and re-subscribes their topic-filters.
+//! Un-acknowledged PUBLISH messages
+//! are re-sent to the client, with DUP flag, once it reconnects with clean-start as
+//! ZERO, and messages pending with other shards are routed again. Hence clients can
+//! see duplicate messages after a restart, but no QoS-1 message is lost.
+//!
+//! **Log format**
+//!
+//! Log file is named `shard-<shard_id>.wal`, and uses the same record framing as
+//! [RetainLog][crate::RetainLog]. Records are checkpointed, by re-writing the live
+//! state into a new file, when the log is replayed and when the log out-grows the
+//! previous checkpoint.
+
+use log::{error, info, warn};
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::{fs, path, time};
+
+use crate::config::FsyncPolicy;
+use crate::session::SessionState;
+use crate::store::{decode_record, encode_record, is_partial_record, unix_secs};
+use crate::{v5, ClientID, Config, Message, PacketID, Packetize, TopicFilter};
+use crate::{Error, ErrorKind, Result};
+
+/// Single change to session state, logged by the shard.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    /// Persistent session created for `client_id`, if session already exists, its
+    /// `session_expiry_interval` is updated.
+    Session {
+        client_id: ClientID,
+        session_expiry_interval: u32,
+    },
+    /// Client went offline, `timestamp` is in seconds since UNIX_EPOCH.
+    Offline { client_id: ClientID, timestamp: u64 },
+    /// Session is removed, on clean-start or when it has expired.
+    Remove { client_id: ClientID },
+    /// Subscription added or replaced, refer [v5::Subscription::client_id].
+    Subscribe { subscription: v5::Subscription },
+    /// Subscription removed.
+    Unsubscribe {
+        client_id: ClientID,
+        topic_filter: TopicFilter,
+    },
+    /// PUBLISH sent to `client_id`, waiting for PUBACK.
+    Inflight {
+        client_id: ClientID,
+        seqno: u64,
+        packet_id: PacketID,
+        publish: v5::Publish,
+    },
+    /// PUBACK received from `client_id`.
+    Acked {
+        client_id: ClientID,
+        packet_id: PacketID,
+    },
+    /// PUBLISH received from `client_id` and routed to `subscriptions`, waiting for
+    /// the subscribing shard to acknowledge.
+    Unack {
+        seqno: u64,
+        client_id: ClientID,
+        subscriptions: Vec<v5::Subscription>,
+        publish: v5::Publish,
+    },
+    /// Subscribing shard has acknowledged the PUBLISH routed as `seqno`.
+    Routed { seqno: u64 },
+    /// PUBLISH routed by `shard_id` as `seqno`, from `client_id`, and queued as
+    /// `index` at `timestamp` for the offline session `subscriptions[0].client_id`.
+    Queued {
+        index: u64,
+        timestamp: u64,
+        shard_id: u32,
+        seqno: u64,
+        client_id: ClientID,
+        subscriptions: Vec<v5::Subscription>,
+        publish: v5::Publish,
+    },
+    /// PUBLISH queued as `index` is removed from the offline queue of `client_id`,
+    /// either delivered, dropped or expired.
+    Dequeued { client_id: ClientID, index: u64 },
+}
+
+impl Record {
+    const OP_SESSION: u8 = 1;
+    const OP_OFFLINE: u8 = 2;
+    const OP_REMOVE: u8 = 3;
+    const OP_SUBSCRIBE: u8 = 4;
+    const OP_UNSUBSCRIBE: u8 = 5;
+    const OP_INFLIGHT: u8 = 6;
+    const OP_ACKED: u8 = 7;
+    const OP_UNACK: u8 = 8;
+    const OP_ROUTED: u8 = 9;
+    const OP_QUEUED: u8 = 10;
+    const OP_DEQUEUED: u8 = 11;
+
+    // return (op, body)
+    pub(crate) fn encode(&self) -> Result<(u8, Vec<u8>)> {
+        let mut body = vec![];
+        let op = match self {
+            Record::Session { client_id, session_expiry_interval } => {
+                client_id.encode_into(&mut body)?;
+                session_expiry_interval.encode_into(&mut body)?;
+                Self::OP_SESSION
+            }
+            Record::Offline { client_id, timestamp } => {
+                client_id.encode_into(&mut body)?;
+                body.extend_from_slice(&timestamp.to_be_bytes());
+                Self::OP_OFFLINE
+            }
+            Record::Remove { client_id } => {
+                client_id.encode_into(&mut body)?;
+                Self::OP_REMOVE
+            }
+            Record::Subscribe { subscription } => {
+                encode_subscription(subscription, &mut body)?;
+                Self::OP_SUBSCRIBE
+            }
+            Record::Unsubscribe { client_id, topic_filter } => {
+                client_id.encode_into(&mut body)?;
+                topic_filter.encode_into(&mut body)?;
+                Self::OP_UNSUBSCRIBE
+            }
+            Record::Inflight { client_id, seqno, packet_id, publish } => {
+                client_id.encode_into(&mut body)?;
+                body.extend_from_slice(&seqno.to_be_bytes());
+                packet_id.encode_into(&mut body)?;
+                publish.encode_into(&mut body)?;
+                Self::OP_INFLIGHT
+            }
+            Record::Acked { client_id, packet_id } => {
+                client_id.encode_into(&mut body)?;
+                packet_id.encode_into(&mut body)?;
+                Self::OP_ACKED
+            }
+            Record::Unack { seqno, client_id, subscriptions, publish } => {
+                body.extend_from_slice(&seqno.to_be_bytes());
+                client_id.encode_into(&mut body)?;
+                u32::try_from(subscriptions.len())?.encode_into(&mut body)?;
+                for subscription in subscriptions.iter() {
+                    encode_subscription(subscription, &mut body)?;
+                }
+                publish.encode_into(&mut body)?;
+                Self::OP_UNACK
+            }
+            Record::Routed { seqno } => {
+                body.extend_from_slice(&seqno.to_be_bytes());
+                Self::OP_ROUTED
+            }
+            Record::Queued {
+                index,
+                timestamp,
+                shard_id,
+                seqno,
+                client_id,
+                subscriptions,
+                publish,
+            } => {
+                body.extend_from_slice(&index.to_be_bytes());
+                body.extend_from_slice(&timestamp.to_be_bytes());
+                shard_id.encode_into(&mut body)?;
+                body.extend_from_slice(&seqno.to_be_bytes());
+                client_id.encode_into(&mut body)?;
+                u32::try_from(subscriptions.len())?.encode_into(&mut body)?;
+                for subscription in subscriptions.iter() {
+                    encode_subscription(subscription, &mut body)?;
+                }
+                publish.encode_into(&mut body)?;
+                Self::OP_QUEUED
+            }
+            Record::Dequeued { client_id, index } => {
+                client_id.encode_into(&mut body)?;
+                body.extend_from_slice(&index.to_be_bytes());
+                Self::OP_DEQUEUED
+            }
+        };
+
+        Ok((op, body))
+    }
+
+    pub(crate) fn decode(op: u8, body: &[u8]) -> Result<Record> {
+        let mut n = 0;
+        let record = match op {
+            Self::OP_SESSION => Record::Session {
+                client_id: decode_field(body, &mut n)?,
+                session_expiry_interval: decode_field(body, &mut n)?,
+            },
+            Self::OP_OFFLINE => Record::Offline {
+                client_id: decode_field(body, &mut n)?,
+                timestamp: decode_u64(body, &mut n)?,
+            },
+            Self::OP_REMOVE => Record::Remove { client_id: decode_field(body, &mut n)? },
+            Self::OP_SUBSCRIBE => {
+                Record::Subscribe { subscription: decode_subscription(body, &mut n)? }
+            }
+            Self::OP_UNSUBSCRIBE => Record::Unsubscribe {
+                client_id: decode_field(body, &mut n)?,
+                topic_filter: decode_field(body, &mut n)?,
+            },
+            Self::OP_INFLIGHT => Record::Inflight {
+                client_id: decode_field(body, &mut n)?,
+                seqno: decode_u64(body, &mut n)?,
+                packet_id: decode_field(body, &mut n)?,
+                publish: decode_field(body, &mut n)?,
+            },
+            Self::OP_ACKED => Record::Acked {
+                client_id: decode_field(body, &mut n)?,
+                packet_id: decode_field(body, &mut n)?,
+            },
+            Self::OP_UNACK => {
+                let seqno = decode_u64(body, &mut n)?;
+                let client_id = decode_field(body, &mut n)?;
+                let count: u32 = decode_field(body, &mut n)?;
+                let mut subscriptions = vec![];
+                for _ in 0..count {
+                    subscriptions.push(decode_subscription(body, &mut n)?);
+                }
+                let publish = decode_field(body, &mut n)?;
+                Record::Unack { seqno, client_id, subscriptions, publish }
+            }
+            Self::OP_ROUTED => Record::Routed { seqno: decode_u64(body, &mut n)? },
+            Self::OP_QUEUED => {
+                let index = decode_u64(body, &mut n)?;
+                let timestamp = decode_u64(body, &mut n)?;
+                let shard_id = decode_field(body, &mut n)?;
+                let seqno = decode_u64(body, &mut n)?;
+                let client_id = decode_field(body, &mut n)?;
+                let count: u32 = decode_field(body, &mut n)?;
+                let mut subscriptions = vec![];
+                for _ in 0..count {
+                    subscriptions.push(decode_subscription(body, &mut n)?);
+                }
+                let publish = decode_field(body, &mut n)?;
+                Record::Queued {
+                    index,
+                    timestamp,
+                    shard_id,
+                    seqno,
+                    client_id,
+                    subscriptions,
+                    publish,
+                }
+            }
+            Self::OP_DEQUEUED => Record::Dequeued {
+                client_id: decode_field(body, &mut n)?,
+                index: decode_u64(body, &mut n)?,
+            },
+            op => err!(InvalidInput, desc: "invalid session log op {}", op)?,
+        };
+
+        Ok(record)
+    }
+}
+
+/// Session state rebuilt by replaying the [SessionLog].
+#[derive(Default)]
+pub struct Replay {
+    /// Persistent sessions, all of them are offline after a restart.
+    pub sessions: BTreeMap<ClientID, SessionState>,
+    /// Un-acknowledged PUBLISH messages received by this shard, indexed by
+    /// [crate::message::ClientInp::seqno].
+    pub unacks: BTreeMap<u64, Message>,
+    /// Messages queued for offline sessions, as (timestamp, message) indexed by
+    /// [Record::Queued] index.
+    pub queued: BTreeMap<ClientID, BTreeMap<u64, (u64, Message)>>,
+}
+
+impl Replay {
+    /// Apply `records` in the order they were logged, by shard `shard_id`.
+    pub fn from_records(shard_id: u32, records: Vec<Record>) -> Replay {
+        let mut val = Replay::default();
+        let now = unix_secs();
+
+        for record in records.into_iter() {
+            val.apply(shard_id, record, now);
+        }
+
+        val.sessions.retain(|_, state| !state.is_expired(now));
+        let sessions = &val.sessions;
+        val.queued.retain(|client_id, _| sessions.contains_key(client_id));
+        val
+    }
+
+    /// Apply a single `record`, logged by shard `shard_id`, `now` is in seconds since
+    /// UNIX_EPOCH.
+    pub fn apply(&mut self, shard_id: u32, record: Record, now: u64) {
+        match record {
+            Record::Session { client_id, session_expiry_interval } => {
+                self.sessions
+                    .entry(client_id.clone())
+                    .or_insert_with(|| SessionState::new(client_id, now))
+                    .session_expiry_interval = session_expiry_interval;
+            }
+            Record::Offline { client_id, timestamp } => {
+                if let Some(state) = self.sessions.get_mut(&client_id) {
+                    state.offline_at = timestamp;
+                }
+            }
+            Record::Remove { client_id } => {
+                self.sessions.remove(&client_id);
+                self.queued.remove(&client_id);
+            }
+            Record::Subscribe { mut subscription } => {
+                if let Some(state) = self.sessions.get_mut(&subscription.client_id) {
+                    subscription.shard_id = shard_id;
+                    let topic_filter = subscription.topic_filter.clone();
+                    state.subscriptions.insert(topic_filter, subscription);
+                }
+            }
+            Record::Unsubscribe { client_id, topic_filter } => {
+                if let Some(state) = self.sessions.get_mut(&client_id) {
+                    state.subscriptions.remove(&topic_filter);
+                }
+            }
+            Record::Inflight { client_id, seqno, packet_id, publish } => {
+                if let Some(state) = self.sessions.get_mut(&client_id) {
+                    let msg = Message::Packet {
+                        client_id,
+                        shard_id,
+                        seqno,
+                        packet_id,
+                        subscriptions: Vec::new(),
+                        packet: v5::Packet::Publish(publish),
+                    };
+                    state.cout.index.insert(packet_id, msg);
+                    state.cout.seqno = state.cout.seqno.max(seqno.saturating_add(1));
+                    state.cout.next_packet_id = match packet_id.wrapping_add(1) {
+                        0 => 1,
+                        n => n,
+                    };
+                }
+            }
+            Record::Acked { client_id, packet_id } => {
+                if let Some(state) = self.sessions.get_mut(&client_id) {
+                    state.cout.index.remove(&packet_id);
+                }
+            }
+            Record::Unack { seqno, client_id, subscriptions, publish } => {
+                let msg = Message::Packet {
+                    client_id,
+                    shard_id,
+                    seqno,
+                    packet_id: PacketID::default(),
+                    subscriptions,
+                    packet: v5::Packet::Publish(publish),
+                };
+                self.unacks.insert(seqno, msg);
+            }
+            Record::Routed { seqno } => {
+                self.unacks.remove(&seqno);
+            }
+            Record::Queued {
+                index,
+                timestamp,
+                shard_id,
+                seqno,
+                client_id,
+                subscriptions,
+                publish,
+            } => {
+                let session = subscriptions.first().map(|s| s.client_id.clone());
+                match session {
+                    Some(session) if self.sessions.contains_key(&session) => {
+                        let msg = Message::Packet {
+                            client_id,
+                            shard_id,
+                            seqno,
+                            packet_id: PacketID::default(),
+                            subscriptions,
+                            packet: v5::Packet::Publish(publish),
+                        };
+                        let queued = self.queued.entry(session).or_default();
+                        queued.insert(index, (timestamp, msg));
+                    }
+                    Some(_) | None => (),
+                }
+            }
+            Record::Dequeued { client_id, index } => {
+                if let Some(queued) = self.queued.get_mut(&client_id) {
+                    queued.remove(&index);
+                    if queued.is_empty() {
+                        self.queued.remove(&client_id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the records to checkpoint the replayed state.
+    pub fn to_records(&self) -> Vec<Record> {
+        let mut records = vec![];
+        for state in self.sessions.values() {
+            records.extend(state.to_records());
+        }
+        for queued in self.queued.values() {
+            for (index, (timestamp, msg)) in queued.iter() {
+                records.extend(Record::from_queued(*index, *timestamp, msg));
+            }
+        }
+        for msg in self.unacks.values() {
+            records.extend(Record::from_unack(msg));
+        }
+        records
+    }
+}
+
+impl Record {
+    /// Return [Record::Unack] for `msg` booked in [crate::message::ClientInp].
+    pub fn from_unack(msg: &Message) -> Option<Record> {
+        match msg {
+            Message::Packet {
+                client_id,
+                seqno,
+                subscriptions,
+                packet: v5::Packet::Publish(publish),
+                ..
+            } => Some(Record::Unack {
+                seqno: *seqno,
+                client_id: client_id.clone(),
+                subscriptions: subscriptions.clone(),
+                publish: publish.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Return [Record::Queued] for routed `msg`, queued as `index` at `timestamp`.
+    pub fn from_queued(index: u64, timestamp: u64, msg: &Message) -> Option<Record> {
+        match msg {
+            Message::Packet {
+                client_id,
+                shard_id,
+                seqno,
+                subscriptions,
+                packet: v5::Packet::Publish(publish),
+                ..
+            } => Some(Record::Queued {
+                index,
+                timestamp,
+                shard_id: *shard_id,
+                seqno: *seqno,
+                client_id: client_id.clone(),
+                subscriptions: subscriptions.clone(),
+                publish: publish.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Write-ahead log of session state for a single shard, refer to module
+/// documentation for details.
+pub struct SessionLog {
+    loc: path::PathBuf,
+    file: fs::File,
+    fsync: FsyncPolicy,
+    fsync_interval: time::Duration,
+    last_fsync: time::Instant,
+    dirty: bool,
+
+    // size of the log file, in bytes.
+    size: u64,
+    // size of the log file, in bytes, after the last checkpoint.
+    checkpoint_size: u64,
+}
+
+impl SessionLog {
+    /// Checkpoint is not attempted, if the log is smaller than this size.
+    pub const CHECKPOINT_MIN_SIZE: u64 = 1024 * 1024;
+
+    /// Return the session log for shard `shard_id`, if configured.
+    pub fn from_config(config: &Config, shard_id: u32) -> Result<Option<SessionLog>> {
+        match config.session_store_dir() {
+            Some(dir) => Ok(Some(SessionLog::open(config, dir, shard_id)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Open the log for shard `shard_id` under directory `dir`, create the
+    /// directory and log file if they are missing.
+    pub fn open<P>(config: &Config, dir: P, shard_id: u32) -> Result<SessionLog>
+    where
+        P: AsRef<path::Path>,
+    {
+        let dir: &path::Path = dir.as_ref();
+        err!(IOError, try: fs::create_dir_all(dir), "create session store {:?}", dir)?;
+
+        let loc = dir.join(format!("shard-{}.wal", shard_id));
+        let file = Self::open_file(&loc)?;
+        let size = err!(IOError, try: file.metadata(), "metadata {:?}", loc)?.len();
+
+        let val = SessionLog {
+            loc,
+            file,
+            fsync: config.session_store_fsync(),
+            fsync_interval: time::Duration::from_secs(
+                config.session_store_fsync_interval().into(),
+            ),
+            last_fsync: time::Instant::now(),
+            dirty: false,
+
+            size,
+            checkpoint_size: size,
+        };
+
+        Ok(val)
+    }
+
+    /// Return the location of the log file.
+    pub fn to_location(&self) -> path::PathBuf {
+        self.loc.clone()
+    }
+
+    /// Return the size of the log file, in bytes.
+    pub fn to_size(&self) -> u64 {
+        self.size
+    }
+
+    /// Return all the records in the log. A partial record at the tail of the log is
+    /// truncated, a corrupt record anywhere else fails the load.
+    pub fn load(&mut self) -> Result<Vec<Record>> {
+        let data = err!(IOError, try: fs::read(&self.loc), "read {:?}", self.loc)?;
+
+        let mut records = vec![];
+        let mut offset = 0;
+        while offset < data.len() {
+            let (op, body, n) = match decode_record(&data[offset..]) {
+                Some(val) => val,
+                None if !is_partial_record(&data[offset..]) => err!(
+                    InvalidInput,
+                    desc: "{:?} corrupt record at {}/{}",
+                    self.loc,
+                    offset,
+                    data.len()
+                )?,
+                None => {
+                    warn!(
+                        "{:?} truncating partial record at {}/{}",
+                        self.loc,
+                        offset,
+                        data.len()
+                    );
+                    err!(IOError, try: self.file.set_len(offset as u64))?;
+                    err!(IOError, try: self.file.sync_all(), "fsync {:?}", self.loc)?;
+                    break;
+                }
+            };
+            records.push(Record::decode(op, body)?);
+            offset += n;
+        }
+
+        self.size = offset as u64;
+        self.checkpoint_size = self.size;
+
+        Ok(records)
+    }
+
+    /// Append `records` to the log.
+    pub fn append(&mut self, records: &[Record]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let data = Self::encode_records(records)?;
+        err!(IOError, try: self.file.write_all(&data), "append {:?}", self.loc)?;
+        self.size += data.len() as u64;
+        self.dirty = true;
+
+        if let FsyncPolicy::Always = self.fsync {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Return whether the log has out-grown its previous checkpoint.
+    pub fn need_checkpoint(&self) -> bool {
+        self.size >= Self::CHECKPOINT_MIN_SIZE && self.size > (self.checkpoint_size * 2)
+    }
+
+    /// Replace the log with `records`, that shall capture the live state of the
+    /// shard.
+    pub fn checkpoint(&mut self, records: &[Record]) -> Result<()> {
+        let tmp_loc = self.loc.with_extension("checkpoint");
+
+        let data = Self::encode_records(records)?;
+        {
+            let mut tmp =
+                err!(IOError, try: fs::File::create(&tmp_loc), "{:?}", tmp_loc)?;
+            err!(IOError, try: tmp.write_all(&data), "write {:?}", tmp_loc)?;
+            err!(IOError, try: tmp.sync_all(), "fsync {:?}", tmp_loc)?;
+        }
+
+        err!(IOError, try: fs::rename(&tmp_loc, &self.loc), "rename {:?}", tmp_loc)?;
+        if let Some(dir) = self.loc.parent() {
+            err!(IOError, try: fs::File::open(dir).and_then(|d| d.sync_all()))?;
+        }
+
+        info!(
+            "{:?} checkpoint {} bytes to {} bytes, {} records",
+            self.loc,
+            self.size,
+            data.len(),
+            records.len()
+        );
+
+        self.file = Self::open_file(&self.loc)?;
+        self.size = data.len() as u64;
+        self.checkpoint_size = self.size;
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Called periodically by the shard, to persist pending writes as per the fsync
+    /// policy.
+    pub fn flush(&mut self) -> Result<()> {
+        let interval = matches!(self.fsync, FsyncPolicy::Interval);
+        if self.dirty && interval && self.last_fsync.elapsed() >= self.fsync_interval {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// Persist all pending writes.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.dirty {
+            err!(IOError, try: self.file.sync_data(), "fsync {:?}", self.loc)?;
+            self.dirty = false;
+        }
+        self.last_fsync = time::Instant::now();
+
+        Ok(())
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            error!("{:?} sync on drop: {}", self.loc, err);
+        }
+    }
+}
+
+impl SessionLog {
+    fn open_file(loc: &path::Path) -> Result<fs::File> {
+        let mut opts = fs::OpenOptions::new();
+        let file = opts.read(true).append(true).create(true).open(loc);
+        err!(IOError, try: file, "open session log {:?}", loc)
+    }
+
+    fn encode_records(records: &[Record]) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        for record in records.iter() {
+            let (op, body) = record.encode()?;
+            data.extend(encode_record(op, &body)?);
+        }
+        Ok(data)
+    }
+}
+
+pub(crate) fn encode_subscription(
+    subscr: &v5::Subscription,
+    body: &mut Vec<u8>,
+) -> Result<()> {
+    subscr.topic_filter.encode_into(body)?;
+    subscr.client_id.encode_into(body)?;
+    subscr.shard_id.encode_into(body)?;
+    match subscr.subscription_id {
+        Some(id) => {
+            1_u8.encode_into(body)?;
+            id.encode_into(body)?;
+        }
+        None => {
+            0_u8.encode_into(body)?;
+        }
+    }
+    let opt = v5::SubscriptionOpt::new(
+        subscr.retain_forward_rule.clone(),
+        subscr.retain_as_published,
+        subscr.no_local,
+        subscr.qos,
+    );
+    opt.encode_into(body)?;
+
+    Ok(())
+}
+
+pub(crate) fn decode_subscription(
+    body: &[u8],
+    n: &mut usize,
+) -> Result<v5::Subscription> {
+    let topic_filter = decode_field(body, n)?;
+    let client_id = decode_field(body, n)?;
+    let shard_id = decode_field(body, n)?;
+    let subscription_id = match decode_field::<u8>(body, n)? {
+        0 => None,
+        _ => Some(decode_field(body, n)?),
+    };
+    let opt: v5::SubscriptionOpt = decode_field(body, n)?;
+    let (retain_forward_rule, retain_as_published, no_local, qos) = opt.unwrap();
+
+    let val = v5::Subscription {
+        topic_filter,
+        client_id,
+        shard_id,
+        subscription_id,
+        qos,
+        no_local,
+        retain_as_published,
+        retain_forward_rule,
+    };
+    Ok(val)
+}
+
+pub(crate) fn decode_field<T: Packetize>(body: &[u8], n: &mut usize) -> Result<T> {
+    let (val, m) = T::decode(&body[*n..])?;
+    *n += m;
+    Ok(val)
+}
+
+pub(crate) fn decode_u64(body: &[u8], n: &mut usize) -> Result<u64> {
+    match body.get(*n..*n + 8) {
+        Some(bytes) => {
+            *n += 8;
+            Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+        }
+        None => err!(InvalidInput, desc: "insufficient bytes for u64"),
+    }
+}
+
+#[cfg(test)]
+#[path = "wal_test.rs"]
+mod wal_test;
@@ -0,0 +1,131 @@
+use super::*;
+
+use crate::fixtures::{client_id, new_publish, new_subscription, temp_dir};
+
+fn new_records() -> Vec<Record> {
+    let (a, b) = (client_id("a"), client_id("b"));
+    vec![
+        Record::Session {
+            client_id: a.clone(),
+            session_expiry_interval: 3600,
+        },
+        Record::Session { client_id: b.clone(), session_expiry_interval: 1 },
+        Record::Offline { client_id: b.clone(), timestamp: 0 },
+        Record::Subscribe { subscription: new_subscription("a", "x/+") },
+        Record::Subscribe { subscription: new_subscription("a", "y/#") },
+        Record::Unsubscribe {
+            client_id: a.clone(),
+            topic_filter: "y/#".to_string().into(),
+        },
+        Record::Inflight {
+            client_id: a.clone(),
+            seqno: 7,
+            packet_id: 3,
+            publish: new_publish("x/1", v5::QoS::AtLeastOnce, Some(3)),
+        },
+        Record::Inflight {
+            client_id: a.clone(),
+            seqno: 8,
+            packet_id: 4,
+            publish: new_publish("x/2", v5::QoS::AtLeastOnce, Some(4)),
+        },
+        Record::Acked { client_id: a.clone(), packet_id: 3 },
+        Record::Unack {
+            seqno: 1,
+            client_id: a.clone(),
+            subscriptions: vec![new_subscription("c", "x/+")],
+            publish: new_publish("x/3", v5::QoS::AtLeastOnce, Some(1)),
+        },
+        Record::Unack {
+            seqno: 2,
+            client_id: a,
+            subscriptions: vec![new_subscription("c", "x/+")],
+            publish: new_publish("x/4", v5::QoS::AtLeastOnce, Some(2)),
+        },
+        Record::Routed { seqno: 1 },
+        Record::Queued {
+            index: 0,
+            timestamp: 10,
+            shard_id: 2,
+            seqno: 5,
+            client_id: client_id("d"),
+            subscriptions: vec![new_subscription("a", "x/+")],
+            publish: new_publish("x/5", v5::QoS::AtLeastOnce, Some(5)),
+        },
+        Record::Queued {
+            index: 1,
+            timestamp: 11,
+            shard_id: 2,
+            seqno: 6,
+            client_id: client_id("d"),
+            subscriptions: vec![new_subscription("a", "x/+")],
+            publish: new_publish("x/6", v5::QoS::AtLeastOnce, Some(6)),
+        },
+        Record::Dequeued { client_id: client_id("a"), index: 0 },
+        Record::Remove { client_id: client_id("c") },
+    ]
+}
+
+#[test]
+fn test_session_log() {
+    let dir = temp_dir("test_session_log");
+    let config = Config {
+        session_store_fsync: Some(FsyncPolicy::Always),
+        ..Config::default()
+    };
+
+    let records = new_records();
+    let size = {
+        let mut log = SessionLog::open(&config, &dir, 1).unwrap();
+        assert_eq!(log.load().unwrap(), vec![]);
+        log.append(&records[..4]).unwrap();
+        log.append(&records[4..]).unwrap();
+        log.to_size()
+    };
+
+    // leave a partial record at the tail.
+    let loc = dir.join("shard-1.wal");
+    {
+        let mut file = fs::OpenOptions::new().append(true).open(&loc).unwrap();
+        file.write_all(&[0, 0, 0, 100, 1, 2, 3, 4, 1]).unwrap();
+    }
+    let mut log = SessionLog::open(&config, &dir, 1).unwrap();
+    assert_eq!(log.load().unwrap(), records);
+    assert_eq!(fs::metadata(&loc).unwrap().len(), size);
+
+    // corrupt a record in the middle of the log, load shall fail without truncating.
+    {
+        let mut data = fs::read(&loc).unwrap();
+        data[9] ^= 0xFF;
+        fs::write(&loc, &data).unwrap();
+        assert!(SessionLog::open(&config, &dir, 1).unwrap().load().is_err());
+        assert_eq!(fs::metadata(&loc).unwrap().len(), size);
+        data[9] ^= 0xFF;
+        fs::write(&loc, &data).unwrap();
+    }
+
+    // session `b` has expired.
+    let replay = Replay::from_records(1, records);
+    assert_eq!(replay.sessions.keys().cloned().collect::<Vec<_>>(), vec![client_id("a")]);
+
+    let state = &replay.sessions[&client_id("a")];
+    assert_eq!(state.session_expiry_interval, 3600);
+    let filters: Vec<&str> = state.subscriptions.keys().map(|f| f.as_str()).collect();
+    assert_eq!(filters, vec!["x/+"]);
+    assert_eq!(state.subscriptions.values().next().unwrap().shard_id, 1);
+    assert_eq!(state.cout.index.keys().cloned().collect::<Vec<_>>(), vec![4]);
+    assert_eq!((state.cout.seqno, state.cout.next_packet_id), (9, 5));
+    assert_eq!(replay.unacks.keys().cloned().collect::<Vec<_>>(), vec![2]);
+    let queued = &replay.queued[&client_id("a")];
+    assert_eq!(queued.keys().cloned().collect::<Vec<_>>(), vec![1]);
+    assert!(matches!(queued[&1], (11, Message::Packet { seqno: 6, shard_id: 2, .. })));
+
+    // checkpoint shall capture the same state.
+    log.checkpoint(&replay.to_records()).unwrap();
+    let records = SessionLog::open(&config, &dir, 1).unwrap().load().unwrap();
+    let replay2 = Replay::from_records(1, records);
+    assert_eq!(replay.to_records(), replay2.to_records());
+    assert!(log.to_size() < size, "{} {}", log.to_size(), size);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
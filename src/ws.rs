@@ -0,0 +1,372 @@
+//! Module implement WebSocket transport for client connections, refer
+//! [Transport::Ws][crate::Transport::Ws].
+//!
+//! HTTP upgrade request is read along with the first bytes from the connection, so
+//! that listener threads handle it along with the CONNECT handshake, within the same
+//! connect-timeout. There after, MQTT packets are carried as a byte stream in binary
+//! frames, RFC 6455 and MQTT-6.0.0.
+//!
+//! * Client frames must be masked, and must be binary or continuation frames. Any
+//!   other data frame closes the connection.
+//! * PING is answered with PONG, CLOSE is answered with CLOSE and the connection is
+//!   treated as closed by the client.
+//! * Subprotocol `mqtt` is selected, when offered by the client.
+//! * Every write is sent as a single, unmasked, binary frame. Frames that can't be
+//!   written without blocking are kept with the connection. They are written before
+//!   any more data is accepted from the caller.
+
+use std::{cell::RefCell, io, io::Read, io::Write};
+
+/// Type implement a server side WebSocket connection, over a non-blocking TCP stream.
+pub struct WsStream {
+    conn: mio::net::TcpStream,
+    // reads and writes are made via shared reference, refer [crate::Stream].
+    inner: RefCell<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // HTTP upgrade request is read and answered.
+    upgraded: bool,
+    // CLOSE frame is received.
+    closed: bool,
+    // Bytes read from the connection, yet to be decoded.
+    rbuf: Vec<u8>,
+    // Data frame being read, its masking key and remaining payload.
+    frame: Option<Frame>,
+    // Upgrade response and frames, yet to be written to the connection.
+    wbuf: Vec<u8>,
+}
+
+struct Frame {
+    mask: [u8; 4],
+    offset: usize,
+    remaining: u64,
+}
+
+struct Header {
+    fin: bool,
+    opcode: u8,
+    mask: [u8; 4],
+    // payload length.
+    len: u64,
+    // header length.
+    size: usize,
+}
+
+impl WsStream {
+    /// Maximum size of the HTTP upgrade request, including headers.
+    pub const MAX_REQUEST_SIZE: usize = 8192;
+
+    const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+    const OP_CONTINUATION: u8 = 0x0;
+    const OP_BINARY: u8 = 0x2;
+    const OP_CLOSE: u8 = 0x8;
+    const OP_PING: u8 = 0x9;
+    const OP_PONG: u8 = 0xA;
+
+    /// Wrap an accepted TCP connection, HTTP upgrade shall happen as and when data is
+    /// read from the connection.
+    pub fn new(conn: mio::net::TcpStream) -> WsStream {
+        WsStream { conn, inner: RefCell::new(Inner::default()) }
+    }
+
+    fn read_payload(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+        loop {
+            // upgrade response, PONG and CLOSE, from previous calls.
+            match inner.flush(&self.conn) {
+                Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+                _ => (),
+            }
+
+            if inner.closed {
+                break Ok(0);
+            } else if !inner.upgraded {
+                if inner.upgrade()? {
+                    continue;
+                }
+            } else if let Some(n) = inner.decode(buf)? {
+                if inner.closed {
+                    inner.flush(&self.conn).ok();
+                }
+                break Ok(n);
+            }
+
+            let mut data = [0; 4096];
+            match (&self.conn).read(&mut data) {
+                Ok(0) => break Ok(0),
+                Ok(n) => inner.rbuf.extend_from_slice(&data[..n]),
+                Err(err) => break Err(err),
+            }
+        }
+    }
+
+    fn write_payload(&self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let mut inner = self.inner.borrow_mut();
+        if !inner.upgraded {
+            return Err(io::Error::from(io::ErrorKind::WouldBlock));
+        }
+        inner.flush(&self.conn)?;
+
+        let n: usize = bufs.iter().map(|buf| buf.len()).sum();
+        if n == 0 {
+            return Ok(0);
+        }
+        encode_header(Self::OP_BINARY, n, &mut inner.wbuf);
+        for buf in bufs.iter() {
+            inner.wbuf.extend_from_slice(buf);
+        }
+        match inner.flush(&self.conn) {
+            Ok(()) => Ok(n),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Inner {
+    // Return true once the upgrade request is read and answered, false if more
+    // bytes are needed.
+    fn upgrade(&mut self) -> io::Result<bool> {
+        let end = match self.rbuf.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(end) => end + 4,
+            None if self.rbuf.len() > WsStream::MAX_REQUEST_SIZE => {
+                return Err(invalid_data("upgrade request too large"))
+            }
+            None => return Ok(false),
+        };
+
+        let request = match std::str::from_utf8(&self.rbuf[..end]) {
+            Ok(request) => request,
+            Err(_) => return Err(invalid_data("upgrade request not utf8")),
+        };
+        let mut lines = request.split("\r\n");
+        match lines.next() {
+            Some(line) if line.starts_with("GET ") => (),
+            _ => return Err(invalid_data("upgrade request not GET")),
+        }
+
+        let (mut upgrade, mut key, mut mqtt) = (false, None, false);
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("upgrade") {
+                upgrade = value.eq_ignore_ascii_case("websocket");
+            } else if name.eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.to_string());
+            } else if name.eq_ignore_ascii_case("sec-websocket-protocol") {
+                mqtt = mqtt || value.split(',').any(|p| p.trim() == "mqtt");
+            }
+        }
+        let key = match key {
+            Some(key) if upgrade => key,
+            _ => return Err(invalid_data("not a websocket upgrade request")),
+        };
+
+        let mut response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n",
+            accept_key(&key)
+        );
+        if mqtt {
+            response.push_str("Sec-WebSocket-Protocol: mqtt\r\n");
+        }
+        response.push_str("\r\n");
+
+        self.wbuf.extend_from_slice(response.as_bytes());
+        self.rbuf.drain(..end);
+        self.upgraded = true;
+        Ok(true)
+    }
+
+    // Copy payload of data frames into `buf`, handling control frames on the way.
+    // Return None if more bytes are needed.
+    fn decode(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        loop {
+            if let Some(frame) = self.frame.as_mut() {
+                let n =
+                    (frame.remaining.min(buf.len() as u64) as usize).min(self.rbuf.len());
+                if n == 0 && frame.remaining > 0 {
+                    return Ok(None);
+                }
+                for (i, (dst, src)) in
+                    buf.iter_mut().zip(self.rbuf.drain(..n)).enumerate()
+                {
+                    *dst = src ^ frame.mask[(frame.offset + i) % 4];
+                }
+                frame.offset += n;
+                frame.remaining -= n as u64;
+                if frame.remaining == 0 {
+                    self.frame = None;
+                }
+                match n {
+                    0 => continue,
+                    n => return Ok(Some(n)),
+                }
+            }
+
+            let Header { fin, opcode, mask, len, size } = match decode_header(&self.rbuf)?
+            {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+            match opcode {
+                WsStream::OP_CONTINUATION | WsStream::OP_BINARY => {
+                    self.rbuf.drain(..size);
+                    self.frame = Some(Frame { mask, offset: 0, remaining: len });
+                }
+                WsStream::OP_CLOSE | WsStream::OP_PING | WsStream::OP_PONG => {
+                    // control frames are not fragmented, and are at most 125 bytes.
+                    if !fin || len > 125 {
+                        return Err(invalid_data("invalid control frame"));
+                    }
+                    let end = size + len as usize;
+                    if self.rbuf.len() < end {
+                        return Ok(None);
+                    }
+                    let mut payload: Vec<u8> =
+                        self.rbuf.drain(..end).skip(size).collect();
+                    for (i, byte) in payload.iter_mut().enumerate() {
+                        *byte ^= mask[i % 4];
+                    }
+                    let reply = match opcode {
+                        WsStream::OP_CLOSE => WsStream::OP_CLOSE,
+                        WsStream::OP_PING => WsStream::OP_PONG,
+                        _ => continue,
+                    };
+                    encode_header(reply, payload.len(), &mut self.wbuf);
+                    self.wbuf.extend_from_slice(&payload);
+                    if opcode == WsStream::OP_CLOSE {
+                        self.closed = true;
+                        return Ok(Some(0));
+                    }
+                }
+                opcode => {
+                    return Err(invalid_data(&format!(
+                        "unexpected frame opcode {}",
+                        opcode
+                    )))
+                }
+            }
+        }
+    }
+
+    // Write pending bytes, return WouldBlock if they are not written in full.
+    fn flush(&mut self, mut conn: &mio::net::TcpStream) -> io::Result<()> {
+        while !self.wbuf.is_empty() {
+            match conn.write(&self.wbuf)? {
+                0 => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                n => self.wbuf.drain(..n),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl io::Read for &WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_payload(buf)
+    }
+}
+
+impl io::Write for &WsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_payload(&[io::IoSlice::new(buf)])
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.write_payload(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.borrow_mut().flush(&self.conn)
+    }
+}
+
+impl mio::event::Source for WsStream {
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.conn.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> io::Result<()> {
+        self.conn.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> io::Result<()> {
+        self.conn.deregister(registry)
+    }
+}
+
+// Return Sec-WebSocket-Accept for the client's Sec-WebSocket-Key.
+fn accept_key(key: &str) -> String {
+    use base64::Engine;
+
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WsStream::GUID.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(sha1.digest().bytes())
+}
+
+// Return None if more bytes are needed.
+fn decode_header(data: &[u8]) -> io::Result<Option<Header>> {
+    let (b0, b1) = match data {
+        [b0, b1, ..] => (*b0, *b1),
+        _ => return Ok(None),
+    };
+    if b0 & 0x70 != 0 {
+        return Err(invalid_data("frame with reserved bits"));
+    } else if b1 & 0x80 == 0 {
+        return Err(invalid_data("unmasked client frame"));
+    }
+
+    let (len, n) = match b1 & 0x7F {
+        126 if data.len() >= 4 => (u16::from_be_bytes([data[2], data[3]]) as u64, 4),
+        127 if data.len() >= 10 => {
+            (u64::from_be_bytes(data[2..10].try_into().unwrap()), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (u64::from(len), 2),
+    };
+    let mask = match data.get(n..n + 4) {
+        Some(mask) => mask.try_into().unwrap(),
+        None => return Ok(None),
+    };
+
+    let (fin, opcode) = (b0 & 0x80 != 0, b0 & 0x0F);
+    Ok(Some(Header { fin, opcode, mask, len, size: n + 4 }))
+}
+
+// Append a final, unmasked, frame header for `len` bytes of payload.
+fn encode_header(opcode: u8, len: usize, data: &mut Vec<u8>) {
+    data.push(0x80 | opcode);
+    match len {
+        len if len < 126 => data.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            data.push(126);
+            data.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            data.push(127);
+            data.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}